@@ -1,35 +1,187 @@
 use std::error::Error;
 use std::fmt;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    General,
+    /// The source text could not be parsed.
+    Parse,
+    /// A special form or parameter list was malformed.
+    Syntax,
+    /// A variable was referenced before being defined.
+    UndefinedName,
+    /// A function was called with arguments of the wrong type, or something that isn't
+    /// a function was called.
+    Type,
+    /// A user-defined function was called with the wrong number of arguments.
+    Arity,
+    /// A cell reference or range was malformed.
+    Reference,
+    /// Evaluation was stopped because it exceeded its step budget.
+    Timeout,
+    /// Evaluation was stopped because it exceeded its maximum call depth.
+    StackOverflow,
+    /// Raised by the formula itself, with `error` or `raise`.
+    User,
+}
+
+impl ErrorKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::General => "general",
+            ErrorKind::Parse => "parse",
+            ErrorKind::Syntax => "syntax",
+            ErrorKind::UndefinedName => "undefined-name",
+            ErrorKind::Type => "type",
+            ErrorKind::Arity => "arity",
+            ErrorKind::Reference => "reference",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::StackOverflow => "stack-overflow",
+            ErrorKind::User => "user",
+        }
+    }
+}
+
+/// A range of byte offsets into the source text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A function that was being evaluated when an error occurred. Consecutive calls to
+/// the same function (i.e. recursion) are collapsed into one frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackFrame {
+    pub function: String,
+    pub count: usize,
+}
+
+/// Boxed so that `AppResult`s stay small; they are returned from deeply recursive
+/// code like the compiler.
+#[derive(Clone, Debug, PartialEq)]
 pub struct AppError {
+    inner: Box<AppErrorInner>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct AppErrorInner {
+    kind: ErrorKind,
     message: String,
+    span: Option<Span>,
+    /// Innermost frame first. `None` until the evaluator has recorded a trace, so that
+    /// the innermost point of failure wins when the error propagates outwards.
+    stack_trace: Option<Vec<StackFrame>>,
 }
 
 impl AppError {
     pub fn new<T: ToString>(message: T) -> Self {
+        Self::with_kind(ErrorKind::General, message)
+    }
+
+    pub fn with_kind<T: ToString>(kind: ErrorKind, message: T) -> Self {
         Self {
-            message: message.to_string(),
+            inner: Box::new(AppErrorInner {
+                kind,
+                message: message.to_string(),
+                span: None,
+                stack_trace: None,
+            }),
         }
     }
-}
 
-impl std::cmp::PartialEq for AppError {
-    fn eq(&self, _other: &AppError) -> bool {
-        false
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.inner.span = Some(span);
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.inner.kind
+    }
+
+    pub fn message(&self) -> &str {
+        &self.inner.message
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.inner.span
+    }
+
+    pub fn stack_trace(&self) -> &[StackFrame] {
+        self.inner.stack_trace.as_deref().unwrap_or(&[])
+    }
+
+    pub fn has_stack_trace(&self) -> bool {
+        self.inner.stack_trace.is_some()
+    }
+
+    pub fn set_stack_trace(&mut self, stack_trace: Vec<StackFrame>) {
+        self.inner.stack_trace = Some(stack_trace);
+    }
+
+    pub fn set_kind(&mut self, kind: ErrorKind) {
+        self.inner.kind = kind;
     }
 }
 
+/// `{}` shows just the message; `{:#}` also shows where the error happened, e.g.
+/// `Cannot take the car of an empty list (in car, called from second)`.
 impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.message)
+        write!(f, "{}", self.inner.message)?;
+        if !f.alternate() {
+            return Ok(());
+        }
+        for (i, frame) in self.stack_trace().iter().enumerate() {
+            write!(
+                f,
+                "{}{}",
+                if i == 0 { " (in " } else { ", called from " },
+                frame.function
+            )?;
+            if frame.count > 1 {
+                write!(f, " ×{}", frame.count)?;
+            }
+        }
+        if !self.stack_trace().is_empty() {
+            write!(f, ")")?;
+        }
+        Ok(())
     }
 }
 
 impl Error for AppError {
     fn description(&self) -> &str {
-        &self.message
+        &self.inner.message
     }
 }
 
 pub type AppResult<T> = Result<T, AppError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let mut err = AppError::with_kind(ErrorKind::Type, "Bad arguments for `car`");
+        assert_eq!(format!("{:#}", err), "Bad arguments for `car`");
+        err.set_stack_trace(vec![
+            StackFrame {
+                function: "car".into(),
+                count: 1,
+            },
+            StackFrame {
+                function: "f".into(),
+                count: 3,
+            },
+        ]);
+        assert_eq!(err.to_string(), "Bad arguments for `car`");
+        assert_eq!(
+            format!("{:#}", err),
+            "Bad arguments for `car` (in car, called from f ×3)"
+        );
+        assert_eq!(err, err.clone());
+        assert_ne!(err, AppError::new("Bad arguments for `car`"));
+    }
+}
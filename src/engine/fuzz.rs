@@ -0,0 +1,257 @@
+//! Throws generated and mangled formulas at the parsers, the compiler and the evaluator,
+//! checking that whatever they make of them, they never panic. A panic in wasm takes
+//! down the whole sheet, whereas an error only affects one cell.
+
+use crate::interpreter::{compile_with_prelude, eval_with_stats, Definitions, EvalLimits, Value};
+use crate::parser::{interpret_cell, InterpretCellResult};
+use crate::sheet::{Sheet, SheetAddress};
+
+/// A small deterministic PRNG (xorshift64*), so that failures can be reproduced from the
+/// seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Zero is a fixed point of xorshift.
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[self.below(items.len())]
+    }
+}
+
+const HEADS: &[&str] = &[
+    "if",
+    "begin",
+    "def",
+    "defun",
+    "lambda",
+    "let",
+    "let*",
+    "letrec",
+    "quote",
+    "quasiquote",
+    "unquote",
+    "unquote-splicing",
+    "defmacro",
+    "apply",
+    "cond",
+    "and",
+    "or",
+    "+",
+    "-",
+    "*",
+    "/",
+    "<",
+    ">",
+    "=",
+    "list",
+    "car",
+    "cdr",
+    "cons",
+    "map",
+    "filter",
+    "reduce",
+    "sum",
+    "concat",
+    "nth",
+    "f",
+    "x",
+    "try",
+    "catch",
+    "error",
+    "raise",
+    "error-message",
+];
+
+const ATOMS: &[&str] = &[
+    "0",
+    "1",
+    "-1",
+    "2.5",
+    "1e308",
+    "-1e308",
+    "\"\"",
+    "\"a\"",
+    "#t",
+    "#f",
+    "nil",
+    "x",
+    "y",
+    "f",
+    "&optional",
+    "&rest",
+    ":a1",
+    ":b2",
+    ":a1-a3",
+    ":zz99",
+    "+",
+    "map",
+    "'x",
+    "`(x ,y)",
+];
+
+const INFIX_TOKENS: &[&str] = &[
+    "A1", "B2", "A1:B3", "1", "2.5", "\"s\"", "TRUE", "SUM(", "IF(", "MAX(", "F(", "(", ")", ",",
+    "+", "-", "*", "/", "^", "&", "=", "<>", "<", ">=", "%", ":", " ",
+];
+
+const GARBAGE: &[u8] = b"()[]{}#=:;'`,@&+-*/^<>%!\"\\ \n\tabcxyzAB019.eE";
+
+fn lisp_expr(rng: &mut Rng, depth: usize) -> String {
+    if depth == 0 || rng.below(3) == 0 {
+        return rng.pick(ATOMS).to_string();
+    }
+    let mut parts = vec![rng.pick(HEADS).to_string()];
+    for _ in 0..rng.below(4) {
+        parts.push(lisp_expr(rng, depth - 1));
+    }
+    format!("({})", parts.join(" "))
+}
+
+fn infix_expr(rng: &mut Rng) -> String {
+    (0..1 + rng.below(12))
+        .map(|_| rng.pick(INFIX_TOKENS))
+        .collect()
+}
+
+fn garbage(rng: &mut Rng) -> String {
+    (0..rng.below(20))
+        .map(|_| GARBAGE[rng.below(GARBAGE.len())] as char)
+        .collect()
+}
+
+/// Delete, duplicate or insert a character somewhere in `s`.
+fn mutate(rng: &mut Rng, s: &str) -> String {
+    let mut chars: Vec<char> = s.chars().collect();
+    let at = rng.below(chars.len() + 1);
+    match rng.below(3) {
+        0 if at < chars.len() => {
+            chars.remove(at);
+        }
+        1 if at < chars.len() => {
+            let c = chars[at];
+            chars.insert(at, c);
+        }
+        _ => chars.insert(at, GARBAGE[rng.below(GARBAGE.len())] as char),
+    }
+    chars.into_iter().collect()
+}
+
+fn cell_contents(rng: &mut Rng) -> String {
+    let contents = match rng.below(4) {
+        0 => format!("#={}", lisp_expr(rng, 4)),
+        1 => format!("={}", infix_expr(rng)),
+        2 => garbage(rng),
+        _ => format!("#={}", lisp_expr(rng, 2)),
+    };
+    if rng.below(2) == 0 {
+        mutate(rng, &contents)
+    } else {
+        contents
+    }
+}
+
+struct Resolver;
+
+impl crate::interpreter::KeywordResolver for Resolver {
+    fn resolve_keyword(&self, _kw: &str) -> crate::error::AppResult<Value> {
+        Ok(Value::Number(1.0))
+    }
+}
+
+/// Parse, compile and evaluate `contents`; any outcome but a panic is fine.
+fn run(contents: &str) {
+    let limits = EvalLimits {
+        max_steps: 10_000,
+        max_call_depth: 50,
+    };
+    if let Ok(InterpretCellResult::Expr(expr, _)) = interpret_cell(contents) {
+        if let Ok(program) = compile_with_prelude(&expr, &Definitions::default()) {
+            let _ = eval_with_stats(&program, Definitions::default().env(), &Resolver, limits);
+        }
+    }
+}
+
+fn fuzz(seed: u64, iterations: usize) {
+    let mut rng = Rng::new(seed);
+    for _ in 0..iterations {
+        let contents = cell_contents(&mut rng);
+        // Report what was being run if it panics.
+        let result = std::panic::catch_unwind(|| run(&contents));
+        assert!(result.is_ok(), "Panicked on {:?}", contents);
+    }
+}
+
+#[test]
+fn test_fuzz_formulas() {
+    for src in &[
+        "#=(begin)",
+        "#=(lambda)",
+        "#=()",
+        "=",
+        "#=(apply)",
+        "#=(if)",
+    ] {
+        run(src);
+    }
+    // Nesting deep enough to overflow the stack if nothing limited it.
+    let deep = 1_000;
+    for src in &[
+        format!("#={}1{}", "(+ 1 ".repeat(deep), ")".repeat(deep)),
+        format!("#={}x", "'".repeat(deep)),
+        format!("#={}x{}", "`(,".repeat(deep), "))".repeat(deep)),
+        format!("#={}x{}", "(lambda (x) ".repeat(deep), ")".repeat(deep)),
+        format!("={}1{}", "(".repeat(deep), ")".repeat(deep)),
+        format!("={}1", "-".repeat(deep)),
+        format!("=1{}", "*2".repeat(deep)),
+        format!("=(and {})", "1 ".repeat(deep)),
+        format!("#=(and {})", "#t ".repeat(deep)),
+    ] {
+        run(src);
+    }
+    fuzz(1, 2_000);
+}
+
+#[test]
+fn test_fuzz_sheet() {
+    let mut rng = Rng::new(2);
+    let mut sheet = Sheet::new();
+    for _ in 0..300 {
+        let address = SheetAddress {
+            row: rng.below(3) as i32,
+            col: rng.below(3) as i32,
+        };
+        // References between the nine cells are likely, cycles included.
+        let contents = match rng.below(3) {
+            0 => format!("#=(+ :a1 :b2 {})", lisp_expr(&mut rng, 2)),
+            1 => format!("=SUM(A1:C3)+{}", infix_expr(&mut rng)),
+            _ => cell_contents(&mut rng),
+        };
+        let _ = sheet.set_cell(&address, contents);
+    }
+}
+
+/// A longer run, for after changing the compiler or evaluator. Use
+/// `cargo test -- --ignored fuzz` and set `FUZZ_SEED` to explore other inputs.
+#[test]
+#[ignore]
+fn test_fuzz_formulas_long() {
+    let seed = std::env::var("FUZZ_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or(3);
+    fuzz(seed, 500_000);
+}
@@ -0,0 +1,320 @@
+use nom::{
+    branch::alt,
+    bytes::complete::{take_while, take_while1},
+    character::complete::{char, multispace0},
+    combinator::{cut, map_res, recognize, verify},
+    error::{context, VerboseError},
+    multi::separated_list0,
+    number::complete::recognize_float,
+    sequence::{delimited, pair, preceded},
+};
+
+use crate::error::{AppResult, Span};
+use crate::parser::{
+    consumed_span, describe_parse_error, parse_string, positioned_parse_error, resolve_spans,
+    spanned, Expr, ExprKind, ExprParseResult, Nesting, ParseResult,
+};
+
+/// Binary operators at each precedence level, lowest first, with the function each one
+/// calls. Longer tokens come first so that `<=` isn't read as `<`.
+const COMPARISON_OPERATORS: &[(&str, &str)] = &[
+    ("<>", "<>"),
+    ("<=", "<="),
+    (">=", ">="),
+    ("=", "="),
+    ("<", "<"),
+    (">", ">"),
+];
+const ADDITIVE_OPERATORS: &[(&str, &str)] = &[("+", "+"), ("-", "-")];
+const MULTIPLICATIVE_OPERATORS: &[(&str, &str)] = &[("*", "*"), ("/", "/")];
+
+/// Combine `lhs` and `rhs` into `(function lhs rhs)`, spanning both.
+fn binary_expr(operator: Expr, lhs: Expr, rhs: Expr) -> Expr {
+    let span = match (lhs.span, rhs.span) {
+        (Some(lhs_span), Some(rhs_span)) => Some(Span {
+            start: lhs_span.start,
+            end: rhs_span.end,
+        }),
+        _ => None,
+    };
+    // There's no not-equals builtin, so `a <> b` is `(not (= a b))`.
+    let expr = match &operator.kind {
+        ExprKind::Symbol(sym) if sym == "<>" => {
+            let equals = Expr {
+                span,
+                ..Expr::list(vec![
+                    Expr {
+                        span: operator.span,
+                        ..Expr::symbol("=")
+                    },
+                    lhs,
+                    rhs,
+                ])
+            };
+            Expr::list(vec![Expr::symbol("not"), equals])
+        }
+        _ => Expr::list(vec![operator, lhs, rhs]),
+    };
+    Expr { span, ..expr }
+}
+
+/// A left-associative chain of `operand`s separated by `operators`.
+fn parse_binary<'a>(
+    input: &'a str,
+    operators: &[(&str, &'static str)],
+    operand: fn(&'a str) -> ExprParseResult<'a>,
+) -> ExprParseResult<'a> {
+    let (mut rest, mut lhs) = operand(input)?;
+    // Each operator nests the expression so far one level deeper.
+    let mut nesting = Vec::new();
+    loop {
+        let (at_operator, _) = multispace0(rest)?;
+        let (token, function) = match operators
+            .iter()
+            .find(|(token, _)| at_operator.starts_with(token))
+        {
+            Some(operator) => operator,
+            None => return Ok((rest, lhs)),
+        };
+        nesting.push(Nesting::enter(at_operator)?);
+        let after_operator = &at_operator[token.len()..];
+        let operator = Expr {
+            span: Some(consumed_span(at_operator, after_operator)),
+            ..Expr::symbol(*function)
+        };
+        let (after_rhs, rhs) = context("expression after operator", cut(operand))(after_operator)?;
+        lhs = binary_expr(operator, lhs, rhs);
+        rest = after_rhs;
+    }
+}
+
+fn parse_comparison<'a>(input: &'a str) -> ExprParseResult<'a> {
+    parse_binary(input, COMPARISON_OPERATORS, parse_additive)
+}
+
+fn parse_additive<'a>(input: &'a str) -> ExprParseResult<'a> {
+    parse_binary(input, ADDITIVE_OPERATORS, parse_multiplicative)
+}
+
+fn parse_multiplicative<'a>(input: &'a str) -> ExprParseResult<'a> {
+    parse_binary(input, MULTIPLICATIVE_OPERATORS, parse_unary)
+}
+
+/// `-x` is `(- x)`; `+x` is just `x`.
+fn parse_unary<'a>(input: &'a str) -> ExprParseResult<'a> {
+    let (input, _) = multispace0(input)?;
+    let _nesting = Nesting::enter(input)?;
+    if let Some(after_sign) = input.strip_prefix('-') {
+        let (rest, operand) = context("expression after `-`", cut(parse_unary))(after_sign)?;
+        let minus = Expr {
+            span: Some(consumed_span(input, after_sign)),
+            ..Expr::symbol("-")
+        };
+        let expr = Expr {
+            span: Some(consumed_span(input, rest)),
+            ..Expr::list(vec![minus, operand])
+        };
+        return Ok((rest, expr));
+    }
+    if let Some(after_sign) = input.strip_prefix('+') {
+        return context("expression after `+`", cut(parse_unary))(after_sign);
+    }
+    parse_primary(input)
+}
+
+fn parse_number<'a>(input: &'a str) -> ExprParseResult<'a> {
+    // Not nom's `double`, which would also read names like `INFO` as infinity.
+    spanned(map_res(recognize_float, |s: &str| {
+        s.parse::<f64>().map(Expr::number)
+    }))(input)
+}
+
+fn parse_name<'a>(input: &'a str) -> ParseResult<'a, &'a str> {
+    recognize(pair(
+        take_while1(|c: char| c.is_ascii_alphabetic() || c == '_'),
+        take_while(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.'),
+    ))(input)
+}
+
+/// Column letters followed by a row number, like `A1` or `AB12`.
+pub(crate) fn is_cell_reference(name: &str) -> bool {
+    let digits = name.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    digits.len() < name.len() && !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+/// A name, which is one of: a function call `NAME(arg, ...)`, a range `A1:B5` (the
+/// keyword `:a1-b5`), a cell reference `A1` (the keyword `:a1`), `TRUE` or `FALSE`, or a
+/// symbol such as a value defined in the workbook. Names are case-insensitive.
+fn parse_name_expr<'a>(input: &'a str) -> ExprParseResult<'a> {
+    let (after_name, name) = parse_name(input)?;
+    let lowercase = name.to_ascii_lowercase();
+    let name_expr = Expr {
+        span: Some(consumed_span(input, after_name)),
+        ..Expr::symbol(lowercase.clone())
+    };
+
+    let (at_paren, _) = multispace0(after_name)?;
+    if at_paren.starts_with('(') {
+        let (rest, mut args) = delimited(
+            char('('),
+            separated_list0(preceded(multispace0, char(',')), parse_comparison),
+            context("closing paren", cut(preceded(multispace0, char(')')))),
+        )(at_paren)?;
+        args.insert(0, name_expr);
+        let expr = Expr {
+            span: Some(consumed_span(input, rest)),
+            ..Expr::list(args)
+        };
+        return Ok((rest, expr));
+    }
+
+    if is_cell_reference(name) {
+        if let Some(after_colon) = after_name.strip_prefix(':') {
+            let (rest, end) = context(
+                "cell reference after `:`",
+                cut(verify(parse_name, |end: &str| is_cell_reference(end))),
+            )(after_colon)?;
+            let expr = Expr {
+                span: Some(consumed_span(input, rest)),
+                ..Expr::keyword(format!("{}-{}", lowercase, end.to_ascii_lowercase()))
+            };
+            return Ok((rest, expr));
+        }
+        let expr = Expr {
+            span: name_expr.span,
+            ..Expr::keyword(lowercase)
+        };
+        return Ok((after_name, expr));
+    }
+
+    let expr = match lowercase.as_str() {
+        "true" => Expr::boolean(true),
+        "false" => Expr::boolean(false),
+        _ => return Ok((after_name, name_expr)),
+    };
+    Ok((
+        after_name,
+        Expr {
+            span: name_expr.span,
+            ..expr
+        },
+    ))
+}
+
+fn parse_primary<'a>(input: &'a str) -> ExprParseResult<'a> {
+    let (input, _) = multispace0(input)?;
+    if input.starts_with('(') {
+        return delimited(
+            char('('),
+            parse_comparison,
+            context("closing paren", cut(preceded(multispace0, char(')')))),
+        )(input);
+    }
+    context("expression", |input| {
+        alt((parse_number, spanned(parse_string), parse_name_expr))(input)
+    })(input)
+}
+
+/// Parse an Excel-style formula such as `A1*2+SUM(B1:B5)` from `src` (a suffix of
+/// `source`, against which spans are measured) into the equivalent Lisp expression,
+/// here `(+ (* :a1 2) (sum :b1-b5))`.
+///
+/// From lowest to highest precedence there are comparisons (`=`, `<>`, `<`, `>`, `<=`,
+/// `>=`), then `+` and `-`, then `*` and `/`, then unary `-`. Strings use the same
+/// escapes as in Lisp.
+pub fn parse_infix_in(src: &str, source: &str) -> AppResult<Expr> {
+    let (rest, mut expr) =
+        parse_comparison(src).map_err(|err| describe_parse_error(source, err))?;
+    resolve_spans(&mut expr, source.len());
+    let (rest, _) = multispace0::<_, VerboseError<&str>>(rest).unwrap();
+    if !rest.is_empty() {
+        let start = source.len() - rest.len();
+        let end = start + rest.chars().next().map_or(0, char::len_utf8);
+        return Err(positioned_parse_error(
+            source,
+            Span { start, end },
+            "expected an operator or the end of the formula",
+        ));
+    }
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn infix(src: &str) -> Expr {
+        parse_infix_in(src, src).unwrap()
+    }
+
+    #[test]
+    fn test_infix_lowering() {
+        assert_eq!(
+            infix("A1*2+SUM(B1:B5)"),
+            parse("(+ (* :a1 2) (sum :b1-b5))").unwrap()
+        );
+        assert_eq!(infix("1 - 2 - 3"), parse("(- (- 1 2) 3)").unwrap());
+        assert_eq!(infix("1 + 2 * 3"), parse("(+ 1 (* 2 3))").unwrap());
+        assert_eq!(infix("(1 + 2) * 3"), parse("(* (+ 1 2) 3)").unwrap());
+        assert_eq!(infix("-A1 / +2"), parse("(/ (- :a1) 2)").unwrap());
+        assert_eq!(infix("a1+1 >= 2"), parse("(>= (+ :a1 1) 2)").unwrap());
+        assert_eq!(infix("A1 <> B2"), parse("(not (= :a1 :b2))").unwrap());
+        assert_eq!(
+            infix(r#"IF(A1 < 0, "negative", Round(A1, 2))"#),
+            parse(r#"(if (< :a1 0) "negative" (round :a1 2))"#).unwrap()
+        );
+        assert_eq!(infix("TRUE"), Expr::boolean(true));
+        assert_eq!(infix("rate * 2"), parse("(* rate 2)").unwrap());
+        assert_eq!(infix("NOW()"), parse("(now)").unwrap());
+        assert_eq!(infix("INFO"), Expr::symbol("info"));
+        assert_eq!(infix("1.5e3"), Expr::number(1500.0));
+    }
+
+    #[test]
+    fn test_infix_spans() {
+        let expr = infix("A1 * 2 + B1");
+        assert_eq!(expr.span, Some(Span { start: 0, end: 11 }));
+        let elems = expr.as_list().unwrap();
+        assert_eq!(elems[0].span, Some(Span { start: 7, end: 8 }));
+        assert_eq!(elems[1].span, Some(Span { start: 0, end: 6 }));
+        assert_eq!(elems[2].span, Some(Span { start: 9, end: 11 }));
+
+        let expr = infix("SUM(A1:B5)");
+        assert_eq!(
+            expr.as_list().unwrap()[1].span,
+            Some(Span { start: 4, end: 9 })
+        );
+    }
+
+    #[test]
+    fn test_infix_errors() {
+        let err = parse_infix_in("1 +", "1 +").unwrap_err();
+        assert!(
+            err.message().contains("column 4: expected expression"),
+            "{}",
+            err
+        );
+        let err = parse_infix_in("1 2", "1 2").unwrap_err();
+        assert_eq!(err.span(), Some(Span { start: 2, end: 3 }));
+        assert!(parse_infix_in("SUM(1, 2", "SUM(1, 2")
+            .unwrap_err()
+            .message()
+            .contains("expected closing paren"));
+        assert!(parse_infix_in("A1:foo", "A1:foo").is_err());
+
+        // Deep nesting is an error rather than a stack overflow.
+        let deep = [
+            format!("{}1{}", "(".repeat(1_000), ")".repeat(1_000)),
+            format!("{}1", "-".repeat(1_000)),
+            format!("1{}", "+1".repeat(1_000)),
+            format!("{}1{}", "F(".repeat(1_000), ")".repeat(1_000)),
+        ];
+        for src in &deep {
+            let err = parse_infix_in(src, src).unwrap_err();
+            assert!(err.message().contains("less deeply nested"), "{}", err);
+        }
+        let src = format!("1{}", "+1".repeat(50));
+        assert!(parse_infix_in(&src, &src).is_ok());
+    }
+}
@@ -1,20 +1,76 @@
 use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
 use std::iter::Extend;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 use std::thread_local;
 
-use crate::error::{AppError, AppResult};
+use crate::error::{AppError, AppResult, ErrorKind};
 
+use super::decimal::{Decimal, RoundingMode};
 use super::env::Env;
-use super::model::Value;
+use super::evaluator::{call_function, EmptyKeywordResolver};
+use super::model::{MapKey, Value};
+use super::random::Random;
 
 use crate::parser;
 
+/// Access to the evaluator for built-ins that take functions as arguments, such as `map`.
+pub trait CallContext {
+    /// Call a function value (built-in or user-defined) to completion.
+    fn call_function(&mut self, func: Value, args: Vec<Value>) -> AppResult<Value>;
+
+    /// A random number in [0, 1) from the evaluation's seeded generator.
+    fn random(&mut self) -> f64;
+
+    /// How `round` breaks ties, and how floats are rounded to their displayed digits
+    /// before any rounding.
+    fn rounding_mode(&self) -> RoundingMode;
+}
+
+/// Calls functions on a fresh machine, for when a built-in is called from outside the
+/// evaluator. Cell references in the functions called resolve to nil.
+struct StandaloneCallContext;
+
+thread_local! {
+    /// Random numbers for built-ins called outside of any evaluation.
+    static STANDALONE_RANDOM: RefCell<Random> = RefCell::new(Random::new(0));
+}
+
+impl CallContext for StandaloneCallContext {
+    fn call_function(&mut self, func: Value, args: Vec<Value>) -> AppResult<Value> {
+        call_function(func, args, &EmptyKeywordResolver)
+    }
+
+    /// There's no evaluation to draw from, so numbers come from a generator shared by
+    /// all such calls (on this thread), seeded with 0.
+    fn random(&mut self) -> f64 {
+        STANDALONE_RANDOM.with(|random| random.borrow_mut().next_f64())
+    }
+
+    fn rounding_mode(&self) -> RoundingMode {
+        RoundingMode::default()
+    }
+}
+
 pub trait BuiltinFunction: Sync {
     fn name(&self) -> String;
     fn call(&self, args: Vec<Value>) -> AppResult<Value>;
+
+    /// Call the built-in from within the evaluator, which `ctx` gives access to. Only
+    /// built-ins that call other functions need to override this.
+    fn call_with_context(&self, _ctx: &mut dyn CallContext, args: Vec<Value>) -> AppResult<Value> {
+        self.call(args)
+    }
+
+    /// Whether calls with the same arguments can give different results (e.g. random
+    /// numbers), so that formulas calling the built-in have to be recalculated even if
+    /// nothing they refer to has changed.
+    fn is_volatile(&self) -> bool {
+        false
+    }
 }
 
 impl fmt::Debug for dyn BuiltinFunction {
@@ -30,6 +86,30 @@ impl std::cmp::PartialEq for dyn BuiltinFunction {
 }
 
 macro_rules! define_builtin_function {
+    ($struct_name:ident, $string_name:expr, volatile, $ctx:ident, $args:ident => $body:expr) => {
+        struct $struct_name;
+        impl BuiltinFunction for $struct_name {
+            fn name(&self) -> String {
+                String::from($string_name)
+            }
+
+            fn call(&self, args: Vec<Value>) -> AppResult<Value> {
+                self.call_with_context(&mut StandaloneCallContext, args)
+            }
+
+            fn call_with_context(
+                &self,
+                $ctx: &mut dyn CallContext,
+                $args: Vec<Value>,
+            ) -> AppResult<Value> {
+                $body
+            }
+
+            fn is_volatile(&self) -> bool {
+                true
+            }
+        }
+    };
     ($struct_name:ident, $string_name:expr, $args:ident => $body:expr) => {
         struct $struct_name;
         impl BuiltinFunction for $struct_name {
@@ -42,41 +122,362 @@ macro_rules! define_builtin_function {
             }
         }
     };
+    ($struct_name:ident, $string_name:expr, $ctx:ident, $args:ident => $body:expr) => {
+        struct $struct_name;
+        impl BuiltinFunction for $struct_name {
+            fn name(&self) -> String {
+                String::from($string_name)
+            }
+
+            fn call(&self, args: Vec<Value>) -> AppResult<Value> {
+                self.call_with_context(&mut StandaloneCallContext, args)
+            }
+
+            fn call_with_context(
+                &self,
+                $ctx: &mut dyn CallContext,
+                $args: Vec<Value>,
+            ) -> AppResult<Value> {
+                $body
+            }
+        }
+    };
 }
 
-define_builtin_function!(Plus, "+", args => {
-    let mut accum: f32 = 0.0;
+/// Flatten nested lists and vectors (as produced by 1D and 2D ranges) into their leaf
+/// values, skipping nil (empty cells).
+fn flatten_values(args: Vec<Value>, out: &mut Vec<Value>) {
+    for arg in args {
+        match arg {
+            Value::List(list) => flatten_values(list, out),
+            Value::Vector(elems) => flatten_values(elems.into_iter().collect(), out),
+            Value::Nil => {}
+            _ => out.push(arg),
+        }
+    }
+}
+
+fn to_decimal(value: &Value, func_name: &str) -> AppResult<Decimal> {
+    match value {
+        Value::Number(n) => Decimal::from_f64(*n),
+        Value::Decimal(d) => Ok(*d),
+        _ => Err(AppError::with_kind(
+            ErrorKind::Type,
+            format!("Bad arguments for `{}`", func_name),
+        )),
+    }
+}
+
+/// Shared implementation of `+` and `sum`. If any argument is a decimal the whole
+/// computation is done exactly in decimal; otherwise floats are used.
+fn add_values(args: Vec<Value>, func_name: &str) -> AppResult<Value> {
+    if args.iter().any(|arg| matches!(arg, Value::Decimal(_))) {
+        let mut accum = Decimal::new(0, 0);
+        for arg in args {
+            accum = accum.checked_add(&to_decimal(&arg, func_name)?)?;
+        }
+        return Ok(Value::Decimal(accum));
+    }
+    let mut accum: f64 = 0.0;
     for arg in args {
         match arg {
             Value::Number(n) => {
                 accum += n;
             }
-            _ => return Err(AppError::new("Bad arguments for `+`"))
+            _ => {
+                return Err(AppError::with_kind(
+                    ErrorKind::Type,
+                    format!("Bad arguments for `{}`", func_name),
+                ))
+            }
         }
     }
     Ok(Value::Number(accum))
+}
+
+define_builtin_function!(Plus, "+", args => {
+    add_values(args, "+")
 });
 
 define_builtin_function!(Mult, "*", args => {
-    let mut accum: f32 = 1.0;
+    if args.iter().any(|arg| matches!(arg, Value::Decimal(_))) {
+        let mut accum = Decimal::new(1, 0);
+        for arg in args {
+            accum = accum.checked_mul(&to_decimal(&arg, "*")?)?;
+        }
+        return Ok(Value::Decimal(accum));
+    }
+    let mut accum: f64 = 1.0;
     for arg in args {
         match arg {
             Value::Number(n) => {
                 accum *= n;
             }
-            _ => return Err(AppError::new("Bad arguments for `*`"))
+            _ => return Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `*`"))
         }
     }
     Ok(Value::Number(accum))
 });
 
+// `(- x)` negates; `(- x y z)` subtracts `y` and `z` from `x`.
+define_builtin_function!(Minus, "-", args => {
+    if args.iter().any(|arg| matches!(arg, Value::Decimal(_))) {
+        let decimals = args
+            .iter()
+            .map(|arg| to_decimal(arg, "-"))
+            .collect::<AppResult<Vec<_>>>()?;
+        return match decimals.split_first() {
+            Some((first, [])) => Ok(Value::Decimal(first.checked_neg()?)),
+            Some((first, rest)) => {
+                let mut accum = *first;
+                for d in rest {
+                    accum = accum.checked_sub(d)?;
+                }
+                Ok(Value::Decimal(accum))
+            }
+            None => Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `-`: expected at least 1 argument")),
+        };
+    }
+    let numbers = args
+        .iter()
+        .map(|arg| match arg {
+            Value::Number(n) => Ok(*n),
+            _ => Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `-`")),
+        })
+        .collect::<AppResult<Vec<_>>>()?;
+    match numbers.split_first() {
+        Some((first, [])) => Ok(Value::Number(-first)),
+        Some((first, rest)) => Ok(Value::Number(rest.iter().fold(*first, |accum, n| accum - n))),
+        None => Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `-`: expected at least 1 argument")),
+    }
+});
+
+// `(/ x y z)` divides `x` by `y` and then by `z`. Dividing by zero is an error rather
+// than infinity, like a spreadsheet's `#DIV/0!`.
+define_builtin_function!(Divide, "/", args => {
+    let bad_args = || AppError::with_kind(ErrorKind::Type, "Bad arguments for `/`: expected at least 2 numbers");
+    if args.len() < 2 {
+        return Err(bad_args());
+    }
+    if args.iter().any(|arg| matches!(arg, Value::Decimal(_))) {
+        let mut accum = to_decimal(&args[0], "/")?;
+        for arg in &args[1..] {
+            accum = accum.checked_div(&to_decimal(arg, "/")?)?;
+        }
+        return Ok(Value::Decimal(accum));
+    }
+    let mut numbers = args.iter().map(|arg| match arg {
+        Value::Number(n) => Ok(*n),
+        _ => Err(bad_args()),
+    });
+    let mut accum = numbers.next().unwrap()?;
+    for n in numbers {
+        let n = n?;
+        if n == 0.0 {
+            return Err(AppError::new("Division by zero"));
+        }
+        accum /= n;
+    }
+    Ok(Value::Number(accum))
+});
+
+define_builtin_function!(Sum, "sum", args => {
+    let mut values = Vec::new();
+    flatten_values(args, &mut values);
+    add_values(values, "sum")
+});
+
+fn value_to_decimal(value: Value) -> AppResult<Value> {
+    match value {
+        Value::Number(n) => Ok(Value::Decimal(Decimal::from_f64(n)?)),
+        Value::Decimal(_) | Value::Nil => Ok(value),
+        Value::String(s) => Ok(Value::Decimal(Decimal::parse(&s)?)),
+        Value::List(list) => Ok(Value::List(
+            list.into_iter()
+                .map(value_to_decimal)
+                .collect::<AppResult<Vec<_>>>()?,
+        )),
+        Value::Vector(elems) => Ok(Value::Vector(
+            elems
+                .into_iter()
+                .map(value_to_decimal)
+                .collect::<AppResult<imbl::Vector<_>>>()?,
+        )),
+        _ => Err(AppError::with_kind(
+            ErrorKind::Type,
+            "Bad arguments for `dec`: expected a number, string, list or vector",
+        )),
+    }
+}
+
+define_builtin_function!(Dec, "dec", args => {
+    match args.as_slice() {
+        [value] => value_to_decimal(value.clone()),
+        _ => Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `dec`: expected 1 argument")),
+    }
+});
+
+/// Number of significant digits a float is taken to have, as in other spreadsheets.
+const FLOAT_SIGNIFICANT_DIGITS: u32 = 15;
+
+/// Rounding to more digits than this either way leaves any decimal as it is or overflows,
+/// so `digits` are clamped to it before they're converted to an integer.
+const MAX_ROUND_DIGITS: f64 = 1000.0;
+
+/// Shared implementation of `round`, `roundup` and `rounddown`: `(round x [digits])`.
+/// `round` rounds with the evaluation's rounding mode, or one given by name, e.g.
+/// `(round x 2 "half-even")`; `roundup` and `rounddown` pass their own `direction`.
+fn round_value(
+    ctx: &mut dyn CallContext,
+    args: Vec<Value>,
+    func_name: &str,
+    direction: Option<RoundingMode>,
+) -> AppResult<Value> {
+    let bad_args = || {
+        AppError::with_kind(
+            ErrorKind::Type,
+            format!("Bad arguments for `{}`", func_name),
+        )
+    };
+    let default_mode = direction.unwrap_or_else(|| ctx.rounding_mode());
+    let (value, digits, mode) = match args.as_slice() {
+        [value] => (value, 0.0, default_mode),
+        [value, Value::Number(digits)] => (value, *digits, default_mode),
+        [value, Value::Number(digits), Value::String(mode)]
+        | [value, Value::Number(digits), Value::Symbol(mode)]
+            if func_name == "round" =>
+        {
+            (value, *digits, RoundingMode::from_name(mode)?)
+        }
+        _ => return Err(bad_args()),
+    };
+    let digits = digits.clamp(-MAX_ROUND_DIGITS, MAX_ROUND_DIGITS) as i32;
+    match value {
+        Value::Decimal(d) => Ok(Value::Decimal(d.round(digits, mode)?)),
+        // Floats are rounded through their decimal representation so that e.g.
+        // `(round 2.675 2)` gives 2.68 rather than the binary-float answer 2.67. That's
+        // first cut to the digits a float is good for (with the evaluation's rounding
+        // mode), so that `(roundup (+ 0.1 0.2) 1)` is 0.3 rather than 0.4.
+        Value::Number(n) => Ok(Value::Number(
+            Decimal::from_f64(*n)?
+                .round_significant(FLOAT_SIGNIFICANT_DIGITS, ctx.rounding_mode())?
+                .round(digits, mode)?
+                .to_f64(),
+        )),
+        _ => Err(bad_args()),
+    }
+}
+
+define_builtin_function!(Round, "round", ctx, args => {
+    round_value(ctx, args, "round", None)
+});
+
+define_builtin_function!(RoundUp, "roundup", ctx, args => {
+    round_value(ctx, args, "roundup", Some(RoundingMode::Up))
+});
+
+define_builtin_function!(RoundDown, "rounddown", ctx, args => {
+    round_value(ctx, args, "rounddown", Some(RoundingMode::Down))
+});
+
+/// Equality used by `=` and `case`. Numbers compare by value whether they're floats
+/// or decimals; everything else uses structural equality.
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(n), Value::Decimal(d)) | (Value::Decimal(d), Value::Number(n)) => {
+            Decimal::from_f64(*n).is_ok_and(|n| n == *d)
+        }
+        _ => a == b,
+    }
+}
+
+define_builtin_function!(Equals, "=", args => {
+    match args.split_first() {
+        Some((first, rest)) => Ok(Value::Boolean(rest.iter().all(|arg| values_equal(first, arg)))),
+        None => Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `=`: expected at least 1 argument")),
+    }
+});
+
+/// Ordering used by `<`, `>`, `<=` and `>=`: numbers (floats or decimals) by value and
+/// strings alphabetically. Anything else can't be ordered.
+fn compare_values(a: &Value, b: &Value, func_name: &str) -> AppResult<Ordering> {
+    let ordering = match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Number(_), Value::Decimal(_))
+        | (Value::Decimal(_), Value::Number(_))
+        | (Value::Decimal(_), Value::Decimal(_)) => {
+            Some(to_decimal(a, func_name)?.cmp_value(&to_decimal(b, func_name)?))
+        }
+        _ => None,
+    };
+    ordering.ok_or_else(|| {
+        AppError::with_kind(
+            ErrorKind::Type,
+            format!(
+                "Bad arguments for `{}`: cannot compare {} with {}",
+                func_name,
+                a.type_string(),
+                b.type_string()
+            ),
+        )
+    })
+}
+
+/// Whether each adjacent pair of `args` is ordered as `accept` requires, so that e.g.
+/// `(< 1 2 3)` checks `1 < 2` and `2 < 3`.
+fn compare_chain(
+    args: Vec<Value>,
+    func_name: &str,
+    accept: fn(Ordering) -> bool,
+) -> AppResult<Value> {
+    if args.is_empty() {
+        return Err(AppError::with_kind(
+            ErrorKind::Type,
+            format!(
+                "Bad arguments for `{}`: expected at least 1 argument",
+                func_name
+            ),
+        ));
+    }
+    for pair in args.windows(2) {
+        if !accept(compare_values(&pair[0], &pair[1], func_name)?) {
+            return Ok(Value::Boolean(false));
+        }
+    }
+    Ok(Value::Boolean(true))
+}
+
+define_builtin_function!(LessThan, "<", args => {
+    compare_chain(args, "<", |ordering| ordering == Ordering::Less)
+});
+
+define_builtin_function!(GreaterThan, ">", args => {
+    compare_chain(args, ">", |ordering| ordering == Ordering::Greater)
+});
+
+define_builtin_function!(LessOrEqual, "<=", args => {
+    compare_chain(args, "<=", |ordering| ordering != Ordering::Greater)
+});
+
+define_builtin_function!(GreaterOrEqual, ">=", args => {
+    compare_chain(args, ">=", |ordering| ordering != Ordering::Less)
+});
+
+define_builtin_function!(Not, "not", args => {
+    match args.as_slice() {
+        [value] => Ok(Value::Boolean(!matches!(value, Value::Boolean(true)))),
+        _ => Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `not`: expected 1 argument")),
+    }
+});
+
 define_builtin_function!(Show, "show", args => {
-    let arg = args.first().ok_or(AppError::new("Bad arguments for `show`"))?;
+    let arg = args.first().ok_or(AppError::with_kind(ErrorKind::Type, "Bad arguments for `show`"))?;
     Ok(Value::String(format!("{:?}", arg)))
 });
 
 define_builtin_function!(Type, "type", args => {
-    let arg = args.first().ok_or(AppError::new("Bad arguments for `type`"))?;
+    let arg = args.first().ok_or(AppError::with_kind(ErrorKind::Type, "Bad arguments for `type`"))?;
     Ok(Value::String(arg.type_string().to_string()))
 });
 
@@ -90,21 +491,21 @@ define_builtin_function!(Cons, "cons", args => {
                 }
                 Value::Nil => {}
                 _ => {
-                    return Err(AppError::new(
+                    return Err(AppError::with_kind(ErrorKind::Type,
                         "Bad arguments for `cons`: expected nil or a list for second arg",
                     ));
                 }
             }
             Ok(Value::List(result))
         }
-        _ => Err(AppError::new(
+        _ => Err(AppError::with_kind(ErrorKind::Type,
             "Bad arguments for `cons`: expected 2 arguments",
         )),
     }
 });
 
 define_builtin_function!(Car, "car", args => {
-    let arg = args.first().ok_or(AppError::new(
+    let arg = args.first().ok_or(AppError::with_kind(ErrorKind::Type,
         "Bad arguments for `car`: expected 1 argument",
     ))?;
     match arg {
@@ -112,15 +513,19 @@ define_builtin_function!(Car, "car", args => {
             Some(head) => Ok(head.clone()),
             None => Err(AppError::new("Cannot take the car of an empty list")),
         },
+        Value::Vector(elems) => match elems.front() {
+            Some(head) => Ok(head.clone()),
+            None => Err(AppError::new("Cannot take the car of an empty vector")),
+        },
         Value::Nil => Err(AppError::new("Cannot take the car of an empty list")),
-        _ => Err(AppError::new(
-            "Bad arguments for `car`: expected a list as the first argument",
+        _ => Err(AppError::with_kind(ErrorKind::Type,
+            "Bad arguments for `car`: expected a list or vector as the first argument",
         )),
     }
 });
 
 define_builtin_function!(Cdr, "cdr", args => {
-    let arg = args.first().ok_or(AppError::new(
+    let arg = args.first().ok_or(AppError::with_kind(ErrorKind::Type,
         "Bad arguments for `cdr`: expected 1 argument",
     ))?;
     match arg {
@@ -131,25 +536,639 @@ define_builtin_function!(Cdr, "cdr", args => {
                 Err(AppError::new("Cannot take the cdr of an empty list"))
             }
         }
+        // The rest of a vector is a list, so that recursive list functions work on both.
+        Value::Vector(elems) => {
+            if !elems.is_empty() {
+                Ok(Value::List(elems.iter().skip(1).cloned().collect()))
+            } else {
+                Err(AppError::new("Cannot take the cdr of an empty vector"))
+            }
+        }
         Value::Nil => Err(AppError::new("Cannot take the cdr of an empty list")),
-        _ => Err(AppError::new(
-            "Bad arguments for `cdr`: expected a list as the first argument",
+        _ => Err(AppError::with_kind(ErrorKind::Type,
+            "Bad arguments for `cdr`: expected a list or vector as the first argument",
         )),
     }
 });
 
+define_builtin_function!(List, "list", args => {
+    Ok(Value::List(args))
+});
+
+define_builtin_function!(Append, "append", args => {
+    let mut result = Vec::new();
+    for arg in args {
+        match arg {
+            Value::List(list) => result.extend(list),
+            Value::Vector(elems) => result.extend(elems),
+            Value::Nil => {}
+            _ => return Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `append`: expected lists or vectors")),
+        }
+    }
+    Ok(Value::List(result))
+});
+
+static GENSYM_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Generated symbols contain `%`, which the parser doesn't allow in identifiers, so they
+// can never capture (or be captured by) names in user code.
+define_builtin_function!(Gensym, "gensym", args => {
+    let prefix = match args.as_slice() {
+        [] => "g".to_string(),
+        [Value::String(s)] | [Value::Symbol(s)] => s.clone(),
+        _ => return Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `gensym`")),
+    };
+    let n = GENSYM_COUNTER.fetch_add(1, AtomicOrdering::Relaxed);
+    Ok(Value::Symbol(format!("%{}{}", prefix, n)))
+});
+
 define_builtin_function!(NilQ, "nil?", args => {
-    let arg = args.first().ok_or(AppError::new("Bad arguments for `nil?`: expected 1 argument"))?;
+    let arg = args.first().ok_or(AppError::with_kind(ErrorKind::Type, "Bad arguments for `nil?`: expected 1 argument"))?;
     Ok(Value::Boolean(match arg {
         Value::Nil => true,
         Value::List(list) if list.len() == 0 => true,
+        Value::Vector(elems) if elems.is_empty() => true,
         _ => false,
     }))
 });
 
+define_builtin_function!(Vector, "vector", args => {
+    Ok(Value::Vector(args.into_iter().collect()))
+});
+
+/// Build a map from alternating keys and values, as written in a `{...}` literal.
+fn map_from_pairs(args: Vec<Value>, func_name: &str) -> AppResult<Value> {
+    if !args.len().is_multiple_of(2) {
+        return Err(AppError::with_kind(
+            ErrorKind::Type,
+            format!("Bad arguments for `{}`: expected a value for every key", func_name),
+        ));
+    }
+    let mut map = imbl::OrdMap::new();
+    let mut args = args.into_iter();
+    while let (Some(key), Some(value)) = (args.next(), args.next()) {
+        map.insert(MapKey::from_value(&key)?, value);
+    }
+    Ok(Value::Map(map))
+}
+
+define_builtin_function!(MakeMap, "hash-map", args => {
+    map_from_pairs(args, "hash-map")
+});
+
+/// Convert an index argument into a position within a sequence of length `len`, or
+/// `None` if it's out of bounds.
+fn sequence_index(index: &Value, len: usize, func_name: &str) -> AppResult<Option<usize>> {
+    match index {
+        Value::Number(n) if n.fract() == 0.0 => {
+            Ok(if *n >= 0.0 && (*n as usize) < len { Some(*n as usize) } else { None })
+        }
+        _ => Err(AppError::with_kind(
+            ErrorKind::Type,
+            format!("Bad arguments for `{}`: expected an integer index", func_name),
+        )),
+    }
+}
+
+// `(get coll key [default])` looks up a key in a map or an index in a vector or list,
+// returning the default (or nil) if it's missing.
+define_builtin_function!(Get, "get", args => {
+    let (coll, key, default) = match args.as_slice() {
+        [coll, key] => (coll, key, Value::Nil),
+        [coll, key, default] => (coll, key, default.clone()),
+        _ => return Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `get`: expected 2 or 3 arguments")),
+    };
+    let found = match coll {
+        Value::Map(map) => map.get(&MapKey::from_value(key)?).cloned(),
+        Value::Vector(elems) => sequence_index(key, elems.len(), "get")?.map(|i| elems[i].clone()),
+        Value::List(list) => sequence_index(key, list.len(), "get")?.map(|i| list[i].clone()),
+        Value::Nil => None,
+        _ => return Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `get`: expected a map, vector or list")),
+    };
+    Ok(found.unwrap_or(default))
+});
+
+// `(assoc coll key value ...)` returns a copy of a map with the keys set, or of a vector
+// with the indices replaced. A vector may be extended by assigning just past its end.
+define_builtin_function!(Assoc, "assoc", args => {
+    let (coll, pairs) = match args.split_first() {
+        Some((coll, pairs)) if pairs.len() % 2 == 0 => (coll, pairs),
+        _ => return Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `assoc`: expected a collection and key/value pairs")),
+    };
+    match coll {
+        Value::Map(map) => {
+            let mut map = map.clone();
+            for pair in pairs.chunks(2) {
+                map.insert(MapKey::from_value(&pair[0])?, pair[1].clone());
+            }
+            Ok(Value::Map(map))
+        }
+        Value::Nil => map_from_pairs(pairs.to_vec(), "assoc"),
+        Value::Vector(elems) => {
+            let mut elems = elems.clone();
+            for pair in pairs.chunks(2) {
+                match sequence_index(&pair[0], elems.len() + 1, "assoc")? {
+                    Some(i) if i == elems.len() => elems.push_back(pair[1].clone()),
+                    Some(i) => {
+                        elems.set(i, pair[1].clone());
+                    }
+                    None => return Err(AppError::new("Index out of bounds for `assoc`")),
+                }
+            }
+            Ok(Value::Vector(elems))
+        }
+        _ => Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `assoc`: expected a map or vector")),
+    }
+});
+
+define_builtin_function!(Dissoc, "dissoc", args => {
+    match args.split_first() {
+        Some((Value::Map(map), keys)) => {
+            let mut map = map.clone();
+            for key in keys {
+                map.remove(&MapKey::from_value(key)?);
+            }
+            Ok(Value::Map(map))
+        }
+        Some((Value::Nil, _)) => Ok(Value::Nil),
+        _ => Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `dissoc`: expected a map")),
+    }
+});
+
+define_builtin_function!(Keys, "keys", args => {
+    match args.as_slice() {
+        [Value::Map(map)] => Ok(Value::Vector(map.keys().map(MapKey::to_value).collect())),
+        _ => Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `keys`: expected a map")),
+    }
+});
+
+define_builtin_function!(Vals, "vals", args => {
+    match args.as_slice() {
+        [Value::Map(map)] => Ok(Value::Vector(map.values().cloned().collect())),
+        _ => Err(AppError::with_kind(ErrorKind::Type, "Bad arguments for `vals`: expected a map")),
+    }
+});
+
+fn bad_args(func_name: &str, expected: &str) -> AppError {
+    AppError::with_kind(
+        ErrorKind::Type,
+        format!("Bad arguments for `{}`: expected {}", func_name, expected),
+    )
+}
+
+/// Whether a sequence function was given a list (or nil) or a vector. Results are the
+/// same kind as the input, so that e.g. mapping over a range gives a vector.
+#[derive(Clone, Copy)]
+enum SequenceKind {
+    List,
+    Vector,
+}
+
+impl SequenceKind {
+    /// Empty lists are nil, as they were when these functions were written in Lisp.
+    fn make(self, items: Vec<Value>) -> Value {
+        match self {
+            SequenceKind::Vector => Value::Vector(items.into_iter().collect()),
+            SequenceKind::List if items.is_empty() => Value::Nil,
+            SequenceKind::List => Value::List(items),
+        }
+    }
+}
+
+fn sequence_items(value: &Value, func_name: &str) -> AppResult<(Vec<Value>, SequenceKind)> {
+    match value {
+        Value::List(list) => Ok((list.clone(), SequenceKind::List)),
+        Value::Vector(elems) => Ok((elems.iter().cloned().collect(), SequenceKind::Vector)),
+        Value::Nil => Ok((Vec::new(), SequenceKind::List)),
+        _ => Err(bad_args(func_name, &format!("a list or vector, not {}", value.type_string()))),
+    }
+}
+
+/// Corresponding elements of each of `colls`, up to the end of the shortest.
+fn transpose(colls: &[Value], func_name: &str) -> AppResult<(Vec<Vec<Value>>, SequenceKind)> {
+    let mut kind = SequenceKind::List;
+    let mut iters = Vec::with_capacity(colls.len());
+    for (idx, coll) in colls.iter().enumerate() {
+        let (items, coll_kind) = sequence_items(coll, func_name)?;
+        if idx == 0 {
+            kind = coll_kind;
+        }
+        iters.push(items.into_iter());
+    }
+    let mut rows = Vec::new();
+    if iters.is_empty() {
+        return Ok((rows, kind));
+    }
+    while let Some(row) = iters.iter_mut().map(Iterator::next).collect::<Option<Vec<_>>>() {
+        rows.push(row);
+    }
+    Ok((rows, kind))
+}
+
+fn count_arg(value: &Value, func_name: &str) -> AppResult<usize> {
+    match value {
+        Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 => Ok(*n as usize),
+        _ => Err(bad_args(func_name, "a non-negative integer count")),
+    }
+}
+
+// `(map f coll ...)` calls `f` with corresponding elements of each collection.
+define_builtin_function!(Map, "map", ctx, args => {
+    let (func, colls) = match args.split_first() {
+        Some((func, colls)) if !colls.is_empty() => (func, colls),
+        _ => return Err(bad_args("map", "a function and at least one list or vector")),
+    };
+    let (rows, kind) = transpose(colls, "map")?;
+    let results = rows
+        .into_iter()
+        .map(|row| ctx.call_function(func.clone(), row))
+        .collect::<AppResult<Vec<_>>>()?;
+    Ok(kind.make(results))
+});
+
+define_builtin_function!(Filter, "filter", ctx, args => {
+    let (func, coll) = match args.as_slice() {
+        [func, coll] => (func, coll),
+        _ => return Err(bad_args("filter", "a predicate and a list or vector")),
+    };
+    let (items, kind) = sequence_items(coll, "filter")?;
+    let mut results = Vec::new();
+    for item in items {
+        if ctx.call_function(func.clone(), vec![item.clone()])? == Value::Boolean(true) {
+            results.push(item);
+        }
+    }
+    Ok(kind.make(results))
+});
+
+fn fold_values(ctx: &mut dyn CallContext, func: &Value, init: Value, items: Vec<Value>) -> AppResult<Value> {
+    items
+        .into_iter()
+        .try_fold(init, |accum, item| ctx.call_function(func.clone(), vec![accum, item]))
+}
+
+// `(reduce f coll)` combines elements from the left, starting with the first;
+// `(reduce f init coll)` starts with `init` instead, like `fold`.
+define_builtin_function!(Reduce, "reduce", ctx, args => {
+    match args.as_slice() {
+        [func, coll] => {
+            let (mut items, _) = sequence_items(coll, "reduce")?;
+            if items.is_empty() {
+                return Err(AppError::new("Cannot reduce an empty sequence without an initial value"));
+            }
+            let first = items.remove(0);
+            fold_values(ctx, func, first, items)
+        }
+        [func, init, coll] => fold_values(ctx, func, init.clone(), sequence_items(coll, "reduce")?.0),
+        _ => Err(bad_args("reduce", "a function, an optional initial value and a list or vector")),
+    }
+});
+
+define_builtin_function!(Fold, "fold", ctx, args => {
+    match args.as_slice() {
+        [func, init, coll] => fold_values(ctx, func, init.clone(), sequence_items(coll, "fold")?.0),
+        _ => Err(bad_args("fold", "a function, an initial value and a list or vector")),
+    }
+});
+
+/// A stable merge sort, with a comparison that may fail. `less(a, b)` says whether `a`
+/// belongs before `b`.
+fn merge_sort<T>(
+    mut items: Vec<T>,
+    less: &mut dyn FnMut(&T, &T) -> AppResult<bool>,
+) -> AppResult<Vec<T>> {
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+    let right = items.split_off(items.len() / 2);
+    let mut left = merge_sort(items, less)?.into_iter().peekable();
+    let mut right = merge_sort(right, less)?.into_iter().peekable();
+    let mut result = Vec::with_capacity(left.len() + right.len());
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        // Equal elements are taken from the left, so they keep their order.
+        let next = if less(r, l)? { right.next() } else { left.next() };
+        result.push(next.unwrap());
+    }
+    result.extend(left);
+    result.extend(right);
+    Ok(result)
+}
+
+/// Order by `comparator`, a function returning `#t` if its first argument belongs before
+/// its second, or by the natural order of numbers and strings if it's `None`.
+fn is_before(
+    ctx: &mut dyn CallContext,
+    comparator: Option<&Value>,
+    a: &Value,
+    b: &Value,
+    func_name: &str,
+) -> AppResult<bool> {
+    match comparator {
+        Some(func) => Ok(ctx.call_function(func.clone(), vec![a.clone(), b.clone()])? == Value::Boolean(true)),
+        None => Ok(compare_values(a, b, func_name)? == Ordering::Less),
+    }
+}
+
+// `(sort coll [less?])`.
+define_builtin_function!(Sort, "sort", ctx, args => {
+    let (coll, comparator) = match args.as_slice() {
+        [coll] => (coll, None),
+        [coll, comparator] => (coll, Some(comparator)),
+        _ => return Err(bad_args("sort", "a list or vector and an optional comparator")),
+    };
+    let (items, kind) = sequence_items(coll, "sort")?;
+    let sorted = merge_sort(items, &mut |a, b| is_before(ctx, comparator, a, b, "sort"))?;
+    Ok(kind.make(sorted))
+});
+
+// `(sort-by key-fn coll [less?])` orders elements by the result of calling `key-fn` on
+// each, which is called once per element.
+define_builtin_function!(SortBy, "sort-by", ctx, args => {
+    let (key_func, coll, comparator) = match args.as_slice() {
+        [key_func, coll] => (key_func, coll, None),
+        [key_func, coll, comparator] => (key_func, coll, Some(comparator)),
+        _ => return Err(bad_args("sort-by", "a key function, a list or vector and an optional comparator")),
+    };
+    let (items, kind) = sequence_items(coll, "sort-by")?;
+    let keyed = items
+        .into_iter()
+        .map(|item| Ok((ctx.call_function(key_func.clone(), vec![item.clone()])?, item)))
+        .collect::<AppResult<Vec<_>>>()?;
+    let sorted = merge_sort(keyed, &mut |(a, _), (b, _)| is_before(ctx, comparator, a, b, "sort-by"))?;
+    Ok(kind.make(sorted.into_iter().map(|(_, item)| item).collect()))
+});
+
+/// Longest vector `range` will make, so that a typo can't exhaust memory.
+const MAX_RANGE_LENGTH: usize = 1_000_000;
+
+// `(range end)`, `(range start end)` or `(range start end step)`: a vector of numbers
+// from `start` (by default 0) up to but not including `end`.
+define_builtin_function!(Range, "range", args => {
+    let numbers = args
+        .iter()
+        .map(|arg| match arg {
+            Value::Number(n) => Ok(*n),
+            _ => Err(bad_args("range", "numbers")),
+        })
+        .collect::<AppResult<Vec<_>>>()?;
+    let (start, end, step) = match numbers.as_slice() {
+        [end] => (0.0, *end, 1.0),
+        [start, end] => (*start, *end, 1.0),
+        [start, end, step] => (*start, *end, *step),
+        _ => return Err(bad_args("range", "1 to 3 numbers")),
+    };
+    if step == 0.0 {
+        return Err(AppError::new("The step for `range` cannot be zero"));
+    }
+    let len = ((end - start) / step).ceil().max(0.0);
+    if len.is_nan() || len > MAX_RANGE_LENGTH as f64 {
+        return Err(AppError::new(format!(
+            "`range` would have more than {} elements",
+            MAX_RANGE_LENGTH
+        )));
+    }
+    Ok(Value::Vector(
+        (0..len as usize)
+            .map(|i| Value::Number(start + i as f64 * step))
+            .collect(),
+    ))
+});
+
+// `(zip coll ...)` groups corresponding elements, e.g. `(zip [1 2] [3 4])` is
+// `[[1 3] [2 4]]`.
+define_builtin_function!(Zip, "zip", args => {
+    if args.is_empty() {
+        return Err(bad_args("zip", "at least one list or vector"));
+    }
+    let (rows, kind) = transpose(&args, "zip")?;
+    Ok(kind.make(rows.into_iter().map(|row| kind.make(row)).collect()))
+});
+
+define_builtin_function!(Take, "take", args => {
+    match args.as_slice() {
+        [n, coll] => {
+            let (items, kind) = sequence_items(coll, "take")?;
+            Ok(kind.make(items.into_iter().take(count_arg(n, "take")?).collect()))
+        }
+        _ => Err(bad_args("take", "a count and a list or vector")),
+    }
+});
+
+define_builtin_function!(Drop, "drop", args => {
+    match args.as_slice() {
+        [n, coll] => {
+            let (items, kind) = sequence_items(coll, "drop")?;
+            Ok(kind.make(items.into_iter().skip(count_arg(n, "drop")?).collect()))
+        }
+        _ => Err(bad_args("drop", "a count and a list or vector")),
+    }
+});
+
+define_builtin_function!(Reverse, "reverse", args => {
+    match args.as_slice() {
+        [coll] => {
+            let (items, kind) = sequence_items(coll, "reverse")?;
+            Ok(kind.make(items.into_iter().rev().collect()))
+        }
+        _ => Err(bad_args("reverse", "a list or vector")),
+    }
+});
+
+define_builtin_function!(Length, "length", args => {
+    let len = match args.as_slice() {
+        [Value::List(list)] => list.len(),
+        [Value::Vector(elems)] => elems.len(),
+        [Value::Map(map)] => map.len(),
+        [Value::String(s)] => s.chars().count(),
+        [Value::Nil] => 0,
+        _ => return Err(bad_args("length", "a list, vector, map or string")),
+    };
+    Ok(Value::Number(len as f64))
+});
+
+// Unlike `get`, `(nth coll index)` fails if the index is out of bounds.
+define_builtin_function!(Nth, "nth", args => {
+    match args.as_slice() {
+        [coll, index] => {
+            let (items, _) = sequence_items(coll, "nth")?;
+            match sequence_index(index, items.len(), "nth")? {
+                Some(i) => Ok(items[i].clone()),
+                None => Err(AppError::new(format!(
+                    "Index {:?} is out of bounds for a sequence of length {}",
+                    index,
+                    items.len()
+                ))),
+            }
+        }
+        _ => Err(bad_args("nth", "a list or vector and an index")),
+    }
+});
+
+// `(distinct coll)` keeps the first of each run of structurally equal elements.
+define_builtin_function!(Distinct, "distinct", args => {
+    match args.as_slice() {
+        [coll] => {
+            let (items, kind) = sequence_items(coll, "distinct")?;
+            let mut seen_keys = std::collections::BTreeSet::new();
+            let mut seen_others: Vec<Value> = Vec::new();
+            let mut results = Vec::new();
+            for item in items {
+                // Most elements can be map keys, which are quick to look up.
+                let is_new = match MapKey::from_value(&item) {
+                    Ok(key) => seen_keys.insert(key),
+                    Err(_) if seen_others.contains(&item) => false,
+                    Err(_) => {
+                        seen_others.push(item.clone());
+                        true
+                    }
+                };
+                if is_new {
+                    results.push(item);
+                }
+            }
+            Ok(kind.make(results))
+        }
+        _ => Err(bad_args("distinct", "a list or vector")),
+    }
+});
+
+// `(flatten coll)` replaces nested lists and vectors with their elements, at any depth.
+define_builtin_function!(Flatten, "flatten", args => {
+    match args.as_slice() {
+        [coll] => {
+            let (items, kind) = sequence_items(coll, "flatten")?;
+            let mut results = Vec::new();
+            flatten_values(items, &mut results);
+            Ok(kind.make(results))
+        }
+        _ => Err(bad_args("flatten", "a list or vector")),
+    }
+});
+
+// `(error message)` fails with a user error; `try` can catch it.
+define_builtin_function!(RaiseError, "error", args => {
+    match args.as_slice() {
+        [Value::String(message)] => Err(AppError::with_kind(ErrorKind::User, message)),
+        _ => Err(bad_args("error", "a message")),
+    }
+});
+
+// `(raise e)` fails with an error caught by `try`, as it was (keeping its kind and
+// stack trace). Given a message instead, it's the same as `error`.
+define_builtin_function!(Raise, "raise", args => {
+    match args.as_slice() {
+        [Value::Error(err)] => Err(err.clone()),
+        [Value::String(message)] => Err(AppError::with_kind(ErrorKind::User, message)),
+        _ => Err(bad_args("raise", "an error or a message")),
+    }
+});
+
+define_builtin_function!(ErrorQ, "error?", args => {
+    match args.as_slice() {
+        [value] => Ok(Value::Boolean(matches!(value, Value::Error(_)))),
+        _ => Err(bad_args("error?", "1 argument")),
+    }
+});
+
+// The kind of an error as a string, e.g. "type" or "user".
+define_builtin_function!(ErrorKindOf, "error-kind", args => {
+    match args.as_slice() {
+        [Value::Error(err)] => Ok(Value::String(err.kind().name().to_string())),
+        _ => Err(bad_args("error-kind", "an error")),
+    }
+});
+
+define_builtin_function!(ErrorMessage, "error-message", args => {
+    match args.as_slice() {
+        [Value::Error(err)] => Ok(Value::String(err.message().to_string())),
+        _ => Err(bad_args("error-message", "an error")),
+    }
+});
+
+// `(rand)` is a random number in [0, 1), different every time the formula is
+// recalculated.
+define_builtin_function!(Rand, "rand", volatile, ctx, args => {
+    match args.as_slice() {
+        [] => Ok(Value::Number(ctx.random())),
+        _ => Err(bad_args("rand", "no arguments")),
+    }
+});
+
+// `(randbetween low high)` is a random integer from `low` to `high` inclusive, like
+// Excel's RANDBETWEEN.
+define_builtin_function!(RandBetween, "randbetween", volatile, ctx, args => {
+    match args.as_slice() {
+        [Value::Number(low), Value::Number(high)] if low.is_finite() && high.is_finite() => {
+            let (low, high) = (low.ceil(), high.floor());
+            if low > high {
+                return Err(AppError::new(
+                    "Bad arguments for `randbetween`: there's no integer between the bounds",
+                ));
+            }
+            let n = (ctx.random() * (high - low + 1.0)).floor();
+            // Rounding could reach `high + 1` for huge ranges.
+            Ok(Value::Number((low + n).min(high)))
+        }
+        _ => Err(bad_args("randbetween", "2 numbers")),
+    }
+});
+
 lazy_static! {
-    static ref BUILTIN_FUNCTIONS: Vec<&'static dyn BuiltinFunction> =
-        vec![&Plus, &Mult, &Show, &Cons, &Car, &Type, &NilQ, &Cdr];
+    static ref BUILTIN_FUNCTIONS: Vec<&'static dyn BuiltinFunction> = vec![
+        &Plus,
+        &Mult,
+        &Show,
+        &Cons,
+        &Car,
+        &Type,
+        &NilQ,
+        &Cdr,
+        &Sum,
+        &Dec,
+        &Round,
+        &RoundUp,
+        &RoundDown,
+        &Equals,
+        &List,
+        &Append,
+        &Gensym,
+        &Minus,
+        &Divide,
+        &LessThan,
+        &GreaterThan,
+        &LessOrEqual,
+        &GreaterOrEqual,
+        &Not,
+        &Vector,
+        &MakeMap,
+        &Get,
+        &Assoc,
+        &Dissoc,
+        &Keys,
+        &Vals,
+        &Map,
+        &Filter,
+        &Reduce,
+        &Fold,
+        &Sort,
+        &SortBy,
+        &Range,
+        &Zip,
+        &Take,
+        &Drop,
+        &Reverse,
+        &Length,
+        &Nth,
+        &Distinct,
+        &Flatten,
+        &RaiseError,
+        &Raise,
+        &ErrorQ,
+        &ErrorKindOf,
+        &ErrorMessage,
+        &Rand,
+        &RandBetween,
+    ];
     static ref BUILTIN_FUNCTIONS_BY_NAME: HashMap<String, &'static dyn BuiltinFunction> =
         BUILTIN_FUNCTIONS
             .iter()
@@ -157,15 +1176,20 @@ lazy_static! {
             .collect();
 }
 
+/// The built-in function called `name`, if there is one.
+pub(super) fn lookup_builtin(name: &str) -> Option<&'static dyn BuiltinFunction> {
+    BUILTIN_FUNCTIONS_BY_NAME.get(name).cloned()
+}
+
 thread_local! {
-    pub static BUILTINS_ENVIRONMENT: Rc<RefCell<Env>> = Rc::new(RefCell::new(Env {
-        table: BUILTIN_FUNCTIONS_BY_NAME
+    pub static BUILTINS_ENVIRONMENT: Rc<RefCell<Env>> = Rc::new(RefCell::new(Env::new(
+        BUILTIN_FUNCTIONS_BY_NAME
             .iter()
             .map(|(name, func)| (name.clone(), Value::BuiltinFunction(func.clone())))
             .chain(std::iter::once(("nil".to_string(), Value::Nil)))
             .collect(),
-        parent: None,
-    }));
+        None,
+    )));
 }
 
 #[cfg(test)]
@@ -181,23 +1205,378 @@ mod tests {
         );
         assert_eq!(Plus.name(), "+");
     }
+
+    #[test]
+    fn test_plus_builtin_precision() {
+        assert_eq!(
+            Plus.call(vec![Value::Number(16777216.0), Value::Number(1.0)])
+                .unwrap(),
+            Value::Number(16777217.0)
+        );
+        let cents = (0..1000).map(|_| Value::Number(0.01)).collect();
+        match Plus.call(cents).unwrap() {
+            Value::Number(n) => assert_eq!(format!("{:.2}", n), "10.00"),
+            other => panic!("Expected a number, got {:?}", other),
+        }
+    }
+
+    fn dec(s: &str) -> Value {
+        Value::Decimal(Decimal::parse(s).unwrap())
+    }
+
+    #[test]
+    fn test_decimal_arithmetic() {
+        assert_eq!(
+            Plus.call(vec![dec("0.1"), Value::Number(0.2)]).unwrap(),
+            dec("0.3")
+        );
+        assert_eq!(
+            Mult.call(vec![dec("19.99"), Value::Number(3.0)]).unwrap(),
+            dec("59.97")
+        );
+        let cents = (0..1000).map(|_| dec("0.01")).collect();
+        assert_eq!(
+            Sum.call(vec![Value::List(cents), Value::Nil]).unwrap(),
+            dec("10")
+        );
+        assert_eq!(
+            Dec.call(vec![Value::List(vec![Value::Number(0.1), Value::Nil])])
+                .unwrap(),
+            Value::List(vec![dec("0.1"), Value::Nil])
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_and_comparison_builtins() {
+        let n = Value::Number;
+        assert_eq!(Minus.call(vec![n(5.0)]).unwrap(), n(-5.0));
+        assert_eq!(Minus.call(vec![n(10.0), n(3.0), n(2.0)]).unwrap(), n(5.0));
+        assert_eq!(Minus.call(vec![dec("0.3"), n(0.1)]).unwrap(), dec("0.2"));
+        assert_eq!(Divide.call(vec![n(12.0), n(3.0), n(2.0)]).unwrap(), n(2.0));
+        assert_eq!(Divide.call(vec![dec("1"), n(8.0)]).unwrap(), dec("0.125"));
+        assert_eq!(
+            Divide.call(vec![n(1.0), n(0.0)]).unwrap_err().message(),
+            "Division by zero"
+        );
+
+        assert_eq!(
+            LessThan.call(vec![n(1.0), n(2.0), n(3.0)]).unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            LessThan.call(vec![n(1.0), n(3.0), n(2.0)]).unwrap(),
+            Value::Boolean(false)
+        );
+        assert_eq!(
+            LessOrEqual.call(vec![n(2.0), dec("2.00")]).unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            GreaterThan
+                .call(vec![Value::String("b".into()), Value::String("a".into())])
+                .unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            GreaterOrEqual.call(vec![n(1.0), n(2.0)]).unwrap(),
+            Value::Boolean(false)
+        );
+        assert_eq!(
+            LessThan.call(vec![n(1.0), Value::Nil]).unwrap_err().kind(),
+            ErrorKind::Type
+        );
+        assert_eq!(
+            Not.call(vec![Value::Boolean(false)]).unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            Not.call(vec![Value::Boolean(true)]).unwrap(),
+            Value::Boolean(false)
+        );
+    }
+
+    #[test]
+    fn test_round_builtins() {
+        assert_eq!(
+            Round.call(vec![dec("2.665"), Value::Number(2.0)]).unwrap(),
+            dec("2.67")
+        );
+        assert_eq!(
+            Round
+                .call(vec![
+                    dec("2.665"),
+                    Value::Number(2.0),
+                    Value::String("half-even".into())
+                ])
+                .unwrap(),
+            dec("2.66")
+        );
+        assert_eq!(
+            Round
+                .call(vec![Value::Number(2.675), Value::Number(2.0)])
+                .unwrap(),
+            Value::Number(2.68)
+        );
+        assert_eq!(
+            RoundUp.call(vec![Value::Number(1.01)]).unwrap(),
+            Value::Number(2.0)
+        );
+        assert_eq!(RoundDown.call(vec![dec("-1.99")]).unwrap(), dec("-1"));
+        // Digit counts far out of range are errors rather than overflows.
+        for (value, digits) in &[(5.0, -1e10), (12345.0, -2147483648.0), (1.0, -1e308)] {
+            assert!(Round
+                .call(vec![Value::Number(*value), Value::Number(*digits)])
+                .is_err());
+            assert!(Round.call(vec![dec("5"), Value::Number(*digits)]).is_err());
+        }
+        assert_eq!(
+            Round.call(vec![dec("1.25"), Value::Number(1e10)]).unwrap(),
+            dec("1.25")
+        );
+        assert!(Round
+            .call(vec![
+                dec("1"),
+                Value::Number(0.0),
+                Value::String("sideways".into())
+            ])
+            .is_err());
+        // Floats are cut to 15 significant digits first.
+        assert_eq!(
+            RoundUp
+                .call(vec![Value::Number(0.1 + 0.2), Value::Number(1.0)])
+                .unwrap(),
+            Value::Number(0.3)
+        );
+
+        // Without a mode of its own, `round` uses the evaluation's.
+        struct HalfEvenContext;
+        impl CallContext for HalfEvenContext {
+            fn call_function(&mut self, func: Value, args: Vec<Value>) -> AppResult<Value> {
+                StandaloneCallContext.call_function(func, args)
+            }
+
+            fn random(&mut self) -> f64 {
+                StandaloneCallContext.random()
+            }
+
+            fn rounding_mode(&self) -> RoundingMode {
+                RoundingMode::HalfEven
+            }
+        }
+        let round_half_even = |builtin: &dyn BuiltinFunction, args| {
+            builtin
+                .call_with_context(&mut HalfEvenContext, args)
+                .unwrap()
+        };
+        assert_eq!(round_half_even(&Round, vec![dec("2.5")]), dec("2"));
+        assert_eq!(
+            round_half_even(&Round, vec![Value::Number(2.665), Value::Number(2.0)]),
+            Value::Number(2.66)
+        );
+        assert_eq!(
+            round_half_even(
+                &Round,
+                vec![
+                    dec("2.5"),
+                    Value::Number(0.0),
+                    Value::String("half-up".into())
+                ]
+            ),
+            dec("3")
+        );
+        assert_eq!(round_half_even(&RoundUp, vec![dec("2.1")]), dec("3"));
+    }
+
+    #[test]
+    fn test_map_and_vector_builtins() {
+        let n = Value::Number;
+        let s = |s: &str| Value::String(s.into());
+        let map = MakeMap.call(vec![s("a"), n(1.0), s("b"), n(2.0)]).unwrap();
+        assert_eq!(Get.call(vec![map.clone(), s("b")]).unwrap(), n(2.0));
+        assert_eq!(Get.call(vec![map.clone(), s("c")]).unwrap(), Value::Nil);
+        assert_eq!(Get.call(vec![map.clone(), s("c"), n(0.0)]).unwrap(), n(0.0));
+
+        let map = Assoc.call(vec![map, s("c"), n(3.0)]).unwrap();
+        let map = Dissoc.call(vec![map, s("a")]).unwrap();
+        assert_eq!(
+            Keys.call(vec![map.clone()]).unwrap(),
+            Vector.call(vec![s("b"), s("c")]).unwrap()
+        );
+        assert_eq!(
+            Vals.call(vec![map]).unwrap(),
+            Vector.call(vec![n(2.0), n(3.0)]).unwrap()
+        );
+        assert!(MakeMap.call(vec![s("a")]).is_err());
+        assert!(MakeMap.call(vec![Value::Nil, n(1.0)]).is_err());
+
+        let vector = Vector.call(vec![n(10.0), n(20.0)]).unwrap();
+        assert_eq!(Get.call(vec![vector.clone(), n(1.0)]).unwrap(), n(20.0));
+        assert_eq!(Get.call(vec![vector.clone(), n(2.0)]).unwrap(), Value::Nil);
+        assert!(Get.call(vec![vector.clone(), n(0.5)]).is_err());
+        assert_eq!(
+            Assoc
+                .call(vec![vector.clone(), n(0.0), n(5.0), n(2.0), n(30.0)])
+                .unwrap(),
+            Vector.call(vec![n(5.0), n(20.0), n(30.0)]).unwrap()
+        );
+        assert!(Assoc.call(vec![vector.clone(), n(5.0), n(0.0)]).is_err());
+        assert_eq!(Car.call(vec![vector.clone()]).unwrap(), n(10.0));
+        assert_eq!(
+            Cdr.call(vec![vector.clone()]).unwrap(),
+            Value::List(vec![n(20.0)])
+        );
+        assert_eq!(Sum.call(vec![vector]).unwrap(), n(30.0));
+    }
+
+    #[test]
+    fn test_sequence_builtins() {
+        let n = Value::Number;
+        let list = |items: &[f64]| Value::List(items.iter().cloned().map(n).collect());
+        let vector = |items: &[f64]| Value::Vector(items.iter().cloned().map(n).collect());
+        let plus = Value::BuiltinFunction(&Plus);
+
+        assert_eq!(
+            Map.call(vec![plus.clone(), list(&[1.0, 2.0]), vector(&[10.0, 20.0, 30.0])])
+                .unwrap(),
+            list(&[11.0, 22.0])
+        );
+        assert_eq!(Map.call(vec![plus.clone(), Value::Nil]).unwrap(), Value::Nil);
+        assert_eq!(
+            Reduce.call(vec![plus.clone(), vector(&[1.0, 2.0, 3.0])]).unwrap(),
+            n(6.0)
+        );
+        assert!(Reduce.call(vec![plus.clone(), Value::Nil]).is_err());
+        assert_eq!(
+            Fold.call(vec![Value::BuiltinFunction(&Minus), n(10.0), list(&[1.0, 2.0])])
+                .unwrap(),
+            n(7.0)
+        );
+        assert_eq!(
+            Sort.call(vec![list(&[3.0, 1.0, 2.0])]).unwrap(),
+            list(&[1.0, 2.0, 3.0])
+        );
+        assert_eq!(
+            Sort.call(vec![vector(&[1.0, 3.0, 2.0]), Value::BuiltinFunction(&GreaterThan)])
+                .unwrap(),
+            vector(&[3.0, 2.0, 1.0])
+        );
+        assert!(Sort.call(vec![Value::List(vec![n(1.0), Value::Nil])]).is_err());
+
+        assert_eq!(Range.call(vec![n(3.0)]).unwrap(), vector(&[0.0, 1.0, 2.0]));
+        assert_eq!(
+            Range.call(vec![n(5.0), n(0.0), n(-2.0)]).unwrap(),
+            vector(&[5.0, 3.0, 1.0])
+        );
+        assert_eq!(Range.call(vec![n(2.0), n(1.0)]).unwrap(), vector(&[]));
+        assert!(Range.call(vec![n(0.0), n(1.0), n(0.0)]).is_err());
+        assert!(Range.call(vec![n(1e12)]).is_err());
+
+        assert_eq!(
+            Zip.call(vec![vector(&[1.0, 2.0]), list(&[3.0, 4.0, 5.0])]).unwrap(),
+            Value::Vector(vec![vector(&[1.0, 3.0]), vector(&[2.0, 4.0])].into())
+        );
+        assert_eq!(
+            Take.call(vec![n(2.0), list(&[1.0, 2.0, 3.0])]).unwrap(),
+            list(&[1.0, 2.0])
+        );
+        assert_eq!(
+            Drop.call(vec![n(5.0), list(&[1.0, 2.0, 3.0])]).unwrap(),
+            Value::Nil
+        );
+        assert!(Take.call(vec![n(-1.0), list(&[1.0])]).is_err());
+        assert_eq!(
+            Reverse.call(vec![vector(&[1.0, 2.0])]).unwrap(),
+            vector(&[2.0, 1.0])
+        );
+        assert_eq!(
+            Length.call(vec![Value::String("héllo".into())]).unwrap(),
+            n(5.0)
+        );
+        assert_eq!(Nth.call(vec![list(&[1.0, 2.0]), n(1.0)]).unwrap(), n(2.0));
+        assert!(Nth.call(vec![list(&[1.0, 2.0]), n(2.0)]).is_err());
+        assert_eq!(
+            Distinct
+                .call(vec![Value::List(vec![
+                    n(1.0),
+                    list(&[2.0]),
+                    n(1.0),
+                    list(&[2.0]),
+                    n(3.0)
+                ])])
+                .unwrap(),
+            Value::List(vec![n(1.0), list(&[2.0]), n(3.0)])
+        );
+        assert_eq!(
+            Flatten
+                .call(vec![Value::Vector(
+                    vec![n(1.0), list(&[2.0, 3.0]), Value::Nil, vector(&[4.0])].into()
+                )])
+                .unwrap(),
+            vector(&[1.0, 2.0, 3.0, 4.0])
+        );
+    }
+
+    #[test]
+    fn test_error_builtins() {
+        let message = || Value::String("Oops".into());
+        let err = RaiseError.call(vec![message()]).unwrap_err();
+        assert_eq!((err.kind(), err.message()), (ErrorKind::User, "Oops"));
+        assert_eq!(Raise.call(vec![message()]), Err(err.clone()));
+        assert!(RaiseError.call(vec![]).is_err());
+
+        let caught = AppError::with_kind(ErrorKind::Type, "Bad arguments");
+        let value = Value::Error(caught.clone());
+        assert_eq!(Raise.call(vec![value.clone()]), Err(caught));
+        assert_eq!(ErrorQ.call(vec![value.clone()]), Ok(Value::Boolean(true)));
+        assert_eq!(ErrorQ.call(vec![message()]), Ok(Value::Boolean(false)));
+        assert_eq!(
+            ErrorKindOf.call(vec![value.clone()]),
+            Ok(Value::String("type".into()))
+        );
+        assert_eq!(
+            ErrorMessage.call(vec![value]),
+            Ok(Value::String("Bad arguments".into()))
+        );
+        assert!(ErrorMessage.call(vec![message()]).is_err());
+    }
+
+    #[test]
+    fn test_random_builtins() {
+        assert!(Rand.is_volatile() && RandBetween.is_volatile());
+        assert!(!Plus.is_volatile());
+        match Rand.call(vec![]) {
+            Ok(Value::Number(n)) => assert!((0.0..1.0).contains(&n)),
+            result => panic!("Expected a number, got {:?}", result),
+        }
+        // Successive calls draw different numbers.
+        assert_ne!(Rand.call(vec![]), Rand.call(vec![]));
+        match RandBetween.call(vec![Value::Number(0.5), Value::Number(3.0)]) {
+            Ok(Value::Number(n)) => assert!([1.0, 2.0, 3.0].contains(&n), "{}", n),
+            result => panic!("Expected a number, got {:?}", result),
+        }
+        assert_eq!(
+            RandBetween.call(vec![Value::Number(2.0), Value::Number(2.0)]),
+            Ok(Value::Number(2.0))
+        );
+        assert!(RandBetween
+            .call(vec![Value::Number(1.2), Value::Number(1.8)])
+            .is_err());
+        assert!(RandBetween.call(vec![Value::Number(1.0)]).is_err());
+    }
 }
 
 pub const prelude: &str = r#"
-(
-    (defun map (fun lst)
-        (if (nil? lst)
-            nil
-            (cons (fun (car lst)) (map fun (cdr lst)))))
-    (defun filter (fun lst)
-        (if (nil? lst)
-            nil
-            (if (fun (car lst))
-                (cons (car lst) (filter fun (cdr lst)))
-                (filter fun (cdr lst)))))
-)
+; Short-circuiting boolean operators. Each expands into nested `if`s.
+(defmacro and (&rest forms)
+    (if (nil? forms)
+        #t
+        `(if ,(car forms) (and ,@(cdr forms)) #f)))
+(defmacro or (&rest forms)
+    (if (nil? forms)
+        #f
+        `(if ,(car forms) #t (or ,@(cdr forms)))))
 "#;
 
 thread_local! {
-    pub static PARSED_PRELUDE: parser::Expr = parser::parse(prelude).unwrap();
+    pub static PARSED_PRELUDE: Vec<parser::Expr> = parser::parse_program(prelude).unwrap();
 }
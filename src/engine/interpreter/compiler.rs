@@ -1,167 +1,1220 @@
-use crate::error::{AppError, AppResult};
-use crate::parser::Expr;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
-use super::builtins::PARSED_PRELUDE;
-use super::model::{Instruction, Value};
+use crate::error::{AppError, AppResult, ErrorKind, Span};
+use crate::parser::{Expr, ExprKind, MAX_NESTING_DEPTH};
+
+use super::builtins::{lookup_builtin, PARSED_PRELUDE};
+use super::env::Env;
+use super::evaluator::{call_function, eval_in_env, EmptyKeywordResolver, EvalLimits};
+use super::model::{Bytecode, FunctionParams, Instruction, Value};
+use super::optimizer::optimize;
+use super::verifier::verify;
+
+/// Guards against macros that expand into themselves forever.
+const MAX_MACRO_EXPANSION_DEPTH: usize = 200;
+
+/// Maximum nesting of the expressions being compiled (or macroexpanded), which
+/// desugaring and macros can make deeper than any the parser accepts.
+const MAX_COMPILE_DEPTH: usize = 2 * MAX_NESTING_DEPTH;
 
 pub struct Program {
-    pub(super) instructions: Vec<Instruction>,
+    pub(super) code: Rc<Bytecode>,
+    volatile: bool,
 }
 
-fn compile_to_instruction_vec(expr: &Expr) -> AppResult<Vec<Instruction>> {
-    let mut instructions = Vec::new();
-    compile_to_instructions(expr, &mut instructions)?;
-    Ok(instructions)
+impl Program {
+    pub(super) fn new(code: Bytecode) -> Self {
+        let volatile = refers_to_volatile_builtin(&code.instructions);
+        Self {
+            code: Rc::new(code),
+            volatile,
+        }
+    }
+
+    /// Whether the program may call a volatile built-in such as `rand`, so that its
+    /// result can change without anything it refers to changing. This errs on the side
+    /// of volatility: mentioning the built-in by name is enough.
+    pub fn is_volatile(&self) -> bool {
+        self.volatile
+    }
 }
 
-fn compile_to_instructions(expr: &Expr, instructions: &mut Vec<Instruction>) -> AppResult<()> {
-    match expr {
-        Expr::Number(_) | Expr::String(_) | Expr::Boolean(_) => {
-            instructions.push(Instruction::LoadConst(Box::new(Value::from_expr(&expr))));
+/// Whether `instructions` (or code nested in them) loads a volatile built-in by name.
+fn refers_to_volatile_builtin(instructions: &[Instruction]) -> bool {
+    instructions.iter().any(|instruction| match instruction {
+        Instruction::LoadName(name) => {
+            lookup_builtin(name).is_some_and(|builtin| builtin.is_volatile())
         }
-        Expr::Keyword(kw) => {
-            instructions.push(Instruction::LoadKeyword(kw.clone()));
+        Instruction::LoadConst(value) => match value.as_ref() {
+            Value::CompiledCode(code) => refers_to_volatile_builtin(&code.instructions),
+            _ => false,
+        },
+        Instruction::MakeFunction { params } => params
+            .optional
+            .iter()
+            .any(|(_, default)| refers_to_volatile_builtin(&default.instructions)),
+        _ => false,
+    })
+}
+
+fn invalid_syntax(head_sym: &str) -> AppError {
+    AppError::with_kind(
+        ErrorKind::Syntax,
+        format!("Invalid syntax for built-in form `{}`", head_sym),
+    )
+}
+
+fn symbol(name: &str) -> Expr {
+    Expr::symbol(name)
+}
+
+/// Wrap a sequence of body expressions in a `begin` if there's more than one.
+fn body_expr(body: &[Expr]) -> Expr {
+    match body {
+        [single] => single.clone(),
+        _ => Expr::list(
+            std::iter::once(symbol("begin"))
+                .chain(body.iter().cloned())
+                .collect(),
+        ),
+    }
+}
+
+/// Split `((name expr) ...)` into names and expressions.
+fn parse_bindings(head_sym: &str, bindings: &[Expr]) -> AppResult<(Vec<Expr>, Vec<Expr>)> {
+    let mut names = Vec::with_capacity(bindings.len());
+    let mut values = Vec::with_capacity(bindings.len());
+    for binding in bindings {
+        match &binding.kind {
+            ExprKind::List(pair) => match pair.as_slice() {
+                [name @ Expr {
+                    kind: ExprKind::Symbol(_),
+                    ..
+                }, value] => {
+                    names.push(name.clone());
+                    values.push(value.clone());
+                }
+                _ => return Err(invalid_syntax(head_sym)),
+            },
+            _ => return Err(invalid_syntax(head_sym)),
         }
-        Expr::Symbol(sym) => {
-            instructions.push(Instruction::LoadName(sym.clone()));
+    }
+    Ok((names, values))
+}
+
+/// Desugar `let`, `let*` and `letrec`. Each creates a child environment by way of a
+/// lambda application:
+///
+/// ```text
+/// (let ((x 1) (y 2)) body) --> ((lambda (x y) body) 1 2)
+/// (let* ((x 1) (y x)) body) --> (let ((x 1)) (let* ((y x)) body))
+/// (letrec ((f ...)) body) --> ((lambda () (begin (def f ...) body)))
+/// ```
+fn desugar_let(head_sym: &str, bindings: &[Expr], body: &[Expr]) -> AppResult<Expr> {
+    let (names, values) = parse_bindings(head_sym, bindings)?;
+    Ok(match head_sym {
+        "let" => Expr::list(
+            std::iter::once(Expr::list(vec![
+                symbol("lambda"),
+                Expr::list(names),
+                body_expr(body),
+            ]))
+            .chain(values)
+            .collect(),
+        ),
+        "let*" => match bindings {
+            [] | [_] => desugar_let("let", bindings, body)?,
+            [first, rest @ ..] => Expr::list(vec![
+                symbol("let"),
+                Expr::list(vec![first.clone()]),
+                Expr::list(
+                    vec![symbol("let*"), Expr::list(rest.to_vec())]
+                        .into_iter()
+                        .chain(body.iter().cloned())
+                        .collect(),
+                ),
+            ]),
+        },
+        "letrec" => {
+            let mut statements: Vec<Expr> = names
+                .into_iter()
+                .zip(values)
+                .map(|(name, value)| Expr::list(vec![symbol("def"), name, value]))
+                .collect();
+            statements.extend(body.iter().cloned());
+            Expr::list(vec![Expr::list(vec![
+                symbol("lambda"),
+                Expr::list(vec![]),
+                body_expr(&statements),
+            ])])
         }
-        Expr::List(elems) => match elems.as_slice() {
-            [Expr::Symbol(head_sym), name_sym, arg_spec, body] if head_sym == "defun" => {
-                // Desugar: (defun foo (x y) body) --> (def foo (fn (x y) body))
-                compile_to_instructions(
-                    &Expr::List(vec![
-                        Expr::Symbol("def".into()),
-                        name_sym.clone(),
-                        Expr::List(vec![
-                            Expr::Symbol("lambda".into()),
-                            arg_spec.clone(),
-                            body.clone(),
-                        ]),
-                    ]),
-                    instructions,
-                )?;
+        _ => unreachable!(),
+    })
+}
+
+/// Desugar `(cond (test body...) ... (else body...))` into nested `if`s. Falling off
+/// the end of a `cond` yields nil.
+fn desugar_cond(clauses: &[Expr]) -> AppResult<Expr> {
+    let mut result = symbol("nil");
+    for (idx, clause) in clauses.iter().enumerate().rev() {
+        result = match &clause.kind {
+            ExprKind::List(parts) => match parts.as_slice() {
+                [Expr {
+                    kind: ExprKind::Symbol(else_sym),
+                    ..
+                }, body @ ..]
+                    if else_sym == "else" && !body.is_empty() =>
+                {
+                    if idx != clauses.len() - 1 {
+                        return Err(invalid_syntax("cond"));
+                    }
+                    body_expr(body)
+                }
+                [test, body @ ..] if !body.is_empty() => {
+                    Expr::list(vec![symbol("if"), test.clone(), body_expr(body), result])
+                }
+                _ => return Err(invalid_syntax("cond")),
+            },
+            _ => return Err(invalid_syntax("cond")),
+        };
+    }
+    Ok(result)
+}
+
+/// Name the `case` key is bound to. `%` can't appear in identifiers, so this can't
+/// clash with user code.
+const CASE_KEY: &str = "%case-key";
+
+/// Desugar `(case key ((datum...) body...) ... (else body...))` into a `let` binding
+/// the key followed by a `cond` comparing it against each (quoted) datum with `=`.
+fn desugar_case(key: &Expr, clauses: &[Expr]) -> AppResult<Expr> {
+    let cond_clauses = clauses
+        .iter()
+        .map(|clause| match &clause.kind {
+            ExprKind::List(parts) => match parts.as_slice() {
+                [else_sym @ Expr {
+                    kind: ExprKind::Symbol(s),
+                    ..
+                }, body @ ..]
+                    if s == "else" && !body.is_empty() =>
+                {
+                    Ok(Expr::list(
+                        std::iter::once(else_sym.clone())
+                            .chain(body.iter().cloned())
+                            .collect(),
+                    ))
+                }
+                [Expr {
+                    kind: ExprKind::List(datums),
+                    ..
+                }, body @ ..]
+                    if !body.is_empty() =>
+                {
+                    let test = datums
+                        .iter()
+                        .rev()
+                        .fold(Expr::boolean(false), |rest, datum| {
+                            Expr::list(vec![
+                                symbol("if"),
+                                Expr::list(vec![
+                                    symbol("="),
+                                    symbol(CASE_KEY),
+                                    Expr::list(vec![symbol("quote"), datum.clone()]),
+                                ]),
+                                Expr::boolean(true),
+                                rest,
+                            ])
+                        });
+                    Ok(Expr::list(
+                        std::iter::once(test).chain(body.iter().cloned()).collect(),
+                    ))
+                }
+                _ => Err(invalid_syntax("case")),
+            },
+            _ => Err(invalid_syntax("case")),
+        })
+        .collect::<AppResult<Vec<_>>>()?;
+    Ok(Expr::list(vec![
+        symbol("let"),
+        Expr::list(vec![Expr::list(vec![symbol(CASE_KEY), key.clone()])]),
+        desugar_cond(&cond_clauses)?,
+    ]))
+}
+
+/// `expr` without source spans, for code whose errors shouldn't point into its source:
+/// the prelude's, or workbook definitions compiled into a formula.
+fn without_spans(expr: &Expr) -> Expr {
+    match &expr.kind {
+        ExprKind::List(elems) => Expr::list(elems.iter().map(without_spans).collect()),
+        kind => Expr {
+            kind: kind.clone(),
+            span: None,
+        },
+    }
+}
+
+fn quote(expr: Expr) -> Expr {
+    Expr::list(vec![symbol("quote"), expr])
+}
+
+fn contains_unquote(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::List(elems) => match elems.as_slice() {
+            [Expr {
+                kind: ExprKind::Symbol(head_sym),
+                ..
+            }, _]
+                if head_sym == "unquote" || head_sym == "unquote-splicing" =>
+            {
+                true
             }
-            [Expr::Symbol(head_sym), Expr::Symbol(name), body] if head_sym == "def" => {
-                compile_to_instructions(body, instructions)?;
-                instructions.push(Instruction::StoreName(name.clone()));
-                // The result of a def is just nil.
-                instructions.push(Instruction::LoadConst(Box::new(Value::Nil)));
+            _ => elems.iter().any(contains_unquote),
+        },
+        _ => false,
+    }
+}
+
+/// Desugar a quasiquoted template into code that builds it at runtime, e.g.
+///
+/// ```text
+/// `(a ,b ,@c) --> (append (list (quote a)) (list b) c)
+/// ```
+///
+/// `depth` tracks nested quasiquotes: only unquotes at depth 1 are evaluated.
+fn desugar_quasiquote(template: &Expr, depth: usize) -> AppResult<Expr> {
+    if !contains_unquote(template) {
+        return Ok(quote(template.clone()));
+    }
+    let elems = match &template.kind {
+        ExprKind::List(elems) => elems,
+        _ => unreachable!(),
+    };
+    match elems.as_slice() {
+        [Expr {
+            kind: ExprKind::Symbol(head_sym),
+            ..
+        }, inner]
+            if head_sym == "unquote" =>
+        {
+            if depth == 1 {
+                Ok(inner.clone())
+            } else {
+                Ok(Expr::list(vec![
+                    symbol("list"),
+                    quote(symbol("unquote")),
+                    desugar_quasiquote(inner, depth - 1)?,
+                ]))
+            }
+        }
+        [Expr {
+            kind: ExprKind::Symbol(head_sym),
+            ..
+        }, _]
+            if head_sym == "unquote-splicing" && depth == 1 =>
+        {
+            Err(AppError::with_kind(
+                ErrorKind::Syntax,
+                "`unquote-splicing` must appear inside a quasiquoted list",
+            ))
+        }
+        [Expr {
+            kind: ExprKind::Symbol(head_sym),
+            ..
+        }, inner]
+            if head_sym == "quasiquote" =>
+        {
+            Ok(Expr::list(vec![
+                symbol("list"),
+                quote(symbol("quasiquote")),
+                desugar_quasiquote(inner, depth + 1)?,
+            ]))
+        }
+        _ => {
+            let mut parts = vec![symbol("append")];
+            for elem in elems {
+                parts.push(match &elem.kind {
+                    ExprKind::List(inner) => match inner.as_slice() {
+                        [Expr {
+                            kind: ExprKind::Symbol(head_sym),
+                            ..
+                        }, spliced]
+                            if head_sym == "unquote-splicing" && depth == 1 =>
+                        {
+                            spliced.clone()
+                        }
+                        _ => Expr::list(vec![symbol("list"), desugar_quasiquote(elem, depth)?]),
+                    },
+                    _ => Expr::list(vec![symbol("list"), desugar_quasiquote(elem, depth)?]),
+                });
             }
-            [Expr::Symbol(head_sym), rest @ ..] if head_sym == "begin" => {
-                for (idx, sub_expr) in rest.iter().enumerate() {
-                    compile_to_instructions(sub_expr, instructions)?;
-                    let is_last = idx == rest.len() - 1;
-                    if !is_last {
-                        instructions.push(Instruction::DiscardValue);
+            Ok(Expr::list(parts))
+        }
+    }
+}
+
+/// Collect the names that `expr` defines with `def` or `defun`, other than inside nested
+/// functions (which have scopes of their own) and quoted data. These become local
+/// variables when `expr` is a function body.
+fn find_local_definitions(expr: &Expr, names: &mut Vec<String>) {
+    let elems = match &expr.kind {
+        ExprKind::List(elems) => elems,
+        _ => return,
+    };
+    match elems.as_slice() {
+        [Expr {
+            kind: ExprKind::Symbol(head_sym),
+            ..
+        }, Expr {
+            kind: ExprKind::Symbol(name),
+            ..
+        }, ..]
+            if head_sym == "def" || head_sym == "defun" =>
+        {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+            if head_sym == "defun" {
+                return;
+            }
+        }
+        [Expr {
+            kind: ExprKind::Symbol(head_sym),
+            ..
+        }, ..]
+            if matches!(
+                head_sym.as_str(),
+                "lambda"
+                    | "lam"
+                    | "let"
+                    | "let*"
+                    | "letrec"
+                    | "quote"
+                    | "quasiquote"
+                    | "defmacro"
+                    | "catch"
+            ) =>
+        {
+            return;
+        }
+        _ => {}
+    }
+    for elem in elems {
+        find_local_definitions(elem, names);
+    }
+}
+
+/// The local variables of a function being compiled, which are stored in numbered slots
+/// of its environment rather than looked up by name.
+#[derive(Default)]
+struct Scope {
+    /// Parameters, then variables defined in the body.
+    locals: Vec<String>,
+}
+
+pub(super) struct Compiler {
+    /// Macros defined so far with `defmacro`, by name. Each is a function value that is
+    /// called at compile time with the (unevaluated) argument forms and returns the
+    /// code to compile in their place.
+    macros: HashMap<String, Value>,
+    expansion_depth: usize,
+    /// How deeply nested the expression being compiled is, see `MAX_COMPILE_DEPTH`.
+    nesting_depth: usize,
+    /// Functions being compiled, innermost last. Empty at the top level, where variables
+    /// are global.
+    scopes: Vec<Scope>,
+    /// Global variables the code compiled so far defines. Names among them can't be
+    /// assumed to refer to built-ins when optimizing.
+    global_definitions: HashSet<String>,
+    /// Span of the innermost expression being compiled that came from source code, which
+    /// the instructions emitted are attributed to.
+    span: Option<Span>,
+    /// The environment macros are defined in, and so the functions they can call while
+    /// expanding.
+    macro_env: Rc<RefCell<Env>>,
+}
+
+impl Compiler {
+    pub(super) fn new() -> Self {
+        Self {
+            macros: HashMap::new(),
+            expansion_depth: 0,
+            nesting_depth: 0,
+            scopes: Vec::new(),
+            global_definitions: HashSet::new(),
+            span: None,
+            macro_env: Rc::new(RefCell::new(Env::with_builtins())),
+        }
+    }
+
+    /// A compiler that knows the macros in `definitions`. Code it compiles should be run
+    /// in `definitions.env()`.
+    pub(super) fn with_definitions(definitions: &Definitions) -> Self {
+        Self {
+            macros: definitions.macros.clone(),
+            expansion_depth: 0,
+            nesting_depth: 0,
+            scopes: Vec::new(),
+            global_definitions: definitions.definitions.clone(),
+            span: None,
+            macro_env: Rc::new(RefCell::new(definitions.env())),
+        }
+    }
+
+    /// A compiler that knows the prelude's macros. Code it compiles should be run in
+    /// `Definitions::default().env()`.
+    pub(super) fn with_prelude() -> Self {
+        PRELUDE.with(Self::with_definitions)
+    }
+
+    pub(super) fn macro_env(&self) -> Rc<RefCell<Env>> {
+        self.macro_env.clone()
+    }
+
+    fn define_macro(&mut self, name: &str, params: &[Expr], body: &Expr) -> AppResult<()> {
+        // Macros run at compile time, so they can't see the local variables of any
+        // function they're defined in.
+        let scopes = std::mem::take(&mut self.scopes);
+        let instructions = self.compile_to_bytecode(&Expr::list(vec![
+            symbol("lambda"),
+            Expr::list(params.to_vec()),
+            body.clone(),
+        ]));
+        self.scopes = scopes;
+        let instructions = instructions?;
+        let func = eval_in_env(
+            &Program::new(instructions),
+            self.macro_env.clone(),
+            &EmptyKeywordResolver,
+            EvalLimits::default(),
+        )?;
+        self.macros.insert(name.to_string(), func);
+        Ok(())
+    }
+
+    /// Expand a single macro call (not recursively).
+    fn expand_macro(&mut self, name: &str, args: &[Expr]) -> AppResult<Expr> {
+        if self.expansion_depth >= MAX_MACRO_EXPANSION_DEPTH {
+            return Err(AppError::with_kind(
+                ErrorKind::Syntax,
+                format!("Macro expansion of `{}` is too deeply nested", name),
+            ));
+        }
+        let func = self.macros[name].clone();
+        let args = args.iter().map(Value::from_expr).collect();
+        call_function(func, args, &EmptyKeywordResolver)
+            .and_then(|expansion| expansion.to_expr())
+            .map_err(|err| {
+                AppError::with_kind(
+                    err.kind(),
+                    format!("Error expanding macro `{}`: {}", name, err),
+                )
+            })
+    }
+
+    /// Recursively expand all macro calls in `expr`, registering any macros it defines.
+    /// Expansions are attributed to the span of the macro call they replace.
+    fn macroexpand_all(&mut self, expr: &Expr) -> AppResult<Expr> {
+        let result = self.nested(|compiler| compiler.macroexpand_form(expr));
+        result.map_err(|err| match expr.span {
+            Some(span) if err.span().is_none() => err.with_span(span),
+            _ => err,
+        })
+    }
+
+    fn macroexpand_form(&mut self, expr: &Expr) -> AppResult<Expr> {
+        match &expr.kind {
+            ExprKind::List(elems) => match elems.as_slice() {
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, ..]
+                    if head_sym == "quote" || head_sym == "quasiquote" =>
+                {
+                    // Quasiquoted code is expanded once it's been desugared.
+                    Ok(expr.clone())
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, Expr {
+                    kind: ExprKind::Symbol(name),
+                    ..
+                }, Expr {
+                    kind: ExprKind::List(params),
+                    ..
+                }, body]
+                    if head_sym == "defmacro" =>
+                {
+                    self.define_macro(name, params, body)?;
+                    Ok(expr.clone())
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, args @ ..]
+                    if self.macros.contains_key(head_sym) =>
+                {
+                    let mut expansion = self.expand_macro(head_sym, args)?;
+                    expansion.span = expansion.span.or(expr.span);
+                    self.expansion_depth += 1;
+                    let result = self.macroexpand_all(&expansion);
+                    self.expansion_depth -= 1;
+                    result
+                }
+                _ => Ok(Expr {
+                    kind: ExprKind::List(
+                        elems
+                            .iter()
+                            .map(|elem| self.macroexpand_all(elem))
+                            .collect::<AppResult<Vec<_>>>()?,
+                    ),
+                    span: expr.span,
+                }),
+            },
+            _ => Ok(expr.clone()),
+        }
+    }
+
+    /// Compile and optimize a complete top-level expression. Macros it defines stay
+    /// defined for later calls.
+    pub(super) fn compile_program(&mut self, expr: &Expr) -> AppResult<Program> {
+        let code = self.compile_to_bytecode(expr)?;
+        let mut code = optimize(code, &self.global_definitions);
+        mark_tail_calls(&mut code.instructions);
+        verify(&code.instructions)?;
+        Ok(Program::new(code))
+    }
+
+    fn compile_to_bytecode(&mut self, expr: &Expr) -> AppResult<Bytecode> {
+        let mut instructions = Bytecode::default();
+        self.compile_to_instructions(expr, &mut instructions)?;
+        Ok(instructions)
+    }
+
+    /// Resolve `name` to the slot of a local variable, as (depth, index), where depth
+    /// counts functions out from the innermost.
+    fn resolve_local(&self, name: &str) -> Option<(usize, usize)> {
+        self.scopes
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(depth, scope)| {
+                scope
+                    .locals
+                    .iter()
+                    .rposition(|local| local == name)
+                    .map(|index| (depth, index))
+            })
+    }
+
+    /// Add a local variable to the innermost scope, returning its slot index.
+    fn add_local(&mut self, name: &str) -> usize {
+        let scope = self.scopes.last_mut().unwrap();
+        scope.locals.push(name.to_string());
+        scope.locals.len() - 1
+    }
+
+    /// Compile a lambda parameter list into the innermost scope, whose first slots are
+    /// the parameters. Supports `&optional` parameters, written either as a bare name
+    /// (defaulting to nil) or as `(name default)`, and a rest parameter written as
+    /// `&rest name` or dotted `(a b . name)`.
+    fn compile_params(&mut self, params: &[Expr]) -> AppResult<FunctionParams> {
+        enum Section {
+            Required,
+            Optional,
+            Rest,
+        }
+
+        let invalid = || AppError::with_kind(ErrorKind::Syntax, "Invalid parameter list");
+        let mut result = FunctionParams {
+            required: Vec::new(),
+            optional: Vec::new(),
+            rest: None,
+            locals: Rc::new(Vec::new()),
+        };
+        let mut section = Section::Required;
+        for param in params {
+            match (&param.kind, &section) {
+                (ExprKind::Symbol(sym), Section::Required) if sym == "&optional" => {
+                    section = Section::Optional;
+                }
+                (ExprKind::Symbol(sym), Section::Required | Section::Optional)
+                    if sym == "&rest" || sym == "." =>
+                {
+                    section = Section::Rest;
+                }
+                (ExprKind::Symbol(sym), _) if sym.starts_with('&') || sym == "." => {
+                    return Err(invalid());
+                }
+                (ExprKind::Symbol(sym), Section::Required) => {
+                    result.required.push(sym.clone());
+                    self.add_local(sym);
+                }
+                (ExprKind::Symbol(sym), Section::Optional) => {
+                    result.optional.push((
+                        sym.clone(),
+                        Rc::new(Bytecode::new(vec![Instruction::LoadConst(Box::new(
+                            Value::Nil,
+                        ))])),
+                    ));
+                    self.add_local(sym);
+                }
+                (ExprKind::List(parts), Section::Optional) => match parts.as_slice() {
+                    [Expr {
+                        kind: ExprKind::Symbol(sym),
+                        ..
+                    }, default] => {
+                        // Compiled before the parameter is added, so it can only see
+                        // earlier ones.
+                        let default = self.compile_to_bytecode(default)?;
+                        result.optional.push((sym.clone(), Rc::new(default)));
+                        self.add_local(sym);
                     }
+                    _ => return Err(invalid()),
+                },
+                (ExprKind::Symbol(sym), Section::Rest) if result.rest.is_none() => {
+                    result.rest = Some(sym.clone());
+                    self.add_local(sym);
+                }
+                (ExprKind::Symbol(_), Section::Rest) => return Err(invalid()),
+                _ => {
+                    return Err(AppError::with_kind(
+                        ErrorKind::Syntax,
+                        "Expected symbol in parameter list",
+                    ))
                 }
             }
-            [Expr::Symbol(head_sym), value] if head_sym == "quote" => {
-                instructions.push(Instruction::LoadConst(Box::new(Value::from_expr(&value))));
-            }
-            [Expr::Symbol(head_sym), Expr::List(params), body]
-                if head_sym == "lambda" || head_sym == "lam" =>
-            {
-                instructions.push(Instruction::LoadConst(Box::new(Value::List(
-                    params
-                        .iter()
-                        .map(|param| -> AppResult<Value> {
-                            match param {
-                                Expr::Symbol(sym) => Ok(Value::Symbol(sym.clone())),
-                                _ => Err(AppError::new("Expected symbol in parameter list")),
-                            }
-                        })
-                        .collect::<AppResult<Vec<Value>>>()?,
-                ))));
-                instructions.push(Instruction::LoadConst(Box::new(Value::CompiledCode(
-                    compile_to_instruction_vec(body)?,
-                ))));
-                instructions.push(Instruction::MakeFunction);
+        }
+        if let (Section::Rest, None) = (section, &result.rest) {
+            return Err(invalid());
+        }
+        Ok(result)
+    }
+
+    /// Compile a function in a new scope, with `definitions` (the variables its body
+    /// defines) as local variables after the parameters.
+    fn compile_function_scope(
+        &mut self,
+        params: &[Expr],
+        definitions: &[String],
+        body: &Expr,
+    ) -> AppResult<(FunctionParams, Bytecode)> {
+        let mut params = self.compile_params(params)?;
+        for name in definitions {
+            if !self.scopes.last().unwrap().locals.contains(name) {
+                self.add_local(name);
             }
-            [Expr::Symbol(head_sym), cond, if_true, if_false] if head_sym == "if" => {
-                let true_instructions = compile_to_instruction_vec(if_true)?;
-                let false_instructions = compile_to_instruction_vec(if_false)?;
+        }
+        let mut body_instructions = self.compile_to_bytecode(body)?;
+        mark_tail_calls(&mut body_instructions.instructions);
+        params.locals = Rc::new(self.scopes.last().unwrap().locals.clone());
+        Ok((params, body_instructions))
+    }
 
-                let true_instruction_count = true_instructions.len() as i32;
-                let false_instruction_count = false_instructions.len() as i32;
+    fn compile_function(
+        &mut self,
+        params: &[Expr],
+        body: &Expr,
+    ) -> AppResult<(FunctionParams, Bytecode)> {
+        // Expand macros first, so that variables defined by their expansions are found.
+        let body = self.macroexpand_all(body)?;
+        let mut definitions = Vec::new();
+        find_local_definitions(&body, &mut definitions);
+        self.scopes.push(Scope::default());
+        let result = self.compile_function_scope(params, &definitions, &body);
+        self.scopes.pop();
+        result
+    }
 
-                compile_to_instructions(cond, instructions)?;
-                instructions.push(Instruction::RelativeJumpIfTrue {
-                    offset: false_instruction_count + 1, // +1 is to skip the relative jump at the end of FALSE
-                });
-                instructions.extend(false_instructions.into_iter());
-                instructions.push(Instruction::RelativeJump {
-                    offset: true_instruction_count,
-                });
-                instructions.extend(true_instructions.into_iter());
+    /// Run `f` one level deeper in the expression being compiled.
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> AppResult<T>) -> AppResult<T> {
+        if self.nesting_depth >= MAX_COMPILE_DEPTH {
+            return Err(AppError::with_kind(
+                ErrorKind::Syntax,
+                "Expression is too deeply nested",
+            ));
+        }
+        self.nesting_depth += 1;
+        let result = f(self);
+        self.nesting_depth -= 1;
+        result
+    }
+
+    /// Compile `expr`, appending to `instructions`. Errors, and the instructions emitted,
+    /// are attributed to the innermost enclosing expression that came from source code.
+    fn compile_to_instructions(
+        &mut self,
+        expr: &Expr,
+        instructions: &mut Bytecode,
+    ) -> AppResult<()> {
+        let outer_span = self.span;
+        self.span = expr.span.or(outer_span);
+        let result = self.nested(|compiler| compiler.compile_form(expr, instructions));
+        self.span = outer_span;
+        result.map_err(|err| match expr.span {
+            Some(span) if err.span().is_none() => err.with_span(span),
+            _ => err,
+        })
+    }
+
+    fn compile_form(&mut self, expr: &Expr, instructions: &mut Bytecode) -> AppResult<()> {
+        match &expr.kind {
+            ExprKind::Number(_) | ExprKind::String(_) | ExprKind::Boolean(_) => {
+                instructions.push(
+                    Instruction::LoadConst(Box::new(Value::from_expr(expr))),
+                    self.span,
+                );
             }
-            [Expr::Symbol(head_sym), function_expr, arg_expr] if head_sym == "apply" => {
-                compile_to_instructions(function_expr, instructions)?;
-                compile_to_instructions(arg_expr, instructions)?;
-                instructions.push(Instruction::ApplyFunction);
+            ExprKind::Keyword(kw) => {
+                instructions.push(Instruction::LoadKeyword(kw.clone()), self.span);
             }
-            // Catch-all for malformed forms; must go towards the end of pattern matching
-            // but before function application.
-            [Expr::Symbol(head_sym), ..]
-                if (head_sym == "defun"
-                    || head_sym == "if"
-                    || head_sym == "def"
-                    || head_sym == "lambda"
-                    || head_sym == "apply") =>
-            {
-                return Err(AppError::new(format!(
-                    "Invalid syntax for built-in form `{}`",
-                    head_sym
-                )))
+            ExprKind::Symbol(sym) => match self.resolve_local(sym) {
+                Some((depth, index)) => {
+                    instructions.push(Instruction::LoadLocal { depth, index }, self.span);
+                }
+                None => instructions.push(Instruction::LoadName(sym.clone()), self.span),
+            },
+            ExprKind::List(elems) => match elems.as_slice() {
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, name_sym, arg_spec, body]
+                    if head_sym == "defun" =>
+                {
+                    // Desugar: (defun foo (x y) body) --> (def foo (fn (x y) body))
+                    self.compile_to_instructions(
+                        &Expr::list(vec![
+                            symbol("def"),
+                            name_sym.clone(),
+                            Expr::list(vec![symbol("lambda"), arg_spec.clone(), body.clone()]),
+                        ]),
+                        instructions,
+                    )?;
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, Expr {
+                    kind: ExprKind::Symbol(name),
+                    ..
+                }, body]
+                    if head_sym == "def" =>
+                {
+                    if self.scopes.is_empty() {
+                        self.compile_to_instructions(body, instructions)?;
+                        instructions.push(Instruction::StoreName(name.clone()), self.span);
+                        self.global_definitions.insert(name.clone());
+                    } else {
+                        // Definitions in a function are local to it. The slot exists before
+                        // the body is compiled, so recursive functions can refer to it.
+                        let scope = self.scopes.last_mut().unwrap();
+                        let index = match scope.locals.iter().rposition(|local| local == name) {
+                            Some(index) => index,
+                            None => self.add_local(name),
+                        };
+                        self.compile_to_instructions(body, instructions)?;
+                        instructions.push(Instruction::StoreLocal { index }, self.span);
+                    }
+                    // The result of a def is just nil.
+                    instructions.push(Instruction::LoadConst(Box::new(Value::Nil)), self.span);
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, rest @ ..]
+                    if head_sym == "begin" =>
+                {
+                    if rest.is_empty() {
+                        instructions.push(Instruction::LoadConst(Box::new(Value::Nil)), self.span);
+                    }
+                    for (idx, sub_expr) in rest.iter().enumerate() {
+                        self.compile_to_instructions(sub_expr, instructions)?;
+                        let is_last = idx == rest.len() - 1;
+                        if !is_last {
+                            instructions.push(Instruction::DiscardValue, self.span);
+                        }
+                    }
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, value]
+                    if head_sym == "quote" =>
+                {
+                    instructions.push(
+                        Instruction::LoadConst(Box::new(Value::from_expr(value))),
+                        self.span,
+                    );
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, Expr {
+                    kind: ExprKind::List(params),
+                    ..
+                }, body]
+                    if head_sym == "lambda" || head_sym == "lam" =>
+                {
+                    let (params, body_instructions) = self.compile_function(params, body)?;
+                    instructions.push(
+                        Instruction::LoadConst(Box::new(Value::CompiledCode(Rc::new(
+                            body_instructions,
+                        )))),
+                        self.span,
+                    );
+                    instructions.push(
+                        Instruction::MakeFunction {
+                            params: Rc::new(params),
+                        },
+                        self.span,
+                    );
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, cond, if_true, if_false]
+                    if head_sym == "if" =>
+                {
+                    let true_instructions = self.compile_to_bytecode(if_true)?;
+                    let false_instructions = self.compile_to_bytecode(if_false)?;
+
+                    let true_instruction_count = true_instructions.len() as i32;
+                    let false_instruction_count = false_instructions.len() as i32;
+
+                    self.compile_to_instructions(cond, instructions)?;
+                    instructions.push(
+                        Instruction::RelativeJumpIfTrue {
+                            offset: false_instruction_count + 1, // +1 is to skip the relative jump at the end of FALSE
+                        },
+                        self.span,
+                    );
+                    instructions.append(false_instructions);
+                    instructions.push(
+                        Instruction::RelativeJump {
+                            offset: true_instruction_count,
+                        },
+                        self.span,
+                    );
+                    instructions.append(true_instructions);
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, Expr {
+                    kind: ExprKind::List(bindings),
+                    ..
+                }, body @ ..]
+                    if (head_sym == "let" || head_sym == "let*" || head_sym == "letrec")
+                        && !body.is_empty() =>
+                {
+                    self.compile_to_instructions(
+                        &desugar_let(head_sym, bindings, body)?,
+                        instructions,
+                    )?;
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, clauses @ ..]
+                    if head_sym == "cond" =>
+                {
+                    self.compile_to_instructions(&desugar_cond(clauses)?, instructions)?;
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, cond, body @ ..]
+                    if (head_sym == "when" || head_sym == "unless") && !body.is_empty() =>
+                {
+                    // Desugar: (when c body...) --> (if c (begin body...) nil)
+                    let (if_true, if_false) = if head_sym == "when" {
+                        (body_expr(body), symbol("nil"))
+                    } else {
+                        (symbol("nil"), body_expr(body))
+                    };
+                    self.compile_to_instructions(
+                        &Expr::list(vec![symbol("if"), cond.clone(), if_true, if_false]),
+                        instructions,
+                    )?;
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, key, clauses @ ..]
+                    if head_sym == "case" =>
+                {
+                    self.compile_to_instructions(&desugar_case(key, clauses)?, instructions)?;
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, Expr {
+                    kind: ExprKind::Symbol(name),
+                    ..
+                }, Expr {
+                    kind: ExprKind::List(params),
+                    ..
+                }, body]
+                    if head_sym == "defmacro" =>
+                {
+                    self.define_macro(name, params, body)?;
+                    instructions.push(Instruction::LoadConst(Box::new(Value::Nil)), self.span);
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, template]
+                    if head_sym == "quasiquote" =>
+                {
+                    self.compile_to_instructions(&desugar_quasiquote(template, 1)?, instructions)?;
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, function_expr, arg_expr]
+                    if head_sym == "apply" =>
+                {
+                    self.compile_to_instructions(function_expr, instructions)?;
+                    self.compile_to_instructions(arg_expr, instructions)?;
+                    instructions.push(Instruction::ApplyFunction, self.span);
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, body, Expr {
+                    kind: ExprKind::List(catch),
+                    ..
+                }] if head_sym == "try" => {
+                    let (name, handler) = match catch.as_slice() {
+                        [Expr {
+                            kind: ExprKind::Symbol(catch_sym),
+                            ..
+                        }, name @ Expr {
+                            kind: ExprKind::Symbol(_),
+                            ..
+                        }, handler @ ..]
+                            if catch_sym == "catch" && !handler.is_empty() =>
+                        {
+                            (name, handler)
+                        }
+                        _ => return Err(invalid_syntax(head_sym)),
+                    };
+                    // The handler is a function of the error. It's created first, so that it's
+                    // below the body's operands and still there to call once they've been
+                    // unwound:
+                    //
+                    //     <(lambda (e) handler...)>
+                    //     PushHandler -> H
+                    //     <body>
+                    //     PopHandler
+                    //     RelativeJump -> END
+                    // H:  CallFunction 1
+                    // END:
+                    self.compile_to_instructions(
+                        &Expr::list(vec![
+                            symbol("lambda"),
+                            Expr::list(vec![name.clone()]),
+                            body_expr(handler),
+                        ]),
+                        instructions,
+                    )?;
+                    let body_instructions = self.compile_to_bytecode(body)?;
+                    instructions.push(
+                        Instruction::PushHandler {
+                            offset: body_instructions.len() as i32 + 2,
+                        },
+                        self.span,
+                    );
+                    instructions.append(body_instructions);
+                    instructions.push(Instruction::PopHandler, self.span);
+                    instructions.push(Instruction::RelativeJump { offset: 1 }, self.span);
+                    instructions.push(Instruction::CallFunction { nargs: 1 }, self.span);
+                }
+                // Catch-all for malformed forms; must go towards the end of pattern matching
+                // but before function application.
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, ..]
+                    if (head_sym == "defun"
+                        || head_sym == "if"
+                        || head_sym == "def"
+                        || head_sym == "lambda"
+                        || head_sym == "apply"
+                        || head_sym == "let"
+                        || head_sym == "let*"
+                        || head_sym == "letrec"
+                        || head_sym == "when"
+                        || head_sym == "unless"
+                        || head_sym == "case"
+                        || head_sym == "defmacro"
+                        || head_sym == "quasiquote"
+                        || head_sym == "unquote"
+                        || head_sym == "unquote-splicing"
+                        || head_sym == "try"
+                        || head_sym == "catch") =>
+                {
+                    return Err(invalid_syntax(head_sym))
+                }
+                [Expr {
+                    kind: ExprKind::Symbol(head_sym),
+                    ..
+                }, args @ ..]
+                    if self.macros.contains_key(head_sym) =>
+                {
+                    let expansion = self.expand_macro(head_sym, args)?;
+                    self.expansion_depth += 1;
+                    let result = self.compile_to_instructions(&expansion, instructions);
+                    self.expansion_depth -= 1;
+                    result?;
+                }
+                [function_expr, args @ ..] => {
+                    // Function application
+                    self.compile_to_instructions(function_expr, instructions)?;
+                    for arg in args {
+                        self.compile_to_instructions(arg, instructions)?
+                    }
+                    let nargs = (elems.len() - 1) as i32;
+                    instructions.push(Instruction::CallFunction { nargs }, self.span);
+                }
+                [] => {
+                    return Err(AppError::with_kind(
+                        ErrorKind::Syntax,
+                        "Cannot evaluate an empty list",
+                    ))
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Whether execution starting at `pc` reaches the end of the code without doing
+/// anything other than (forward) unconditional jumps.
+fn returns_immediately(instructions: &[Instruction], mut pc: usize) -> bool {
+    loop {
+        match instructions.get(pc) {
+            None => return true,
+            Some(Instruction::RelativeJump { offset }) if *offset >= 0 => {
+                pc += 1 + *offset as usize;
             }
-            [function_expr, args @ ..] => {
-                // Function application
-                compile_to_instructions(function_expr, instructions)?;
-                for arg in args {
-                    compile_to_instructions(arg, instructions)?
-                }
-                let nargs = (elems.len() - 1) as i32;
-                instructions.push(Instruction::CallFunction { nargs });
+            Some(_) => return false,
+        }
+    }
+}
+
+/// Turn calls in tail position (the last thing a function does, e.g. at the end of an
+/// `if` branch or a `begin`) into `TailCall`s. Must only be run on complete function
+/// bodies, since a call at the end of a fragment may not be at the end once spliced.
+pub(super) fn mark_tail_calls(instructions: &mut [Instruction]) {
+    for idx in 0..instructions.len() {
+        if let Instruction::CallFunction { nargs } = instructions[idx] {
+            if returns_immediately(instructions, idx + 1) {
+                instructions[idx] = Instruction::TailCall { nargs };
             }
-            [] => return Err(AppError::new("Cannot evaluate an empty list")),
-        },
+        }
     }
-    Ok(())
 }
 
 pub fn compile(expr: &Expr) -> AppResult<Program> {
-    Ok(Program {
-        instructions: compile_to_instruction_vec(&expr)?,
-    })
+    Compiler::new().compile_program(expr)
 }
 
-pub fn compile_with_prelude(expr: &Expr) -> AppResult<Program> {
-    let statements = PARSED_PRELUDE.with(|parsed_prelude| {
-        let mut statements = Vec::new();
-        statements.push(Expr::Symbol("begin".to_string()));
-        match parsed_prelude {
-            Expr::List(list) => {
-                statements.extend(list.iter().cloned());
-            }
-            _ => panic!("Prelude should be a list of statements"),
+/// Statements compiled and evaluated once rather than into every formula: the prelude,
+/// once per thread, and workbook-level definitions, each time they change. The default
+/// is just the prelude.
+#[derive(Clone)]
+pub struct Definitions {
+    /// Macros defined, which are expanded when compiling formulas.
+    macros: HashMap<String, Value>,
+    /// Global variables defined.
+    definitions: HashSet<String>,
+    /// Whether any of the statements may call a volatile built-in, in which case so may
+    /// every formula.
+    volatile: bool,
+    /// Everything else defined. Nothing is defined in it after the statements have run:
+    /// formulas are run in children of it.
+    pub(super) env: Rc<RefCell<Env>>,
+}
+
+impl Definitions {
+    /// Compile and evaluate workbook-level `statements` after the prelude. They're
+    /// evaluated without a sheet, so can't refer to cells.
+    pub fn load(statements: &[Expr]) -> AppResult<Self> {
+        Self::evaluate(Compiler::with_prelude(), statements)
+    }
+
+    fn prelude() -> AppResult<Self> {
+        PARSED_PRELUDE.with(|statements| Self::evaluate(Compiler::new(), statements))
+    }
+
+    fn evaluate(mut compiler: Compiler, statements: &[Expr]) -> AppResult<Self> {
+        let mut volatile = false;
+        for statement in statements {
+            // Spans of errors at run time should point into the formula being evaluated,
+            // never into the definitions.
+            let program = compiler.compile_program(&without_spans(statement))?;
+            volatile |= program.is_volatile();
+            eval_in_env(
+                &program,
+                compiler.macro_env.clone(),
+                &EmptyKeywordResolver,
+                EvalLimits::default(),
+            )?;
         }
-        statements.push(expr.clone());
-        statements
-    });
-    let new_expr = Expr::List(statements);
-    compile(&new_expr)
+        Ok(Self {
+            macros: compiler.macros,
+            definitions: compiler.global_definitions,
+            volatile,
+            env: compiler.macro_env,
+        })
+    }
+
+    /// An environment for code compiled against the definitions, which can see
+    /// everything they (and the prelude) define as well as the builtins.
+    pub fn env(&self) -> Env {
+        Env::child(self.env.clone())
+    }
+}
+
+impl Default for Definitions {
+    fn default() -> Self {
+        PRELUDE.with(Definitions::clone)
+    }
+}
+
+thread_local! {
+    pub(super) static PRELUDE: Definitions = Definitions::prelude().unwrap();
+}
+
+/// Compile `expr` against the prelude and workbook-level `definitions`, neither of which
+/// is compiled in: the program must be run in `definitions.env()`.
+pub fn compile_with_prelude(expr: &Expr, definitions: &Definitions) -> AppResult<Program> {
+    let mut program = Compiler::with_definitions(definitions).compile_program(expr)?;
+    program.volatile |= definitions.volatile;
+    Ok(program)
+}
+
+/// Fully expand all macros in `expr`, including those defined in the prelude and in
+/// `definitions`.
+pub fn macroexpand_with_prelude(expr: &Expr, definitions: &Definitions) -> AppResult<Expr> {
+    Compiler::with_definitions(definitions).macroexpand_all(expr)
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::evaluator::eval;
     use super::*;
 
     #[test]
     fn test_compile_simple() {
-        let instructions =
-            compile_to_instruction_vec(&Expr::from_string("(+ 1 2)").unwrap()).unwrap();
+        let instructions = Compiler::new()
+            .compile_to_bytecode(&Expr::from_string("(+ 1 2)").unwrap())
+            .unwrap();
         assert_eq!(
-            instructions,
+            instructions.instructions,
             vec![
                 Instruction::LoadName("+".to_string()),
                 Instruction::LoadConst(Box::new(Value::Number(1.0))),
@@ -170,4 +1223,300 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_optimizer_preserves_semantics() {
+        let run = |src: &str, optimized: bool| {
+            let expr = Expr::from_string(src).unwrap();
+            let mut compiler = Compiler::with_prelude();
+            let program = if optimized {
+                compiler.compile_program(&expr).unwrap()
+            } else {
+                let mut instructions = compiler.compile_to_bytecode(&expr).unwrap();
+                mark_tail_calls(&mut instructions.instructions);
+                Program::new(instructions)
+            };
+            format!(
+                "{:?}",
+                eval(
+                    &program,
+                    Definitions::default().env(),
+                    &EmptyKeywordResolver
+                )
+            )
+        };
+        for src in &[
+            "(+ 1 (* 2 3))",
+            "(if (< 1 2) 'yes 'no)",
+            "(if (> 1 2) 'yes 'no)",
+            "(if nil 1 2)",
+            "(begin 1 2 (list 1 2 (car '(3 4))))",
+            "(and #t (or #f (= 1 1)))",
+            "(/ 1 0)",
+            "(begin (def + -) (+ 3 1))",
+            "((lambda (x) (if #t (* x 2) (undefined x))) 21)",
+            "(begin (defun f (n acc) (if (= n 0) acc (f (- n 1) (+ acc 1)))) (f 10000 0))",
+            "(let ((x (vector 1 2))) (get x (- 2 1)))",
+            "((lambda (&optional (a (+ 1 1))) a))",
+            "(if (if #t #f #t) 1 (if #f 2 (begin 3 4)))",
+            // Built-ins redefined by the program, globally or locally, aren't folded.
+            "(begin (defun car (x) 'mine) (car '(1 2)))",
+            "(list (car '(1 2)) (begin (def not (lambda (x) x)) (not 1)))",
+            "((lambda () (begin (def + -) (+ 3 1))))",
+            "(let ((* +)) (* 2 3))",
+            // Errors in branches that are folded away, or kept, happen the same way.
+            "(if #t (car 1) 2)",
+            "(if #f 1 (/ 1 0))",
+            "(if (< 1 2) (car '()) (car 1))",
+            "(+ 1 (car 5))",
+            "(try (+ 1 (car 5)) (catch e (list 'caught e)))",
+            "(if (= 1 1) 'ok (undefined-function))",
+            // Volatile built-ins are called as many times, in the same order.
+            "(list (rand) (rand) (randbetween 1 100))",
+            "(if #t (rand) (randbetween 1 2))",
+            "(+ (rand) (* 2 3))",
+            "(length (list (gensym) (gensym)))",
+        ] {
+            assert_eq!(run(src, true), run(src, false), "{}", src);
+        }
+        // Folded calls are gone from the optimized code.
+        let program = compile(&Expr::from_string("(if (< 1 2) (+ 1 2) x)").unwrap()).unwrap();
+        assert_eq!(
+            program.code.instructions,
+            vec![Instruction::LoadConst(Box::new(Value::Number(3.0)))]
+        );
+    }
+
+    fn expand(src: &str) -> String {
+        macroexpand_with_prelude(&Expr::from_string(src).unwrap(), &Definitions::default())
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_mark_tail_calls() {
+        let program = compile(
+            &Expr::from_string("(lambda (x) (if (f x) (begin (g x) (h x)) (k (j x))))").unwrap(),
+        )
+        .unwrap();
+        let body = match &program.code.instructions[0] {
+            Instruction::LoadConst(value) => match value.as_ref() {
+                Value::CompiledCode(body) => body.clone(),
+                _ => panic!("Expected compiled code"),
+            },
+            _ => panic!("Expected a constant"),
+        };
+        let calls: Vec<_> = body
+            .instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::CallFunction { .. } => Some("call"),
+                Instruction::TailCall { .. } => Some("tail"),
+                _ => None,
+            })
+            .collect();
+        // (f x), then the false branch (j x) and (k ...), then (g x) and (h x).
+        assert_eq!(calls, vec!["call", "call", "tail", "call", "tail"]);
+    }
+
+    #[test]
+    fn test_desugar_quasiquote() {
+        let template = Expr::from_string("(a ,b ,@c)").unwrap();
+        assert_eq!(
+            desugar_quasiquote(&template, 1).unwrap().to_string(),
+            "(append (list (quote a)) (list b) c)"
+        );
+        let template = Expr::from_string("(a b)").unwrap();
+        assert_eq!(
+            desugar_quasiquote(&template, 1).unwrap().to_string(),
+            "(quote (a b))"
+        );
+    }
+
+    #[test]
+    fn test_macroexpand() {
+        assert_eq!(
+            expand("(begin (defmacro swap (a b) `(,b ,a)) (swap 1 f))"),
+            "(begin (defmacro swap (a b) (quasiquote ((unquote b) (unquote a)))) (f 1))"
+        );
+        // Prelude macros expand recursively.
+        assert_eq!(expand("(and x y)"), "(if x (if y #t #f) #f)");
+        // Quoted forms aren't expanded.
+        assert_eq!(expand("'(and x y)"), "(quote (and x y))");
+
+        // Macros can call functions defined before them, in the prelude or the workbook
+        // definitions.
+        let definitions = Definitions::load(&[
+            Expr::from_string("(defun helper (x) `(* ,x 2))").unwrap(),
+            Expr::from_string("(defmacro dbl (x) (helper x))").unwrap(),
+        ])
+        .unwrap();
+        let expr = Expr::from_string("(dbl (+ 1 2))").unwrap();
+        assert_eq!(
+            macroexpand_with_prelude(&expr, &definitions)
+                .unwrap()
+                .to_string(),
+            "(* (+ 1 2) 2)"
+        );
+        let program = compile_with_prelude(&expr, &definitions).unwrap();
+        assert_eq!(
+            eval(&program, definitions.env(), &EmptyKeywordResolver).unwrap(),
+            Value::Number(6.0)
+        );
+        assert_eq!(
+            expand("(begin (defmacro firsts (xs) `(list ,@(map car xs))) (firsts ((1 2) (3 4))))"),
+            "(begin (defmacro firsts (xs) (quasiquote (list (unquote-splicing (map car xs))))) (list 1 3))"
+        );
+    }
+
+    #[test]
+    fn test_lexical_addressing() {
+        let program = compile(
+            &Expr::from_string("(lambda (x) (begin (def y 1) (lambda (z) (+ x y z))))").unwrap(),
+        )
+        .unwrap();
+        let body = |instruction: &Instruction| match instruction {
+            Instruction::LoadConst(value) => match value.as_ref() {
+                Value::CompiledCode(body) => body.clone(),
+                _ => panic!("Expected compiled code"),
+            },
+            _ => panic!("Expected a constant"),
+        };
+        let outer = body(&program.code.instructions[0]);
+        assert_eq!(outer.instructions[1], Instruction::StoreLocal { index: 1 });
+        let inner = body(&outer.instructions[2]);
+        assert_eq!(
+            &inner.instructions[..4],
+            &[
+                Instruction::LoadName("+".to_string()),
+                Instruction::LoadLocal { depth: 1, index: 0 },
+                Instruction::LoadLocal { depth: 1, index: 1 },
+                Instruction::LoadLocal { depth: 0, index: 0 },
+            ]
+        );
+        // Top-level definitions are still global.
+        let program = compile(&Expr::from_string("(def x 1)").unwrap()).unwrap();
+        assert_eq!(
+            program.code.instructions[1],
+            Instruction::StoreName("x".to_string())
+        );
+
+        // Variables defined by macro expansions are local too. Each function body is
+        // expanded and compiled once, however deeply they're nested.
+        let mut src = "0".to_string();
+        for depth in 0..20 {
+            src = format!("((lambda () (begin (defvar x {}) (+ x {}))))", depth, src);
+        }
+        let src = format!(
+            "(begin (defmacro defvar (name value) `(def ,name ,value)) {})",
+            src
+        );
+        let program = compile(&Expr::from_string(&src).unwrap()).unwrap();
+        assert_eq!(
+            eval(
+                &program,
+                Definitions::default().env(),
+                &EmptyKeywordResolver
+            )
+            .unwrap(),
+            Value::Number(190.0)
+        );
+    }
+
+    #[test]
+    fn test_compile_with_prelude() {
+        // The prelude's macros are expanded, but its code isn't compiled into the formula.
+        let program = compile_with_prelude(
+            &Expr::from_string("(and x #t)").unwrap(),
+            &Definitions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            program.code.len(),
+            compile(&Expr::from_string("(if x (if #t #t #f) #f)").unwrap())
+                .unwrap()
+                .code
+                .instructions
+                .len()
+        );
+        // Nor are workbook definitions, which are evaluated once, into an environment
+        // layered over the prelude's.
+        let definitions =
+            Definitions::load(&[Expr::from_string("(defun f (x) (and x x))").unwrap()]).unwrap();
+        let program =
+            compile_with_prelude(&Expr::from_string("(f #t)").unwrap(), &definitions).unwrap();
+        assert_eq!(program.code.len(), 3);
+        assert_eq!(
+            eval(&program, definitions.env(), &EmptyKeywordResolver).unwrap(),
+            Value::Boolean(true)
+        );
+        assert!(Definitions::default().env().lookup("f").is_err());
+
+        // Every formula shares the one prelude environment, without being able to change it.
+        let parent = |env: Env| env.parent.unwrap();
+        assert!(Rc::ptr_eq(
+            &parent(Definitions::default().env()),
+            &parent(Definitions::default().env())
+        ));
+        let env = Rc::new(RefCell::new(Definitions::default().env()));
+        let program = compile_with_prelude(
+            &Expr::from_string("(def y 1)").unwrap(),
+            &Definitions::default(),
+        )
+        .unwrap();
+        eval_in_env(&program, env, &EmptyKeywordResolver, EvalLimits::default()).unwrap();
+        assert!(Definitions::default().env().lookup("y").is_err());
+    }
+
+    #[test]
+    fn test_volatile() {
+        let is_volatile = |src: &str| {
+            compile(&Expr::from_string(src).unwrap())
+                .unwrap()
+                .is_volatile()
+        };
+        assert!(!is_volatile("(+ 1 2)"));
+        assert!(is_volatile("(+ 1 (rand))"));
+        assert!(is_volatile(
+            "(map (lambda (x) (randbetween 1 x)) (list 1 2))"
+        ));
+        assert!(is_volatile("(map rand (list))"));
+        assert!(is_volatile("(lambda (&optional (x (rand))) x)"));
+    }
+
+    #[test]
+    fn test_compile_error_span() {
+        let err = match compile(&Expr::from_string("(begin 1\n  (if 1 2))").unwrap()) {
+            Err(err) => err,
+            Ok(_) => panic!("Expected a syntax error"),
+        };
+        assert_eq!(err.kind(), ErrorKind::Syntax);
+        assert_eq!(err.span(), Some(Span { start: 11, end: 19 }));
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        // Deeper than the parser allows, e.g. code generated by a program.
+        let mut expr = Expr::number(1.0);
+        for _ in 0..1_000 {
+            expr = Expr::list(vec![symbol("+"), Expr::number(1.0), expr]);
+        }
+        let err = compile(&expr).err().expect("Expected an error");
+        assert_eq!(err.kind(), ErrorKind::Syntax);
+        assert!(err.message().contains("too deeply nested"), "{}", err);
+
+        // Macros can nest code more deeply than it's written.
+        let src = format!("(lambda (x) (and {}))", "x ".repeat(300));
+        let expr = Expr::from_string(&src).unwrap();
+        assert!(compile_with_prelude(&expr, &Definitions::default()).is_err());
+        let src = format!("(lambda (x) (and {}))", "x ".repeat(20));
+        let expr = Expr::from_string(&src).unwrap();
+        assert!(compile_with_prelude(&expr, &Definitions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_runaway_macro_expansion() {
+        let expr = Expr::from_string("(begin (defmacro loop () '(loop)) (loop))").unwrap();
+        assert!(compile(&expr).is_err());
+    }
 }
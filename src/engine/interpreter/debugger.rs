@@ -0,0 +1,567 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::error::AppResult;
+
+use super::builtins::BUILTINS_ENVIRONMENT;
+use super::compiler::{Definitions, Program, PRELUDE};
+use super::decimal::RoundingMode;
+use super::disassembler::disassemble_lines;
+use super::env::Env;
+use super::evaluator::{eval_until, EvalLimits, KeywordResolver, Suspended};
+use super::model::{Bytecode, Instruction, Value};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Breakpoint {
+    /// Pause before running the instruction at `index` in `function` (`None` for the
+    /// top-level formula), as numbered by the disassembler.
+    Instruction {
+        function: Option<String>,
+        index: usize,
+    },
+    /// Pause before evaluating the expression that starts at this byte offset in the
+    /// formula's source (as in error spans), whichever function it's in.
+    Offset(usize),
+}
+
+/// Where in the compiled code a `Breakpoint::Offset` is.
+struct Location {
+    offset: usize,
+    code: Rc<Bytecode>,
+    index: usize,
+}
+
+/// Find where evaluating the expression starting at `offset` begins, in `code` or the
+/// code of a function it creates: the first instruction attributed to that expression or
+/// one inside it.
+fn find_offset(code: &Rc<Bytecode>, offset: usize) -> Option<(Rc<Bytecode>, usize)> {
+    let expr_span = code
+        .spans
+        .iter()
+        .flatten()
+        .filter(|span| span.start == offset)
+        .max_by_key(|span| span.end);
+    if let Some(expr_span) = expr_span {
+        let index = code.spans.iter().position(|span| {
+            matches!(span, Some(span) if span.start >= expr_span.start && span.end <= expr_span.end)
+        })?;
+        return Some((code.clone(), index));
+    }
+    code.instructions
+        .iter()
+        .find_map(|instruction| match instruction {
+            Instruction::LoadConst(value) => match value.as_ref() {
+                Value::CompiledCode(body) => find_offset(body, offset),
+                _ => None,
+            },
+            Instruction::MakeFunction { params } => params
+                .optional
+                .iter()
+                .find_map(|(_, default)| find_offset(default, offset)),
+            _ => None,
+        })
+}
+
+/// A function call in progress when evaluation paused.
+#[derive(Clone, Debug)]
+pub struct DebugFrame {
+    /// Name of the function, or `None` for the top-level formula.
+    pub function: Option<String>,
+    /// Index of the next instruction to run.
+    pub index: usize,
+    /// The frame's code, one line per instruction.
+    pub code: Vec<String>,
+    /// The frame's part of the operand stack, top last.
+    pub stack: Vec<Value>,
+    /// Variables of the frame's environment and those enclosing it, innermost first.
+    /// Built-ins and the prelude are left out.
+    pub environments: Vec<Vec<(String, Value)>>,
+}
+
+/// Where evaluation stopped.
+#[derive(Clone, Debug)]
+pub struct Pause {
+    /// Number of instructions run so far.
+    pub step: u64,
+    /// The call stack, outermost first.
+    pub frames: Vec<DebugFrame>,
+}
+
+#[derive(Clone, Debug)]
+pub enum DebugState {
+    Paused(Pause),
+    Finished(AppResult<Value>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum StepMode {
+    /// Pause at the next instruction.
+    Into,
+    /// Pause at the next instruction not in a function called from the current frame.
+    Over { depth: usize },
+    /// Pause once the current frame has returned.
+    Out { depth: usize },
+    /// Pause only at breakpoints.
+    Continue,
+}
+
+/// When a debugged evaluation should stop.
+pub(super) struct PauseTarget<'b> {
+    from_step: u64,
+    mode: StepMode,
+    breakpoints: &'b [Breakpoint],
+    locations: &'b [Location],
+    /// The environment of the definitions the program was compiled against, which it's
+    /// run in a child of.
+    pub(super) globals: &'b Rc<RefCell<Env>>,
+}
+
+impl<'b> PauseTarget<'b> {
+    /// Whether to pause before running instruction number `step`, at `index` in `code`,
+    /// in a frame running `function` with `depth` frames on the call stack.
+    pub(super) fn should_pause(
+        &self,
+        step: u64,
+        depth: usize,
+        function: Option<&str>,
+        code: &Rc<Bytecode>,
+        index: usize,
+    ) -> bool {
+        if step < self.from_step {
+            return false;
+        }
+        let at_breakpoint = self.breakpoints.iter().any(|breakpoint| match breakpoint {
+            Breakpoint::Instruction {
+                function: breakpoint_function,
+                index: breakpoint_index,
+            } => *breakpoint_index == index && breakpoint_function.as_deref() == function,
+            Breakpoint::Offset(_) => false,
+        }) || self
+            .locations
+            .iter()
+            .any(|location| location.index == index && Rc::ptr_eq(&location.code, code));
+        at_breakpoint
+            || match self.mode {
+                StepMode::Into => true,
+                StepMode::Over { depth: from } => depth <= from,
+                StepMode::Out { depth: from } => depth < from,
+                StepMode::Continue => false,
+            }
+    }
+}
+
+impl DebugFrame {
+    pub(super) fn new(
+        function: Option<&str>,
+        index: usize,
+        instructions: &[Instruction],
+        stack: &[Value],
+        env: &Rc<RefCell<Env>>,
+        globals: &Rc<RefCell<Env>>,
+    ) -> Self {
+        let chain = environment_chain(env, globals);
+        // Local variable names, for annotating the code.
+        let scopes = chain
+            .iter()
+            .rev()
+            .map(|env| env.borrow().slot_names.clone())
+            .collect();
+        Self {
+            function: function.map(str::to_string),
+            index,
+            code: disassemble_lines(instructions, scopes),
+            stack: stack.to_vec(),
+            environments: chain.iter().map(|env| env.borrow().bindings()).collect(),
+        }
+    }
+}
+
+/// `env` and its parents, innermost first, up to the environments shared by every
+/// formula: `globals`, the prelude's and the builtins'.
+fn environment_chain(env: &Rc<RefCell<Env>>, globals: &Rc<RefCell<Env>>) -> Vec<Rc<RefCell<Env>>> {
+    let prelude = PRELUDE.with(|prelude| prelude.env.clone());
+    let builtins = BUILTINS_ENVIRONMENT.with(|builtins| builtins.clone());
+    let mut chain = Vec::new();
+    let mut current = Some(env.clone());
+    while let Some(env) = current {
+        if [globals, &prelude, &builtins]
+            .iter()
+            .any(|shared| Rc::ptr_eq(&env, shared))
+        {
+            break;
+        }
+        current = env.borrow().parent.clone();
+        chain.push(env);
+    }
+    chain
+}
+
+/// Resolves keywords to the values they had when a debug session started, so that the
+/// session doesn't need to hold on to the sheet.
+struct KeywordSnapshot {
+    values: HashMap<String, AppResult<Value>>,
+    /// So that every run gets the same random numbers.
+    random_seed: u64,
+    rounding_mode: RoundingMode,
+}
+
+impl KeywordSnapshot {
+    fn new<R: KeywordResolver>(instructions: &[Instruction], kw_resolver: &R) -> Self {
+        let mut snapshot = Self {
+            values: HashMap::new(),
+            random_seed: kw_resolver.random_seed(),
+            rounding_mode: kw_resolver.rounding_mode(),
+        };
+        snapshot.resolve_all(instructions, kw_resolver);
+        snapshot
+    }
+
+    fn resolve_all<R: KeywordResolver>(&mut self, instructions: &[Instruction], kw_resolver: &R) {
+        for instruction in instructions {
+            match instruction {
+                Instruction::LoadKeyword(kw) if !self.values.contains_key(kw) => {
+                    self.values
+                        .insert(kw.clone(), kw_resolver.resolve_keyword(kw));
+                }
+                Instruction::LoadConst(value) => {
+                    if let Value::CompiledCode(code) = value.as_ref() {
+                        self.resolve_all(&code.instructions, kw_resolver);
+                    }
+                }
+                Instruction::MakeFunction { params } => {
+                    for (_, default) in &params.optional {
+                        self.resolve_all(&default.instructions, kw_resolver);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+impl KeywordResolver for KeywordSnapshot {
+    fn resolve_keyword(&self, kw: &str) -> AppResult<Value> {
+        self.values.get(kw).cloned().unwrap_or(Ok(Value::Nil))
+    }
+
+    fn random_seed(&self) -> u64 {
+        self.random_seed
+    }
+
+    fn rounding_mode(&self) -> RoundingMode {
+        self.rounding_mode
+    }
+}
+
+/// Steps through the evaluation of a program, which is run in the environment of the
+/// definitions it was compiled against.
+///
+/// Each command continues the evaluation from where it paused. Evaluation can't be
+/// suspended in the middle of a built-in (e.g. `map` calling a function) though, so
+/// after pausing there the next command evaluates the program afresh up to the next
+/// point to pause at. Formulas are deterministic, so that run retraces the same steps.
+pub struct DebugSession {
+    code: Rc<Bytecode>,
+    globals: Rc<RefCell<Env>>,
+    keywords: KeywordSnapshot,
+    limits: EvalLimits,
+    breakpoints: Vec<Breakpoint>,
+    locations: Vec<Location>,
+    suspended: Option<Suspended>,
+    state: DebugState,
+}
+
+impl DebugSession {
+    /// Start debugging `program`, compiled against `definitions`, paused before its first
+    /// instruction.
+    pub fn new<R: KeywordResolver>(
+        program: &Program,
+        definitions: &Definitions,
+        kw_resolver: &R,
+        limits: EvalLimits,
+    ) -> Self {
+        let code = program.code.clone();
+        let keywords = KeywordSnapshot::new(&code.instructions, kw_resolver);
+        let mut session = Self {
+            code,
+            globals: definitions.env.clone(),
+            keywords,
+            limits,
+            breakpoints: Vec::new(),
+            locations: Vec::new(),
+            suspended: None,
+            state: DebugState::Finished(Ok(Value::Nil)),
+        };
+        session.run(0, StepMode::Into);
+        session
+    }
+
+    pub fn state(&self) -> &DebugState {
+        &self.state
+    }
+
+    /// Returns whether there's code where the breakpoint is. A `Breakpoint::Offset` that
+    /// isn't the start of an expression that's compiled to code is never reached.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> bool {
+        if self.breakpoints.contains(&breakpoint) {
+            return true;
+        }
+        if let Breakpoint::Offset(offset) = breakpoint {
+            match find_offset(&self.code, offset) {
+                Some((code, index)) => self.locations.push(Location {
+                    offset,
+                    code,
+                    index,
+                }),
+                None => return false,
+            }
+        }
+        self.breakpoints.push(breakpoint);
+        true
+    }
+
+    /// Returns whether there was such a breakpoint.
+    pub fn remove_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        if let Breakpoint::Offset(offset) = breakpoint {
+            self.locations.retain(|location| location.offset != *offset);
+        }
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|existing| existing != breakpoint);
+        self.breakpoints.len() != len
+    }
+
+    /// Run one instruction, stopping in any function it calls.
+    pub fn step_into(&mut self) {
+        self.step(|_| StepMode::Into);
+    }
+
+    /// Run one instruction, including the whole of any function it calls.
+    pub fn step_over(&mut self) {
+        self.step(|depth| StepMode::Over { depth });
+    }
+
+    /// Run until the current function returns.
+    pub fn step_out(&mut self) {
+        self.step(|depth| StepMode::Out { depth });
+    }
+
+    /// Run until the next breakpoint, or to the end.
+    pub fn resume(&mut self) {
+        self.step(|_| StepMode::Continue);
+    }
+
+    fn step(&mut self, mode: impl FnOnce(usize) -> StepMode) {
+        if let DebugState::Paused(pause) = &self.state {
+            let (from_step, mode) = (pause.step + 1, mode(pause.frames.len()));
+            self.run(from_step, mode);
+        }
+    }
+
+    fn run(&mut self, from_step: u64, mode: StepMode) {
+        let target = PauseTarget {
+            from_step,
+            mode,
+            breakpoints: &self.breakpoints,
+            locations: &self.locations,
+            globals: &self.globals,
+        };
+        self.state = eval_until(
+            self.code.clone(),
+            &mut self.suspended,
+            &self.keywords,
+            self.limits,
+            &target,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::compile_with_prelude;
+    use crate::parser::Expr;
+
+    struct Resolver;
+
+    impl KeywordResolver for Resolver {
+        fn resolve_keyword(&self, _kw: &str) -> AppResult<Value> {
+            Ok(Value::Number(2.0))
+        }
+    }
+
+    fn start(src: &str) -> DebugSession {
+        let definitions = Definitions::default();
+        let program = compile_with_prelude(&Expr::from_string(src).unwrap(), &definitions).unwrap();
+        DebugSession::new(&program, &definitions, &Resolver, EvalLimits::default())
+    }
+
+    fn pause(session: &DebugSession) -> &Pause {
+        match session.state() {
+            DebugState::Paused(pause) => pause,
+            DebugState::Finished(result) => panic!("Finished with {:?}", result),
+        }
+    }
+
+    fn functions(pause: &Pause) -> Vec<Option<&str>> {
+        pause
+            .frames
+            .iter()
+            .map(|frame| frame.function.as_deref())
+            .collect()
+    }
+
+    const SRC: &str = "(begin (defun sq (x) (* x x)) (+ (sq 3) (car (map sq (list :a1)))))";
+
+    #[test]
+    fn test_stepping() {
+        let mut session = start(SRC);
+        let first = pause(&session);
+        assert_eq!(first.step, 0);
+        assert_eq!(functions(first), vec![None]);
+        assert_eq!(first.frames[0].index, 0);
+
+        // Step into `sq`.
+        while pause(&session).frames.len() == 1 {
+            session.step_into();
+        }
+        let frame = &pause(&session).frames[1];
+        assert_eq!(frame.function.as_deref(), Some("sq"));
+        assert_eq!(frame.index, 0);
+        assert_eq!(frame.code[1], "LoadLocal 0 0  ; x");
+        assert_eq!(
+            frame.environments[0],
+            vec![("x".to_string(), Value::Number(3.0))]
+        );
+        // The top level defined `sq` globally.
+        assert!(frame.environments[1].iter().any(|(name, _)| name == "sq"));
+
+        session.step_into();
+        session.step_into();
+        assert_eq!(pause(&session).frames[1].index, 2);
+        assert_eq!(pause(&session).frames[1].stack.len(), 2);
+        session.step_out();
+        let frames = &pause(&session).frames;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].stack.last(), Some(&Value::Number(9.0)));
+
+        session.resume();
+        assert!(matches!(
+            session.state(),
+            DebugState::Finished(Ok(Value::Number(n))) if *n == 13.0
+        ));
+        // Nothing more happens once finished.
+        session.step_into();
+        assert!(matches!(session.state(), DebugState::Finished(Ok(_))));
+    }
+
+    #[test]
+    fn test_step_over_and_breakpoints() {
+        let mut session = start(SRC);
+        // Stepping over never enters `sq`, even when `map` calls it.
+        let mut steps = 0;
+        while let DebugState::Paused(pause) = session.state() {
+            assert_eq!(pause.frames.len(), 1);
+            session.step_over();
+            steps += 1;
+        }
+        assert!(steps > 5);
+
+        let breakpoint = Breakpoint::Instruction {
+            function: Some("sq".to_string()),
+            index: 3,
+        };
+        let mut session = start(SRC);
+        session.add_breakpoint(breakpoint.clone());
+        session.resume();
+        assert_eq!(functions(pause(&session)), vec![None, Some("sq")]);
+        assert_eq!(pause(&session).frames[1].index, 3);
+        assert_eq!(pause(&session).frames[1].stack.len(), 3);
+        session.resume();
+        assert_eq!(
+            functions(pause(&session)),
+            vec![None, Some("map"), Some("sq")]
+        );
+        assert!(session.remove_breakpoint(&breakpoint));
+        session.resume();
+        assert!(matches!(session.state(), DebugState::Finished(Ok(_))));
+    }
+
+    #[test]
+    fn test_breakpoints_at_offsets() {
+        let mut session = start(SRC);
+        let in_sq = SRC.find("(* x x)").unwrap();
+        assert!(session.add_breakpoint(Breakpoint::Offset(in_sq)));
+        let car = SRC.find("(car (map").unwrap();
+        assert!(session.add_breakpoint(Breakpoint::Offset(car)));
+        // Between expressions.
+        assert!(!session.add_breakpoint(Breakpoint::Offset(in_sq - 1)));
+
+        session.resume();
+        assert_eq!(functions(pause(&session)), vec![None, Some("sq")]);
+        assert_eq!(pause(&session).frames[1].index, 0);
+        session.resume();
+        assert_eq!(functions(pause(&session)), vec![None]);
+        assert_eq!(
+            pause(&session).frames[0].stack.last(),
+            Some(&Value::Number(9.0))
+        );
+        session.resume();
+        assert_eq!(
+            functions(pause(&session)),
+            vec![None, Some("map"), Some("sq")]
+        );
+        assert!(session.remove_breakpoint(&Breakpoint::Offset(in_sq)));
+        session.resume();
+        assert!(matches!(
+            session.state(),
+            DebugState::Finished(Ok(Value::Number(n))) if *n == 13.0
+        ));
+    }
+
+    #[test]
+    fn test_resuming_many_times() {
+        // Each pause continues from the last, through tail calls.
+        let src = "(begin (defun count (n acc) (if (= n 0) acc (count (- n 1) (+ acc 1)))) \
+                   (count 2000 0))";
+        let mut session = start(src);
+        session.add_breakpoint(Breakpoint::Offset(src.find("(+ acc 1)").unwrap()));
+        let mut last_step = 0;
+        for i in 0..2000 {
+            session.resume();
+            let pause = pause(&session);
+            assert!(pause.step > last_step);
+            last_step = pause.step;
+            // The top-level call is a tail call too.
+            assert_eq!(functions(pause), vec![Some("count")]);
+            assert!(pause.frames[0].environments[0]
+                .contains(&("acc".to_string(), Value::Number(i as f64))));
+        }
+        session.resume();
+        assert!(matches!(
+            session.state(),
+            DebugState::Finished(Ok(Value::Number(n))) if *n == 2000.0
+        ));
+    }
+
+    #[test]
+    fn test_pausing_inside_try() {
+        // Pausing isn't an error that `try` can catch, even from within `map`.
+        let mut session =
+            start("(begin (defun sq (x) (* x x)) (try (map sq (list 2)) (catch e 0)))");
+        session.add_breakpoint(Breakpoint::Instruction {
+            function: Some("sq".to_string()),
+            index: 0,
+        });
+        session.resume();
+        assert_eq!(
+            functions(pause(&session)),
+            vec![None, Some("map"), Some("sq")]
+        );
+        session.resume();
+        assert!(matches!(
+            session.state(),
+            DebugState::Finished(Ok(Value::List(items))) if items == &vec![Value::Number(4.0)]
+        ));
+    }
+}
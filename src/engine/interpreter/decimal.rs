@@ -0,0 +1,381 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::error::{AppError, AppResult};
+
+/// Maximum number of digits kept after the decimal point. Results of multiplication
+/// that would exceed this are rounded (half-even) back down to it.
+const MAX_SCALE: u32 = 28;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Round to nearest, ties away from zero (what spreadsheets do for ROUND).
+    #[default]
+    HalfUp,
+    /// Round to nearest, ties to the even neighbour ("banker's rounding").
+    HalfEven,
+    /// Round away from zero (ROUNDUP).
+    Up,
+    /// Round towards zero (ROUNDDOWN).
+    Down,
+}
+
+impl RoundingMode {
+    pub fn from_name(name: &str) -> AppResult<Self> {
+        match name {
+            "half-up" => Ok(RoundingMode::HalfUp),
+            "half-even" => Ok(RoundingMode::HalfEven),
+            "up" => Ok(RoundingMode::Up),
+            "down" => Ok(RoundingMode::Down),
+            _ => Err(AppError::new(format!("Unknown rounding mode: {}", name))),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RoundingMode::HalfUp => "half-up",
+            RoundingMode::HalfEven => "half-even",
+            RoundingMode::Up => "up",
+            RoundingMode::Down => "down",
+        }
+    }
+}
+
+/// An exact base-10 number: `mantissa * 10^-scale`.
+#[derive(Clone, Copy, Debug)]
+pub struct Decimal {
+    mantissa: i128,
+    scale: u32,
+}
+
+fn pow10(exp: u32) -> AppResult<i128> {
+    10i128
+        .checked_pow(exp)
+        .ok_or_else(|| AppError::new("Decimal overflow"))
+}
+
+impl Decimal {
+    pub fn new(mantissa: i128, scale: u32) -> Self {
+        Self { mantissa, scale }
+    }
+
+    pub fn parse(s: &str) -> AppResult<Self> {
+        let invalid = || AppError::new(format!("Invalid decimal: {:?}", s));
+        let (negative, digits) = match s.trim() {
+            t if t.starts_with('-') => (true, &t[1..]),
+            t if t.starts_with('+') => (false, &t[1..]),
+            t => (false, t),
+        };
+        let (int_part, frac_part) = match digits.split_once('.') {
+            Some((int_part, frac_part)) => (int_part, frac_part),
+            None => (digits, ""),
+        };
+        if (int_part.is_empty() && frac_part.is_empty())
+            || !int_part
+                .chars()
+                .chain(frac_part.chars())
+                .all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+        let mut mantissa: i128 = 0;
+        for c in int_part.chars().chain(frac_part.chars()) {
+            mantissa = mantissa
+                .checked_mul(10)
+                .and_then(|m| m.checked_add(c.to_digit(10).unwrap() as i128))
+                .ok_or_else(|| AppError::new("Decimal overflow"))?;
+        }
+        let decimal = Self::new(
+            if negative { -mantissa } else { mantissa },
+            frac_part.len() as u32,
+        );
+        if decimal.scale > MAX_SCALE {
+            decimal.round(MAX_SCALE as i32, RoundingMode::HalfEven)
+        } else {
+            Ok(decimal)
+        }
+    }
+
+    /// Convert from a float using its shortest round-tripping representation, so that
+    /// `0.1` becomes exactly `0.1` rather than `0.1000000000000000055511151231257827`.
+    pub fn from_f64(n: f64) -> AppResult<Self> {
+        if !n.is_finite() {
+            return Err(AppError::new(format!("Cannot convert {} to a decimal", n)));
+        }
+        Self::parse(&n.to_string())
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.to_string().parse().unwrap_or(f64::NAN)
+    }
+
+    fn rescale(&self, scale: u32) -> AppResult<i128> {
+        self.mantissa
+            .checked_mul(pow10(scale - self.scale)?)
+            .ok_or_else(|| AppError::new("Decimal overflow"))
+    }
+
+    pub fn checked_add(&self, other: &Decimal) -> AppResult<Decimal> {
+        let scale = self.scale.max(other.scale);
+        let mantissa = self
+            .rescale(scale)?
+            .checked_add(other.rescale(scale)?)
+            .ok_or_else(|| AppError::new("Decimal overflow"))?;
+        Ok(Decimal::new(mantissa, scale))
+    }
+
+    pub fn checked_neg(&self) -> AppResult<Decimal> {
+        let mantissa = self
+            .mantissa
+            .checked_neg()
+            .ok_or_else(|| AppError::new("Decimal overflow"))?;
+        Ok(Decimal::new(mantissa, self.scale))
+    }
+
+    pub fn checked_sub(&self, other: &Decimal) -> AppResult<Decimal> {
+        self.checked_add(&other.checked_neg()?)
+    }
+
+    /// Long division, stopping once the quotient is exact or has `MAX_SCALE` digits after
+    /// the decimal point (the last one rounded half-even).
+    pub fn checked_div(&self, other: &Decimal) -> AppResult<Decimal> {
+        if other.mantissa == 0 {
+            return Err(AppError::new("Division by zero"));
+        }
+        let overflow = || AppError::new("Decimal overflow");
+        let scale = self.scale.max(other.scale);
+        let dividend = self.rescale(scale)?.checked_abs().ok_or_else(overflow)?;
+        let divisor = other.rescale(scale)?.checked_abs().ok_or_else(overflow)?;
+        let mut mantissa = dividend / divisor;
+        let mut remainder = dividend % divisor;
+        let mut result_scale = 0;
+        while remainder != 0 && result_scale < MAX_SCALE {
+            remainder = remainder.checked_mul(10).ok_or_else(overflow)?;
+            mantissa = mantissa
+                .checked_mul(10)
+                .and_then(|m| m.checked_add(remainder / divisor))
+                .ok_or_else(overflow)?;
+            remainder %= divisor;
+            result_scale += 1;
+        }
+        if remainder != 0 {
+            let round_away = match remainder.checked_mul(2).map(|r| r.cmp(&divisor)) {
+                Some(Ordering::Greater) | None => true,
+                Some(Ordering::Equal) => mantissa % 2 != 0,
+                Some(Ordering::Less) => false,
+            };
+            if round_away {
+                mantissa = mantissa.checked_add(1).ok_or_else(overflow)?;
+            }
+        }
+        if (self.mantissa < 0) != (other.mantissa < 0) {
+            mantissa = -mantissa;
+        }
+        Ok(Decimal::new(mantissa, result_scale))
+    }
+
+    pub fn checked_mul(&self, other: &Decimal) -> AppResult<Decimal> {
+        let mantissa = self
+            .mantissa
+            .checked_mul(other.mantissa)
+            .ok_or_else(|| AppError::new("Decimal overflow"))?;
+        let product = Decimal::new(mantissa, self.scale + other.scale);
+        if product.scale > MAX_SCALE {
+            product.round(MAX_SCALE as i32, RoundingMode::HalfEven)
+        } else {
+            Ok(product)
+        }
+    }
+
+    /// Round to `digits` places after the decimal point. Negative `digits` round to
+    /// the left of the decimal point, e.g. `round(1234, -2) == 1200`.
+    pub fn round(&self, digits: i32, mode: RoundingMode) -> AppResult<Decimal> {
+        if digits >= self.scale as i32 {
+            return Ok(*self);
+        }
+        let shift = (self.scale as i32)
+            .checked_sub(digits)
+            .ok_or_else(|| AppError::new("Decimal overflow"))?;
+        let divisor = pow10(shift as u32)?;
+        let quotient = self.mantissa / divisor;
+        let remainder = (self.mantissa % divisor).abs();
+        // Compared with what's left of the divisor rather than doubled, which could
+        // overflow for divisors near 10^38.
+        let round_away = match mode {
+            RoundingMode::Down => false,
+            RoundingMode::Up => remainder != 0,
+            RoundingMode::HalfUp => remainder >= divisor - remainder,
+            RoundingMode::HalfEven => match remainder.cmp(&(divisor - remainder)) {
+                Ordering::Greater => true,
+                Ordering::Equal => quotient % 2 != 0,
+                Ordering::Less => false,
+            },
+        };
+        let quotient = if round_away {
+            quotient + self.mantissa.signum()
+        } else {
+            quotient
+        };
+        if digits >= 0 {
+            Ok(Decimal::new(quotient, digits as u32))
+        } else {
+            let mantissa = quotient
+                .checked_mul(pow10(digits.unsigned_abs())?)
+                .ok_or_else(|| AppError::new("Decimal overflow"))?;
+            Ok(Decimal::new(mantissa, 0))
+        }
+    }
+
+    /// Round to `digits` significant digits.
+    pub fn round_significant(&self, digits: u32, mode: RoundingMode) -> AppResult<Decimal> {
+        if self.mantissa == 0 {
+            return Ok(*self);
+        }
+        let total_digits = self.mantissa.unsigned_abs().to_string().len() as i32;
+        let integer_digits = total_digits - self.scale as i32;
+        self.round(digits as i32 - integer_digits, mode)
+    }
+
+    /// Compare two decimals by value, regardless of scale.
+    pub fn cmp_value(&self, other: &Decimal) -> Ordering {
+        let scale = self.scale.max(other.scale);
+        match (self.rescale(scale), other.rescale(scale)) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            // Rescaling only overflows for huge magnitudes; fall back to floats.
+            _ => self
+                .to_f64()
+                .partial_cmp(&other.to_f64())
+                .unwrap_or(Ordering::Equal),
+        }
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Decimal) -> bool {
+        self.cmp_value(other) == Ordering::Equal
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = self.mantissa.unsigned_abs().to_string();
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+        let scale = self.scale as usize;
+        if scale == 0 {
+            write!(f, "{}{}", sign, digits)
+        } else if digits.len() > scale {
+            let (int_part, frac_part) = digits.split_at(digits.len() - scale);
+            write!(f, "{}{}.{}", sign, int_part, frac_part)
+        } else {
+            write!(
+                f,
+                "{}0.{}{}",
+                sign,
+                "0".repeat(scale - digits.len()),
+                digits
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!(dec("0.1").to_string(), "0.1");
+        assert_eq!(dec("-12.340").to_string(), "-12.340");
+        assert_eq!(dec("42").to_string(), "42");
+        assert_eq!(dec("-0.05").to_string(), "-0.05");
+        assert!(Decimal::parse("1.2.3").is_err());
+        assert!(Decimal::parse("abc").is_err());
+    }
+
+    #[test]
+    fn test_from_f64() {
+        assert_eq!(Decimal::from_f64(0.1).unwrap().to_string(), "0.1");
+        assert_eq!(Decimal::from_f64(19.99).unwrap().to_string(), "19.99");
+        assert!(Decimal::from_f64(f64::NAN).is_err());
+    }
+
+    #[test]
+    fn test_exact_arithmetic() {
+        assert_eq!(
+            dec("0.1").checked_add(&dec("0.2")).unwrap().to_string(),
+            "0.3"
+        );
+        assert_eq!(
+            dec("19.99").checked_mul(&dec("3")).unwrap().to_string(),
+            "59.97"
+        );
+        assert_eq!(dec("0.30"), dec("0.3"));
+        assert_eq!(
+            dec("0.3").checked_sub(&dec("0.1")).unwrap().to_string(),
+            "0.2"
+        );
+        assert_eq!(
+            dec("1").checked_div(&dec("8")).unwrap().to_string(),
+            "0.125"
+        );
+        assert_eq!(
+            dec("-10.5").checked_div(&dec("0.25")).unwrap().to_string(),
+            "-42"
+        );
+        assert_eq!(
+            dec("2").checked_div(&dec("3")).unwrap().to_string(),
+            "0.6666666666666666666666666667"
+        );
+        assert!(dec("1").checked_div(&dec("0.00")).is_err());
+    }
+
+    #[test]
+    fn test_round() {
+        let round = |s: &str, digits: i32, mode: RoundingMode| {
+            dec(s).round(digits, mode).unwrap().to_string()
+        };
+        assert_eq!(round("2.675", 2, RoundingMode::HalfUp), "2.68");
+        assert_eq!(round("2.665", 2, RoundingMode::HalfEven), "2.66");
+        assert_eq!(round("2.675", 2, RoundingMode::HalfEven), "2.68");
+        assert_eq!(round("-2.5", 0, RoundingMode::HalfUp), "-3");
+        assert_eq!(round("-2.5", 0, RoundingMode::HalfEven), "-2");
+        assert_eq!(round("3.14159", 3, RoundingMode::Up), "3.142");
+        assert_eq!(round("-3.14159", 3, RoundingMode::Down), "-3.141");
+        assert_eq!(round("1250", -2, RoundingMode::HalfEven), "1200");
+        assert_eq!(round("1250", -2, RoundingMode::HalfUp), "1300");
+        assert_eq!(round("1.5", 4, RoundingMode::HalfUp), "1.5");
+        // Extreme digit counts are errors (or no-ops), never overflows.
+        assert!(dec("5").round(i32::MIN, RoundingMode::HalfUp).is_err());
+        assert!(dec("1.5").round(-40, RoundingMode::Up).is_err());
+        assert_eq!(round("1.5", i32::MAX, RoundingMode::HalfUp), "1.5");
+        let nines = "9".repeat(38);
+        for mode in &[RoundingMode::HalfUp, RoundingMode::HalfEven] {
+            assert_eq!(round(&nines, -38, *mode), format!("1{}", "0".repeat(38)));
+            assert_eq!(
+                round(&format!("-{}", nines), -38, *mode),
+                format!("-1{}", "0".repeat(38))
+            );
+        }
+        assert_eq!(
+            round(&format!("4{}", &nines[1..]), -38, RoundingMode::HalfUp),
+            "0"
+        );
+        let round_significant = |s: &str, digits: u32, mode: RoundingMode| {
+            dec(s).round_significant(digits, mode).unwrap().to_string()
+        };
+        assert_eq!(
+            round_significant("0.30000000000000004", 15, RoundingMode::HalfUp),
+            "0.300000000000000"
+        );
+        assert_eq!(round_significant("12.5", 2, RoundingMode::HalfEven), "12");
+        assert_eq!(round_significant("-12.5", 2, RoundingMode::HalfUp), "-13");
+        assert_eq!(
+            round_significant("0.001235", 3, RoundingMode::HalfEven),
+            "0.00124"
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use std::rc::Rc;
+
+use super::compiler::Program;
+use super::model::{Instruction, Value};
+
+const INDENT: &str = "    ";
+
+struct Disassembler {
+    output: String,
+    /// Local variable names of the functions whose code is being listed, innermost last,
+    /// for annotating `LoadLocal` and `StoreLocal`.
+    scopes: Vec<Rc<Vec<String>>>,
+}
+
+impl Disassembler {
+    fn local_name(&self, depth: usize, index: usize) -> Option<&str> {
+        let scope = self.scopes.iter().rev().nth(depth)?;
+        scope.get(index).map(|name| name.as_str())
+    }
+
+    fn line(&mut self, level: usize, text: &str) {
+        for _ in 0..level {
+            self.output.push_str(INDENT);
+        }
+        self.output.push_str(text);
+        self.output.push('\n');
+    }
+
+    fn describe(&self, idx: usize, instruction: &Instruction) -> String {
+        let target = |offset: i32| idx as i32 + 1 + offset;
+        let local = |depth: usize, index: usize| match self.local_name(depth, index) {
+            Some(name) => format!("  ; {}", name),
+            None => String::new(),
+        };
+        match instruction {
+            Instruction::LoadConst(value) => match value.as_ref() {
+                Value::CompiledCode(code) => {
+                    format!("LoadConst <code: {} instructions>", code.len())
+                }
+                value => format!("LoadConst {:?}", value),
+            },
+            Instruction::StoreName(name) => format!("StoreName {}", name),
+            Instruction::LoadName(name) => format!("LoadName {}", name),
+            Instruction::StoreLocal { index } => {
+                format!("StoreLocal {}{}", index, local(0, *index))
+            }
+            Instruction::LoadLocal { depth, index } => {
+                format!("LoadLocal {} {}{}", depth, index, local(*depth, *index))
+            }
+            Instruction::LoadKeyword(keyword) => format!("LoadKeyword :{}", keyword),
+            Instruction::CallFunction { nargs } => format!("CallFunction {}", nargs),
+            Instruction::TailCall { nargs } => format!("TailCall {}", nargs),
+            Instruction::ApplyFunction => "ApplyFunction".to_string(),
+            Instruction::RelativeJumpIfTrue { offset } => {
+                format!("RelativeJumpIfTrue {:+} -> {}", offset, target(*offset))
+            }
+            Instruction::RelativeJump { offset } => {
+                format!("RelativeJump {:+} -> {}", offset, target(*offset))
+            }
+            Instruction::MakeFunction { params } => format!("MakeFunction {}", params),
+            Instruction::DiscardValue => "DiscardValue".to_string(),
+            Instruction::PushHandler { offset } => {
+                format!("PushHandler {:+} -> {}", offset, target(*offset))
+            }
+            Instruction::PopHandler => "PopHandler".to_string(),
+        }
+    }
+
+    fn disassemble(&mut self, instructions: &[Instruction], level: usize) {
+        let width = instructions.len().saturating_sub(1).to_string().len();
+        for (idx, instruction) in instructions.iter().enumerate() {
+            let text = format!(
+                "{:>width$}  {}",
+                idx,
+                self.describe(idx, instruction),
+                width = width
+            );
+            self.line(level, &text);
+            match instruction {
+                // A function body, which is turned into a function by the `MakeFunction`
+                // that follows it.
+                Instruction::LoadConst(value) => {
+                    if let Value::CompiledCode(code) = value.as_ref() {
+                        let locals = match instructions.get(idx + 1) {
+                            Some(Instruction::MakeFunction { params }) => params.locals.clone(),
+                            _ => Rc::new(Vec::new()),
+                        };
+                        self.scopes.push(locals);
+                        self.disassemble(&code.instructions, level + 1);
+                        self.scopes.pop();
+                    }
+                }
+                // Defaults are evaluated in the new function's environment.
+                Instruction::MakeFunction { params } => {
+                    self.scopes.push(params.locals.clone());
+                    for (name, default) in &params.optional {
+                        self.line(level + 1, &format!("default for {}:", name));
+                        self.disassemble(&default.instructions, level + 2);
+                    }
+                    self.scopes.pop();
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// One line per instruction of `instructions`, without listing nested code. `scopes`
+/// are the local variable names of the enclosing functions, innermost last.
+pub(super) fn disassemble_lines(
+    instructions: &[Instruction],
+    scopes: Vec<Rc<Vec<String>>>,
+) -> Vec<String> {
+    let disassembler = Disassembler {
+        output: String::new(),
+        scopes,
+    };
+    instructions
+        .iter()
+        .enumerate()
+        .map(|(idx, instruction)| disassembler.describe(idx, instruction))
+        .collect()
+}
+
+/// A readable listing of `program`: one numbered instruction per line, with jump
+/// targets resolved to instruction numbers and local variables to their names. Function
+/// bodies and the defaults of optional parameters are listed, indented, below the
+/// instruction that creates them.
+pub fn disassemble(program: &Program) -> String {
+    let mut disassembler = Disassembler {
+        output: String::new(),
+        scopes: Vec::new(),
+    };
+    disassembler.disassemble(&program.code.instructions, 0);
+    disassembler.output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::compile;
+    use crate::parser::Expr;
+
+    fn listing(src: &str) -> String {
+        disassemble(&compile(&Expr::from_string(src).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(
+            listing("(if (< x 1) (f x) :A1)"),
+            "\
+0  LoadName <
+1  LoadName x
+2  LoadConst Number(1)
+3  CallFunction 2
+4  RelativeJumpIfTrue +2 -> 7
+5  LoadKeyword :A1
+6  RelativeJump +3 -> 10
+7  LoadName f
+8  LoadName x
+9  TailCall 1
+"
+        );
+        assert_eq!(
+            listing("(def g (lambda (a &optional (b a)) (begin (def c (* a b)) c)))"),
+            "\
+0  LoadConst <code: 6 instructions>
+    0  LoadName *
+    1  LoadLocal 0 0  ; a
+    2  LoadLocal 0 1  ; b
+    3  CallFunction 2
+    4  StoreLocal 2  ; c
+    5  LoadLocal 0 2  ; c
+1  MakeFunction (a &optional b)
+    default for b:
+        0  LoadLocal 0 0  ; a
+2  StoreName g
+3  LoadConst Nil
+"
+        );
+        assert_eq!(
+            listing("(try (f) (catch e e))"),
+            "\
+0  LoadConst <code: 1 instructions>
+    0  LoadLocal 0 0  ; e
+1  MakeFunction (e)
+2  PushHandler +4 -> 7
+3  LoadName f
+4  CallFunction 0
+5  PopHandler
+6  RelativeJump +1 -> 8
+7  TailCall 1
+"
+        );
+    }
+}
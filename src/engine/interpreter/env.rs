@@ -2,7 +2,7 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 
-use crate::error::{AppError, AppResult};
+use crate::error::{AppError, AppResult, ErrorKind};
 
 use super::builtins::BUILTINS_ENVIRONMENT;
 use super::model::Value;
@@ -11,9 +11,26 @@ use super::model::Value;
 pub struct Env {
     /// Variable assignments within the env
     pub(super) table: HashMap<String, Value>,
+    /// Local variables of a function call, which the compiler resolves to an index.
+    /// `None` until the variable is assigned.
+    pub(super) slots: Vec<Option<Value>>,
+    /// Names of the variables in `slots`, for error messages.
+    pub(super) slot_names: Rc<Vec<String>>,
     pub(super) parent: Option<Rc<RefCell<Env>>>,
 }
 
+fn undefined_variable(name: &str) -> AppError {
+    AppError::with_kind(
+        ErrorKind::UndefinedName,
+        format!("Variable is not defined: {}", name),
+    )
+}
+
+/// Compiled code is verified, so this means the bytecode is broken.
+fn invalid_local() -> AppError {
+    AppError::new("Invalid bytecode: local variable out of range")
+}
+
 impl Env {
     /// Map a name to a value in the current env
     pub(super) fn define(&mut self, name: &str, value: Value) {
@@ -21,31 +38,88 @@ impl Env {
     }
 
     pub fn lookup(&self, name: &str) -> AppResult<Value> {
-        if self.table.contains_key(name) {
-            return Ok(self.table.get(name).unwrap().clone());
-        } else {
-            let mut current_parent = self.parent.clone();
-            while let Some(parent) = current_parent {
-                if let Some(value) = parent.borrow().table.get(name) {
+        if let Some(value) = self.table.get(name) {
+            return Ok(value.clone());
+        }
+        let mut current_parent = self.parent.clone();
+        while let Some(parent) = current_parent {
+            let parent = parent.borrow();
+            // Function call environments keep their variables in slots, so most tables
+            // on the way up to the globals are empty and needn't be hashed into.
+            if !parent.table.is_empty() {
+                if let Some(value) = parent.table.get(name) {
                     return Ok(value.clone());
                 }
-                current_parent = parent.borrow().parent.clone();
             }
+            current_parent = parent.parent.clone();
+        }
+        Err(undefined_variable(name))
+    }
+
+    pub(super) fn store_local(&mut self, index: usize, value: Value) -> AppResult<()> {
+        match self.slots.get_mut(index) {
+            Some(slot) => *slot = Some(value),
+            None => return Err(invalid_local()),
+        }
+        Ok(())
+    }
+
+    /// Read the local variable `index` from the environment `depth` levels up.
+    pub(super) fn lookup_local(&self, depth: usize, index: usize) -> AppResult<Value> {
+        if depth > 0 {
+            return match &self.parent {
+                Some(parent) => parent.borrow().lookup_local(depth - 1, index),
+                None => Err(invalid_local()),
+            };
+        }
+        match self.slots.get(index) {
+            Some(Some(value)) => Ok(value.clone()),
+            Some(None) => Err(undefined_variable(&self.slot_names[index])),
+            None => Err(invalid_local()),
+        }
+    }
+
+    /// Variables defined directly in this environment: local variables that have been
+    /// assigned, in slot order, then the rest by name.
+    pub(super) fn bindings(&self) -> Vec<(String, Value)> {
+        let mut globals: Vec<(String, Value)> = self
+            .table
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+        self.slot_names
+            .iter()
+            .zip(self.slots.iter())
+            .filter_map(|(name, value)| Some((name.clone(), value.clone()?)))
+            .chain(globals)
+            .collect()
+    }
+
+    pub(super) fn new(table: HashMap<String, Value>, parent: Option<Rc<RefCell<Env>>>) -> Self {
+        Self {
+            table,
+            slots: Vec::new(),
+            slot_names: Rc::new(Vec::new()),
+            parent,
         }
-        Err(AppError::new(format!("Variable is not defined: {}", name)))
     }
 
     pub(super) fn child(parent: Rc<RefCell<Env>>) -> Self {
+        Self::new(HashMap::new(), Some(parent))
+    }
+
+    /// The environment for a call to a function with the local variables `slot_names`.
+    pub(super) fn for_call(parent: Rc<RefCell<Env>>, slot_names: Rc<Vec<String>>) -> Self {
         Self {
             table: HashMap::new(),
+            slots: vec![None; slot_names.len()],
+            slot_names,
             parent: Some(parent),
         }
     }
 
     pub fn with_builtins() -> Self {
-        BUILTINS_ENVIRONMENT.with(|builtins_environment| Self {
-            table: HashMap::new(),
-            parent: Some(builtins_environment.clone()),
-        })
+        BUILTINS_ENVIRONMENT.with(|builtins_environment| Self::child(builtins_environment.clone()))
     }
 }
@@ -2,14 +2,29 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 
-use crate::error::{AppError, AppResult};
+use crate::error::{AppError, AppResult, ErrorKind, StackFrame};
 
+use super::builtins::CallContext;
 use super::compiler::Program;
+use super::debugger::{DebugFrame, DebugState, Pause, PauseTarget};
+use super::decimal::RoundingMode;
 use super::env::Env;
-use super::model::{Instruction, Value};
+use super::model::{Bytecode, FunctionParams, Instruction, Value};
+use super::random::Random;
 
 pub trait KeywordResolver {
     fn resolve_keyword(&self, kw: &str) -> AppResult<Value>;
+
+    /// Seed for the random numbers (e.g. from `rand`) of an evaluation. Evaluations
+    /// with the same seed get the same numbers.
+    fn random_seed(&self) -> u64 {
+        0
+    }
+
+    /// How `round` breaks ties (see `CallContext::rounding_mode`).
+    fn rounding_mode(&self) -> RoundingMode {
+        RoundingMode::default()
+    }
 }
 
 pub struct EmptyKeywordResolver;
@@ -20,125 +35,757 @@ impl KeywordResolver for EmptyKeywordResolver {
     }
 }
 
-fn eval_instructions<R: KeywordResolver>(
-    instructions: &Vec<Instruction>,
+/// Bounds on a single evaluation, so that runaway formulas such as
+/// `(begin (defun f (x) (f x)) (f 1))` fail with a timeout instead of hanging the page.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EvalLimits {
+    /// Maximum number of instructions executed.
+    pub max_steps: u64,
+    /// Maximum depth of nested (non-tail) function calls. Frames live on the heap so
+    /// this isn't bounded by the native stack, but runaway recursion should still fail
+    /// cleanly.
+    pub max_call_depth: usize,
+}
+
+impl Default for EvalLimits {
+    fn default() -> Self {
+        Self {
+            max_steps: 10_000_000,
+            max_call_depth: 20_000,
+        }
+    }
+}
+
+/// Maximum number of runs nested in each other, e.g. by a function that recurses through
+/// `map`. Frames live on the heap, but each nested run is a built-in calling back into
+/// the machine on the native stack, which is much smaller (about 1MB in wasm).
+const MAX_NESTED_RUNS: usize = 64;
+
+/// Maximum number of (collapsed) frames recorded in an error's stack trace.
+const MAX_STACK_TRACE_FRAMES: usize = 16;
+
+/// Name shown in stack traces for functions that were never bound to a name.
+const ANONYMOUS_FUNCTION_NAME: &str = "<lambda>";
+
+/// An activation record: the code being run, where we are in it, and the environment
+/// it runs in.
+struct Frame {
+    /// Name of the function being run, or `None` for top-level code.
+    function: Option<String>,
+    code: Rc<Bytecode>,
+    pc: usize,
     env: Rc<RefCell<Env>>,
-    kw_resolver: &R,
-) -> AppResult<Value> {
-    let mut pc: usize = 0;
-    let mut stack: Vec<Value> = Vec::new();
-    while pc < instructions.len() {
-        let instruction = &instructions[pc];
-        pc += 1;
+    /// Height of the operand stack when the frame was entered.
+    stack_base: usize,
+    /// The `try`s in progress in this frame, innermost last.
+    handlers: Vec<Handler>,
+}
+
+/// An evaluation paused by the debugger in its outermost run, which can be resumed
+/// where it left off. Runs nested in built-ins (e.g. `map` calling a function) live on
+/// the native stack, so evaluations paused in them can't be kept.
+pub(super) struct Suspended {
+    frames: Vec<Frame>,
+    stack: Vec<Value>,
+    steps: u64,
+    random: Random,
+}
+
+/// Where to continue if an error occurs in the body of a `try`.
+struct Handler {
+    /// Index of the instruction to continue at.
+    target: usize,
+    /// Height of the operand stack when the `try` started, to unwind to.
+    stack_height: usize,
+}
+
+/// State of a single evaluation. Calls to user-defined functions push a `Frame` onto an
+/// explicit stack instead of recursing, and tail calls replace the current frame, so
+/// deeply recursive formulas can't overflow the native (wasm) stack.
+struct Machine<'a, R: KeywordResolver> {
+    kw_resolver: &'a R,
+    limits: EvalLimits,
+    /// Instructions executed so far, across all (nested) runs.
+    steps: u64,
+    frames: Vec<Frame>,
+    stack: Vec<Value>,
+    /// Number of runs in progress, see `MAX_NESTED_RUNS`.
+    nested_runs: usize,
+    /// When debugging, where to stop evaluating.
+    pause_target: Option<&'a PauseTarget<'a>>,
+    /// The state of evaluation when it reached the pause target. Evaluation is then
+    /// abandoned by returning an error.
+    paused: Option<Pause>,
+    /// The machine's state when it paused, if it can be resumed.
+    suspended: Option<Suspended>,
+    random: Random,
+}
+
+impl<'a, R: KeywordResolver> Machine<'a, R> {
+    fn new(kw_resolver: &'a R, limits: EvalLimits) -> Self {
+        Self {
+            kw_resolver,
+            limits,
+            steps: 0,
+            frames: Vec::new(),
+            stack: Vec::new(),
+            nested_runs: 0,
+            pause_target: None,
+            paused: None,
+            suspended: None,
+            random: Random::new(kw_resolver.random_seed()),
+        }
+    }
+
+    fn should_pause(&self) -> bool {
+        match self.pause_target {
+            Some(target) => {
+                let frame = self.frames.last().unwrap();
+                target.should_pause(
+                    self.steps,
+                    self.frames.len(),
+                    frame.function.as_deref(),
+                    &frame.code,
+                    frame.pc,
+                )
+            }
+            None => false,
+        }
+    }
+
+    fn pause(&self) -> Pause {
+        let frames = self
+            .frames
+            .iter()
+            .enumerate()
+            .map(|(depth, frame)| {
+                let stack_end = self
+                    .frames
+                    .get(depth + 1)
+                    .map_or(self.stack.len(), |next| next.stack_base);
+                DebugFrame::new(
+                    frame.function.as_deref(),
+                    frame.pc,
+                    &frame.code.instructions,
+                    &self.stack[frame.stack_base..stack_end],
+                    &frame.env,
+                    self.pause_target.unwrap().globals,
+                )
+            })
+            .collect();
+        Pause {
+            step: self.steps,
+            frames,
+        }
+    }
+
+    fn push_frame(
+        &mut self,
+        function: Option<String>,
+        code: Rc<Bytecode>,
+        env: Rc<RefCell<Env>>,
+    ) -> AppResult<()> {
+        if self.frames.len() >= self.limits.max_call_depth {
+            return Err(AppError::with_kind(
+                ErrorKind::StackOverflow,
+                format!(
+                    "Stack overflow: exceeded the maximum call depth of {}",
+                    self.limits.max_call_depth
+                ),
+            ));
+        }
+        self.frames.push(Frame {
+            function,
+            code,
+            pc: 0,
+            env,
+            stack_base: self.stack.len(),
+            handlers: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Bind call arguments to a function's parameters in its (fresh) environment,
+    /// checking that the number of arguments is acceptable.
+    fn bind_arguments(
+        &mut self,
+        params: &FunctionParams,
+        args: Vec<Value>,
+        env: &Rc<RefCell<Env>>,
+    ) -> AppResult<()> {
+        let min_args = params.required.len();
+        let max_args = min_args + params.optional.len();
+        if args.len() < min_args || (params.rest.is_none() && args.len() > max_args) {
+            let expected = if params.rest.is_some() {
+                format!("at least {}", min_args)
+            } else if min_args == max_args {
+                format!("{}", min_args)
+            } else {
+                format!("{} to {}", min_args, max_args)
+            };
+            return Err(AppError::with_kind(
+                ErrorKind::Arity,
+                format!(
+                    "Wrong number of arguments to function {}: expected {}, got {}",
+                    params,
+                    expected,
+                    args.len()
+                ),
+            ));
+        }
+
+        // Parameters occupy the first local variable slots, in order.
+        let mut args = args.into_iter();
+        let mut index = 0;
+        for _ in params.required.iter() {
+            env.borrow_mut().store_local(index, args.next().unwrap())?;
+            index += 1;
+        }
+        for (_, default) in params.optional.iter() {
+            let value = match args.next() {
+                Some(arg) => arg,
+                None => self.run(default.clone(), env.clone())?,
+            };
+            env.borrow_mut().store_local(index, value)?;
+            index += 1;
+        }
+        if params.rest.is_some() {
+            env.borrow_mut()
+                .store_local(index, Value::List(args.collect()))?;
+        }
+        Ok(())
+    }
+
+    /// Begin a call to `func`. Built-in functions are run to completion and their result
+    /// pushed; user-defined functions get a new frame, or take over the current one if
+    /// this is a tail call.
+    fn call(&mut self, func: Value, args: Vec<Value>, is_tail_call: bool) -> AppResult<()> {
+        match func {
+            Value::BuiltinFunction(builtin_func) => {
+                let name = builtin_func.name();
+                let mut ctx = BuiltinCallContext {
+                    machine: self,
+                    caller: &name,
+                };
+                let result = builtin_func
+                    .call_with_context(&mut ctx, args)
+                    .map_err(|err| self.with_stack_trace(err, Some(&name)))?;
+                self.stack.push(result);
+            }
+            Value::UserFunction {
+                name,
+                params,
+                body,
+                env: function_env,
+            } => {
+                let name = name.unwrap_or_else(|| ANONYMOUS_FUNCTION_NAME.to_string());
+                let child_env = Rc::new(RefCell::new(Env::for_call(
+                    function_env,
+                    params.locals.clone(),
+                )));
+                self.bind_arguments(&params, args, &child_env)
+                    .map_err(|err| self.with_stack_trace(err, Some(&name)))?;
+                if is_tail_call {
+                    let frame = self.frames.last_mut().unwrap();
+                    self.stack.truncate(frame.stack_base);
+                    frame.function = Some(name);
+                    frame.code = body;
+                    frame.pc = 0;
+                    frame.env = child_env;
+                    // Verified code never makes a tail call inside a `try`, but the
+                    // handlers would refer to the replaced code.
+                    frame.handlers.clear();
+                } else {
+                    self.push_frame(Some(name.clone()), body, child_env)
+                        .map_err(|err| self.with_stack_trace(err, Some(&name)))?;
+                }
+            }
+            _ => {
+                return Err(AppError::with_kind(
+                    ErrorKind::Type,
+                    format!("Expression {:?} is not callable!", func),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the current call stack on `err`, unless a more deeply nested evaluation
+    /// already has. `callee` names a function that failed before getting a frame of its
+    /// own (e.g. a built-in, or a call with the wrong number of arguments).
+    fn with_stack_trace(&self, mut err: AppError, callee: Option<&str>) -> AppError {
+        if err.has_stack_trace() {
+            return err;
+        }
+        let names = callee.into_iter().chain(
+            self.frames
+                .iter()
+                .rev()
+                .filter_map(|frame| frame.function.as_deref()),
+        );
+        let mut stack_trace: Vec<StackFrame> = Vec::new();
+        for name in names {
+            match stack_trace.last_mut() {
+                Some(last) if last.function == name => {
+                    last.count += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            if stack_trace.len() >= MAX_STACK_TRACE_FRAMES {
+                break;
+            }
+            stack_trace.push(StackFrame {
+                function: name.to_string(),
+                count: 1,
+            });
+        }
+        err.set_stack_trace(stack_trace);
+        err
+    }
+
+    /// Point `err` at the source of the instruction that failed, unless a more deeply
+    /// nested evaluation already has. Code without a source (e.g. the prelude's) is
+    /// skipped, so the error points at the call into it instead.
+    fn with_span(&self, err: AppError) -> AppError {
+        if err.span().is_some() {
+            return err;
+        }
+        // The program counter has already moved past each frame's current instruction.
+        let span = self
+            .frames
+            .iter()
+            .rev()
+            .find_map(|frame| frame.code.span(frame.pc.saturating_sub(1)));
+        match span {
+            Some(span) => err.with_span(span),
+            None => err,
+        }
+    }
+
+    /// Call a function value to completion. `caller` names the built-in making the call,
+    /// if any, so that it shows up in stack traces.
+    fn call_function(
+        &mut self,
+        caller: Option<&str>,
+        func: Value,
+        args: Vec<Value>,
+    ) -> AppResult<Value> {
+        let nargs = args.len();
+        let code = Rc::new(Bytecode::new(vec![Instruction::CallFunction {
+            nargs: nargs as i32,
+        }]));
+        self.stack.push(func);
+        self.stack.extend(args);
+        self.run_as(
+            caller,
+            nargs + 1,
+            code,
+            Rc::new(RefCell::new(Env::with_builtins())),
+        )
+    }
+
+    /// Run `code` in `env` until it returns. May be re-entered (e.g. to
+    /// evaluate default parameter values); nested runs share the frame stack.
+    fn run(&mut self, code: Rc<Bytecode>, env: Rc<RefCell<Env>>) -> AppResult<Value> {
+        self.run_as(None, 0, code, env)
+    }
+
+    /// Like `run`, but the outermost frame is attributed to `function`. The top
+    /// `operands` values on the stack (e.g. a function and its arguments, see
+    /// `call_function`) belong to the new frame.
+    fn run_as(
+        &mut self,
+        function: Option<&str>,
+        operands: usize,
+        code: Rc<Bytecode>,
+        env: Rc<RefCell<Env>>,
+    ) -> AppResult<Value> {
+        let base_depth = self.frames.len();
+        let base_stack = self.stack.len() - operands;
+        if self.nested_runs >= MAX_NESTED_RUNS {
+            self.stack.truncate(base_stack);
+            let err = AppError::with_kind(
+                ErrorKind::StackOverflow,
+                format!(
+                    "Stack overflow: exceeded the maximum of {} nested calls through built-ins \
+                     such as `map`",
+                    MAX_NESTED_RUNS
+                ),
+            );
+            return Err(self.with_stack_trace(err, function));
+        }
+        self.nested_runs += 1;
+        let result = self.run_frames(base_depth, base_stack, function, code, env);
+        self.nested_runs -= 1;
+        if result.is_err() {
+            // Unwind anything left over from the failed evaluation.
+            self.frames.truncate(base_depth);
+            self.stack.truncate(base_stack);
+        }
+        result
+    }
+
+    fn run_frames(
+        &mut self,
+        base_depth: usize,
+        stack_base: usize,
+        function: Option<&str>,
+        code: Rc<Bytecode>,
+        env: Rc<RefCell<Env>>,
+    ) -> AppResult<Value> {
+        self.push_frame(function.map(str::to_string), code, env)
+            .map_err(|err| self.with_stack_trace(err, None))?;
+        self.frames.last_mut().unwrap().stack_base = stack_base;
+        self.run_loop(base_depth)
+    }
+
+    /// Continue an evaluation where it was suspended.
+    fn resume(&mut self, suspended: Suspended) -> AppResult<Value> {
+        self.frames = suspended.frames;
+        self.stack = suspended.stack;
+        self.steps = suspended.steps;
+        self.random = suspended.random;
+        self.nested_runs += 1;
+        let result = self.run_loop(0);
+        self.nested_runs -= 1;
+        if result.is_err() {
+            self.frames.clear();
+            self.stack.clear();
+        }
+        result
+    }
+
+    /// Run instructions until the frame at `base_depth` returns.
+    fn run_loop(&mut self, base_depth: usize) -> AppResult<Value> {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            if frame.pc >= frame.code.len() {
+                // Return from the current frame.
+                let result = self.pop().map_err(|err| self.with_stack_trace(err, None))?;
+                let frame = self.frames.pop().unwrap();
+                self.stack.truncate(frame.stack_base);
+                if self.frames.len() == base_depth {
+                    return Ok(result);
+                }
+                self.stack.push(result);
+                continue;
+            }
+            if self.should_pause() {
+                self.paused = Some(self.pause());
+                if self.nested_runs == 1 {
+                    self.suspended = Some(Suspended {
+                        frames: std::mem::take(&mut self.frames),
+                        stack: std::mem::take(&mut self.stack),
+                        steps: self.steps,
+                        random: self.random.clone(),
+                    });
+                }
+                return Err(AppError::new("Paused by the debugger"));
+            }
+            let frame = self.frames.last_mut().unwrap();
+            let code = frame.code.clone();
+            let env = frame.env.clone();
+            let instruction = &code.instructions[frame.pc];
+            frame.pc += 1;
+            self.steps += 1;
+            if self.steps > self.limits.max_steps {
+                let err = AppError::with_kind(
+                    ErrorKind::Timeout,
+                    format!(
+                        "Timed out: exceeded the budget of {} evaluation steps",
+                        self.limits.max_steps
+                    ),
+                );
+                return Err(self.with_span(self.with_stack_trace(err, None)));
+            }
+            if let Err(err) = self.step(instruction, env) {
+                let err = self.with_span(self.with_stack_trace(err, None));
+                self.catch(base_depth, err)?;
+            }
+        }
+    }
+
+    /// Continue at the innermost `try` in progress in this run (i.e. in frames from
+    /// `base_depth` up), unwinding the frames and operands above it and pushing `err`
+    /// for the handler. If there's no `try`, `err` is returned.
+    ///
+    /// Exceeding the evaluation limits can't be caught, since they bound the whole
+    /// evaluation whatever the handler does, and neither can the debugger pausing.
+    fn catch(&mut self, base_depth: usize, err: AppError) -> AppResult<()> {
+        if matches!(err.kind(), ErrorKind::Timeout | ErrorKind::StackOverflow)
+            || self.paused.is_some()
+        {
+            return Err(err);
+        }
+        let depth = (base_depth..self.frames.len())
+            .rev()
+            .find(|depth| !self.frames[*depth].handlers.is_empty());
+        let depth = match depth {
+            Some(depth) => depth,
+            None => return Err(err),
+        };
+        self.frames.truncate(depth + 1);
+        let frame = self.frames.last_mut().unwrap();
+        let handler = frame.handlers.pop().unwrap();
+        frame.pc = handler.target;
+        self.stack.truncate(handler.stack_height);
+        self.stack.push(Value::Error(err));
+        Ok(())
+    }
+
+    /// Pop a value pushed by the current frame. Compiled code is verified, so running
+    /// out means the bytecode is broken; that's reported as an error rather than a panic,
+    /// which would take down the whole sheet.
+    fn pop(&mut self) -> AppResult<Value> {
+        let base = self.frames.last().map_or(0, |frame| frame.stack_base);
+        if self.stack.len() <= base {
+            return Err(AppError::new("Invalid bytecode: stack underflow"));
+        }
+        Ok(self.stack.pop().unwrap())
+    }
+
+    /// The index of the instruction `offset` after the next one in the current frame.
+    fn jump_target(&self, offset: i32) -> AppResult<usize> {
+        let frame = self.frames.last().unwrap();
+        let target = frame.pc as i64 + offset as i64;
+        if target < 0 || target > frame.code.len() as i64 {
+            return Err(AppError::new("Invalid bytecode: jump out of range"));
+        }
+        Ok(target as usize)
+    }
+
+    fn jump(&mut self, offset: i32) -> AppResult<()> {
+        let target = self.jump_target(offset)?;
+        self.frames.last_mut().unwrap().pc = target;
+        Ok(())
+    }
+
+    fn step(&mut self, instruction: &Instruction, env: Rc<RefCell<Env>>) -> AppResult<()> {
         match instruction {
             Instruction::LoadConst(value) => {
-                stack.push(*value.clone());
+                self.stack.push(*value.clone());
             }
             Instruction::StoreName(name) => {
-                let value = stack.pop().unwrap();
+                let mut value = self.pop()?;
+                if let Value::UserFunction {
+                    name: function_name @ None,
+                    ..
+                } = &mut value
+                {
+                    *function_name = Some(name.clone());
+                }
                 env.borrow_mut().define(name, value);
             }
             Instruction::LoadName(name) => {
-                stack.push(env.borrow().lookup(name)?);
+                self.stack.push(env.borrow().lookup(name)?);
+            }
+            Instruction::StoreLocal { index } => {
+                let mut value = self.pop()?;
+                if let Value::UserFunction {
+                    name: function_name @ None,
+                    ..
+                } = &mut value
+                {
+                    *function_name = env.borrow().slot_names.get(*index).cloned();
+                }
+                env.borrow_mut().store_local(*index, value)?;
+            }
+            Instruction::LoadLocal { depth, index } => {
+                self.stack.push(env.borrow().lookup_local(*depth, *index)?);
             }
             Instruction::LoadKeyword(kw) => {
-                stack.push(kw_resolver.resolve_keyword(&kw)?);
+                self.stack.push(self.kw_resolver.resolve_keyword(&kw)?);
             }
             Instruction::DiscardValue => {
-                stack.pop().unwrap();
+                self.pop()?;
             }
-            Instruction::CallFunction { .. } | Instruction::ApplyFunction => {
+            Instruction::CallFunction { .. }
+            | Instruction::TailCall { .. }
+            | Instruction::ApplyFunction => {
                 let args = match instruction {
-                    Instruction::CallFunction { nargs } => {
-                        let mut args = Vec::with_capacity(*nargs as usize);
+                    Instruction::CallFunction { nargs } | Instruction::TailCall { nargs } => {
+                        let mut args = Vec::with_capacity((*nargs).max(0) as usize);
                         for _ in 0..*nargs {
-                            args.push(stack.pop().unwrap());
+                            args.push(self.pop()?);
                         }
                         args.reverse();
                         args
                     }
-                    Instruction::ApplyFunction => {
-                        let arg_list = stack.pop().unwrap();
-                        match arg_list {
-                            Value::List(args) => args,
-                            _ => {
-                                return Err(AppError::new(
-                                    "The second argument to `apply` must be a list",
-                                ))
-                            }
+                    Instruction::ApplyFunction => match self.pop()? {
+                        Value::List(args) => args,
+                        Value::Vector(args) => args.into_iter().collect(),
+                        _ => {
+                            return Err(AppError::with_kind(
+                                ErrorKind::Type,
+                                "The second argument to `apply` must be a list or vector",
+                            ))
                         }
-                    }
+                    },
                     _ => unreachable!(),
                 };
-                let func = stack.pop().unwrap();
-                match func {
-                    Value::BuiltinFunction(builtin_func) => {
-                        stack.push(builtin_func.call(args)?);
-                    }
-                    Value::UserFunction {
-                        params,
-                        body,
-                        env: function_env,
-                    } => {
-                        let child_env = Rc::new(RefCell::new(Env::child(function_env)));
-                        for (param, arg) in params.iter().zip(args) {
-                            child_env.borrow_mut().define(param, arg);
-                        }
-                        stack.push(eval_instructions(&body, child_env, kw_resolver)?);
-                    }
-                    _ => {
-                        return Err(AppError::new(format!(
-                            "Expression {:?} is not callable!",
-                            func
-                        )))
-                    }
-                }
+                let func = self.pop()?;
+                let is_tail_call = matches!(instruction, Instruction::TailCall { .. });
+                self.call(func, args, is_tail_call)?;
             }
-            Instruction::MakeFunction => {
-                if let (Value::CompiledCode(func_instructions), Value::List(param_syms)) =
-                    (stack.pop().unwrap(), stack.pop().unwrap())
-                {
-                    stack.push(Value::UserFunction {
-                        params: param_syms
-                            .iter()
-                            .map(|sym| match sym {
-                                Value::Symbol(s) => s.clone(),
-                                _ => panic!(),
-                            })
-                            .collect(),
-                        body: func_instructions,
-                        env: env.clone(),
+            Instruction::MakeFunction { params } => match self.pop()? {
+                Value::CompiledCode(body) => {
+                    self.stack.push(Value::UserFunction {
+                        name: None,
+                        params: params.clone(),
+                        body,
+                        env,
                     });
-                } else {
-                    panic!("Unexpected stack for MakeFunction");
                 }
-            }
+                _ => {
+                    return Err(AppError::new(
+                        "Invalid bytecode: MakeFunction without a function body",
+                    ))
+                }
+            },
             Instruction::RelativeJump { offset } => {
-                pc += *offset as usize;
+                self.jump(*offset)?;
             }
             Instruction::RelativeJumpIfTrue { offset } => {
-                let cond = stack.pop().unwrap();
-                let should_jump = match cond {
-                    Value::Boolean(true) => true,
-                    _ => false,
+                let cond = self.pop()?;
+                if matches!(cond, Value::Boolean(true)) {
+                    self.jump(*offset)?;
+                }
+            }
+            Instruction::PushHandler { offset } => {
+                let handler = Handler {
+                    target: self.jump_target(*offset)?,
+                    stack_height: self.stack.len(),
                 };
-                if should_jump {
-                    pc += *offset as usize;
+                self.frames.last_mut().unwrap().handlers.push(handler);
+            }
+            Instruction::PopHandler => {
+                if self.frames.last_mut().unwrap().handlers.pop().is_none() {
+                    return Err(AppError::new(
+                        "Invalid bytecode: PopHandler outside of a try",
+                    ));
                 }
+                let result = self.pop()?;
+                self.pop()?;
+                self.stack.push(result);
             }
         }
+        Ok(())
     }
-    Ok(stack.pop().unwrap())
+}
+
+/// Lets a built-in call function values on the machine that is running it, sharing its
+/// step budget and call depth.
+struct BuiltinCallContext<'m, 'a, R: KeywordResolver> {
+    machine: &'m mut Machine<'a, R>,
+    /// Name of the built-in, which appears in stack traces of errors in the functions
+    /// it calls.
+    caller: &'m str,
+}
+
+impl<'m, 'a, R: KeywordResolver> CallContext for BuiltinCallContext<'m, 'a, R> {
+    fn call_function(&mut self, func: Value, args: Vec<Value>) -> AppResult<Value> {
+        self.machine.call_function(Some(self.caller), func, args)
+    }
+
+    fn random(&mut self) -> f64 {
+        self.machine.random.next_f64()
+    }
+
+    fn rounding_mode(&self) -> RoundingMode {
+        self.machine.kw_resolver.rounding_mode()
+    }
+}
+
+/// Call a function value (built-in or user-defined) with already evaluated arguments.
+pub(super) fn call_function<R: KeywordResolver>(
+    func: Value,
+    args: Vec<Value>,
+    kw_resolver: &R,
+) -> AppResult<Value> {
+    Machine::new(kw_resolver, EvalLimits::default()).call_function(None, func, args)
 }
 
 pub fn eval<R: KeywordResolver>(program: &Program, env: Env, kw_resolver: &R) -> AppResult<Value> {
-    let env_rc = Rc::new(RefCell::new(env));
-    eval_instructions(&program.instructions, env_rc, kw_resolver)
+    eval_with_limits(program, env, kw_resolver, EvalLimits::default())
+}
+
+pub fn eval_with_limits<R: KeywordResolver>(
+    program: &Program,
+    env: Env,
+    kw_resolver: &R,
+    limits: EvalLimits,
+) -> AppResult<Value> {
+    eval_in_env(program, Rc::new(RefCell::new(env)), kw_resolver, limits)
+}
+
+/// Counters describing an evaluation, for profiling.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EvalStats {
+    /// Instructions executed, including in the functions called.
+    pub steps: u64,
+}
+
+/// Like `eval_with_limits`, but also reports how much work the evaluation did (even if
+/// it failed).
+pub fn eval_with_stats<R: KeywordResolver>(
+    program: &Program,
+    env: Env,
+    kw_resolver: &R,
+    limits: EvalLimits,
+) -> (AppResult<Value>, EvalStats) {
+    let mut machine = Machine::new(kw_resolver, limits);
+    let result = machine.run(program.code.clone(), Rc::new(RefCell::new(env)));
+    (
+        result,
+        EvalStats {
+            steps: machine.steps,
+        },
+    )
+}
+
+/// Evaluate `program` in a shared environment, so that its definitions outlive it.
+pub(super) fn eval_in_env<R: KeywordResolver>(
+    program: &Program,
+    env: Rc<RefCell<Env>>,
+    kw_resolver: &R,
+    limits: EvalLimits,
+) -> AppResult<Value> {
+    Machine::new(kw_resolver, limits).run(program.code.clone(), env)
+}
+
+/// Evaluate `code` in a child of `target.globals` until reaching `target`, continuing
+/// from `suspended` if it's set rather than from the start. If evaluation pauses where
+/// it can be resumed, `suspended` is set to where.
+pub(super) fn eval_until<R: KeywordResolver>(
+    code: Rc<Bytecode>,
+    suspended: &mut Option<Suspended>,
+    kw_resolver: &R,
+    limits: EvalLimits,
+    target: &PauseTarget,
+) -> DebugState {
+    let mut machine = Machine::new(kw_resolver, limits);
+    machine.pause_target = Some(target);
+    let result = match suspended.take() {
+        Some(state) => machine.resume(state),
+        None => {
+            let env = Env::child(target.globals.clone());
+            machine.run(code, Rc::new(RefCell::new(env)))
+        }
+    };
+    *suspended = machine.suspended.take();
+    match machine.paused {
+        Some(pause) => DebugState::Paused(pause),
+        None => DebugState::Finished(result),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{super::compiler::compile, *};
+    use super::{
+        super::compiler::{compile, compile_with_prelude, Definitions},
+        *,
+    };
     use crate::parser::Expr;
 
     #[test]
@@ -155,5 +802,615 @@ mod tests {
         let program = compile(&Expr::from_string("(begin 1 2)").unwrap()).unwrap();
         let res = eval(&program, env, &EmptyKeywordResolver).unwrap();
         assert_eq!(res, Value::Number(2.0));
+        let program = compile(&Expr::from_string("(begin)").unwrap()).unwrap();
+        let res = eval(&program, Env::with_builtins(), &EmptyKeywordResolver).unwrap();
+        assert_eq!(res, Value::Nil);
+    }
+
+    fn eval_str(src: &str) -> AppResult<Value> {
+        let env = Env::with_builtins();
+        let program = compile(&Expr::from_string(src).unwrap())?;
+        eval(&program, env, &EmptyKeywordResolver)
+    }
+
+    #[test]
+    fn test_let_forms() {
+        assert_eq!(
+            eval_str("(let ((x 1) (y 2)) (+ x y))").unwrap(),
+            Value::Number(3.0)
+        );
+        // Bindings in `let` can't see each other, but those in `let*` can.
+        assert!(eval_str("(let ((x 1) (y x)) y)").is_err());
+        assert_eq!(
+            eval_str("(let* ((x 2) (y (* x 10))) (+ x y))").unwrap(),
+            Value::Number(22.0)
+        );
+        assert_eq!(
+            eval_str(
+                "(letrec ((even? (lambda (n) (if (= n 0) #t (odd? (+ n -1)))))
+                          (odd? (lambda (n) (if (= n 0) #f (even? (+ n -1))))))
+                   (even? 10))"
+            )
+            .unwrap(),
+            Value::Boolean(true)
+        );
+        // Local bindings don't leak into the enclosing environment.
+        assert!(eval_str("(begin (let ((x 1)) x) x)").is_err());
+        assert_eq!(eval_str("(let () 1 2)").unwrap(), Value::Number(2.0));
+    }
+
+    #[test]
+    fn test_conditional_forms() {
+        assert_eq!(
+            eval_str("(cond ((= 1 2) 10) ((= 2 2) 20) (else 30))").unwrap(),
+            Value::Number(20.0)
+        );
+        assert_eq!(
+            eval_str("(cond ((= 1 2) 10) (else 30))").unwrap(),
+            Value::Number(30.0)
+        );
+        assert_eq!(eval_str("(cond (#f 10))").unwrap(), Value::Nil);
+        assert_eq!(eval_str("(when #t 1 2)").unwrap(), Value::Number(2.0));
+        assert_eq!(eval_str("(when #f 1)").unwrap(), Value::Nil);
+        assert_eq!(eval_str("(unless #f 1)").unwrap(), Value::Number(1.0));
+        assert_eq!(eval_str("(unless #t 1)").unwrap(), Value::Nil);
+        assert_eq!(
+            eval_str("(case (+ 1 2) ((1 2) \"low\") ((3 4) \"mid\") (else \"high\"))").unwrap(),
+            Value::String("mid".into())
+        );
+        assert_eq!(
+            eval_str("(case 'b ((a) 1) ((b c) 2))").unwrap(),
+            Value::Number(2.0)
+        );
+        assert_eq!(eval_str("(case 9 ((1) 1))").unwrap(), Value::Nil);
+    }
+
+    #[test]
+    fn test_malformed_special_forms() {
+        for src in &[
+            "(let (x) x)",
+            "(let ((x 1)))",
+            "(let* ((1 2)) 3)",
+            "(letrec x 1)",
+            "(cond (#t))",
+            "(cond (else 1) (#t 2))",
+            "(when #t)",
+            "(unless)",
+            "(case 1 (2 3))",
+        ] {
+            let err = eval_str(src).unwrap_err();
+            assert!(
+                err.to_string()
+                    .starts_with("Invalid syntax for built-in form"),
+                "{}: {}",
+                src,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_function_arity() {
+        let err = eval_str("((lambda (x y) x) 1)").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Wrong number of arguments to function (x y): expected 2, got 1"
+        );
+        let err = eval_str("((lambda (x) x) 1 2)").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Wrong number of arguments to function (x): expected 1, got 2"
+        );
+        assert!(eval_str("((lambda (x &rest xs) x))").is_err());
+        assert!(eval_str("((lambda (x &optional y) x) 1 2 3)").is_err());
+    }
+
+    #[test]
+    fn test_rest_params() {
+        assert_eq!(
+            eval_str("((lambda (x &rest xs) xs) 1 2 3)").unwrap(),
+            Value::List(vec![Value::Number(2.0), Value::Number(3.0)])
+        );
+        assert_eq!(
+            eval_str("((lambda (x . xs) xs) 1)").unwrap(),
+            Value::List(vec![])
+        );
+        assert_eq!(
+            eval_str("(begin (defun add-all (&rest xs) (apply + xs)) (add-all 1 2 3 4))").unwrap(),
+            Value::Number(10.0)
+        );
+    }
+
+    #[test]
+    fn test_optional_params() {
+        assert_eq!(
+            eval_str("((lambda (x &optional y) y) 1)").unwrap(),
+            Value::Nil
+        );
+        assert_eq!(
+            eval_str("((lambda (x &optional (y (* x 10))) (+ x y)) 2)").unwrap(),
+            Value::Number(22.0)
+        );
+        assert_eq!(
+            eval_str("((lambda (x &optional (y 10)) (+ x y)) 2 3)").unwrap(),
+            Value::Number(5.0)
+        );
+        assert_eq!(
+            eval_str("((lambda (&optional (a 1) &rest r) (cons a r)) )").unwrap(),
+            Value::List(vec![Value::Number(1.0)])
+        );
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        for src in &[
+            "(lambda (x &rest) x)",
+            "(lambda (&rest a b) a)",
+            "(lambda (x . y z) x)",
+            "(lambda (&optional a &optional b) a)",
+            "(lambda ((x 1)) x)",
+            "(lambda (&bogus x) x)",
+        ] {
+            assert!(eval_str(src).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_macros() {
+        assert_eq!(
+            eval_str("(begin (defmacro my-unless (c x) `(if ,c nil ,x)) (my-unless #f 5))")
+                .unwrap(),
+            Value::Number(5.0)
+        );
+        assert_eq!(
+            eval_str(
+                "(begin
+                   (defmacro my-list (&rest xs) `(list 0 ,@xs ,(+ 1 2)))
+                   (my-list 1 2))"
+            )
+            .unwrap(),
+            Value::List(vec![
+                Value::Number(0.0),
+                Value::Number(1.0),
+                Value::Number(2.0),
+                Value::Number(3.0)
+            ])
+        );
+        // Macro arguments aren't evaluated.
+        assert_eq!(
+            eval_str("(begin (defmacro first-form (x y) `(quote ,x)) (first-form (f) (g)))")
+                .unwrap(),
+            Value::List(vec![Value::Symbol("f".into())])
+        );
+        assert!(eval_str("(unquote x)").is_err());
+    }
+
+    #[test]
+    fn test_prelude_macros() {
+        let eval_with_prelude = |src: &str| {
+            let program =
+                compile_with_prelude(&Expr::from_string(src).unwrap(), &Definitions::default())
+                    .unwrap();
+            eval(
+                &program,
+                Definitions::default().env(),
+                &EmptyKeywordResolver,
+            )
+            .unwrap()
+        };
+        assert_eq!(eval_with_prelude("(and #t #t)"), Value::Boolean(true));
+        assert_eq!(eval_with_prelude("(and #t #f #t)"), Value::Boolean(false));
+        assert_eq!(eval_with_prelude("(or #f #f)"), Value::Boolean(false));
+        assert_eq!(eval_with_prelude("(or #f (= 1 1))"), Value::Boolean(true));
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        // Far deeper than the call depth limit, but every call is a tail call.
+        assert_eq!(
+            eval_str(
+                "(begin
+                   (defun count (n acc) (if (= n 0) acc (count (+ n -1) (+ acc 1))))
+                   (count 30000 0))"
+            )
+            .unwrap(),
+            Value::Number(30000.0)
+        );
+        // Tail calls out of `cond` (nested ifs) and `let` bodies.
+        assert_eq!(
+            eval_str(
+                "(letrec ((loop (lambda (n)
+                                  (cond ((= n 0) \"done\")
+                                        (else (let ((m (+ n -1))) (loop m)))))))
+                   (loop 25000))"
+            )
+            .unwrap(),
+            Value::String("done".into())
+        );
+    }
+
+    #[test]
+    fn test_deep_recursion() {
+        // Non-tail recursion over a few thousand elements works...
+        let program = compile_with_prelude(
+            &Expr::from_string(
+                "(begin
+                   (defun build (n acc) (if (= n 0) acc (build (+ n -1) (cons n acc))))
+                   (defun last (lst) (if (nil? (cdr lst)) (car lst) (last (cdr lst))))
+                   (last (map (lambda (x) (* x 2)) (build 2000 nil))))",
+            )
+            .unwrap(),
+            &Definitions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            eval(
+                &program,
+                Definitions::default().env(),
+                &EmptyKeywordResolver
+            )
+            .unwrap(),
+            Value::Number(4000.0)
+        );
+
+        // ...and unbounded recursion fails cleanly rather than crashing.
+        let err = eval_str("(begin (defun f (n) (+ 1 (f n))) (f 1))").unwrap_err();
+        assert!(err.to_string().starts_with("Stack overflow"), "{}", err);
+        assert_eq!(err.kind(), ErrorKind::StackOverflow);
+    }
+
+    #[test]
+    fn test_local_variables() {
+        // Local functions can be mutually recursive, and closures see their enclosing
+        // function's variables.
+        assert_eq!(
+            eval_str(
+                "((lambda (n)
+                    (begin
+                      (defun even? (k) (if (= k 0) #t (odd? (- k 1))))
+                      (defun odd? (k) (if (= k 0) #f (even? (- k 1))))
+                      (def add-n (lambda (x) (+ x n)))
+                      (list (even? n) (add-n 1))))
+                  4)"
+            )
+            .unwrap(),
+            Value::List(vec![Value::Boolean(true), Value::Number(5.0)])
+        );
+        // Variables defined through a macro are local too.
+        assert_eq!(
+            eval_str(
+                "(begin
+                   (defmacro define-five (name) `(def ,name 5))
+                   ((lambda () (begin (defun get () five) (define-five five) (get)))))"
+            )
+            .unwrap(),
+            Value::Number(5.0)
+        );
+        let err = eval_str("((lambda () (begin (defun get () y) (get) (def y 1))))").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UndefinedName);
+        assert_eq!(err.message(), "Variable is not defined: y");
+        // Local definitions don't leak out of the function.
+        assert!(eval_str("(begin ((lambda () (def z 1))) z)").is_err());
+    }
+
+    #[test]
+    fn test_higher_order_builtins() {
+        assert_eq!(
+            eval_str("(map (lambda (x) (* x x)) (filter (lambda (x) (< x 3)) [1 2 3 4]))").unwrap(),
+            Value::Vector(vec![Value::Number(1.0), Value::Number(4.0)].into())
+        );
+        assert_eq!(
+            eval_str("(reduce (lambda (acc x) (+ (* acc 10) x)) 0 (range 1 4))").unwrap(),
+            Value::Number(123.0)
+        );
+        assert_eq!(
+            eval_str(
+                "(map car (sort-by (lambda (p) (car (cdr p))) '((a 2) (b 1) (c 2))
+                                   (lambda (x y) (> x y))))"
+            )
+            .unwrap(),
+            Value::List(vec![
+                Value::Symbol("a".into()),
+                Value::Symbol("c".into()),
+                Value::Symbol("b".into())
+            ])
+        );
+
+        // Functions called from builtins count towards the caller's limits, and appear in
+        // stack traces.
+        let program =
+            compile(&Expr::from_string("(map (lambda (x) (+ x 1)) (range 1000))").unwrap())
+                .unwrap();
+        let limits = EvalLimits {
+            max_steps: 1_000,
+            max_call_depth: 50,
+        };
+        let err = eval_with_limits(
+            &program,
+            Env::with_builtins(),
+            &EmptyKeywordResolver,
+            limits,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+        let err = eval_str("(map (lambda (x) (car x)) [1])").unwrap_err();
+        let functions = err
+            .stack_trace()
+            .iter()
+            .map(|frame| frame.function.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(functions, vec!["car", "<lambda>", "map"]);
+    }
+
+    /// Times recursive Lisp functions, where most of the work is variable lookup and
+    /// function calls, and checks that evaluation steps take under a microsecond on
+    /// average. Not run by default, since timings are only meaningful in release
+    /// builds on an idle machine; use
+    /// `cargo test --release bench_recursive_functions -- --ignored`.
+    #[test]
+    #[ignore]
+    fn bench_recursive_functions() {
+        let program = compile(
+            &Expr::from_string(
+                "(begin
+                   (defun lisp-map (fun lst)
+                     (if (nil? lst) nil (cons (fun (car lst)) (lisp-map fun (cdr lst)))))
+                   (defun lisp-filter (fun lst)
+                     (cond ((nil? lst) nil)
+                           ((fun (car lst)) (cons (car lst) (lisp-filter fun (cdr lst))))
+                           (else (lisp-filter fun (cdr lst)))))
+                   (defun build (n acc) (if (= n 0) acc (build (- n 1) (cons n acc))))
+                   (defun fib (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))
+                   (let ((lst (build 300 nil)))
+                     (+ (fib 20)
+                        (length (lisp-filter (lambda (x) (< x 100))
+                                             (lisp-map (lambda (x) (* x 2)) lst))))))",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let limits = EvalLimits {
+            max_steps: u64::MAX,
+            ..EvalLimits::default()
+        };
+        // The fastest of several runs is the least affected by whatever else the machine
+        // is doing.
+        let mut steps = 0;
+        let best = (0..30)
+            .map(|_| {
+                let start = std::time::Instant::now();
+                let (result, stats) = eval_with_stats(
+                    &program,
+                    Env::with_builtins(),
+                    &EmptyKeywordResolver,
+                    limits,
+                );
+                let elapsed = start.elapsed();
+                assert_eq!(result.unwrap(), Value::Number(6814.0));
+                steps = stats.steps;
+                elapsed
+            })
+            .min()
+            .unwrap();
+        assert!(steps > 100_000, "{} steps", steps);
+        assert!(
+            best < std::time::Duration::from_micros(steps),
+            "{:?} for {} steps",
+            best,
+            steps
+        );
+    }
+
+    #[test]
+    fn test_eval_limits() {
+        let eval_limited = |src: &str, limits: EvalLimits| {
+            let program =
+                compile_with_prelude(&Expr::from_string(src).unwrap(), &Definitions::default())
+                    .unwrap();
+            eval_with_limits(
+                &program,
+                Definitions::default().env(),
+                &EmptyKeywordResolver,
+                limits,
+            )
+        };
+        let limits = EvalLimits {
+            max_steps: 10_000,
+            max_call_depth: 50,
+        };
+
+        // An infinite tail-recursive loop uses constant stack, so only the step budget
+        // stops it.
+        let err = eval_limited("(begin (defun f (x) (f x)) (f 1))", limits).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+        assert!(err.to_string().starts_with("Timed out"), "{}", err);
+
+        let err = eval_limited("(begin (defun f (n) (+ 1 (f n))) (f 1))", limits).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StackOverflow);
+        assert!(err.to_string().contains("call depth of 50"), "{}", err);
+        // Neither can be caught.
+        let err = eval_limited(
+            "(try (begin (defun f (n) (+ 1 (f n))) (f 1)) (catch e 0))",
+            limits,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StackOverflow);
+
+        // Recursion through built-ins such as `map` nests on the native stack, which is
+        // bounded separately, and callbacks share the caller's limits.
+        let recurse_through_map =
+            "(begin (defun f (n) (if (= n 0) 0 (+ 1 (car (map f (list (- n 1))))))) (f {}))";
+        let nested = |n: usize| recurse_through_map.replace("{}", &n.to_string());
+        assert_eq!(
+            eval_limited(&nested(40), EvalLimits::default()).unwrap(),
+            Value::Number(40.0)
+        );
+        let err = eval_limited(&nested(1_000), EvalLimits::default()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StackOverflow);
+        assert!(err.to_string().contains("nested calls"), "{}", err);
+        let err = eval_limited(&nested(40), limits).unwrap_err();
+        assert!(err.to_string().contains("call depth of 50"), "{}", err);
+        let err = eval_limited(
+            "(map (lambda (x) ((lambda (y) (y y)) (lambda (y) (y y)))) '(1))",
+            limits,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+
+        assert_eq!(eval_limited("(+ 1 2)", limits).unwrap(), Value::Number(3.0));
+        assert_eq!(
+            eval_str("(undefined-function 1)").unwrap_err().kind(),
+            ErrorKind::UndefinedName
+        );
+        assert_eq!(
+            eval_str("(car (list))").unwrap_err().kind(),
+            ErrorKind::General
+        );
+    }
+
+    #[test]
+    fn test_random_seed() {
+        struct Seeded(u64);
+
+        impl KeywordResolver for Seeded {
+            fn resolve_keyword(&self, _kw: &str) -> AppResult<Value> {
+                Ok(Value::Nil)
+            }
+
+            fn random_seed(&self) -> u64 {
+                self.0
+            }
+        }
+
+        // Calls from built-ins such as `map` draw on the same generator.
+        let program = compile(
+            &Expr::from_string("(list (rand) (car (map (lambda (x) (rand)) (list 1))) (rand))")
+                .unwrap(),
+        )
+        .unwrap();
+        let run = |seed| eval(&program, Env::with_builtins(), &Seeded(seed)).unwrap();
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+        match run(1) {
+            Value::List(items) => assert!(items[0] != items[1] && items[1] != items[2]),
+            value => panic!("Expected a list, got {:?}", value),
+        }
+    }
+
+    #[test]
+    fn test_try() {
+        let string = |s: &str| Value::String(s.to_string());
+        assert_eq!(
+            eval_str("(try (car (list)) (catch e (error-message e)))").unwrap(),
+            string("Cannot take the car of an empty list")
+        );
+        assert_eq!(
+            eval_str("(try (+ 1 2) (catch e 0))").unwrap(),
+            Value::Number(3.0)
+        );
+        // Operands pushed by the body are unwound.
+        assert_eq!(
+            eval_str("(+ 1 (try (+ 2 (car (list))) (catch e 10)))").unwrap(),
+            Value::Number(11.0)
+        );
+        // Errors are caught from functions called by the body, including through
+        // built-ins such as `map`.
+        assert_eq!(
+            eval_str(
+                "(begin (defun f (x) (+ x (error \"Boom\")))
+                        (list (try (f 1) (catch e (error-kind e)))
+                              (try (map f (list 1)) (catch e (error-message e)))))"
+            )
+            .unwrap(),
+            Value::List(vec![string("user"), string("Boom")])
+        );
+        // Re-raising keeps the original error.
+        assert_eq!(
+            eval_str(
+                "(try (try (car 1) (catch e (raise e)))
+                      (catch e (list (error-kind e) (error? e))))"
+            )
+            .unwrap(),
+            Value::List(vec![string("type"), Value::Boolean(true)])
+        );
+        // The error is bound in the handler only.
+        assert_eq!(
+            eval_str("(begin (def e 5) (try (error \"x\") (catch e (def y 1) e)) e)").unwrap(),
+            Value::Number(5.0)
+        );
+        assert_eq!(
+            eval_str(
+                "(begin (defun f (n) (if (< n 1) (error \"Done\") (try (f (- n 1)) (catch e n))))
+                        (f 3))"
+            )
+            .unwrap(),
+            Value::Number(1.0)
+        );
+        match eval_str("(try (error \"Oops\") (catch e e))").unwrap() {
+            Value::Error(err) => assert_eq!((err.kind(), err.message()), (ErrorKind::User, "Oops")),
+            value => panic!("Expected an error, got {:?}", value),
+        }
+
+        let err = eval_str("(error \"Uncaught\")").unwrap_err();
+        assert_eq!((err.kind(), err.message()), (ErrorKind::User, "Uncaught"));
+        // Running out of steps can't be caught.
+        let program = compile(
+            &Expr::from_string("(try (begin (defun f (x) (f x)) (f 1)) (catch e 0))").unwrap(),
+        )
+        .unwrap();
+        let limits = EvalLimits {
+            max_steps: 1_000,
+            ..EvalLimits::default()
+        };
+        let err = eval_with_limits(
+            &program,
+            Env::with_builtins(),
+            &EmptyKeywordResolver,
+            limits,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+
+        for src in &[
+            "(try 1)",
+            "(try 1 (catch 2 3))",
+            "(try 1 (catch e))",
+            "(try 1 2)",
+        ] {
+            assert_eq!(
+                eval_str(src).unwrap_err().kind(),
+                ErrorKind::Syntax,
+                "{}",
+                src
+            );
+        }
+    }
+
+    #[test]
+    fn test_runtime_error_spans() {
+        // The source of the innermost instruction that failed.
+        let failing_source = |src: &str| {
+            let span = eval_str(src).unwrap_err().span().unwrap();
+            src[span.start..span.end].to_string()
+        };
+        assert_eq!(failing_source("(+ 1 (car (list)))"), "(car (list))");
+        assert_eq!(failing_source("(+ 1 undefined-name)"), "undefined-name");
+        assert_eq!(
+            failing_source("(begin (defun f (x) (car x)) (f (list)))"),
+            "(car x)"
+        );
+        assert_eq!(
+            failing_source("(map (lambda (x) (/ 1 (cdr x))) (list (list 1)))"),
+            "(/ 1 (cdr x))"
+        );
+        assert_eq!(failing_source("((lambda (x) x))"), "((lambda (x) x))");
+        assert_eq!(
+            failing_source("(begin (defun f (n) (+ 1 (f n))) (f 1))"),
+            "(f n)"
+        );
+        // Code generated by macros is attributed to the macro call.
+        let src = "(begin (defmacro twice (x) `(+ ,x ,x)) (list (twice (car 1))))";
+        let span = eval_str(src).unwrap_err().span().unwrap();
+        assert_eq!(&src[span.start..span.end], "(twice (car 1))");
     }
 }
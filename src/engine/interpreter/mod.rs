@@ -1,10 +1,25 @@
 mod builtins;
 mod compiler;
+mod debugger;
+mod decimal;
+mod disassembler;
 mod env;
 mod evaluator;
 mod model;
+mod optimizer;
+mod random;
+mod repl;
+mod verifier;
 
-pub use self::compiler::{compile, compile_with_prelude, Program};
+pub use self::compiler::{
+    compile, compile_with_prelude, macroexpand_with_prelude, Definitions, Program,
+};
+pub use self::debugger::{Breakpoint, DebugFrame, DebugSession, DebugState};
+pub use self::decimal::{Decimal, RoundingMode};
+pub use self::disassembler::disassemble;
 pub use self::env::Env;
-pub use self::evaluator::{eval, EmptyKeywordResolver, KeywordResolver};
+pub use self::evaluator::{
+    eval, eval_with_stats, EmptyKeywordResolver, EvalLimits, KeywordResolver,
+};
 pub use self::model::Value;
+pub use self::repl::Repl;
@@ -1,10 +1,13 @@
 use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::fmt;
 use std::rc::Rc;
 
-use crate::parser::Expr;
+use crate::error::{AppError, AppResult, ErrorKind, Span};
+use crate::parser::{Expr, ExprKind};
 
 use super::builtins::BuiltinFunction;
+use super::decimal::Decimal;
 use super::env::Env;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -15,11 +18,26 @@ pub enum Instruction {
     StoreName(String),
     /// Read a value from the environment
     LoadName(String),
+    /// Store a value into a local variable slot of the current function's environment
+    StoreLocal {
+        index: usize,
+    },
+    /// Read a local variable from the slot `index` of the environment `depth` functions
+    /// out from the current one
+    LoadLocal {
+        depth: usize,
+        index: usize,
+    },
     LoadKeyword(String),
     /// Call a function (built-in or user-defined)
     CallFunction {
         nargs: i32,
     },
+    /// Call a function whose result is immediately returned. User-defined functions
+    /// reuse the current frame instead of pushing a new one.
+    TailCall {
+        nargs: i32,
+    },
     /// Apply a function. The stack should consist of <function>, <list of arguments>
     ApplyFunction,
     /// Jumps if the value on top of the stack is true
@@ -31,28 +49,194 @@ pub enum Instruction {
         offset: i32,
     },
     /// Creates a function object from a code object on the stack and pushes it on the stack
-    MakeFunction,
+    MakeFunction {
+        params: Rc<FunctionParams>,
+    },
     /// Pop a value from the stack and do nothing with it.
     DiscardValue,
+    /// Start a `try`: until the matching `PopHandler`, an error makes the stack unwind to
+    /// its current height and execution continue at `offset`, with the error pushed.
+    PushHandler {
+        offset: i32,
+    },
+    /// Finish a `try` whose body succeeded. The stack should consist of <handler
+    /// function>, <result>; the handler function is dropped from under the result.
+    PopHandler,
+}
+
+/// Compiled instructions, along with the span of the source expression each one was
+/// compiled from, so that errors at run time can point at the code that caused them.
+#[derive(Debug, Clone, Default)]
+pub struct Bytecode {
+    pub instructions: Vec<Instruction>,
+    /// Parallel to `instructions`. `None` for code without a source, e.g. the prelude.
+    pub spans: Vec<Option<Span>>,
+}
+
+impl Bytecode {
+    /// Bytecode without any spans.
+    pub fn new(instructions: Vec<Instruction>) -> Self {
+        let spans = vec![None; instructions.len()];
+        Self {
+            instructions,
+            spans,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn push(&mut self, instruction: Instruction, span: Option<Span>) {
+        self.instructions.push(instruction);
+        self.spans.push(span);
+    }
+
+    pub fn append(&mut self, other: Bytecode) {
+        self.instructions.extend(other.instructions);
+        self.spans.extend(other.spans);
+    }
+
+    /// The span of the instruction at `index`.
+    pub fn span(&self, index: usize) -> Option<Span> {
+        self.spans.get(index).copied().flatten()
+    }
+}
+
+/// Spans are ignored, like those of `Expr`s, so that the same code compiled from
+/// different places compares equal.
+impl PartialEq for Bytecode {
+    fn eq(&self, other: &Bytecode) -> bool {
+        self.instructions == other.instructions
+    }
+}
+
+/// Parameter list of a user-defined function, e.g. `(a b &optional (c 1) &rest d)`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FunctionParams {
+    pub required: Vec<String>,
+    /// Optional parameters along with the code computing their default value. The
+    /// default is evaluated in the function's environment, so it can refer to earlier
+    /// parameters.
+    pub optional: Vec<(String, Rc<Bytecode>)>,
+    /// Receives any remaining arguments as a list.
+    pub rest: Option<String>,
+    /// Names of the function's local variable slots: its parameters in order, then any
+    /// variables defined in its body.
+    pub locals: Rc<Vec<String>>,
+}
+
+impl fmt::Display for FunctionParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = self.required.clone();
+        if !self.optional.is_empty() {
+            parts.push("&optional".to_string());
+            parts.extend(self.optional.iter().map(|(name, _)| name.clone()));
+        }
+        if let Some(rest) = &self.rest {
+            parts.push("&rest".to_string());
+            parts.push(rest.clone());
+        }
+        write!(f, "({})", parts.join(" "))
+    }
+}
+
+/// A float usable as a map key. Floats are totally ordered for this purpose, with
+/// `-0` and `0` being the same key.
+#[derive(Clone, Copy, Debug)]
+pub struct NumberKey(f64);
+
+impl NumberKey {
+    fn new(n: f64) -> Self {
+        NumberKey(if n == 0.0 { 0.0 } else { n })
+    }
+}
+
+impl PartialEq for NumberKey {
+    fn eq(&self, other: &NumberKey) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for NumberKey {}
+
+impl PartialOrd for NumberKey {
+    fn partial_cmp(&self, other: &NumberKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NumberKey {
+    fn cmp(&self, other: &NumberKey) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// The values that can be used as keys in a `Value::Map`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MapKey {
+    Boolean(bool),
+    Number(NumberKey),
+    String(String),
+    Keyword(String),
+    Symbol(String),
+}
+
+impl MapKey {
+    pub fn from_value(value: &Value) -> AppResult<Self> {
+        match value {
+            Value::Boolean(b) => Ok(MapKey::Boolean(*b)),
+            Value::Number(n) => Ok(MapKey::Number(NumberKey::new(*n))),
+            Value::String(s) => Ok(MapKey::String(s.clone())),
+            Value::Keyword(kw) => Ok(MapKey::Keyword(kw.clone())),
+            Value::Symbol(sym) => Ok(MapKey::Symbol(sym.clone())),
+            _ => Err(AppError::with_kind(
+                ErrorKind::Type,
+                format!(
+                    "Map keys must be numbers, strings, keywords, symbols or bools, not {}",
+                    value.type_string()
+                ),
+            )),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            MapKey::Boolean(b) => Value::Boolean(*b),
+            MapKey::Number(n) => Value::Number(n.0),
+            MapKey::String(s) => Value::String(s.clone()),
+            MapKey::Keyword(kw) => Value::Keyword(kw.clone()),
+            MapKey::Symbol(sym) => Value::Symbol(sym.clone()),
+        }
+    }
 }
 
 #[derive(PartialEq, Clone)]
 pub enum Value {
-    Number(f32),
+    Number(f64),
+    /// Exact base-10 number, opted into with `dec`.
+    Decimal(Decimal),
     String(String),
     Boolean(bool),
     Symbol(String),
     // XXX: Keywords should be resolved to a cell value
     Keyword(String),
     List(Vec<Value>),
-    CompiledCode(Vec<Instruction>),
+    /// Persistent vector with fast indexing. Ranges of cells evaluate to these.
+    Vector(imbl::Vector<Value>),
+    /// Persistent map, ordered by key.
+    Map(imbl::OrdMap<MapKey, Value>),
+    CompiledCode(Rc<Bytecode>),
     UserFunction {
-        /// List of names
-        params: Vec<String>,
-        body: Vec<Instruction>,
+        /// Set when the function is first bound to a name, for stack traces.
+        name: Option<String>,
+        params: Rc<FunctionParams>,
+        body: Rc<Bytecode>,
         env: Rc<RefCell<Env>>,
     },
     BuiltinFunction(&'static dyn BuiltinFunction),
+    /// An error caught by `try`.
+    Error(AppError),
     Nil,
 }
 
@@ -60,6 +244,7 @@ impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Value::Number(n) => write!(f, "Number({})", n),
+            Value::Decimal(d) => write!(f, "Decimal({})", d),
             Value::String(s) => write!(f, "String({:?})", s),
             Value::Boolean(b) => {
                 if *b {
@@ -71,9 +256,23 @@ impl fmt::Debug for Value {
             Value::Symbol(sym) => write!(f, "Symbol({})", sym),
             Value::Keyword(kw) => write!(f, "Keyword({})", kw),
             Value::List(elems) => write!(f, "List({:?}", elems),
+            Value::Vector(elems) => write!(f, "Vector({:?})", elems),
+            Value::Map(map) => {
+                write!(f, "Map(")?;
+                f.debug_map()
+                    .entries(map.iter().map(|(key, value)| (key.to_value(), value)))
+                    .finish()?;
+                write!(f, ")")
+            }
             Value::CompiledCode(code) => write!(f, "<compiled code>"),
-            Value::UserFunction { params, .. } => write!(f, "<func: {:?}>", params),
+            Value::UserFunction {
+                name: Some(name),
+                params,
+                ..
+            } => write!(f, "<func {}: {}>", name, params),
+            Value::UserFunction { params, .. } => write!(f, "<func: {}>", params),
             Value::BuiltinFunction(func) => func.fmt(f),
+            Value::Error(err) => write!(f, "Error({}: {})", err.kind().name(), err),
             Value::Nil => write!(f, "Nil"),
         }
     }
@@ -81,27 +280,70 @@ impl fmt::Debug for Value {
 
 impl Value {
     pub fn from_expr(expr: &Expr) -> Self {
-        match expr {
-            Expr::Number(n) => Value::Number(n.clone()),
-            Expr::String(s) => Value::String(s.clone()),
-            Expr::Boolean(b) => Value::Boolean(b.clone()),
-            Expr::Symbol(sym) => Value::Symbol(sym.clone()),
-            Expr::Keyword(kw) => Value::Symbol(kw.clone()),
-            Expr::List(exprs) => Value::List(exprs.iter().map(|e| Value::from_expr(e)).collect()),
+        match &expr.kind {
+            ExprKind::Number(n) => Value::Number(n.clone()),
+            ExprKind::String(s) => Value::String(s.clone()),
+            ExprKind::Boolean(b) => Value::Boolean(b.clone()),
+            ExprKind::Symbol(sym) => Value::Symbol(sym.clone()),
+            ExprKind::Keyword(kw) => Value::Keyword(kw.clone()),
+            ExprKind::List(exprs) => {
+                Value::List(exprs.iter().map(|e| Value::from_expr(e)).collect())
+            }
+        }
+    }
+
+    /// Convert a value back into code, e.g. the result of expanding a macro.
+    pub fn to_expr(&self) -> AppResult<Expr> {
+        match self {
+            Value::Number(n) => Ok(Expr::number(*n)),
+            Value::String(s) => Ok(Expr::string(s.clone())),
+            Value::Boolean(b) => Ok(Expr::boolean(*b)),
+            Value::Symbol(sym) => Ok(Expr::symbol(sym.clone())),
+            Value::Keyword(kw) => Ok(Expr::keyword(kw.clone())),
+            Value::List(elems) => Ok(Expr::list(
+                elems
+                    .iter()
+                    .map(|elem| elem.to_expr())
+                    .collect::<AppResult<Vec<_>>>()?,
+            )),
+            // Collections become the calls that construct them, as written by `[...]` and
+            // `{...}` literals.
+            Value::Vector(elems) => Ok(Expr::list(
+                std::iter::once(Ok(Expr::symbol("vector")))
+                    .chain(elems.iter().map(|elem| elem.to_expr()))
+                    .collect::<AppResult<Vec<_>>>()?,
+            )),
+            Value::Map(map) => {
+                let mut exprs = vec![Expr::symbol("hash-map")];
+                for (key, value) in map {
+                    exprs.push(key.to_value().to_expr()?);
+                    exprs.push(value.to_expr()?);
+                }
+                Ok(Expr::list(exprs))
+            }
+            Value::Nil => Ok(Expr::symbol("nil")),
+            _ => Err(AppError::new(format!(
+                "Value of type {} cannot be converted to code",
+                self.type_string()
+            ))),
         }
     }
 
     pub fn type_string(&self) -> &str {
         match self {
             Value::Number(_) => "number",
+            Value::Decimal(_) => "decimal",
             Value::String(_) => "string",
             Value::Boolean(_) => "bool",
             Value::Symbol(_) => "symbol",
             Value::Keyword(_) => "keyword",
             Value::List(_) => "list",
+            Value::Vector(_) => "vector",
+            Value::Map(_) => "map",
             Value::CompiledCode(code) => "code",
             Value::UserFunction { .. } => "function",
             Value::BuiltinFunction(func) => "builtin",
+            Value::Error(_) => "error",
             Value::Nil => "nil",
         }
     }
@@ -113,6 +355,43 @@ mod tests {
 
     #[test]
     fn test_value_from_expr() {
-        assert_eq!(Value::from_expr(&Expr::Number(2.0)), Value::Number(2.0));
+        assert_eq!(Value::from_expr(&Expr::number(2.0)), Value::Number(2.0));
+    }
+
+    #[test]
+    fn test_value_to_expr_round_trip() {
+        let expr = Expr::from_string("(f :a1 \"s\" 1 #f (g))").unwrap();
+        assert_eq!(Value::from_expr(&expr).to_expr(), Ok(expr));
+        assert!(Value::Decimal(crate::interpreter::Decimal::new(1, 0))
+            .to_expr()
+            .is_err());
+
+        let map = Value::Map(
+            vec![(
+                MapKey::String("k".into()),
+                Value::Vector(vec![Value::Number(1.0)].into()),
+            )]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(
+            map.to_expr().unwrap(),
+            Expr::from_string("{\"k\" [1]}").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_map_keys() {
+        let key = |value: Value| MapKey::from_value(&value).unwrap();
+        assert_eq!(key(Value::Number(0.0)), key(Value::Number(-0.0)));
+        assert!(key(Value::Number(1.0)) < key(Value::Number(2.0)));
+        assert_eq!(
+            key(Value::String("a".into())).to_value(),
+            Value::String("a".into())
+        );
+        assert_eq!(
+            MapKey::from_value(&Value::List(vec![])).unwrap_err().kind(),
+            ErrorKind::Type
+        );
     }
 }
@@ -0,0 +1,506 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use super::builtins::lookup_builtin;
+use super::compiler::mark_tail_calls;
+use crate::error::Span;
+use crate::parser::MAX_NESTING_DEPTH;
+
+use super::model::{Bytecode, FunctionParams, Instruction, Value};
+
+/// Built-ins whose result depends only on their arguments, so that calls to them with
+/// constant arguments can be evaluated at compile time. The rounding built-ins
+/// aren't pure: they depend on the evaluation's rounding mode.
+const PURE_BUILTINS: &[&str] = &[
+    "+", "-", "*", "/", "=", "<", ">", "<=", ">=", "not", "sum", "dec", "type", "list", "vector",
+    "hash-map", "cons", "car", "cdr", "append", "nil?", "get", "length", "nth", "reverse",
+];
+
+/// Guards against passes that keep undoing each other.
+const MAX_PASSES: usize = 100;
+
+/// Code being optimized. Jump targets are held as absolute indices, so instructions can
+/// be removed without recomputing every offset.
+struct Code<'a> {
+    instructions: Vec<Instruction>,
+    /// Parallel to `instructions`: the source span of each.
+    spans: Vec<Option<Span>>,
+    /// For each jump (or `try`, for its handler), the index of the instruction it jumps
+    /// to (which may be one past the end, i.e. a return).
+    targets: Vec<Option<usize>>,
+    removed: Vec<bool>,
+    /// Names that the program assigns globally, which can no longer be assumed to refer
+    /// to built-ins.
+    redefined: &'a HashSet<String>,
+}
+
+impl<'a> Code<'a> {
+    fn new(code: Bytecode, redefined: &'a HashSet<String>) -> Self {
+        let Bytecode {
+            instructions,
+            spans,
+        } = code;
+        let targets = instructions
+            .iter()
+            .enumerate()
+            .map(|(idx, instruction)| match instruction {
+                Instruction::RelativeJump { offset }
+                | Instruction::RelativeJumpIfTrue { offset }
+                | Instruction::PushHandler { offset } => Some((idx as i32 + 1 + offset) as usize),
+                _ => None,
+            })
+            .collect();
+        let removed = vec![false; instructions.len()];
+        Self {
+            instructions,
+            spans,
+            targets,
+            removed,
+            redefined,
+        }
+    }
+
+    fn jump_targets(&self) -> HashSet<usize> {
+        self.targets.iter().flatten().cloned().collect()
+    }
+
+    fn remove(&mut self, idx: usize) {
+        self.removed[idx] = true;
+    }
+
+    fn is_builtin(&self, name: &str) -> bool {
+        !self.redefined.contains(name)
+    }
+
+    /// Drop removed instructions. Jumps to a removed instruction go to the one after it
+    /// instead.
+    fn compact(&mut self) {
+        // A removed instruction maps to the next one that survives, which is where the
+        // number of survivors before it puts it.
+        let mut new_indices = Vec::with_capacity(self.instructions.len() + 1);
+        let mut survivors = 0;
+        for removed in &self.removed {
+            new_indices.push(survivors);
+            if !removed {
+                survivors += 1;
+            }
+        }
+        new_indices.push(survivors);
+        let mut instructions = Vec::new();
+        let mut spans = Vec::new();
+        let mut targets = Vec::new();
+        for (idx, instruction) in std::mem::take(&mut self.instructions)
+            .into_iter()
+            .enumerate()
+        {
+            if !self.removed[idx] {
+                instructions.push(instruction);
+                spans.push(self.spans[idx]);
+                targets.push(self.targets[idx].map(|target| new_indices[target]));
+            }
+        }
+        self.removed = vec![false; instructions.len()];
+        self.instructions = instructions;
+        self.spans = spans;
+        self.targets = targets;
+    }
+
+    /// Replace calls to pure built-ins with constant arguments by their result, and
+    /// `nil` by its value.
+    fn fold_constants(&mut self) -> bool {
+        let jump_targets = self.jump_targets();
+        let mut changed = false;
+        let mut idx = 0;
+        while idx < self.instructions.len() {
+            let name = match &self.instructions[idx] {
+                Instruction::LoadName(name) if self.is_builtin(name) => name.clone(),
+                _ => {
+                    idx += 1;
+                    continue;
+                }
+            };
+            if name == "nil" {
+                self.instructions[idx] = Instruction::LoadConst(Box::new(Value::Nil));
+                changed = true;
+                idx += 1;
+                continue;
+            }
+            let nargs = self.instructions[idx + 1..]
+                .iter()
+                .take_while(|instruction| matches!(instruction, Instruction::LoadConst(_)))
+                .count();
+            let call_idx = idx + 1 + nargs;
+            let is_call = matches!(
+                self.instructions.get(call_idx),
+                Some(Instruction::CallFunction { nargs: n } | Instruction::TailCall { nargs: n })
+                    if *n as usize == nargs
+            );
+            // Jumping into the middle would skip part of the call.
+            let is_straight_line = (idx + 1..=call_idx).all(|idx| !jump_targets.contains(&idx));
+            if !is_call || !is_straight_line || !PURE_BUILTINS.contains(&name.as_str()) {
+                idx += 1;
+                continue;
+            }
+            let args = self.instructions[idx + 1..call_idx]
+                .iter()
+                .map(|instruction| match instruction {
+                    Instruction::LoadConst(value) => *value.clone(),
+                    _ => unreachable!(),
+                })
+                .collect();
+            // Errors (e.g. division by zero) are left to happen at run time.
+            if let Ok(result) = lookup_builtin(&name).unwrap().call(args) {
+                self.instructions[idx] = Instruction::LoadConst(Box::new(result));
+                self.spans[idx] = self.spans[call_idx];
+                for removed in idx + 1..=call_idx {
+                    self.remove(removed);
+                }
+                changed = true;
+            }
+            idx = call_idx + 1;
+        }
+        changed
+    }
+
+    /// Resolve conditional jumps on constants: always taken if the constant is `#t`,
+    /// never otherwise.
+    fn eliminate_branches(&mut self) -> bool {
+        let jump_targets = self.jump_targets();
+        let mut changed = false;
+        for idx in 0..self.instructions.len().saturating_sub(1) {
+            let is_true = match (&self.instructions[idx], &self.instructions[idx + 1]) {
+                (Instruction::LoadConst(value), Instruction::RelativeJumpIfTrue { .. })
+                    if !jump_targets.contains(&(idx + 1)) && !self.removed[idx] =>
+                {
+                    **value == Value::Boolean(true)
+                }
+                _ => continue,
+            };
+            if is_true {
+                self.instructions[idx] = Instruction::RelativeJump { offset: 0 };
+                self.targets[idx] = self.targets[idx + 1];
+            } else {
+                self.remove(idx);
+            }
+            self.remove(idx + 1);
+            changed = true;
+        }
+        changed
+    }
+
+    /// Remove instructions that can't be reached from the start.
+    fn remove_unreachable(&mut self) -> bool {
+        let mut reachable = vec![false; self.instructions.len()];
+        let mut pending = vec![0];
+        while let Some(idx) = pending.pop() {
+            if idx >= self.instructions.len() || reachable[idx] {
+                continue;
+            }
+            reachable[idx] = true;
+            match &self.instructions[idx] {
+                Instruction::RelativeJump { .. } => pending.push(self.targets[idx].unwrap()),
+                Instruction::RelativeJumpIfTrue { .. } | Instruction::PushHandler { .. } => {
+                    pending.push(self.targets[idx].unwrap());
+                    pending.push(idx + 1);
+                }
+                _ => pending.push(idx + 1),
+            }
+        }
+        let mut changed = false;
+        for (idx, reachable) in reachable.into_iter().enumerate() {
+            if !reachable {
+                self.remove(idx);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Remove constants that are immediately discarded.
+    fn remove_discarded_constants(&mut self) -> bool {
+        let jump_targets = self.jump_targets();
+        let mut changed = false;
+        let mut idx = 0;
+        while idx + 1 < self.instructions.len() {
+            if let (Instruction::LoadConst(_), Instruction::DiscardValue) =
+                (&self.instructions[idx], &self.instructions[idx + 1])
+            {
+                if !jump_targets.contains(&(idx + 1)) {
+                    self.remove(idx);
+                    self.remove(idx + 1);
+                    changed = true;
+                    idx += 2;
+                    continue;
+                }
+            }
+            idx += 1;
+        }
+        changed
+    }
+
+    /// Make jumps to unconditional jumps go straight to the final destination, and
+    /// remove jumps to the next instruction.
+    fn thread_jumps(&mut self) -> bool {
+        let mut changed = false;
+        for idx in 0..self.instructions.len() {
+            let mut target = match self.targets[idx] {
+                Some(target) => target,
+                None => continue,
+            };
+            // Bounded, in case of a loop of jumps.
+            for _ in 0..self.instructions.len() {
+                match self.instructions.get(target) {
+                    Some(Instruction::RelativeJump { .. })
+                        if self.targets[target] != Some(target) =>
+                    {
+                        target = self.targets[target].unwrap();
+                    }
+                    _ => break,
+                }
+            }
+            if Some(target) != self.targets[idx] {
+                self.targets[idx] = Some(target);
+                changed = true;
+            }
+            if target == idx + 1 {
+                match self.instructions[idx] {
+                    Instruction::RelativeJump { .. } => self.remove(idx),
+                    // The condition still has to be popped.
+                    Instruction::RelativeJumpIfTrue { .. } => {
+                        self.instructions[idx] = Instruction::DiscardValue;
+                        self.targets[idx] = None;
+                    }
+                    _ => continue,
+                }
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn finish(self) -> Bytecode {
+        let instructions = self
+            .instructions
+            .into_iter()
+            .zip(self.targets)
+            .enumerate()
+            .map(|(idx, (instruction, target))| {
+                let offset = target.map_or(0, |target| target as i32 - idx as i32 - 1);
+                match instruction {
+                    Instruction::RelativeJump { .. } => Instruction::RelativeJump { offset },
+                    Instruction::RelativeJumpIfTrue { .. } => {
+                        Instruction::RelativeJumpIfTrue { offset }
+                    }
+                    Instruction::PushHandler { .. } => Instruction::PushHandler { offset },
+                    instruction => instruction,
+                }
+            })
+            .collect();
+        Bytecode {
+            instructions,
+            spans: self.spans,
+        }
+    }
+}
+
+/// Optimize nested code, `depth` functions deep: function bodies and the defaults of
+/// optional parameters. Code nested too deeply to recurse into is left as it is.
+fn optimize_nested(
+    instruction: Instruction,
+    redefined: &HashSet<String>,
+    depth: usize,
+) -> Instruction {
+    if depth > MAX_NESTING_DEPTH {
+        return instruction;
+    }
+    match instruction {
+        Instruction::LoadConst(value) => match *value {
+            Value::CompiledCode(body) => {
+                // Optimizing can put calls in tail position, e.g. by removing a branch.
+                let mut body = optimize_at_depth((*body).clone(), redefined, depth);
+                mark_tail_calls(&mut body.instructions);
+                Instruction::LoadConst(Box::new(Value::CompiledCode(Rc::new(body))))
+            }
+            value => Instruction::LoadConst(Box::new(value)),
+        },
+        Instruction::MakeFunction { params } if !params.optional.is_empty() => {
+            Instruction::MakeFunction {
+                params: Rc::new(FunctionParams {
+                    optional: params
+                        .optional
+                        .iter()
+                        .map(|(name, default)| {
+                            (
+                                name.clone(),
+                                Rc::new(optimize_at_depth((**default).clone(), redefined, depth)),
+                            )
+                        })
+                        .collect(),
+                    ..(*params).clone()
+                }),
+            }
+        }
+        instruction => instruction,
+    }
+}
+
+/// Simplify compiled code without changing what it does:
+///
+/// - calls to pure built-ins with constant arguments are evaluated, e.g. `(+ 1 2)`
+///   becomes the constant `3`;
+/// - branches on constant conditions are resolved, and code that can no longer be
+///   reached is removed, so `(if #t a b)` is just `a`;
+/// - constants that are pushed and immediately discarded are removed;
+/// - jumps to jumps go directly to the final destination.
+///
+/// Names in `redefined` are assigned by the program, so calls to them aren't folded.
+/// Nested function bodies are optimized too.
+pub(super) fn optimize(code: Bytecode, redefined: &HashSet<String>) -> Bytecode {
+    optimize_at_depth(code, redefined, 0)
+}
+
+fn optimize_at_depth(code: Bytecode, redefined: &HashSet<String>, depth: usize) -> Bytecode {
+    let code = Bytecode {
+        instructions: code
+            .instructions
+            .into_iter()
+            .map(|instruction| optimize_nested(instruction, redefined, depth + 1))
+            .collect(),
+        spans: code.spans,
+    };
+    let mut code = Code::new(code, redefined);
+    for _ in 0..MAX_PASSES {
+        let mut changed = false;
+        changed |= code.fold_constants();
+        code.compact();
+        changed |= code.eliminate_branches();
+        code.compact();
+        changed |= code.remove_unreachable();
+        code.compact();
+        changed |= code.remove_discarded_constants();
+        code.compact();
+        changed |= code.thread_jumps();
+        code.compact();
+        if !changed {
+            break;
+        }
+    }
+    code.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimized(code: Vec<Instruction>, redefined: &HashSet<String>) -> Vec<Instruction> {
+        optimize(Bytecode::new(code), redefined).instructions
+    }
+
+    fn jump(offset: i32) -> Instruction {
+        Instruction::RelativeJump { offset }
+    }
+
+    fn jump_if_true(offset: i32) -> Instruction {
+        Instruction::RelativeJumpIfTrue { offset }
+    }
+
+    fn load(name: &str) -> Instruction {
+        Instruction::LoadName(name.to_string())
+    }
+
+    fn constant(value: Value) -> Instruction {
+        Instruction::LoadConst(Box::new(value))
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let none = HashSet::new();
+        // (+ 1 (* 2 3))
+        let code = vec![
+            load("+"),
+            constant(Value::Number(1.0)),
+            load("*"),
+            constant(Value::Number(2.0)),
+            constant(Value::Number(3.0)),
+            Instruction::CallFunction { nargs: 2 },
+            Instruction::CallFunction { nargs: 2 },
+        ];
+        assert_eq!(
+            optimized(code.clone(), &none),
+            vec![constant(Value::Number(7.0))]
+        );
+        // Not if `*` has been redefined.
+        let redefined = vec!["*".to_string()].into_iter().collect();
+        assert_eq!(optimized(code.clone(), &redefined).len(), 7);
+
+        // Errors are left for run time.
+        let code = vec![
+            load("/"),
+            constant(Value::Number(1.0)),
+            constant(Value::Number(0.0)),
+            Instruction::CallFunction { nargs: 2 },
+        ];
+        assert_eq!(optimized(code.clone(), &none), code);
+        // As are impure functions.
+        let code = vec![load("gensym"), Instruction::CallFunction { nargs: 0 }];
+        assert_eq!(optimized(code.clone(), &none), code);
+    }
+
+    #[test]
+    fn test_eliminate_branches() {
+        let none = HashSet::new();
+        // (if #t a b)
+        let code = vec![
+            constant(Value::Boolean(true)),
+            jump_if_true(2),
+            load("b"),
+            jump(1),
+            load("a"),
+        ];
+        assert_eq!(optimized(code, &none), vec![load("a")]);
+        // (if #f a b)
+        let code = vec![
+            constant(Value::Boolean(false)),
+            jump_if_true(2),
+            load("b"),
+            jump(1),
+            load("a"),
+        ];
+        assert_eq!(optimized(code, &none), vec![load("b")]);
+        // (begin 1 x): the discarded constant goes.
+        let code = vec![
+            constant(Value::Number(1.0)),
+            Instruction::DiscardValue,
+            load("x"),
+        ];
+        assert_eq!(optimized(code, &none), vec![load("x")]);
+    }
+
+    #[test]
+    fn test_thread_jumps() {
+        let none = HashSet::new();
+        let code = vec![
+            load("c"),
+            jump_if_true(2),
+            load("a"),
+            jump(2),
+            load("b"),
+            jump(0),
+            load("d"),
+        ];
+        // The jump to the `jump(0)` goes straight to `d`, and the `jump(0)` goes.
+        assert_eq!(
+            optimized(code, &none),
+            vec![
+                load("c"),
+                jump_if_true(2),
+                load("a"),
+                jump(1),
+                load("b"),
+                load("d")
+            ]
+        );
+        // A loop of jumps doesn't hang the optimizer.
+        assert_eq!(optimized(vec![jump(0), jump(-2)], &none), vec![jump(-1)]);
+    }
+}
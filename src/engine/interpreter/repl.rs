@@ -0,0 +1,76 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::error::AppResult;
+use crate::parser::{parse_program, Expr};
+
+use super::compiler::{Compiler, Definitions};
+use super::env::Env;
+use super::evaluator::{eval_in_env, EvalLimits, KeywordResolver};
+use super::model::Value;
+
+/// Evaluates a series of inputs in one environment, so that functions and macros
+/// defined by earlier inputs can be used by later ones.
+pub struct Repl {
+    compiler: Compiler,
+    env: Rc<RefCell<Env>>,
+}
+
+impl Repl {
+    /// Create a REPL that can use the prelude and `definitions`.
+    pub fn new(definitions: &Definitions) -> Self {
+        // Inputs are evaluated where macros are defined, so that macros can call
+        // functions from earlier inputs.
+        let compiler = Compiler::with_definitions(definitions);
+        let env = compiler.macro_env();
+        Self { compiler, env }
+    }
+
+    pub fn eval_expr<R: KeywordResolver>(
+        &mut self,
+        expr: &Expr,
+        kw_resolver: &R,
+        limits: EvalLimits,
+    ) -> AppResult<Value> {
+        let program = self.compiler.compile_program(expr)?;
+        eval_in_env(&program, self.env.clone(), kw_resolver, limits)
+    }
+
+    /// Evaluate each top-level form in `src` in turn, returning their values.
+    pub fn eval<R: KeywordResolver>(
+        &mut self,
+        src: &str,
+        kw_resolver: &R,
+        limits: EvalLimits,
+    ) -> AppResult<Vec<Value>> {
+        parse_program(src)?
+            .iter()
+            .map(|expr| self.eval_expr(expr, kw_resolver, limits))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::evaluator::EmptyKeywordResolver;
+    use super::*;
+
+    #[test]
+    fn test_repl() {
+        let mut repl = Repl::new(&Definitions::default());
+        let mut eval = |src: &str| repl.eval(src, &EmptyKeywordResolver, EvalLimits::default());
+
+        assert_eq!(
+            eval("(defun sq (x) (* x x)) (sq 3)").unwrap(),
+            vec![Value::Nil, Value::Number(9.0)]
+        );
+        // Definitions and macros persist between inputs.
+        eval("(defmacro twice (x) `(+ ,x ,x))").unwrap();
+        assert_eq!(eval("(twice (sq 2))").unwrap(), vec![Value::Number(8.0)]);
+        assert_eq!(
+            eval("(map sq (list 1 2))").unwrap(),
+            vec![Value::List(vec![Value::Number(1.0), Value::Number(4.0)])]
+        );
+        assert!(eval("(sq 1) (").is_err());
+    }
+}
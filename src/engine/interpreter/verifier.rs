@@ -0,0 +1,295 @@
+use std::rc::Rc;
+
+use crate::error::{AppError, AppResult};
+use crate::parser::MAX_NESTING_DEPTH;
+
+use super::model::{Instruction, Value};
+
+fn invalid(idx: usize, message: &str) -> AppError {
+    AppError::new(format!(
+        "Invalid bytecode at instruction {}: {}",
+        idx, message
+    ))
+}
+
+/// How many values `instruction` pops off the stack, and how many it pushes.
+fn stack_effect(instruction: &Instruction) -> (usize, usize) {
+    match instruction {
+        Instruction::LoadConst(_)
+        | Instruction::LoadName(_)
+        | Instruction::LoadLocal { .. }
+        | Instruction::LoadKeyword(_) => (0, 1),
+        Instruction::StoreName(_)
+        | Instruction::StoreLocal { .. }
+        | Instruction::RelativeJumpIfTrue { .. }
+        | Instruction::DiscardValue => (1, 0),
+        Instruction::CallFunction { nargs } | Instruction::TailCall { nargs } => {
+            (*nargs as usize + 1, 1)
+        }
+        Instruction::ApplyFunction => (2, 1),
+        Instruction::MakeFunction { .. } => (1, 1),
+        Instruction::RelativeJump { .. } | Instruction::PushHandler { .. } => (0, 0),
+        Instruction::PopHandler => (2, 1),
+    }
+}
+
+/// What's known about the machine before running an instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct State {
+    /// Values on the stack (pushed by the code being checked).
+    height: usize,
+    /// `try`s in progress.
+    handlers: usize,
+}
+
+/// Checks code in the scope of enclosing functions, whose local variable names are
+/// `scopes` (innermost last).
+struct Verifier<'a> {
+    instructions: &'a [Instruction],
+    scopes: Vec<Rc<Vec<String>>>,
+}
+
+impl<'a> Verifier<'a> {
+    fn check_local(&self, idx: usize, depth: usize, index: usize) -> AppResult<()> {
+        let scope = match self.scopes.iter().rev().nth(depth) {
+            Some(scope) => scope,
+            None => return Err(invalid(idx, "local variable outside of a function")),
+        };
+        if index >= scope.len() {
+            return Err(invalid(idx, "local variable index out of range"));
+        }
+        Ok(())
+    }
+
+    /// Where execution can go after instruction `idx`, which starts in `state`, and the
+    /// state it's in there. An index equal to the length of the code means returning.
+    fn successors(&self, idx: usize, state: State) -> AppResult<Vec<(usize, State)>> {
+        let instruction = &self.instructions[idx];
+        let (pops, pushes) = stack_effect(instruction);
+        if pops > state.height {
+            return Err(invalid(idx, "stack underflow"));
+        }
+        let mut next = State {
+            height: state.height - pops + pushes,
+            handlers: state.handlers,
+        };
+        let jump_target = |offset: i32| {
+            let target = idx as i64 + 1 + offset as i64;
+            if target < 0 || target > self.instructions.len() as i64 {
+                Err(invalid(idx, "jump out of range"))
+            } else {
+                Ok(target as usize)
+            }
+        };
+        Ok(match instruction {
+            Instruction::RelativeJump { offset } => vec![(jump_target(*offset)?, next)],
+            Instruction::RelativeJumpIfTrue { offset } => {
+                vec![(idx + 1, next), (jump_target(*offset)?, next)]
+            }
+            // The handler starts with the error pushed, and the `try` finished.
+            Instruction::PushHandler { offset } => {
+                let handler = State {
+                    height: state.height + 1,
+                    handlers: state.handlers,
+                };
+                next.handlers += 1;
+                vec![(idx + 1, next), (jump_target(*offset)?, handler)]
+            }
+            Instruction::PopHandler if state.handlers == 0 => {
+                return Err(invalid(idx, "PopHandler outside of a try"));
+            }
+            Instruction::PopHandler => {
+                next.handlers -= 1;
+                vec![(idx + 1, next)]
+            }
+            // A tail call replaces the frame, along with its handlers.
+            Instruction::TailCall { .. } if state.handlers > 0 => {
+                return Err(invalid(idx, "tail call inside a try"));
+            }
+            _ => vec![(idx + 1, next)],
+        })
+    }
+
+    /// Check the instructions that don't depend on the stack, including nested code.
+    fn check_instruction(&self, idx: usize) -> AppResult<()> {
+        match &self.instructions[idx] {
+            Instruction::LoadLocal { depth, index } => self.check_local(idx, *depth, *index),
+            Instruction::StoreLocal { index } => self.check_local(idx, 0, *index),
+            Instruction::CallFunction { nargs } | Instruction::TailCall { nargs } if *nargs < 0 => {
+                Err(invalid(idx, "negative argument count"))
+            }
+            Instruction::LoadConst(value) => match value.as_ref() {
+                Value::CompiledCode(code) => {
+                    let params = match self.instructions.get(idx + 1) {
+                        Some(Instruction::MakeFunction { params }) => params,
+                        _ => return Err(invalid(idx, "function body without MakeFunction")),
+                    };
+                    self.nested(idx, params.locals.clone())?
+                        .verify(&code.instructions)
+                }
+                _ => Ok(()),
+            },
+            Instruction::MakeFunction { params } => {
+                let is_after_body = idx > 0
+                    && matches!(
+                        &self.instructions[idx - 1],
+                        Instruction::LoadConst(value)
+                            if matches!(value.as_ref(), Value::CompiledCode(_))
+                    );
+                if !is_after_body {
+                    return Err(invalid(idx, "MakeFunction without a function body"));
+                }
+                if params.locals.len()
+                    < params.required.len() + params.optional.len() + params.rest.iter().count()
+                {
+                    return Err(invalid(idx, "too few local variables for the parameters"));
+                }
+                // Defaults are evaluated in the new function's environment.
+                for (_, default) in &params.optional {
+                    self.nested(idx, params.locals.clone())?
+                        .verify(&default.instructions)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// A verifier for code nested in a function (at instruction `idx`) whose local
+    /// variables are `locals`.
+    fn nested(&self, idx: usize, locals: Rc<Vec<String>>) -> AppResult<Self> {
+        if self.scopes.len() >= MAX_NESTING_DEPTH {
+            return Err(invalid(idx, "functions nested too deeply"));
+        }
+        let mut scopes = self.scopes.clone();
+        scopes.push(locals);
+        Ok(Verifier {
+            instructions: &[],
+            scopes,
+        })
+    }
+
+    /// Check `instructions`: every instruction has enough operands on the stack, the
+    /// stack is the same height (and the same `try`s are in progress) whichever way an
+    /// instruction is reached, and returning leaves exactly one value (the result)
+    /// outside of any `try`.
+    fn verify(mut self, instructions: &'a [Instruction]) -> AppResult<()> {
+        self.instructions = instructions;
+        let len = instructions.len();
+        // State before each instruction (and on returning, at `len`).
+        let mut states: Vec<Option<State>> = vec![None; len + 1];
+        states[0] = Some(State {
+            height: 0,
+            handlers: 0,
+        });
+        let mut pending = vec![0];
+        while let Some(idx) = pending.pop() {
+            if idx == len {
+                continue;
+            }
+            self.check_instruction(idx)?;
+            for (successor, state) in self.successors(idx, states[idx].unwrap())? {
+                match states[successor] {
+                    None => {
+                        states[successor] = Some(state);
+                        pending.push(successor);
+                    }
+                    Some(existing) if existing.height != state.height => {
+                        return Err(invalid(successor, "inconsistent stack height"));
+                    }
+                    Some(existing) if existing.handlers != state.handlers => {
+                        return Err(invalid(successor, "inconsistent try nesting"));
+                    }
+                    Some(_) => (),
+                }
+            }
+        }
+        match states[len] {
+            Some(State {
+                height: 1,
+                handlers: 0,
+            }) => Ok(()),
+            Some(State { height: 1, .. }) => Err(invalid(len, "return inside a try")),
+            Some(_) => Err(invalid(len, "code must return exactly one value")),
+            // Every path loops forever, which the step limit will stop.
+            None => Ok(()),
+        }
+    }
+}
+
+/// Check that compiled code can't misbehave when run: that every instruction has its
+/// operands on the stack, jumps stay within the code, local variables exist, and the
+/// code returns a single value. Nested function bodies are checked too.
+pub(super) fn verify(instructions: &[Instruction]) -> AppResult<()> {
+    Verifier {
+        instructions: &[],
+        scopes: Vec::new(),
+    }
+    .verify(instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::compile;
+    use crate::parser::Expr;
+
+    fn constant(n: f64) -> Instruction {
+        Instruction::LoadConst(Box::new(Value::Number(n)))
+    }
+
+    #[test]
+    fn test_verify() {
+        for src in &[
+            "(+ 1 2)",
+            "(begin)",
+            "(if x (f 1) (g 2 3))",
+            "(lambda (a &optional (b a) &rest c) (begin (def d (+ a b)) (lambda () d)))",
+            "(apply + (list 1 2))",
+            "(lambda (x) (try (f x) (catch e (g e))))",
+        ] {
+            let program = compile(&Expr::from_string(src).unwrap()).unwrap();
+            assert!(verify(&program.code.instructions).is_ok(), "{}", src);
+        }
+
+        assert!(verify(&[]).is_err());
+        assert!(verify(&[Instruction::DiscardValue]).is_err());
+        assert!(verify(&[constant(1.0), constant(2.0)]).is_err());
+        assert!(verify(&[constant(1.0), Instruction::RelativeJump { offset: 5 }]).is_err());
+        // The two branches leave different numbers of values.
+        assert!(verify(&[
+            constant(1.0),
+            Instruction::RelativeJumpIfTrue { offset: 1 },
+            constant(2.0),
+            constant(3.0),
+        ])
+        .is_err());
+        assert!(verify(&[Instruction::LoadLocal { depth: 0, index: 0 }]).is_err());
+        assert!(verify(&[constant(1.0), Instruction::PopHandler]).is_err());
+        // A `try` that's never finished (and a handler that loops forever).
+        assert_eq!(
+            verify(&[
+                constant(1.0),
+                Instruction::PushHandler { offset: 2 },
+                Instruction::RelativeJump { offset: 2 },
+                Instruction::DiscardValue,
+                Instruction::RelativeJump { offset: -1 },
+            ])
+            .unwrap_err()
+            .message(),
+            "Invalid bytecode at instruction 5: return inside a try"
+        );
+        assert!(verify(&[
+            constant(1.0),
+            Instruction::MakeFunction {
+                params: Rc::new(super::super::model::FunctionParams {
+                    required: Vec::new(),
+                    optional: Vec::new(),
+                    rest: None,
+                    locals: Rc::new(Vec::new()),
+                }),
+            }
+        ])
+        .is_err());
+    }
+}
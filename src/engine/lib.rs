@@ -13,18 +13,94 @@ use wasm_bindgen::prelude::*;
 mod console_log;
 mod dep_graph;
 mod error;
+#[cfg(test)]
+mod fuzz;
+mod infix;
 mod interpreter;
 mod parser;
+mod printer;
 mod sheet;
 
 use interpreter::EmptyKeywordResolver;
-use sheet::{CellSubscription, Sheet, SheetAddress, SheetCellInfo};
+use sheet::{CellSubscription, Sheet, SheetAddress, SheetCellComputedValue, SheetCellInfo};
+
+fn set_property(object: &js_sys::Object, key: &str, value: &JsValue) {
+    js_sys::Reflect::set(object, &JsValue::from_str(key), value).unwrap();
+}
+
+fn error_to_js(err: &error::AppError) -> JsValue {
+    let object = js_sys::Object::new();
+    set_property(&object, "kind", &JsValue::from_str(err.kind().name()));
+    set_property(&object, "message", &JsValue::from_str(err.message()));
+    let span = match err.span() {
+        Some(span) => {
+            let span_object = js_sys::Object::new();
+            set_property(&span_object, "start", &JsValue::from(span.start as u32));
+            set_property(&span_object, "end", &JsValue::from(span.end as u32));
+            span_object.into()
+        }
+        None => JsValue::null(),
+    };
+    set_property(&object, "span", &span);
+    let stack_trace = js_sys::Array::new();
+    for frame in err.stack_trace() {
+        let frame_object = js_sys::Object::new();
+        set_property(
+            &frame_object,
+            "function",
+            &JsValue::from_str(&frame.function),
+        );
+        set_property(&frame_object, "count", &JsValue::from(frame.count as u32));
+        stack_trace.push(&frame_object);
+    }
+    set_property(&object, "stackTrace", &stack_trace);
+    object.into()
+}
+
+fn debug_frame_to_js(frame: &interpreter::DebugFrame) -> JsValue {
+    let object = js_sys::Object::new();
+    let function = match &frame.function {
+        Some(function) => JsValue::from_str(function),
+        None => JsValue::null(),
+    };
+    set_property(&object, "function", &function);
+    set_property(&object, "index", &JsValue::from(frame.index as u32));
+    let code = js_sys::Array::new();
+    for line in &frame.code {
+        code.push(&JsValue::from_str(line));
+    }
+    set_property(&object, "code", &code);
+    let stack = js_sys::Array::new();
+    for value in &frame.stack {
+        stack.push(&JsValue::from_str(&format!("{:?}", value)));
+    }
+    set_property(&object, "stack", &stack);
+    let environments = js_sys::Array::new();
+    for bindings in &frame.environments {
+        let bindings_array = js_sys::Array::new();
+        for (name, value) in bindings {
+            let binding = js_sys::Object::new();
+            set_property(&binding, "name", &JsValue::from_str(name));
+            set_property(
+                &binding,
+                "value",
+                &JsValue::from_str(&format!("{:?}", value)),
+            );
+            bindings_array.push(&binding);
+        }
+        environments.push(&bindings_array);
+    }
+    set_property(&object, "environments", &environments);
+    object.into()
+}
 
 #[wasm_bindgen]
 pub struct JsSheet {
     sheet: Sheet,
     sheet_update_queue: Arc<Mutex<VecDeque<SheetAddress>>>,
     listener_map: HashMap<SheetAddress, (Vec<js_sys::Function>, CellSubscription)>,
+    /// Created on first use, and discarded when the workbook definitions change.
+    repl: Option<interpreter::Repl>,
 }
 
 #[wasm_bindgen]
@@ -44,11 +120,111 @@ impl JsSheetCellInfo {
         self.underlying.source.clone()
     }
 
+    /// Details of the error if evaluating the cell failed, otherwise `null`. The object
+    /// has the shape `{ kind, message, span: { start, end } | null, stackTrace: [{ function,
+    /// count }] }`, innermost frame first.
+    #[wasm_bindgen(getter)]
+    pub fn error(&self) -> JsValue {
+        match &self.underlying.value {
+            SheetCellComputedValue::Error(err) => error_to_js(err),
+            _ => JsValue::null(),
+        }
+    }
+
     fn from(underlying: SheetCellInfo) -> Self {
         Self { underlying }
     }
 }
 
+/// Steps through the evaluation of a formula. Cells are seen as they were when the
+/// session started.
+#[wasm_bindgen]
+pub struct JsDebugSession {
+    session: interpreter::DebugSession,
+}
+
+#[wasm_bindgen]
+impl JsDebugSession {
+    pub fn step_into(&mut self) {
+        self.session.step_into();
+    }
+
+    pub fn step_over(&mut self) {
+        self.session.step_over();
+    }
+
+    pub fn step_out(&mut self) {
+        self.session.step_out();
+    }
+
+    /// Run to the next breakpoint, or to the end.
+    pub fn resume(&mut self) {
+        self.session.resume();
+    }
+
+    /// Pause before the instruction at `index` (as numbered by `debug_disassemble`) in
+    /// `function`, or in the formula itself if `function` is omitted.
+    pub fn add_breakpoint(&mut self, function: Option<String>, index: u32) {
+        self.session
+            .add_breakpoint(interpreter::Breakpoint::Instruction {
+                function,
+                index: index as usize,
+            });
+    }
+
+    pub fn remove_breakpoint(&mut self, function: Option<String>, index: u32) -> bool {
+        self.session
+            .remove_breakpoint(&interpreter::Breakpoint::Instruction {
+                function,
+                index: index as usize,
+            })
+    }
+
+    /// Pause before evaluating the expression that starts at byte `offset` of the
+    /// formula (as in error spans). Returns whether there's such an expression.
+    pub fn add_breakpoint_at(&mut self, offset: u32) -> bool {
+        self.session
+            .add_breakpoint(interpreter::Breakpoint::Offset(offset as usize))
+    }
+
+    pub fn remove_breakpoint_at(&mut self, offset: u32) -> bool {
+        self.session
+            .remove_breakpoint(&interpreter::Breakpoint::Offset(offset as usize))
+    }
+
+    /// Either `{ finished: true, value, error }`, with one of `value` (a string) and
+    /// `error` (as in `JsSheetCellInfo.error`) set, or `{ finished: false, step,
+    /// frames }`, where `frames` is the call stack, outermost first. Each frame has the
+    /// shape `{ function, index, code: [string], stack: [string], environments:
+    /// [[{ name, value }]] }`, with `index` the next instruction in `code` and the
+    /// innermost environment first.
+    #[wasm_bindgen(getter)]
+    pub fn state(&self) -> JsValue {
+        let object = js_sys::Object::new();
+        match self.session.state() {
+            interpreter::DebugState::Finished(result) => {
+                set_property(&object, "finished", &JsValue::TRUE);
+                let (value, error) = match result {
+                    Ok(value) => (JsValue::from_str(&format!("{:?}", value)), JsValue::null()),
+                    Err(err) => (JsValue::null(), error_to_js(err)),
+                };
+                set_property(&object, "value", &value);
+                set_property(&object, "error", &error);
+            }
+            interpreter::DebugState::Paused(pause) => {
+                set_property(&object, "finished", &JsValue::FALSE);
+                set_property(&object, "step", &JsValue::from(pause.step as f64));
+                let frames = js_sys::Array::new();
+                for frame in &pause.frames {
+                    frames.push(&debug_frame_to_js(frame));
+                }
+                set_property(&object, "frames", &frames);
+            }
+        }
+        object.into()
+    }
+}
+
 #[wasm_bindgen]
 impl JsSheet {
     pub fn get_cell(&mut self, row: i32, col: i32) -> JsSheetCellInfo {
@@ -59,11 +235,48 @@ impl JsSheet {
         let result = self
             .sheet
             .set_cell(&SheetAddress { row, col }, contents.to_string())
-            .map_err(|err| JsValue::from_str(format!("{}", err).as_str()))?;
+            // Structured, so that the editor can point at the offending part of the formula.
+            .map_err(|err| error_to_js(&err))?;
         self.flush_update_queue();
         Ok(result)
     }
 
+    /// Reformat cell contents canonically, e.g. `=a1+sum( b1:b5 )` becomes
+    /// `=A1 + SUM(B1:B5)`. Long Lisp formulas are broken across lines.
+    pub fn format_formula(&self, contents: &str) -> Result<String, JsValue> {
+        printer::format_cell(contents).map_err(|err| error_to_js(&err))
+    }
+
+    pub fn copy_cell(
+        &mut self,
+        from_row: i32,
+        from_col: i32,
+        to_row: i32,
+        to_col: i32,
+    ) -> Result<(), JsValue> {
+        let result = self
+            .sheet
+            .copy_cell(
+                &SheetAddress {
+                    row: from_row,
+                    col: from_col,
+                },
+                &SheetAddress {
+                    row: to_row,
+                    col: to_col,
+                },
+            )
+            .map_err(|err| error_to_js(&err));
+        self.flush_update_queue();
+        result
+    }
+
+    pub fn insert_row(&mut self, row: i32) -> Result<(), JsValue> {
+        let result = self.sheet.insert_row(row).map_err(|err| error_to_js(&err));
+        self.flush_update_queue();
+        result
+    }
+
     pub fn add_listener(&mut self, row: i32, col: i32, func: js_sys::Function) {
         let address = SheetAddress { row, col };
 
@@ -138,10 +351,169 @@ impl JsSheet {
         .map_err(|err| JsValue::from_str(format!("{}", err).as_str()))
     }
 
+    pub fn set_workbook_definitions(&mut self, source: &str) -> Result<(), JsValue> {
+        let result = self
+            .sheet
+            .set_workbook_definitions(source.to_string())
+            .map_err(|err| error_to_js(&err));
+        self.repl = None;
+        self.flush_update_queue();
+        result
+    }
+
+    pub fn get_workbook_definitions(&self) -> String {
+        self.sheet.workbook_definitions_source().to_string()
+    }
+
+    /// Limit how much work a single cell's formula may do: `max_steps` instructions and
+    /// `max_call_depth` nested function calls. Formulas exceeding either show a timeout.
+    pub fn set_eval_limits(&mut self, max_steps: u32, max_call_depth: u32) -> Result<(), JsValue> {
+        let result = self
+            .sheet
+            .set_eval_limits(interpreter::EvalLimits {
+                max_steps: max_steps as u64,
+                max_call_depth: max_call_depth as usize,
+            })
+            .map_err(|err| error_to_js(&err));
+        self.flush_update_queue();
+        result
+    }
+
+    pub fn get_max_eval_steps(&self) -> f64 {
+        self.sheet.eval_limits().max_steps as f64
+    }
+
+    pub fn get_max_call_depth(&self) -> u32 {
+        self.sheet.eval_limits().max_call_depth as u32
+    }
+
+    /// Set how `round` breaks ties ("half-up" or "half-even"; also "up" or "down"),
+    /// recomputing every formula.
+    pub fn set_rounding_mode(&mut self, mode: &str) -> Result<(), JsValue> {
+        let result = interpreter::RoundingMode::from_name(mode)
+            .and_then(|mode| self.sheet.set_rounding_mode(mode))
+            .map_err(|err| error_to_js(&err));
+        self.flush_update_queue();
+        result
+    }
+
+    pub fn get_rounding_mode(&self) -> String {
+        self.sheet.rounding_mode().name().to_string()
+    }
+
+    /// Seed the random numbers drawn by `rand` and `randbetween`, recomputing every
+    /// formula. The same seed and edits always give the same numbers.
+    pub fn set_random_seed(&mut self, seed: u32) -> Result<(), JsValue> {
+        let result = self
+            .sheet
+            .set_random_seed(seed as u64)
+            .map_err(|err| error_to_js(&err));
+        self.flush_update_queue();
+        result
+    }
+
+    pub fn get_random_seed(&self) -> f64 {
+        self.sheet.random_seed() as f64
+    }
+
+    /// Recalculate the cells with volatile formulas (those using `rand`, say), and the
+    /// cells that depend on them.
+    pub fn recalculate_volatile(&mut self) {
+        self.sheet.recalculate_volatile();
+        self.flush_update_queue();
+    }
+
+    /// Evaluate one or more forms in a persistent environment (which can refer to cells),
+    /// returning the value of each on its own line.
+    pub fn repl_eval(&mut self, input: &str) -> Result<String, JsValue> {
+        || -> error::AppResult<String> {
+            if self.repl.is_none() {
+                self.repl = Some(interpreter::Repl::new(self.sheet.workbook_definitions()));
+            }
+            let repl = self.repl.as_mut().unwrap();
+            let values = repl.eval(input, &self.sheet, self.sheet.eval_limits())?;
+            Ok(values
+                .iter()
+                .map(|value| format!("{:?}", value))
+                .collect::<Vec<_>>()
+                .join("\n"))
+        }()
+        .map_err(|err| error_to_js(&err))
+    }
+
+    pub fn repl_reset(&mut self) {
+        self.repl = None;
+    }
+
+    pub fn debug_macroexpand(&self, input: &str) -> Result<String, JsValue> {
+        || -> error::AppResult<String> {
+            let expr = parser::parse(input)?;
+            let expanded =
+                interpreter::macroexpand_with_prelude(&expr, self.sheet.workbook_definitions())?;
+            Ok(expanded.to_string())
+        }()
+        .map_err(|err| error_to_js(&err))
+    }
+
+    /// Start recording the time each recalculated cell takes, discarding any earlier
+    /// recording.
+    pub fn start_profiling(&mut self) {
+        self.sheet.start_profiling();
+    }
+
+    pub fn stop_profiling(&mut self) {
+        self.sheet.stop_profiling();
+    }
+
+    /// A plain-text summary of the recording so far, or `undefined` if not profiling.
+    pub fn profile_report(&self) -> Option<String> {
+        self.sheet.profiler().map(|profiler| profiler.report())
+    }
+
+    /// The recording so far as Chrome trace event JSON (for `chrome://tracing` or
+    /// Perfetto), or `undefined` if not profiling.
+    pub fn profile_chrome_trace(&self) -> Option<String> {
+        self.sheet.profiler().map(|profiler| profiler.chrome_trace())
+    }
+
     pub fn debug_graphviz(&self) -> String {
         self.sheet.debug_graphviz()
     }
 
+    /// List the bytecode that `formula` (written as in a cell, e.g. `=A1 + 1`) compiles
+    /// to.
+    pub fn debug_disassemble(&self, formula: &str) -> Result<String, JsValue> {
+        self.sheet
+            .debug_disassemble(formula)
+            .map_err(|err| error_to_js(&err))
+    }
+
+    /// List the bytecode of the formula in the given cell.
+    pub fn debug_disassemble_cell(&self, row: i32, col: i32) -> Result<String, JsValue> {
+        self.sheet
+            .debug_disassemble_cell(&SheetAddress { row, col })
+            .map_err(|err| error_to_js(&err))
+    }
+
+    /// Start debugging `formula` (written as in a cell), paused before its first
+    /// instruction.
+    pub fn debug_start(&self, formula: &str) -> Result<JsDebugSession, JsValue> {
+        let session = self
+            .sheet
+            .debug_session(formula)
+            .map_err(|err| error_to_js(&err))?;
+        Ok(JsDebugSession { session })
+    }
+
+    /// Start debugging the formula in the given cell.
+    pub fn debug_start_cell(&self, row: i32, col: i32) -> Result<JsDebugSession, JsValue> {
+        let session = self
+            .sheet
+            .debug_session_for_cell(&SheetAddress { row, col })
+            .map_err(|err| error_to_js(&err))?;
+        Ok(JsDebugSession { session })
+    }
+
     pub fn debug_call_me(&self, func: &js_sys::Function) -> Result<(), JsValue> {
         let this = JsValue::null();
         func.call0(&this)?;
@@ -154,6 +526,7 @@ impl JsSheet {
             sheet: Sheet::new(),
             sheet_update_queue: Arc::new(Mutex::new(VecDeque::new())),
             listener_map: HashMap::new(),
+            repl: None,
         }
     }
 }
@@ -2,22 +2,26 @@
 
 use nom::{
     branch::alt,
-    bytes::complete::{escaped, tag},
-    character::complete::{anychar, char, multispace0, multispace1, none_of, one_of},
+    bytes::complete::tag,
+    character::complete::{anychar, char, multispace1, not_line_ending},
     character::is_alphanumeric,
     combinator::{cut, map, recognize, value, verify},
-    error::{context, VerboseError},
-    multi::{many1_count, separated_list0},
-    number::complete::float,
-    sequence::{delimited, preceded},
+    error::{context, VerboseError, VerboseErrorKind},
+    multi::{many0_count, many1_count, separated_list0},
+    number::complete::double,
+    sequence::{delimited, pair, preceded, terminated},
     IResult,
 };
 
-use crate::error::{AppError, AppResult};
+use std::cell::Cell;
+use std::fmt;
+
+use crate::error::{AppError, AppResult, ErrorKind, Span};
+use crate::infix::parse_infix_in;
 
 #[derive(Debug, PartialEq, Clone)]
-pub enum Expr {
-    Number(f32),
+pub enum ExprKind {
+    Number(f64),
     String(String),
     Symbol(String),
     Keyword(String),
@@ -25,17 +29,72 @@ pub enum Expr {
     List(Vec<Expr>),
 }
 
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    /// Byte range the expression was read from. `None` for code that was generated
+    /// rather than parsed (desugaring, macro expansion, ...).
+    pub span: Option<Span>,
+}
+
+/// Spans are ignored, so that the same code read from different places compares equal.
+impl PartialEq for Expr {
+    fn eq(&self, other: &Expr) -> bool {
+        self.kind == other.kind
+    }
+}
+
 impl Expr {
+    pub fn new(kind: ExprKind) -> Self {
+        Self { kind, span: None }
+    }
+
+    pub fn number(n: f64) -> Self {
+        Self::new(ExprKind::Number(n))
+    }
+
+    pub fn string(s: impl Into<String>) -> Self {
+        Self::new(ExprKind::String(s.into()))
+    }
+
+    pub fn symbol(sym: impl Into<String>) -> Self {
+        Self::new(ExprKind::Symbol(sym.into()))
+    }
+
+    pub fn keyword(kw: impl Into<String>) -> Self {
+        Self::new(ExprKind::Keyword(kw.into()))
+    }
+
+    pub fn boolean(b: bool) -> Self {
+        Self::new(ExprKind::Boolean(b))
+    }
+
+    pub fn list(exprs: Vec<Expr>) -> Self {
+        Self::new(ExprKind::List(exprs))
+    }
+
+    #[cfg(test)]
+    pub fn as_list(&self) -> Option<&[Expr]> {
+        match &self.kind {
+            ExprKind::List(exprs) => Some(exprs),
+            _ => None,
+        }
+    }
+
     pub fn from_string<S: ToString>(s: S) -> AppResult<Expr> {
         parse(&s.to_string())
     }
 }
 
 pub trait ExprVisitor {
-    fn visit_number(&mut self, _num: f32) {}
+    fn visit_number(&mut self, _num: f64) {}
     fn visit_string(&mut self, _s: &String) {}
     fn visit_symbol(&mut self, _sym: &String) {}
     fn visit_keyword(&mut self, _kw: &String) {}
+    /// Like `visit_keyword`, along with where the keyword was read from.
+    fn visit_keyword_at(&mut self, kw: &String, _span: Option<Span>) {
+        self.visit_keyword(kw)
+    }
     fn visit_boolean(&mut self, _b: bool) {}
 }
 
@@ -43,15 +102,54 @@ pub trait ExprRewriter {
     fn maybe_rewrite(&self, form: &Vec<Expr>) -> Option<Expr>;
 }
 
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExprKind::Number(n) => write!(f, "{}", n),
+            ExprKind::String(s) => write_escaped_string(f, s),
+            ExprKind::Symbol(sym) => write!(f, "{}", sym),
+            ExprKind::Keyword(kw) => write!(f, ":{}", kw),
+            ExprKind::Boolean(b) => write!(f, "{}", if *b { "#t" } else { "#f" }),
+            ExprKind::List(exprs) => {
+                write!(f, "(")?;
+                for (idx, expr) in exprs.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", expr)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Write `s` as a string literal that reads back as the same string.
+fn write_escaped_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '\\' => write!(f, "\\\\")?,
+            '"' => write!(f, "\\\"")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            '\r' => write!(f, "\\r")?,
+            c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
 impl Expr {
     pub fn walk<T: ExprVisitor>(&self, visitor: &mut T) {
-        match self {
-            Expr::Number(num) => visitor.visit_number(*num),
-            Expr::String(s) => visitor.visit_string(&s),
-            Expr::Symbol(sym) => visitor.visit_symbol(&sym),
-            Expr::Keyword(kw) => visitor.visit_keyword(&kw),
-            Expr::Boolean(b) => visitor.visit_boolean(*b),
-            Expr::List(exprs) => {
+        match &self.kind {
+            ExprKind::Number(num) => visitor.visit_number(*num),
+            ExprKind::String(s) => visitor.visit_string(&s),
+            ExprKind::Symbol(sym) => visitor.visit_symbol(&sym),
+            ExprKind::Keyword(kw) => visitor.visit_keyword_at(&kw, self.span),
+            ExprKind::Boolean(b) => visitor.visit_boolean(*b),
+            ExprKind::List(exprs) => {
                 for expr in exprs {
                     expr.walk(visitor);
                 }
@@ -60,31 +158,136 @@ impl Expr {
     }
 
     pub fn rewrite<T: ExprRewriter>(&self, rewriter: &T) -> Expr {
-        match self {
-            Expr::List(exprs) => match rewriter.maybe_rewrite(exprs) {
+        match &self.kind {
+            ExprKind::List(exprs) => match rewriter.maybe_rewrite(exprs) {
                 Some(new_form) => new_form,
-                None => Expr::List(exprs.iter().map(|expr| expr.rewrite(rewriter)).collect()),
+                None => Expr {
+                    kind: ExprKind::List(exprs.iter().map(|expr| expr.rewrite(rewriter)).collect()),
+                    span: self.span,
+                },
             },
             _ => self.clone(),
         }
     }
 }
 
+/// Maximum depth of nesting (lists, quotes, parentheses, operators, ...) in an
+/// expression. Parsing, compiling and optimizing all recurse on it, so deeper code could
+/// overflow the native stack.
+pub const MAX_NESTING_DEPTH: usize = 100;
+
+thread_local! {
+    /// Levels of nesting entered by the expression being parsed.
+    static NESTING_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A level of nesting in the expression being parsed, which it counts until dropped.
+pub(crate) struct Nesting(());
+
+impl Nesting {
+    /// Enter a level of nesting at `input`, failing there if that's one too many.
+    pub(crate) fn enter(input: &str) -> Result<Self, nom::Err<VerboseError<&str>>> {
+        NESTING_DEPTH.with(|depth| {
+            if depth.get() >= MAX_NESTING_DEPTH {
+                return Err(nom::Err::Failure(VerboseError {
+                    errors: vec![(
+                        input,
+                        VerboseErrorKind::Context("an expression that is less deeply nested"),
+                    )],
+                }));
+            }
+            depth.set(depth.get() + 1);
+            Ok(Nesting(()))
+        })
+    }
+}
+
+impl Drop for Nesting {
+    fn drop(&mut self) {
+        NESTING_DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
 pub type ParseResult<'a, T> = IResult<&'a str, T, VerboseError<&'a str>>;
-type ExprParseResult<'a> = ParseResult<'a, Expr>;
+pub(crate) type ExprParseResult<'a> = ParseResult<'a, Expr>;
 
 fn parse_number<'a>(input: &'a str) -> ExprParseResult<'a> {
-    map(float, |n| Expr::Number(n))(input)
+    map(double, Expr::number)(input)
+}
+
+pub(crate) fn parse_failure<'a, T>(input: &'a str, expected: &'static str) -> ParseResult<'a, T> {
+    Err(nom::Err::Failure(VerboseError {
+        errors: vec![(input, VerboseErrorKind::Context(expected))],
+    }))
+}
+
+/// The `{...}` part of a `\u{...}` escape: 1 to 6 hex digits naming a Unicode scalar value.
+fn parse_unicode_escape<'a>(input: &'a str) -> ParseResult<'a, char> {
+    const EXPECTED: &str = "`{`, 1 to 6 hex digits and `}` after `\\u`";
+    let digits = match input.strip_prefix('{') {
+        Some(digits) => digits,
+        None => return parse_failure(input, EXPECTED),
+    };
+    let len = digits.find('}').unwrap_or(digits.len());
+    let hex = &digits[..len];
+    if hex.is_empty() || hex.len() > 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return parse_failure(input, EXPECTED);
+    }
+    let rest = match digits[len..].strip_prefix('}') {
+        Some(rest) => rest,
+        None => return parse_failure(input, EXPECTED),
+    };
+    match std::char::from_u32(u32::from_str_radix(hex, 16).unwrap()) {
+        Some(c) => Ok((rest, c)),
+        None => parse_failure(input, "a Unicode scalar value in `\\u{...}`"),
+    }
 }
 
-fn parse_string<'a>(input: &'a str) -> ExprParseResult<'a> {
-    let esc = escaped(none_of("\\\""), '\\', one_of("\"n"));
-    let esc_or_empty = alt((esc, tag("")));
-    let (input, s) = delimited(tag("\""), esc_or_empty, tag("\""))(input)?;
+/// `"..."`, with the escapes `\\`, `\"`, `\n`, `\t`, `\r` and `\u{...}`.
+pub(crate) fn parse_string<'a>(input: &'a str) -> ExprParseResult<'a> {
+    let (mut rest, _) = char('"')(input)?;
+    let mut s = String::new();
+    loop {
+        let mut chars = rest.chars();
+        match chars.next() {
+            None => return parse_failure(rest, "closing `\"` to end string"),
+            Some('"') => return Ok((chars.as_str(), Expr::string(s))),
+            Some('\\') => {
+                let escape = chars.as_str();
+                let c = match chars.next() {
+                    Some('\\') => '\\',
+                    Some('"') => '"',
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('u') => {
+                        let (after, c) = parse_unicode_escape(chars.as_str())?;
+                        chars = after.chars();
+                        c
+                    }
+                    _ => return parse_failure(
+                        escape,
+                        "an escape sequence (`\\\\`, `\\\"`, `\\n`, `\\t`, `\\r` or `\\u{...}`)",
+                    ),
+                };
+                s.push(c);
+            }
+            Some(c) => s.push(c),
+        }
+        rest = chars.as_str();
+    }
+}
 
-    // Interpret escape sequences
-    let interpreted_s = s.replace("\\\"", "\"").replace("\\n", "\n");
-    Ok((input, Expr::String(interpreted_s)))
+/// `r"..."`, or `r#"..."#` with any number of `#`s so that the string can contain `"`.
+/// Nothing is escaped, which is handy for regexes and Windows paths.
+fn parse_raw_string<'a>(input: &'a str) -> ExprParseResult<'a> {
+    let (rest, hashes) = preceded(char('r'), recognize(many0_count(char('#'))))(input)?;
+    let (rest, _) = char('"')(rest)?;
+    let terminator = format!("\"{}", hashes);
+    match rest.find(&terminator) {
+        Some(idx) => Ok((&rest[idx + terminator.len()..], Expr::string(&rest[..idx]))),
+        None => parse_failure(rest, "closing quote to end raw string"),
+    }
 }
 
 fn parse_ident<'a>(input: &'a str) -> ParseResult<'a, &'a str> {
@@ -111,70 +314,338 @@ fn parse_ident<'a>(input: &'a str) -> ParseResult<'a, &'a str> {
 }
 
 fn parse_symbol<'a>(input: &'a str) -> ExprParseResult<'a> {
-    map(parse_ident, |i| Expr::Symbol(i.into()))(input)
+    map(parse_ident, Expr::symbol)(input)
 }
 
 fn parse_keyword<'a>(input: &'a str) -> ExprParseResult<'a> {
-    map(preceded(tag(":"), parse_ident), |i| Expr::Keyword(i.into()))(input)
+    map(preceded(tag(":"), parse_ident), Expr::keyword)(input)
 }
 
 fn parse_boolean<'a>(input: &'a str) -> ExprParseResult<'a> {
     alt((
-        value(Expr::Boolean(true), tag("#t")),
-        value(Expr::Boolean(false), tag("#f")),
+        value(Expr::boolean(true), tag("#t")),
+        value(Expr::boolean(false), tag("#f")),
     ))(input)
 }
 
+/// Span of the text consumed going from `input` to `rest`. While parsing, spans are
+/// measured backwards from the end of the source (nom only tells us how much input
+/// remains); `resolve_spans` converts them to ordinary offsets afterwards.
+pub(crate) fn consumed_span(input: &str, rest: &str) -> Span {
+    Span {
+        start: input.len(),
+        end: rest.len(),
+    }
+}
+
+pub(crate) fn resolve_spans(expr: &mut Expr, source_len: usize) {
+    if let Some(span) = &mut expr.span {
+        *span = Span {
+            start: source_len - span.start,
+            end: source_len - span.end,
+        };
+    }
+    if let ExprKind::List(exprs) = &mut expr.kind {
+        for expr in exprs {
+            resolve_spans(expr, source_len);
+        }
+    }
+}
+
+/// Record where the expression produced by `parser` came from.
+pub(crate) fn spanned<'a>(
+    mut parser: impl FnMut(&'a str) -> ExprParseResult<'a>,
+) -> impl FnMut(&'a str) -> ExprParseResult<'a> {
+    move |input| {
+        let (rest, mut expr) = parser(input)?;
+        expr.span = Some(consumed_span(input, rest));
+        Ok((rest, expr))
+    }
+}
+
+/// Reader shorthand: `<prefix>x` reads as `(<form> x)`, e.g. `'x` is `(quote x)`.
+fn quote_prefix<'a>(
+    prefix: &'static str,
+    form: &'static str,
+) -> impl FnMut(&'a str) -> ExprParseResult<'a> {
+    move |input| {
+        let (after_prefix, _) = tag(prefix)(input)?;
+        let (rest, expr) = parse_expr(after_prefix)?;
+        let form_symbol = Expr {
+            kind: ExprKind::Symbol(form.into()),
+            span: Some(consumed_span(input, after_prefix)),
+        };
+        Ok((rest, Expr::list(vec![form_symbol, expr])))
+    }
+}
+
+/// Reader shorthand for collection literals: `[a b]` reads as `(vector a b)` and
+/// `{k v}` as `(hash-map k v)`, so the elements are evaluated like any other arguments.
+fn collection_literal<'a>(
+    open: char,
+    close: char,
+    form: &'static str,
+    closing_context: &'static str,
+) -> impl FnMut(&'a str) -> ExprParseResult<'a> {
+    move |input| {
+        let (after_open, _) = char(open)(input)?;
+        let (rest, mut exprs) = terminated(
+            separated_list0(ws1, parse_expr),
+            context(closing_context, cut(preceded(ws, char(close)))),
+        )(after_open)?;
+        if form == "hash-map" && exprs.len() % 2 != 0 {
+            let at_close = &input[input.len() - rest.len() - close.len_utf8()..];
+            return parse_failure(at_close, "a value for every key in map literal");
+        }
+        exprs.insert(
+            0,
+            Expr {
+                kind: ExprKind::Symbol(form.into()),
+                span: Some(consumed_span(input, after_open)),
+            },
+        );
+        Ok((rest, Expr::list(exprs)))
+    }
+}
+
+/// `; ...` up to the end of the line.
+fn line_comment<'a>(input: &'a str) -> ParseResult<'a, &'a str> {
+    recognize(pair(char(';'), not_line_ending))(input)
+}
+
+/// `#| ... |#`. Block comments nest, so they can be used to comment out code that
+/// itself contains block comments.
+fn block_comment<'a>(input: &'a str) -> ParseResult<'a, &'a str> {
+    let (mut rest, _) = tag("#|")(input)?;
+    let mut depth = 1;
+    while depth > 0 {
+        if let Some(after) = rest.strip_prefix("|#") {
+            depth -= 1;
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("#|") {
+            depth += 1;
+            rest = after;
+        } else if let Some(c) = rest.chars().next() {
+            rest = &rest[c.len_utf8()..];
+        } else {
+            return Err(nom::Err::Failure(VerboseError {
+                errors: vec![(rest, VerboseErrorKind::Context("`|#` to end block comment"))],
+            }));
+        }
+    }
+    Ok((rest, &input[..input.len() - rest.len()]))
+}
+
+/// `#;` followed by an expression, which is skipped.
+fn datum_comment<'a>(input: &'a str) -> ParseResult<'a, &'a str> {
+    recognize(pair(
+        tag("#;"),
+        context("expression after `#;`", cut(parse_expr)),
+    ))(input)
+}
+
+fn whitespace_or_comment<'a>(input: &'a str) -> ParseResult<'a, &'a str> {
+    alt((multispace1, line_comment, block_comment, datum_comment))(input)
+}
+
+/// Optional whitespace and comments, which are allowed between any two tokens.
+fn ws<'a>(input: &'a str) -> ParseResult<'a, ()> {
+    value((), many0_count(whitespace_or_comment))(input)
+}
+
+/// Like `ws`, but requires at least some whitespace or a comment.
+fn ws1<'a>(input: &'a str) -> ParseResult<'a, ()> {
+    value((), many1_count(whitespace_or_comment))(input)
+}
+
 fn parse_expr<'a>(input: &'a str) -> ExprParseResult {
+    let (input, _) = ws(input)?;
+    let _nesting = Nesting::enter(input)?;
     let parse_list = map(
         delimited(
             char('('),
-            separated_list0(multispace1, parse_expr),
-            context("closing paren", cut(preceded(multispace0, char(')')))),
+            separated_list0(ws1, parse_expr),
+            context("closing paren", cut(preceded(ws, char(')')))),
         ),
-        |exprs| Expr::List(exprs),
+        Expr::list,
     );
 
-    let parse_quoted_expr = map(preceded(tag("'"), parse_expr), |expr| {
-        Expr::List(vec![Expr::Symbol("quote".into()), expr])
-    });
-
-    preceded(
-        multispace0,
-        alt((
-            parse_quoted_expr,
-            parse_number,
-            parse_string,
-            // Order matters: number+string must go above symbol parsing, since
-            // symbols can contain numbers or pretty much anything they want.
-            parse_symbol,
-            parse_keyword,
-            parse_boolean,
-            parse_list,
-        )),
-    )(input)
+    spanned(alt((
+        quote_prefix("'", "quote"),
+        quote_prefix("`", "quasiquote"),
+        // Order matters: `,@` must be tried before `,`.
+        quote_prefix(",@", "unquote-splicing"),
+        quote_prefix(",", "unquote"),
+        parse_number,
+        parse_raw_string,
+        parse_string,
+        // Order matters: number+string must go above symbol parsing, since
+        // symbols can contain numbers or pretty much anything they want.
+        parse_symbol,
+        parse_keyword,
+        parse_boolean,
+        parse_list,
+        collection_literal('[', ']', "vector", "closing `]`"),
+        collection_literal('{', '}', "hash-map", "closing `}`"),
+    )))(input)
+}
+
+/// 1-based line and column (in characters) of byte `offset` in `source`.
+pub fn line_and_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+/// The line of `source` containing `offset`, with a caret underneath pointing at it:
+///
+/// ```text
+/// (+ 1 2
+///       ^
+/// ```
+pub fn caret_snippet(source: &str, offset: usize) -> String {
+    let offset = offset.min(source.len());
+    let line_start = source[..offset].rfind('\n').map_or(0, |idx| idx + 1);
+    let line_end = source[offset..]
+        .find('\n')
+        .map_or(source.len(), |idx| offset + idx);
+    let (_, column) = line_and_column(source, offset);
+    format!(
+        "{}\n{}^",
+        &source[line_start..line_end],
+        " ".repeat(column - 1)
+    )
+}
+
+/// Turn nom's error (a stack of everything that was being attempted, innermost first)
+/// into a message saying what was expected where.
+pub(crate) fn describe_parse_error(source: &str, error: nom::Err<VerboseError<&str>>) -> AppError {
+    let errors = match error {
+        nom::Err::Error(verbose) | nom::Err::Failure(verbose) => verbose.errors,
+        nom::Err::Incomplete(_) => vec![],
+    };
+    let remaining = errors.first().map_or("", |(rest, _)| *rest);
+    let offset = source.len() - remaining.len();
+    // Prefer a descriptive context over the specific character nom was looking for.
+    let expected = errors
+        .iter()
+        .find_map(|(_, kind)| match kind {
+            VerboseErrorKind::Context(context) => Some(context.to_string()),
+            _ => None,
+        })
+        .or_else(|| {
+            errors.iter().find_map(|(_, kind)| match kind {
+                VerboseErrorKind::Char(c) => Some(format!("`{}`", c)),
+                _ => None,
+            })
+        });
+    let problem = match (expected, remaining.chars().next()) {
+        (Some(expected), _) => format!("expected {}", expected),
+        (None, None) => "unexpected end of input".to_string(),
+        (None, Some(c)) => format!("unexpected `{}`", c),
+    };
+    let end = offset + remaining.chars().next().map_or(0, char::len_utf8);
+    positioned_parse_error(source, Span { start: offset, end }, &problem)
+}
+
+pub(crate) fn positioned_parse_error(source: &str, span: Span, problem: &str) -> AppError {
+    let (line, column) = line_and_column(source, span.start);
+    AppError::with_kind(
+        ErrorKind::Parse,
+        format!(
+            "Parse error at line {}, column {}: {}\n{}",
+            line,
+            column,
+            problem,
+            caret_snippet(source, span.start)
+        ),
+    )
+    .with_span(span)
+}
+
+/// Skip whitespace and comments between forms.
+fn skip_whitespace<'a>(input: &'a str, source: &str) -> AppResult<&'a str> {
+    ws(input)
+        .map(|(rest, _)| rest)
+        .map_err(|err| describe_parse_error(source, err))
+}
+
+/// Parse one form at the start of `src`, which must be a suffix of `source`; spans (and
+/// error positions) are relative to `source`. Returns the remaining input.
+fn parse_form<'a>(src: &'a str, source: &str) -> AppResult<(&'a str, Expr)> {
+    let (rest, mut expr) = parse_expr(src).map_err(|err| describe_parse_error(source, err))?;
+    resolve_spans(&mut expr, source.len());
+    Ok((skip_whitespace(rest, source)?, expr))
 }
 
+/// Parse exactly one form from `src` (a suffix of `source`), rejecting anything after it.
+fn parse_in(src: &str, source: &str) -> AppResult<Expr> {
+    let (rest, expr) = parse_form(src, source)?;
+    if !rest.is_empty() {
+        let start = source.len() - rest.len();
+        let token_len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        return Err(positioned_parse_error(
+            source,
+            Span {
+                start,
+                end: start + token_len,
+            },
+            "expected end of input after expression",
+        ));
+    }
+    Ok(expr)
+}
+
+/// Parse a single expression. Trailing input other than whitespace is an error.
 pub fn parse(src: &str) -> AppResult<Expr> {
-    parse_expr(src)
-        .map_err(|e: nom::Err<VerboseError<&str>>| AppError::new(format!("{:#?}", e)))
-        .map(|(_, exp)| exp)
+    parse_in(src, src)
+}
+
+/// Parse a sequence of top-level forms, such as the prelude or a workbook script.
+pub fn parse_program(src: &str) -> AppResult<Vec<Expr>> {
+    let mut forms = Vec::new();
+    let mut rest = skip_whitespace(src, src)?;
+    while !rest.is_empty() {
+        let (next, expr) = parse_form(rest, src)?;
+        forms.push(expr);
+        rest = next;
+    }
+    Ok(forms)
+}
+
+/// Which syntax a formula was written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FormulaSyntax {
+    /// `=A1*2+SUM(B1:B5)`
+    Infix,
+    /// `#=(+ (* :a1 2) (sum :b1-b5))`
+    Lisp,
 }
 
 pub enum InterpretCellResult {
-    Number(f32),
+    Number(f64),
     Text(String),
-    Expr(Expr),
+    Expr(Expr, FormulaSyntax),
 }
 
+/// Cells starting with `=` hold an Excel-style formula, like `=A1*2+SUM(B1:B5)`; cells
+/// starting with `#=` hold a Lisp one, like `#=(+ (* :a1 2) (sum :b1-b5))`. Anything else
+/// is a number or text.
 pub fn interpret_cell(contents: &str) -> AppResult<InterpretCellResult> {
-    let mut char_iter = contents.chars();
-    if let Some('=') = char_iter.next() {
-        let rest_of_formula = char_iter.collect::<String>();
-        let expr = Expr::from_string(rest_of_formula)?;
-        Ok(InterpretCellResult::Expr(expr))
+    // Parse in the context of the whole cell so positions include the prefix.
+    if let Some(lisp) = contents.strip_prefix("#=") {
+        Ok(InterpretCellResult::Expr(
+            parse_in(lisp, contents)?,
+            FormulaSyntax::Lisp,
+        ))
+    } else if let Some(infix) = contents.strip_prefix('=') {
+        Ok(InterpretCellResult::Expr(
+            parse_infix_in(infix, contents)?,
+            FormulaSyntax::Infix,
+        ))
     } else {
-        Ok(match contents.parse::<f32>() {
+        Ok(match contents.parse::<f64>() {
             Ok(number) => InterpretCellResult::Number(number),
             Err(_) => InterpretCellResult::Text(String::from(contents)),
         })
@@ -187,33 +658,108 @@ mod tests {
 
     #[test]
     fn test_parse_int_number() {
-        assert_eq!(parse("42"), Ok(Expr::Number(42.0)));
+        assert_eq!(parse("42"), Ok(Expr::number(42.0)));
     }
 
     #[test]
     fn test_parse_float_number() {
-        assert_eq!(parse("1.234"), Ok(Expr::Number(1.234)));
+        assert_eq!(parse("1.234"), Ok(Expr::number(1.234)));
+    }
+
+    #[test]
+    fn test_parse_number_precision() {
+        // Integers beyond f32's 24-bit mantissa must survive parsing.
+        assert_eq!(parse("16777217"), Ok(Expr::number(16777217.0)));
+        assert_eq!(
+            parse("9007199254740991"),
+            Ok(Expr::number(9007199254740991.0))
+        );
+        assert_eq!(parse("1234567.89"), Ok(Expr::number(1234567.89)));
+    }
+
+    #[test]
+    fn test_interpret_cell_number_precision() {
+        match interpret_cell("16777217") {
+            Ok(InterpretCellResult::Number(n)) => assert_eq!(n.to_string(), "16777217"),
+            _ => panic!("Expected a number"),
+        }
+        match interpret_cell("98765.43") {
+            Ok(InterpretCellResult::Number(n)) => assert_eq!(n.to_string(), "98765.43"),
+            _ => panic!("Expected a number"),
+        }
     }
 
     #[test]
     fn test_parse_simple_string() {
-        assert_eq!(parse(r#""hello""#), Ok(Expr::String("hello".into())));
+        assert_eq!(parse(r#""hello""#), Ok(Expr::string("hello")));
     }
 
     #[test]
     fn test_parse_string_with_newline_escape() {
+        assert_eq!(parse(r#""hello\nworld""#), Ok(Expr::string("hello\nworld")));
+    }
+
+    #[test]
+    fn test_parse_string_with_quote_escape() {
+        assert_eq!(parse(r#""hello\"world""#), Ok(Expr::string("hello\"world")));
+    }
+
+    #[test]
+    fn test_parse_string_escapes() {
         assert_eq!(
-            parse(r#""hello\nworld""#),
-            Ok(Expr::String("hello\nworld".into()))
+            parse(r#""a\\b\tc\rd\\ne""#),
+            Ok(Expr::string("a\\b\tc\rd\\ne"))
         );
+        assert_eq!(
+            parse(r#""\u{41}\u{1F600}""#),
+            Ok(Expr::string("A\u{1F600}"))
+        );
+        assert_eq!(parse(r#""""#), Ok(Expr::string("")));
+
+        let err = parse(r#""a\qb""#).unwrap_err();
+        assert!(
+            err.message()
+                .contains("column 4: expected an escape sequence"),
+            "{}",
+            err
+        );
+        assert_eq!(err.span(), Some(Span { start: 3, end: 4 }));
+        assert!(parse(r#""\u{}""#).is_err());
+        assert!(parse(r#""\u{110000}""#).is_err());
+        assert!(parse(r#""\u{D800}""#).is_err());
+        assert!(parse(r#""\u41""#).is_err());
+        assert!(parse(r#""abc"#)
+            .unwrap_err()
+            .message()
+            .contains("expected closing `\"` to end string"));
     }
 
     #[test]
-    fn test_parse_string_with_quote_escape() {
+    fn test_parse_raw_string() {
+        assert_eq!(parse(r#"r"C:\path\n""#), Ok(Expr::string(r"C:\path\n")));
         assert_eq!(
-            parse(r#""hello\"world""#),
-            Ok(Expr::String("hello\"world".into()))
+            parse(r###"r#"say "hi""#"###),
+            Ok(Expr::string(r#"say "hi""#))
         );
+        assert_eq!(parse(r#"r"""#), Ok(Expr::string("")));
+        assert_eq!(parse("r"), Ok(Expr::symbol("r")));
+        assert_eq!(parse("rest"), Ok(Expr::symbol("rest")));
+        assert!(parse(r##"r#"abc""##).is_err());
+    }
+
+    #[test]
+    fn test_string_round_trip() {
+        for s in &[
+            "plain",
+            "a\"b\\c",
+            "line\nbreak\ttab\r",
+            "bell\u{7}",
+            "snow ☃",
+        ] {
+            let expr = Expr::string(*s);
+            assert_eq!(parse(&expr.to_string()), Ok(expr));
+        }
+        assert_eq!(Expr::string("a\u{7}\"").to_string(), r#""a\u{7}\"""#);
     }
 
     #[test]
@@ -228,28 +774,25 @@ mod tests {
 
     #[test]
     fn test_parse_symbol() {
-        assert_eq!(parse("foobar"), Ok(Expr::Symbol("foobar".into())));
+        assert_eq!(parse("foobar"), Ok(Expr::symbol("foobar")));
     }
 
     #[test]
     fn test_parse_keyword() {
-        assert_eq!(parse(":foo"), Ok(Expr::Keyword("foo".into())));
+        assert_eq!(parse(":foo"), Ok(Expr::keyword("foo")));
     }
 
     #[test]
     fn test_parse_list() {
         assert_eq!(
             parse("(1 2)"),
-            Ok(Expr::List(vec![Expr::Number(1.0), Expr::Number(2.0)]))
+            Ok(Expr::list(vec![Expr::number(1.0), Expr::number(2.0)]))
         );
         assert_eq!(
             parse("(hey (you :guy))"),
-            Ok(Expr::List(vec![
-                Expr::Symbol("hey".into()),
-                Expr::List(vec![
-                    Expr::Symbol("you".into()),
-                    Expr::Keyword("guy".into()),
-                ])
+            Ok(Expr::list(vec![
+                Expr::symbol("hey"),
+                Expr::list(vec![Expr::symbol("you"), Expr::keyword("guy"),])
             ]))
         );
     }
@@ -258,15 +801,39 @@ mod tests {
     fn test_parse_quotation() {
         assert_eq!(
             parse("'(1 2)"),
-            Ok(Expr::List(vec![
-                Expr::Symbol("quote".into()),
-                Expr::List(vec![Expr::Number(1.0), Expr::Number(2.0)])
+            Ok(Expr::list(vec![
+                Expr::symbol("quote"),
+                Expr::list(vec![Expr::number(1.0), Expr::number(2.0)])
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_parse_quasiquotation() {
+        assert_eq!(
+            parse("`(a ,b ,@c)"),
+            Ok(Expr::list(vec![
+                Expr::symbol("quasiquote"),
+                Expr::list(vec![
+                    Expr::symbol("a"),
+                    Expr::list(vec![Expr::symbol("unquote"), Expr::symbol("b")]),
+                    Expr::list(vec![Expr::symbol("unquote-splicing"), Expr::symbol("c")]),
+                ])
             ]))
         );
     }
 
+    #[test]
+    fn test_display_expr() {
+        let src = r#"(defun f (x) (g :a1 "s\"q" 1.5 #t '(y)))"#;
+        assert_eq!(
+            parse(src).unwrap().to_string(),
+            r#"(defun f (x) (g :a1 "s\"q" 1.5 #t (quote (y))))"#
+        );
+    }
+
     struct TestExprVisitor {
-        visited_numbers: Vec<f32>,
+        visited_numbers: Vec<f64>,
         visited_strings: Vec<String>,
         visited_symbols: Vec<String>,
         visited_keywords: Vec<String>,
@@ -284,7 +851,7 @@ mod tests {
     }
 
     impl ExprVisitor for TestExprVisitor {
-        fn visit_number(&mut self, num: f32) {
+        fn visit_number(&mut self, num: f64) {
             self.visited_numbers.push(num);
         }
 
@@ -303,13 +870,10 @@ mod tests {
 
     #[test]
     fn test_expr_walk() {
-        let expr = Expr::List(vec![
-            Expr::List(vec![Expr::Number(42.0), Expr::String("hello".into())]),
-            Expr::List(vec![
-                Expr::String("world".into()),
-                Expr::Symbol("baz".into()),
-            ]),
-            Expr::String("blah".into()),
+        let expr = Expr::list(vec![
+            Expr::list(vec![Expr::number(42.0), Expr::string("hello")]),
+            Expr::list(vec![Expr::string("world"), Expr::symbol("baz")]),
+            Expr::string("blah"),
         ]);
 
         let mut visitor = TestExprVisitor::new();
@@ -326,5 +890,182 @@ mod tests {
     fn test_parse_error() {
         let res = parse("(asdf");
         assert!(res.is_err());
+        let err = res.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Parse);
+        assert_eq!(err.span(), Some(Span { start: 5, end: 5 }));
+        assert_eq!(
+            err.message(),
+            "Parse error at line 1, column 6: expected closing paren\n(asdf\n     ^"
+        );
+
+        let err = parse("(foo\n  (bar ]))").unwrap_err();
+        assert_eq!(err.span(), Some(Span { start: 12, end: 13 }));
+        assert_eq!(
+            err.message(),
+            "Parse error at line 2, column 8: expected closing paren\n  (bar ]))\n       ^"
+        );
+
+        match interpret_cell("#=(+ 1") {
+            Err(err) => assert_eq!(err.span(), Some(Span { start: 6, end: 6 })),
+            _ => panic!("Expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_parse_nesting_limit() {
+        let nested = |depth: usize| format!("{}x{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse(&nested(MAX_NESTING_DEPTH - 1)).is_ok());
+        let err = parse(&nested(1_000)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Parse);
+        assert_eq!(
+            err.span(),
+            Some(Span {
+                start: MAX_NESTING_DEPTH,
+                end: MAX_NESTING_DEPTH + 1
+            })
+        );
+        assert!(
+            err.message()
+                .contains("expected an expression that is less deeply nested"),
+            "{}",
+            err
+        );
+        assert!(parse(&format!("{}x", "'".repeat(1_000))).is_err());
+        assert!(parse(&format!("{}x", "[".repeat(1_000))).is_err());
+        // The depth is counted afresh for every parse, including failed ones.
+        assert!(parse(&nested(MAX_NESTING_DEPTH - 1)).is_ok());
+    }
+
+    #[test]
+    fn test_parse_trailing_input() {
+        assert_eq!(parse(" (+ 1 2) \n"), parse("(+ 1 2)"));
+        let err = parse("(+ 1 2) oops").unwrap_err();
+        assert_eq!(err.span(), Some(Span { start: 8, end: 12 }));
+        assert_eq!(
+            err.message(),
+            "Parse error at line 1, column 9: expected end of input after expression\n\
+             (+ 1 2) oops\n        ^"
+        );
+        assert!(parse("1 2").is_err());
+        assert!(interpret_cell("=1 2").is_err());
+    }
+
+    #[test]
+    fn test_parse_program() {
+        let forms = parse_program("\n  (def x 1)\n(f x)  'y\n").unwrap();
+        assert_eq!(
+            forms,
+            vec![
+                parse("(def x 1)").unwrap(),
+                parse("(f x)").unwrap(),
+                parse("'y").unwrap(),
+            ]
+        );
+        assert_eq!(forms[1].span, Some(Span { start: 13, end: 18 }));
+        assert_eq!(parse_program("  \n").unwrap(), vec![]);
+
+        let err = parse_program("(f x)\n(g").unwrap_err();
+        assert_eq!(err.span(), Some(Span { start: 8, end: 8 }));
+    }
+
+    #[test]
+    fn test_comments() {
+        let expected = parse("(f 1 2)").unwrap();
+        assert_eq!(parse("(f 1 ; one\n 2) ; done").unwrap(), expected);
+        assert_eq!(parse("; leading\n(f 1 2)").unwrap(), expected);
+        assert_eq!(parse("(f #| one |# 1 #|two|#2)").unwrap(), expected);
+        assert_eq!(
+            parse("(f 1 #| outer #| inner |# still outer |# 2)").unwrap(),
+            expected
+        );
+        assert_eq!(parse("(f 1 #;(g x) 2 #; 3)").unwrap(), expected);
+        assert_eq!(parse("#;ignored (f 1 2)").unwrap(), expected);
+        // Comment markers inside strings are just text.
+        let expr = parse(r#"(f "a ; b #| c |#")"#).unwrap();
+        assert_eq!(
+            expr,
+            Expr::list(vec![Expr::symbol("f"), Expr::string("a ; b #| c |#")])
+        );
+        assert_eq!(parse(&expr.to_string()).unwrap(), expr);
+
+        // Spans still point at the source text.
+        let expr = parse("(f #| x |# 1)").unwrap();
+        assert_eq!(
+            expr.as_list().unwrap()[1].span,
+            Some(Span { start: 11, end: 12 })
+        );
+
+        assert_eq!(
+            parse_program("; header\n(a) #| block |# (b) ; trailing").unwrap(),
+            vec![parse("(a)").unwrap(), parse("(b)").unwrap()]
+        );
+
+        let err = parse("(f 1 #| unterminated").unwrap_err();
+        assert!(
+            err.message().contains("expected `|#` to end block comment"),
+            "{}",
+            err
+        );
+        assert!(parse("(f 1 #;)").is_err());
+    }
+
+    #[test]
+    fn test_collection_literals() {
+        assert_eq!(
+            parse("[1 (f x) [2]]").unwrap(),
+            parse("(vector 1 (f x) (vector 2))").unwrap()
+        );
+        assert_eq!(
+            parse("{\"a\" 1 :b [2]}").unwrap(),
+            parse("(hash-map \"a\" 1 :b (vector 2))").unwrap()
+        );
+        assert_eq!(parse("[]").unwrap(), parse("(vector)").unwrap());
+        assert_eq!(parse("'[a]").unwrap(), parse("(quote (vector a))").unwrap());
+
+        let expr = parse("(f [1])").unwrap();
+        let vector = &expr.as_list().unwrap()[1];
+        assert_eq!(vector.span, Some(Span { start: 3, end: 6 }));
+        assert_eq!(
+            vector.as_list().unwrap()[0].span,
+            Some(Span { start: 3, end: 4 })
+        );
+
+        let err = parse("{1 2 3}").unwrap_err();
+        assert!(
+            err.message()
+                .contains("column 7: expected a value for every key in map literal"),
+            "{}",
+            err
+        );
+        assert!(parse("[1 2")
+            .unwrap_err()
+            .message()
+            .contains("expected closing `]`"));
+    }
+
+    #[test]
+    fn test_parse_spans() {
+        let expr = parse("(f 'x\n  \"s\")").unwrap();
+        assert_eq!(expr.span, Some(Span { start: 0, end: 12 }));
+        let elems = expr.as_list().unwrap();
+        assert_eq!(elems[0].span, Some(Span { start: 1, end: 2 }));
+        // `'x` reads as `(quote x)`, spanning the whole of `'x`.
+        assert_eq!(elems[1].span, Some(Span { start: 3, end: 5 }));
+        let quoted = elems[1].as_list().unwrap();
+        assert_eq!(quoted[0].span, Some(Span { start: 3, end: 4 }));
+        assert_eq!(quoted[1].span, Some(Span { start: 4, end: 5 }));
+        assert_eq!(elems[2].span, Some(Span { start: 8, end: 11 }));
+        assert_eq!(line_and_column("(f 'x\n  \"s\")", 8), (2, 3));
+
+        match interpret_cell("#=(f :a1)") {
+            Ok(InterpretCellResult::Expr(expr, FormulaSyntax::Lisp)) => {
+                assert_eq!(expr.span, Some(Span { start: 2, end: 9 }));
+                assert_eq!(
+                    expr.as_list().unwrap()[1].span,
+                    Some(Span { start: 5, end: 8 })
+                );
+            }
+            _ => panic!("Expected an expression"),
+        }
     }
 }
@@ -0,0 +1,404 @@
+use crate::error::AppResult;
+use crate::infix::is_cell_reference;
+use crate::parser::{interpret_cell, Expr, ExprKind, FormulaSyntax, InterpretCellResult};
+
+/// Lines are broken so that, where possible, none is longer than this.
+pub const DEFAULT_WIDTH: usize = 80;
+
+/// Reader shorthands, which are printed in preference to the forms they stand for.
+const QUOTE_PREFIXES: &[(&str, &str)] = &[
+    ("quote", "'"),
+    ("quasiquote", "`"),
+    ("unquote-splicing", ",@"),
+    ("unquote", ","),
+];
+
+/// How many arguments of a special form stay on the first line when it is broken
+/// across lines, e.g. the name and parameters of a `defun`. The rest are indented as a
+/// body.
+fn special_form_header_len(head: &str) -> Option<usize> {
+    match head {
+        "defun" | "defmacro" => Some(2),
+        "lambda" | "let" | "let*" | "letrec" | "def" | "if" | "case" => Some(1),
+        "begin" | "cond" => Some(0),
+        _ => None,
+    }
+}
+
+fn quote_prefix(exprs: &[Expr]) -> Option<(&'static str, &Expr)> {
+    match exprs {
+        [Expr {
+            kind: ExprKind::Symbol(head),
+            ..
+        }, quoted] => QUOTE_PREFIXES
+            .iter()
+            .find(|(form, _)| form == head)
+            .map(|(_, prefix)| (*prefix, quoted)),
+        _ => None,
+    }
+}
+
+/// The brackets and elements of a `[...]` or `{...}` literal, which are printed in
+/// preference to the `vector` and `hash-map` calls they read as.
+fn collection_literal(exprs: &[Expr]) -> Option<(char, char, &[Expr])> {
+    match exprs.split_first() {
+        Some((
+            Expr {
+                kind: ExprKind::Symbol(head),
+                ..
+            },
+            elems,
+        )) => match head.as_str() {
+            "vector" => Some(('[', ']', elems)),
+            "hash-map" if elems.len() % 2 == 0 => Some(('{', '}', elems)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// `expr` on one line.
+fn print_flat(expr: &Expr) -> String {
+    match &expr.kind {
+        ExprKind::List(exprs) => match quote_prefix(exprs) {
+            Some((prefix, quoted)) => format!("{}{}", prefix, print_flat(quoted)),
+            None => match collection_literal(exprs) {
+                Some((open, close, elems)) => format!(
+                    "{}{}{}",
+                    open,
+                    elems.iter().map(print_flat).collect::<Vec<_>>().join(" "),
+                    close
+                ),
+                None => format!(
+                    "({})",
+                    exprs.iter().map(print_flat).collect::<Vec<_>>().join(" ")
+                ),
+            },
+        },
+        _ => expr.to_string(),
+    }
+}
+
+fn current_column(out: &str) -> usize {
+    out[out.rfind('\n').map_or(0, |idx| idx + 1)..]
+        .chars()
+        .count()
+}
+
+fn print_broken(expr: &Expr, width: usize, out: &mut String) {
+    let flat = print_flat(expr);
+    let column = current_column(out);
+    let exprs = match &expr.kind {
+        ExprKind::List(exprs) if column + flat.chars().count() > width && exprs.len() > 1 => exprs,
+        _ => {
+            out.push_str(&flat);
+            return;
+        }
+    };
+    if let Some((prefix, quoted)) = quote_prefix(exprs) {
+        out.push_str(prefix);
+        print_broken(quoted, width, out);
+        return;
+    }
+    // Literals put each element (or each key and value of a map) on its own line,
+    // aligned after the opening bracket.
+    if let Some((open, close, elems)) = collection_literal(exprs) {
+        let per_line = if open == '{' { 2 } else { 1 };
+        out.push(open);
+        for (idx, elem) in elems.iter().enumerate() {
+            if idx % per_line != 0 {
+                out.push(' ');
+            } else if idx > 0 {
+                out.push('\n');
+                out.push_str(&" ".repeat(column + 1));
+            }
+            print_broken(elem, width, out);
+        }
+        out.push(close);
+        return;
+    }
+
+    out.push('(');
+    let (header_len, indent) = match &exprs[0].kind {
+        ExprKind::Symbol(head) => match special_form_header_len(head) {
+            Some(header_len) => (header_len, column + 2),
+            // Calls put each argument on its own line, aligned with the first.
+            None => (1, column + head.chars().count() + 2),
+        },
+        // Data (such as `let` bindings) is aligned with the first element.
+        _ => (0, column + 1),
+    };
+    print_broken(&exprs[0], width, out);
+    for (idx, arg) in exprs[1..].iter().enumerate() {
+        if idx < header_len {
+            out.push(' ');
+        } else {
+            out.push('\n');
+            out.push_str(&" ".repeat(indent));
+        }
+        print_broken(arg, width, out);
+    }
+    out.push(')');
+}
+
+/// Canonical source text for `expr`, which parses back to an equal expression. Lists that
+/// don't fit in `width` columns (starting from `start_column`) are broken across lines.
+pub fn pretty_print(expr: &Expr, start_column: usize, width: usize) -> String {
+    let mut out = " ".repeat(start_column);
+    print_broken(expr, width, &mut out);
+    out.split_off(start_column)
+}
+
+/// Special forms whose arguments aren't all expressions, so that they can't be written
+/// as infix function calls.
+const BINDING_FORMS: &[&str] = &[
+    "lambda",
+    "let",
+    "letrec",
+    "def",
+    "defun",
+    "defmacro",
+    "quote",
+    "quasiquote",
+    "case",
+    "cond",
+];
+
+/// Binding strength of infix operators: comparisons bind loosest, unary minus tightest.
+const COMPARISON_PRECEDENCE: u8 = 1;
+const ADDITIVE_PRECEDENCE: u8 = 2;
+const MULTIPLICATIVE_PRECEDENCE: u8 = 3;
+const UNARY_PRECEDENCE: u8 = 4;
+const PRIMARY_PRECEDENCE: u8 = 5;
+
+fn binary_operator_precedence(operator: &str) -> Option<u8> {
+    match operator {
+        "=" | "<" | ">" | "<=" | ">=" => Some(COMPARISON_PRECEDENCE),
+        "+" | "-" => Some(ADDITIVE_PRECEDENCE),
+        "*" | "/" => Some(MULTIPLICATIVE_PRECEDENCE),
+        _ => None,
+    }
+}
+
+/// A name the infix parser reads back as the same (lowercase) symbol.
+fn is_infix_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '.')
+        && !is_cell_reference(name)
+        && name != "true"
+        && name != "false"
+}
+
+/// `:a1` is `A1` and `:a1-b5` is `A1:B5`.
+fn print_infix_reference(kw: &str) -> Option<String> {
+    let mut parts = kw.splitn(2, '-');
+    let start = parts.next()?;
+    if !is_cell_reference(start) {
+        return None;
+    }
+    match parts.next() {
+        None => Some(start.to_ascii_uppercase()),
+        Some(end) if is_cell_reference(end) => Some(format!(
+            "{}:{}",
+            start.to_ascii_uppercase(),
+            end.to_ascii_uppercase()
+        )),
+        _ => None,
+    }
+}
+
+/// `expr` in infix syntax with its precedence, or `None` if the infix syntax can't
+/// express it (e.g. a `lambda`).
+fn print_infix_prec(expr: &Expr) -> Option<(String, u8)> {
+    let primary = |s: String| Some((s, PRIMARY_PRECEDENCE));
+    match &expr.kind {
+        // The infix parser reads `-1` as `(- 1)`.
+        ExprKind::Number(n) if *n < 0.0 || !n.is_finite() => None,
+        ExprKind::Number(_) | ExprKind::String(_) => primary(expr.to_string()),
+        ExprKind::Boolean(b) => primary((if *b { "TRUE" } else { "FALSE" }).to_string()),
+        ExprKind::Keyword(kw) => primary(print_infix_reference(kw)?),
+        ExprKind::Symbol(sym) if is_infix_name(sym) => primary(sym.clone()),
+        ExprKind::Symbol(_) => None,
+        ExprKind::List(exprs) => {
+            let (head, args) = match exprs.split_first() {
+                Some((
+                    Expr {
+                        kind: ExprKind::Symbol(head),
+                        ..
+                    },
+                    args,
+                )) => (head.as_str(), args),
+                _ => return None,
+            };
+            match (head, args) {
+                (
+                    "not",
+                    [Expr {
+                        kind: ExprKind::List(inner),
+                        ..
+                    }],
+                ) if inner.len() == 3 && inner[0] == Expr::symbol("=") => {
+                    print_infix_binary("<>", COMPARISON_PRECEDENCE, &inner[1], &inner[2])
+                }
+                ("-", [operand]) => {
+                    let operand = print_infix_operand(operand, UNARY_PRECEDENCE)?;
+                    Some((format!("-{}", operand), UNARY_PRECEDENCE))
+                }
+                (operator, [lhs, rhs]) if binary_operator_precedence(operator).is_some() => {
+                    print_infix_binary(operator, binary_operator_precedence(operator)?, lhs, rhs)
+                }
+                (name, args) if is_infix_name(name) && !BINDING_FORMS.contains(&name) => {
+                    let args = args
+                        .iter()
+                        .map(|arg| print_infix_prec(arg).map(|(s, _)| s))
+                        .collect::<Option<Vec<_>>>()?;
+                    primary(format!(
+                        "{}({})",
+                        name.to_ascii_uppercase(),
+                        args.join(", ")
+                    ))
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+fn print_infix_operand(expr: &Expr, min_precedence: u8) -> Option<String> {
+    let (s, precedence) = print_infix_prec(expr)?;
+    Some(if precedence < min_precedence {
+        format!("({})", s)
+    } else {
+        s
+    })
+}
+
+/// Operators are left-associative, so only the right operand needs brackets at equal
+/// precedence: `(1 - 2) - 3` is `1 - 2 - 3`, but `1 - (2 - 3)` needs them.
+fn print_infix_binary(
+    operator: &str,
+    precedence: u8,
+    lhs: &Expr,
+    rhs: &Expr,
+) -> Option<(String, u8)> {
+    Some((
+        format!(
+            "{} {} {}",
+            print_infix_operand(lhs, precedence)?,
+            operator,
+            print_infix_operand(rhs, precedence + 1)?
+        ),
+        precedence,
+    ))
+}
+
+/// `expr` in the Excel-style syntax, e.g. `A1 * 2 + SUM(B1:B5)`, if it can be expressed
+/// in it.
+pub fn print_infix(expr: &Expr) -> Option<String> {
+    print_infix_prec(expr).map(|(s, _)| s)
+}
+
+/// Cell contents for a formula, in the given syntax where possible. Formulas that can't
+/// be written in infix syntax (say, after a rewrite) fall back to Lisp.
+pub fn format_formula(expr: &Expr, syntax: FormulaSyntax) -> String {
+    match (syntax, print_infix(expr)) {
+        (FormulaSyntax::Infix, Some(infix)) => format!("={}", infix),
+        _ => format!("#={}", pretty_print(expr, 2, DEFAULT_WIDTH)),
+    }
+}
+
+/// Reformat a cell's contents into canonical form. Anything other than a formula is
+/// returned unchanged.
+pub fn format_cell(contents: &str) -> AppResult<String> {
+    Ok(match interpret_cell(contents)? {
+        InterpretCellResult::Expr(expr, syntax) => format_formula(&expr, syntax),
+        _ => contents.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn assert_round_trips(src: &str, width: usize) -> String {
+        let expr = parse(src).unwrap();
+        let printed = pretty_print(&expr, 0, width);
+        assert_eq!(parse(&printed).unwrap(), expr, "{}", printed);
+        printed
+    }
+
+    #[test]
+    fn test_pretty_print() {
+        assert_eq!(
+            assert_round_trips("(f   1 \"a\\nb\" 'x `(a ,b ,@c) :k #t)", 80),
+            "(f 1 \"a\\nb\" 'x `(a ,b ,@c) :k #t)"
+        );
+        assert_eq!(
+            assert_round_trips("(defun fact (n) (if (= n 0) 1 (* n (fact (+ n -1)))))", 30),
+            "(defun fact (n)\n  (if (= n 0)\n    1\n    (* n (fact (+ n -1)))))"
+        );
+        assert_eq!(
+            assert_round_trips("(let ((alpha 1) (beta 2)) (+ alpha beta))", 20),
+            "(let ((alpha 1)\n      (beta 2))\n  (+ alpha beta))"
+        );
+        assert_eq!(
+            assert_round_trips("(some-function argument-one argument-two)", 30),
+            "(some-function argument-one\n               argument-two)"
+        );
+        assert_eq!(
+            assert_round_trips("(vector 1 (hash-map \"a\" [2 3]))", 80),
+            "[1 {\"a\" [2 3]}]"
+        );
+        assert_eq!(
+            assert_round_trips("{\"alpha\" 1 \"beta\" [10 20 30]}", 15),
+            "{\"alpha\" 1\n \"beta\" [10\n         20\n         30]}"
+        );
+        // Printing is idempotent.
+        let printed = assert_round_trips("(begin (def x 1) (def y 2) (list x y))", 15);
+        assert_eq!(pretty_print(&parse(&printed).unwrap(), 0, 15), printed);
+    }
+
+    #[test]
+    fn test_print_infix() {
+        for src in &[
+            "A1 * 2 + SUM(B1:B5)",
+            "1 - (2 - 3)",
+            "(1 + 2) * 3",
+            "-(A1 + 1) / 2",
+            "A1 <> B1",
+            "IF(A1 >= 10, \"big\", FALSE)",
+            "rate * NOW()",
+        ] {
+            let cell = format!("={}", src);
+            assert_eq!(format_cell(&cell).unwrap(), cell);
+        }
+        assert_eq!(format_cell("=a1+sum( b1:b5 )").unwrap(), "=A1 + SUM(B1:B5)");
+        assert_eq!(format_cell("=(1 - 2) - 3").unwrap(), "=1 - 2 - 3");
+        assert_eq!(print_infix(&parse("(lambda (x) x)").unwrap()), None);
+        assert_eq!(print_infix(&parse("(+ 1 2 3)").unwrap()), None);
+    }
+
+    #[test]
+    fn test_format_cell() {
+        assert_eq!(format_cell("#=(+  1   2)").unwrap(), "#=(+ 1 2)");
+        assert_eq!(format_cell("12.5").unwrap(), "12.5");
+        assert_eq!(format_cell("some text").unwrap(), "some text");
+        assert!(format_cell("#=(+ 1").is_err());
+        // Lisp formulas stay Lisp even if they could be written in infix.
+        assert_eq!(
+            format_formula(&parse("(+ :a1 1)").unwrap(), FormulaSyntax::Lisp),
+            "#=(+ :a1 1)"
+        );
+        assert_eq!(
+            format_formula(
+                &parse("(map (lambda (x) x) :a1-a3)").unwrap(),
+                FormulaSyntax::Infix
+            ),
+            "#=(map (lambda (x) x) :a1-a3)"
+        );
+    }
+}
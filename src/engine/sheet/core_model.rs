@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Eq, PartialEq, Hash, Clone, Debug)]
 pub struct SheetAddress {
     pub row: i32,
@@ -12,3 +14,26 @@ impl SheetAddress {
         }
     }
 }
+
+/// Spreadsheet notation, e.g. `A1` or `AB12`.
+impl fmt::Display for SheetAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.row < 0 || self.col < 0 {
+            return write!(f, "R{}C{}", self.row + 1, self.col + 1);
+        }
+        let mut column = Vec::new();
+        let mut n = self.col + 1;
+        while n > 0 {
+            n -= 1;
+            column.push((b'A' + (n % 26) as u8) as char);
+            n /= 26;
+        }
+        column.reverse();
+        write!(
+            f,
+            "{}{}",
+            column.into_iter().collect::<String>(),
+            self.row + 1
+        )
+    }
+}
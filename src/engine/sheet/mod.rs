@@ -1,6 +1,7 @@
 mod core_model;
+mod profiler;
 mod sheet;
 mod sheet_range;
 
 pub use core_model::SheetAddress;
-pub use sheet::{CellSubscription, Sheet, SheetCellInfo};
+pub use sheet::{CellSubscription, Sheet, SheetCellComputedValue, SheetCellInfo};
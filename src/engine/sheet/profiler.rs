@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use super::SheetAddress;
+
+/// Milliseconds since some fixed point, as precisely as the platform allows.
+#[cfg(target_arch = "wasm32")]
+fn now_ms() -> f64 {
+    use wasm_bindgen::JsCast;
+    // `performance.now()` where available (browsers, recent Node), which unlike
+    // `Date.now()` has sub-millisecond resolution.
+    let performance = js_sys::Reflect::get(&js_sys::global(), &"performance".into());
+    if let Ok(performance) = performance {
+        if let Ok(now) = js_sys::Reflect::get(&performance, &"now".into()) {
+            if let Ok(now) = now.dyn_into::<js_sys::Function>() {
+                if let Some(ms) = now.call0(&performance).ok().and_then(|ms| ms.as_f64()) {
+                    return ms;
+                }
+            }
+        }
+    }
+    js_sys::Date::now()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn now_ms() -> f64 {
+    lazy_static! {
+        static ref EPOCH: std::time::Instant = std::time::Instant::now();
+    }
+    EPOCH.elapsed().as_secs_f64() * 1000.0
+}
+
+/// The evaluation of one formula during a recalculation.
+#[derive(Clone, Debug)]
+pub struct CellProfile {
+    pub address: SheetAddress,
+    /// The cell whose new value caused this one to be recalculated, or `None` for the
+    /// edited cell itself and volatile cells.
+    pub triggered_by: Option<SheetAddress>,
+    /// Instructions executed.
+    pub steps: u64,
+    /// Cells read through references (a range counts all of its cells).
+    pub cells_read: usize,
+    /// Milliseconds since profiling started.
+    pub start_ms: f64,
+    pub duration_ms: f64,
+    /// Whether evaluation failed (including timing out).
+    pub failed: bool,
+}
+
+/// Everything recalculated because of one change to a cell, or to refresh the volatile
+/// cells.
+#[derive(Clone, Debug)]
+pub struct Recalculation {
+    /// `None` when refreshing the volatile cells.
+    pub edited: Option<SheetAddress>,
+    /// Milliseconds since profiling started.
+    pub start_ms: f64,
+    pub duration_ms: f64,
+    /// In the order they were recalculated.
+    pub cells: Vec<CellProfile>,
+}
+
+impl Recalculation {
+    fn description(&self) -> String {
+        match &self.edited {
+            Some(address) => format!("Edit of {}", address),
+            None => "Volatile recalculation".to_string(),
+        }
+    }
+
+    /// The chain of cells through which the edit reached `cell`, starting with the
+    /// edited cell (or a volatile one).
+    pub fn chain(&self, cell: &CellProfile) -> Vec<SheetAddress> {
+        let triggers: HashMap<&SheetAddress, &SheetAddress> = self
+            .cells
+            .iter()
+            .filter_map(|cell| Some((&cell.address, cell.triggered_by.as_ref()?)))
+            .collect();
+        let mut chain = vec![cell.address.clone()];
+        let mut current = cell.triggered_by.as_ref();
+        // Each cell is recalculated once per edit, so the chain can't loop, but a
+        // bound is cheap.
+        while let Some(address) = current {
+            if chain.len() > self.cells.len() {
+                break;
+            }
+            chain.push(address.clone());
+            current = triggers.get(address).cloned();
+        }
+        chain.reverse();
+        chain
+    }
+}
+
+/// Records how long recalculations take, and where the time goes.
+pub struct Profiler {
+    origin_ms: f64,
+    recalculations: Vec<Recalculation>,
+}
+
+fn format_chain(chain: &[SheetAddress]) -> String {
+    chain
+        .iter()
+        .map(|address| address.to_string())
+        .collect::<Vec<_>>()
+        .join(" > ")
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(result, "\\u{:04x}", c as u32).unwrap(),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            origin_ms: now_ms(),
+            recalculations: Vec::new(),
+        }
+    }
+
+    /// Milliseconds since profiling started.
+    pub(super) fn now(&self) -> f64 {
+        now_ms() - self.origin_ms
+    }
+
+    pub(super) fn record(&mut self, recalculation: Recalculation) {
+        self.recalculations.push(recalculation);
+    }
+
+    pub fn recalculations(&self) -> &[Recalculation] {
+        &self.recalculations
+    }
+
+    /// A plain-text summary: each recalculation with its cells, then the cells that
+    /// took longest overall.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let mut totals: HashMap<&SheetAddress, (usize, f64, u64)> = HashMap::new();
+        for recalculation in self.recalculations() {
+            writeln!(
+                report,
+                "{}: {} formulas recalculated in {:.3} ms",
+                recalculation.description(),
+                recalculation.cells.len(),
+                recalculation.duration_ms
+            )
+            .unwrap();
+            if recalculation.cells.is_empty() {
+                continue;
+            }
+            writeln!(
+                report,
+                "  {:<8} {:>10} {:>10} {:>10}  chain",
+                "cell", "ms", "steps", "cells read"
+            )
+            .unwrap();
+            for cell in &recalculation.cells {
+                writeln!(
+                    report,
+                    "  {:<8} {:>10.3} {:>10} {:>10}  {}{}",
+                    cell.address.to_string(),
+                    cell.duration_ms,
+                    cell.steps,
+                    cell.cells_read,
+                    format_chain(&recalculation.chain(cell)),
+                    if cell.failed { " (failed)" } else { "" }
+                )
+                .unwrap();
+                let total = totals.entry(&cell.address).or_insert((0, 0.0, 0));
+                total.0 += 1;
+                total.1 += cell.duration_ms;
+                total.2 += cell.steps;
+            }
+        }
+
+        let mut totals: Vec<_> = totals.into_iter().collect();
+        totals.sort_by(|(a, (_, a_ms, _)), (b, (_, b_ms, _))| {
+            b_ms.total_cmp(a_ms)
+                .then_with(|| (a.row, a.col).cmp(&(b.row, b.col)))
+        });
+        if !totals.is_empty() {
+            writeln!(report, "Slowest cells:").unwrap();
+            writeln!(
+                report,
+                "  {:<8} {:>10} {:>10} {:>10}",
+                "cell", "total ms", "steps", "evaluated"
+            )
+            .unwrap();
+            for (address, (count, ms, steps)) in totals.iter().take(10) {
+                writeln!(
+                    report,
+                    "  {:<8} {:>10.3} {:>10} {:>10}",
+                    address.to_string(),
+                    ms,
+                    steps,
+                    count
+                )
+                .unwrap();
+            }
+        }
+        report
+    }
+
+    /// The recordings in Chrome's trace event format, for viewing in `chrome://tracing`
+    /// or Perfetto. Each recalculation is an event containing one per cell.
+    pub fn chrome_trace(&self) -> String {
+        let mut events = Vec::new();
+        // Trace timestamps are in microseconds.
+        let event = |name: &str, start_ms: f64, duration_ms: f64, args: String| {
+            format!(
+                "{{\"name\":{},\"cat\":\"recalc\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\
+                 \"pid\":1,\"tid\":1,\"args\":{{{}}}}}",
+                json_string(name),
+                start_ms * 1000.0,
+                duration_ms * 1000.0,
+                args
+            )
+        };
+        for recalculation in self.recalculations() {
+            let name = match &recalculation.edited {
+                Some(address) => format!("edit {}", address),
+                None => "volatile".to_string(),
+            };
+            events.push(event(
+                &name,
+                recalculation.start_ms,
+                recalculation.duration_ms,
+                format!("\"formulas\":{}", recalculation.cells.len()),
+            ));
+            for cell in &recalculation.cells {
+                events.push(event(
+                    &cell.address.to_string(),
+                    cell.start_ms,
+                    cell.duration_ms,
+                    format!(
+                        "\"steps\":{},\"cellsRead\":{},\"failed\":{},\"chain\":{}",
+                        cell.steps,
+                        cell.cells_read,
+                        cell.failed,
+                        json_string(&format_chain(&recalculation.chain(cell)))
+                    ),
+                ));
+            }
+        }
+        format!("{{\"traceEvents\":[{}]}}", events.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(row: i32, col: i32) -> SheetAddress {
+        SheetAddress { row, col }
+    }
+
+    fn cell(address: SheetAddress, triggered_by: Option<SheetAddress>, ms: f64) -> CellProfile {
+        CellProfile {
+            address,
+            triggered_by,
+            steps: 10,
+            cells_read: 1,
+            start_ms: 1.0,
+            duration_ms: ms,
+            failed: false,
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        assert_eq!(address(0, 0).to_string(), "A1");
+        assert_eq!(address(9, 27).to_string(), "AB10");
+
+        let mut profiler = Profiler::new();
+        profiler.record(Recalculation {
+            edited: Some(address(0, 0)),
+            start_ms: 0.5,
+            duration_ms: 3.0,
+            cells: vec![
+                cell(address(0, 1), Some(address(0, 0)), 0.5),
+                cell(address(0, 2), Some(address(0, 1)), 2.0),
+            ],
+        });
+        let recalculation = &profiler.recalculations()[0];
+        assert_eq!(
+            recalculation.chain(&recalculation.cells[1]),
+            vec![address(0, 0), address(0, 1), address(0, 2)]
+        );
+
+        let report = profiler.report();
+        assert!(report.starts_with("Edit of A1: 2 formulas recalculated in 3.000 ms\n"));
+        assert!(report.contains("A1 > B1 > C1"), "{}", report);
+        // C1 is the slowest.
+        let slowest = report.split("Slowest cells:\n").nth(1).unwrap();
+        assert!(slowest
+            .lines()
+            .nth(1)
+            .unwrap()
+            .trim_start()
+            .starts_with("C1"));
+
+        let trace = profiler.chrome_trace();
+        assert!(trace.starts_with("{\"traceEvents\":[{\"name\":\"edit A1\""));
+        assert!(trace.contains("\"ts\":1000.000,\"dur\":2000.000"));
+        assert!(trace.contains("\"chain\":\"A1 > B1 > C1\""));
+    }
+}
@@ -1,14 +1,19 @@
 use signals2::*;
+use std::cell::Cell;
 use std::collections::{hash_map::Entry, HashMap, HashSet, VecDeque};
 use std::fmt;
 
 use crate::console_log::*;
 use crate::dep_graph;
 use crate::dep_graph::DepGraph;
-use crate::error::{AppError, AppResult};
+use crate::error::{AppError, AppResult, ErrorKind, Span};
 use crate::interpreter;
-use crate::parser::{interpret_cell, Expr, ExprVisitor, InterpretCellResult};
+use crate::parser::{
+    interpret_cell, parse_program, Expr, ExprKind, ExprVisitor, InterpretCellResult,
+};
+use crate::printer;
 
+use super::profiler::{CellProfile, Profiler, Recalculation};
 use super::sheet_range::{SheetRange, SheetRangeShapedAddresses};
 use super::SheetAddress;
 
@@ -16,6 +21,9 @@ pub struct SheetFormula {
     address: SheetAddress,
     program: interpreter::Program,
     references: Vec<SheetAddress>,
+    /// Whether the formula calls a volatile built-in such as `rand`, and so has to be
+    /// recalculated on every edit rather than just when the cells it refers to change.
+    volatile: bool,
 }
 
 impl dep_graph::Node<SheetAddress> for SheetFormula {
@@ -32,31 +40,38 @@ impl dep_graph::Node<SheetAddress> for SheetFormula {
 
 #[derive(Clone, Debug)]
 pub enum SheetCellComputedValue {
-    Number(f32),
+    Number(f64),
+    Decimal(interpreter::Decimal),
     Text(String),
-    Invalid { message: String },
+    /// The formula failed to evaluate (or was stopped for exceeding the sheet's
+    /// evaluation limits).
+    Error(AppError),
 }
 
 impl SheetCellComputedValue {
     pub fn from_interpreter_value(ivalue: interpreter::Value) -> Self {
         match ivalue {
             interpreter::Value::Number(n) => SheetCellComputedValue::Number(n),
+            interpreter::Value::Decimal(d) => SheetCellComputedValue::Decimal(d),
             interpreter::Value::String(s) => SheetCellComputedValue::Text(s),
             interpreter::Value::Boolean(b) => {
                 SheetCellComputedValue::Text((if b { "TRUE" } else { "FALSE" }).into())
             }
             interpreter::Value::Nil => SheetCellComputedValue::Text("<nil>".into()),
-            _ => SheetCellComputedValue::Invalid {
-                message: format!("Expression is not representable in a cell: {:?}", ivalue),
-            },
+            interpreter::Value::Error(err) => SheetCellComputedValue::Error(err),
+            _ => SheetCellComputedValue::Error(AppError::with_kind(
+                ErrorKind::Type,
+                format!("Expression is not representable in a cell: {:?}", ivalue),
+            )),
         }
     }
 
     pub fn to_interpreter_value(&self) -> interpreter::Value {
         match self {
             SheetCellComputedValue::Number(n) => interpreter::Value::Number(*n),
+            SheetCellComputedValue::Decimal(d) => interpreter::Value::Decimal(*d),
             SheetCellComputedValue::Text(s) => interpreter::Value::String(s.into()),
-            SheetCellComputedValue::Invalid { .. } => interpreter::Value::Nil,
+            SheetCellComputedValue::Error(_) => interpreter::Value::Nil,
         }
     }
 
@@ -65,12 +80,36 @@ impl SheetCellComputedValue {
     }
 }
 
+/// Largest magnitude below which every integer is exactly representable as an f64.
+const MAX_EXACT_INTEGER: f64 = 9007199254740992.0;
+
+/// Format a number for display in a cell. Integers are printed exactly; anything else
+/// is rounded to 15 significant digits (like other spreadsheets do) so that binary
+/// floating point noise such as `123456788.99999999` doesn't leak into the sheet.
+fn format_number(n: f64) -> String {
+    if !n.is_finite() {
+        n.to_string()
+    } else if n.fract() == 0.0 && n.abs() < MAX_EXACT_INTEGER {
+        (n as i64).to_string()
+    } else {
+        let rounded: f64 = format!("{:.14e}", n).parse().unwrap_or(n);
+        rounded.to_string()
+    }
+}
+
 impl fmt::Display for SheetCellComputedValue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Number(n) => write!(f, "{}", n),
+            Self::Number(n) => write!(f, "{}", format_number(*n)),
+            Self::Decimal(d) => write!(f, "{}", d),
             Self::Text(s) => write!(f, "{}", s),
-            Self::Invalid { message } => write!(f, "!INVALID: {}", message),
+            Self::Error(err) if err.kind() == ErrorKind::Timeout => {
+                write!(f, "!TIMEOUT: {:#}", err)
+            }
+            Self::Error(err) if err.kind() == ErrorKind::StackOverflow => {
+                write!(f, "!OVERFLOW: {:#}", err)
+            }
+            Self::Error(err) => write!(f, "!INVALID: {:#}", err),
         }
     }
 }
@@ -98,6 +137,21 @@ pub struct Sheet {
     // Not stored in SheetCell itself so that clients can subscribe to cells
     // which haven't been created yet.
     signals: HashMap<SheetAddress, Signal<()>>,
+    /// Workbook-level definitions (functions, macros, ...) made available to every
+    /// formula after the prelude.
+    definitions: interpreter::Definitions,
+    definitions_source: String,
+    eval_limits: interpreter::EvalLimits,
+    /// Seeds the random numbers of every formula, so that recalculating the same
+    /// edits gives the same results.
+    random_seed: u64,
+    /// Recalculations so far, which together with the seed and a cell's address
+    /// determine the random numbers its formula gets.
+    recalculation_count: u64,
+    /// How `round` breaks ties, e.g. half-even for banker's rounding.
+    rounding_mode: interpreter::RoundingMode,
+    /// Set while profiling recalculations.
+    profiler: Option<Profiler>,
 }
 
 pub struct CellSubscription {
@@ -112,40 +166,83 @@ impl Sheet {
             .map(|cell| cell.to_interpreter_value())
             .unwrap_or(interpreter::Value::Nil)
     }
-}
 
-impl interpreter::KeywordResolver for Sheet {
-    fn resolve_keyword(&self, kw: &str) -> AppResult<interpreter::Value> {
-        let range = SheetRange::parse(kw)?;
-
-        Ok(match range.addresses_shaped() {
+    fn resolve_range(&self, range: &SheetRange) -> interpreter::Value {
+        match range.addresses_shaped() {
             SheetRangeShapedAddresses::Single { address } => self.resolve_address(&address),
-            SheetRangeShapedAddresses::Addresses1D(iter) => interpreter::Value::List(
-                iter.map(|address| self.resolve_address(&address))
-                    .collect::<Vec<_>>(),
+            // Ranges are vectors so that formulas can index into them cheaply: a row or
+            // column is a vector of values, a block is a vector of rows.
+            SheetRangeShapedAddresses::Addresses1D(iter) => interpreter::Value::Vector(
+                iter.map(|address| self.resolve_address(&address)).collect(),
             ),
-            SheetRangeShapedAddresses::Addresses2D(iter) => interpreter::Value::List(
+            SheetRangeShapedAddresses::Addresses2D(iter) => interpreter::Value::Vector(
                 iter.map(|row| {
-                    interpreter::Value::List(
-                        row.map(|address| self.resolve_address(&address))
-                            .collect::<Vec<_>>(),
+                    interpreter::Value::Vector(
+                        row.map(|address| self.resolve_address(&address)).collect(),
                     )
                 })
-                .collect::<Vec<_>>(),
+                .collect(),
             ),
-        })
+        }
+    }
+}
+
+impl interpreter::KeywordResolver for Sheet {
+    fn resolve_keyword(&self, kw: &str) -> AppResult<interpreter::Value> {
+        Ok(self.resolve_range(&SheetRange::parse(kw)?))
+    }
+
+    fn random_seed(&self) -> u64 {
+        self.random_seed
+    }
+
+    fn rounding_mode(&self) -> interpreter::RoundingMode {
+        self.rounding_mode
+    }
+}
+
+/// Resolves keywords against a sheet, counting the cells read, for recalculating one
+/// cell.
+struct CountingResolver<'s> {
+    sheet: &'s Sheet,
+    cells_read: Cell<usize>,
+    random_seed: u64,
+}
+
+impl<'s> interpreter::KeywordResolver for CountingResolver<'s> {
+    fn resolve_keyword(&self, kw: &str) -> AppResult<interpreter::Value> {
+        let range = SheetRange::parse(kw)?;
+        self.cells_read
+            .set(self.cells_read.get() + range.addresses_flat().count());
+        Ok(self.sheet.resolve_range(&range))
+    }
+
+    fn random_seed(&self) -> u64 {
+        self.random_seed
+    }
+
+    fn rounding_mode(&self) -> interpreter::RoundingMode {
+        self.sheet.rounding_mode
     }
 }
 
 struct ExprReferencesVisitor {
     references: Vec<SheetAddress>,
-    errors: Vec<AppError>,
+    /// The first keyword that isn't a valid reference.
+    error: Option<AppError>,
 }
 
 impl ExprVisitor for ExprReferencesVisitor {
-    fn visit_keyword(&mut self, kw: &String) {
+    fn visit_keyword_at(&mut self, kw: &String, span: Option<Span>) {
         match SheetRange::parse(kw) {
-            Err(error) => self.errors.push(error),
+            Err(err) if self.error.is_none() => {
+                // Point at the reference in the formula, rather than within it.
+                self.error = Some(match span {
+                    Some(span) => err.with_span(span),
+                    None => err,
+                });
+            }
+            Err(_) => {}
             Ok(range) => self.references.extend(range.addresses_flat()),
         }
     }
@@ -154,35 +251,80 @@ impl ExprVisitor for ExprReferencesVisitor {
 fn get_references_for_expr(expr: &Expr) -> AppResult<Vec<SheetAddress>> {
     let mut visitor = ExprReferencesVisitor {
         references: Vec::new(),
-        errors: Vec::new(),
+        error: None,
     };
     expr.walk(&mut visitor);
-    if visitor.errors.len() > 0 {
-        // TODO: Include actual error messages
-        Err(AppError::new("One or more ranges failed to parse"))
-    } else {
-        Ok(visitor.references)
+    match visitor.error {
+        Some(err) => Err(err),
+        None => Ok(visitor.references),
     }
 }
 
+/// Move every cell reference in `expr` with `shift`. Ranges move both corners, so a
+/// range spanning an inserted row grows to include it.
+fn shift_references(expr: &Expr, shift: &dyn Fn(&SheetAddress) -> SheetAddress) -> AppResult<Expr> {
+    let kind = match &expr.kind {
+        ExprKind::Keyword(kw) => match SheetRange::parse(kw) {
+            Ok(range) => ExprKind::Keyword(
+                SheetRange {
+                    start: shift(&range.start),
+                    end: shift(&range.end),
+                }
+                .to_keyword()?,
+            ),
+            // Not a reference; `get_references_for_expr` reports these.
+            Err(_) => expr.kind.clone(),
+        },
+        ExprKind::List(exprs) => ExprKind::List(
+            exprs
+                .iter()
+                .map(|expr| shift_references(expr, shift))
+                .collect::<AppResult<Vec<_>>>()?,
+        ),
+        _ => expr.kind.clone(),
+    };
+    Ok(Expr {
+        kind,
+        span: expr.span,
+    })
+}
+
+/// `contents` with its references moved by `shift`. Formulas that change are
+/// re-serialised in canonical form; everything else is returned as is.
+fn shift_cell_contents(
+    contents: &str,
+    shift: &dyn Fn(&SheetAddress) -> SheetAddress,
+) -> AppResult<String> {
+    if let InterpretCellResult::Expr(expr, syntax) = interpret_cell(contents)? {
+        let shifted = shift_references(&expr, shift)?;
+        if shifted != expr {
+            return Ok(printer::format_formula(&shifted, syntax));
+        }
+    }
+    Ok(contents.to_string())
+}
+
 const MAX_ITERS: usize = 10_000;
 
 impl Sheet {
-    pub fn set_cell(&mut self, address: &SheetAddress, contents: String) -> AppResult<()> {
+    /// Parse (and compile, against `definitions`) `contents` as the cell at `address`,
+    /// without storing it. A formula's value is pending until it's recalculated.
+    fn build_cell(
+        address: &SheetAddress,
+        contents: String,
+        definitions: &interpreter::Definitions,
+    ) -> AppResult<SheetCell> {
         let interpreted_cell = interpret_cell(&contents)?;
         let (computed_value, formula) = match interpreted_cell {
             InterpretCellResult::Number(n) => (SheetCellComputedValue::Number(n), None),
             InterpretCellResult::Text(s) => (SheetCellComputedValue::Text(s), None),
-            InterpretCellResult::Expr(expr) => {
-                let program = interpreter::compile_with_prelude(&expr)?;
-                let env = interpreter::Env::with_builtins();
-                let result = interpreter::eval(&program, env, &*self)?;
+            InterpretCellResult::Expr(expr, _) => {
+                let program = interpreter::compile_with_prelude(&expr, definitions)?;
                 let references = get_references_for_expr(&expr)?;
-                let computed_value = SheetCellComputedValue::Invalid {
-                    message: "<pending>".to_string(),
-                };
+                let computed_value = SheetCellComputedValue::Error(AppError::new("<pending>"));
                 let formula = SheetFormula {
                     address: address.clone(),
+                    volatile: program.is_volatile(),
                     program,
                     references,
                 };
@@ -190,12 +332,15 @@ impl Sheet {
             }
         };
 
-        let new_cell = SheetCell {
+        Ok(SheetCell {
             computed_value,
             formula,
-            source: contents.clone(),
-        };
+            source: contents,
+        })
+    }
 
+    pub fn set_cell(&mut self, address: &SheetAddress, contents: String) -> AppResult<()> {
+        let new_cell = Self::build_cell(address, contents, &self.definitions)?;
         self.dep_graph = match &new_cell.formula {
             Some(formula) => self.dep_graph.update_node(formula),
             None => self.dep_graph.clear_id(&address),
@@ -204,10 +349,53 @@ impl Sheet {
 
         self.emit_cell_update(&address);
 
+        // Volatile formulas are recalculated on every edit.
+        let mut addresses = vec![address.clone()];
+        addresses.extend(self.volatile_addresses());
+        self.recalculate(Some(address), addresses);
+        Ok(())
+    }
+
+    /// Addresses of the cells with volatile formulas, in order.
+    fn volatile_addresses(&self) -> Vec<SheetAddress> {
+        let mut addresses: Vec<SheetAddress> = self
+            .cells
+            .iter()
+            .filter(|(_, cell)| {
+                cell.formula
+                    .as_ref()
+                    .is_some_and(|formula| formula.volatile)
+            })
+            .map(|(address, _)| address.clone())
+            .collect();
+        addresses.sort_by_key(|address| (address.row, address.col));
+        addresses
+    }
+
+    /// The random seed for evaluating the formula at `address` in the current
+    /// recalculation.
+    fn formula_seed(&self, address: &SheetAddress) -> u64 {
+        // Spread the inputs over the bits; the evaluator's generator mixes them further.
+        self.random_seed
+            ^ self.recalculation_count.wrapping_mul(0x9E37_79B9_7F4A_7C15)
+            ^ (address.row as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F)
+            ^ (address.col as u64).wrapping_mul(0x1656_67B1_9E37_79F9)
+    }
+
+    /// Recalculate the formulas at `addresses`, and then everything that depends on
+    /// them. `edited` is the cell whose change prompted it, if any.
+    fn recalculate(&mut self, edited: Option<&SheetAddress>, addresses: Vec<SheetAddress>) {
+        self.recalculation_count += 1;
+        let recalculation_start = self.profiler.as_ref().map(|profiler| profiler.now());
+        let mut cell_profiles = Vec::new();
         let mut handled_addresses = HashSet::new();
-        let mut computation_queue: VecDeque<SheetAddress> = VecDeque::from([address.clone()]);
+        // Each address is queued along with the cell whose change queued it.
+        let mut computation_queue: VecDeque<(SheetAddress, Option<SheetAddress>)> = addresses
+            .into_iter()
+            .map(|address| (address, None))
+            .collect();
         let mut num_iters = 0;
-        while let Some(address_to_compute) = computation_queue.pop_front() {
+        while let Some((address_to_compute, triggered_by)) = computation_queue.pop_front() {
             num_iters += 1;
             if num_iters > MAX_ITERS {
                 break;
@@ -221,15 +409,40 @@ impl Sheet {
 
             if let Some(cell) = self.cells.get(&address_to_compute) {
                 if let Some(formula) = &cell.formula {
-                    let env = interpreter::Env::with_builtins();
-                    let result = interpreter::eval(&formula.program, env, &*self)?;
-                    {
-                        self.cells
-                            .get_mut(&address_to_compute)
-                            .unwrap()
-                            .computed_value =
-                            SheetCellComputedValue::from_interpreter_value(result);
+                    let env = self.definitions.env();
+                    let start = self.profiler.as_ref().map(|profiler| profiler.now());
+                    let resolver = CountingResolver {
+                        sheet: &*self,
+                        cells_read: Cell::new(0),
+                        random_seed: self.formula_seed(&address_to_compute),
+                    };
+                    // Runtime errors (including stack overflows) are shown in the cell rather
+                    // than aborting the whole update.
+                    let (result, stats) = interpreter::eval_with_stats(
+                        &formula.program,
+                        env,
+                        &resolver,
+                        self.eval_limits,
+                    );
+                    if let (Some(profiler), Some(start_ms)) = (&self.profiler, start) {
+                        cell_profiles.push(CellProfile {
+                            address: address_to_compute.clone(),
+                            triggered_by: triggered_by.clone(),
+                            steps: stats.steps,
+                            cells_read: resolver.cells_read.get(),
+                            start_ms,
+                            duration_ms: profiler.now() - start_ms,
+                            failed: result.is_err(),
+                        });
                     }
+                    let computed_value = match result {
+                        Ok(result) => SheetCellComputedValue::from_interpreter_value(result),
+                        Err(err) => SheetCellComputedValue::Error(err),
+                    };
+                    self.cells
+                        .get_mut(&address_to_compute)
+                        .unwrap()
+                        .computed_value = computed_value;
                     self.emit_cell_update(&address_to_compute);
                 }
             }
@@ -238,13 +451,187 @@ impl Sheet {
                 .dep_graph
                 .get_direct_dependents(address_to_compute.clone())
             {
-                computation_queue.push_back(dependent);
+                computation_queue.push_back((dependent, Some(address_to_compute.clone())));
             }
         }
 
+        if let (Some(profiler), Some(start_ms)) = (&mut self.profiler, recalculation_start) {
+            let duration_ms = profiler.now() - start_ms;
+            profiler.record(Recalculation {
+                edited: edited.cloned(),
+                start_ms,
+                duration_ms,
+                cells: cell_profiles,
+            });
+        }
+    }
+
+    /// Recalculate the volatile formulas (and the formulas that depend on them), as
+    /// happens after every edit, e.g. to draw new random numbers.
+    pub fn recalculate_volatile(&mut self) {
+        let addresses = self.volatile_addresses();
+        self.recalculate(None, addresses);
+    }
+
+    /// Copy the cell at `from` to `to`. References in a formula are relative, so copying
+    /// `=A1+1` one row down gives `=A2 + 1`.
+    pub fn copy_cell(&mut self, from: &SheetAddress, to: &SheetAddress) -> AppResult<()> {
+        let source = self.get_cell(from).source;
+        let (row_offset, col_offset) = (to.row - from.row, to.col - from.col);
+        let contents = shift_cell_contents(&source, &|address| SheetAddress {
+            row: address.row + row_offset,
+            col: address.col + col_offset,
+        })?;
+        self.set_cell(to, contents)
+    }
+
+    /// Insert an empty row before `row`, moving the cells below it down and updating
+    /// every reference to them.
+    pub fn insert_row(&mut self, row: i32) -> AppResult<()> {
+        let shift = |address: &SheetAddress| SheetAddress {
+            row: if address.row >= row {
+                address.row + 1
+            } else {
+                address.row
+            },
+            col: address.col,
+        };
+        let mut old_addresses: Vec<SheetAddress> = self.cells.keys().cloned().collect();
+        old_addresses.sort_by_key(|address| (address.row, address.col));
+
+        // Build every moved cell before changing anything, so that a failure leaves the
+        // sheet as it was.
+        let mut cells = HashMap::new();
+        let mut dep_graph = DepGraph::empty();
+        for address in &old_addresses {
+            let new_address = shift(address);
+            let contents = shift_cell_contents(&self.cells[address].source, &shift)?;
+            let cell = Self::build_cell(&new_address, contents, &self.definitions)?;
+            if let Some(formula) = &cell.formula {
+                dep_graph = dep_graph.update_node(formula);
+            }
+            cells.insert(new_address, cell);
+        }
+        self.cells = cells;
+        self.dep_graph = dep_graph;
+
+        let new_addresses: Vec<SheetAddress> = old_addresses.iter().map(shift).collect();
+        for address in old_addresses.iter().chain(&new_addresses) {
+            self.emit_cell_update(address);
+        }
+        // Every formula moved, so recalculate them all at once, in a fixed order.
+        let formula_addresses = new_addresses
+            .into_iter()
+            .filter(|address| self.cells[address].formula.is_some())
+            .collect();
+        self.recalculate(None, formula_addresses);
         Ok(())
     }
 
+    /// Replace the workbook-level definitions and recompute every formula against them.
+    /// If the definitions don't compile (or fail when evaluated), or a formula doesn't
+    /// compile against them, the current ones are kept.
+    pub fn set_workbook_definitions(&mut self, source: String) -> AppResult<()> {
+        // Definitions are a sequence of statements, just like the prelude.
+        let statements = parse_program(&source)?;
+        // They're evaluated once rather than with every formula, so formulas using them
+        // wouldn't be recalculated when a cell they referred to changed.
+        for statement in &statements {
+            if let Some(address) = get_references_for_expr(statement)?.first() {
+                return Err(AppError::with_kind(
+                    ErrorKind::Reference,
+                    format!(
+                        "Workbook definitions can't refer to cells, like {}",
+                        address
+                    ),
+                ));
+            }
+        }
+        let definitions = interpreter::Definitions::load(&statements)?;
+
+        // Recompile every formula before changing anything, so that a failure leaves the
+        // sheet as it was.
+        let mut addresses: Vec<SheetAddress> = self
+            .cells
+            .iter()
+            .filter(|(_, cell)| cell.formula.is_some())
+            .map(|(address, _)| address.clone())
+            .collect();
+        addresses.sort_by_key(|address| (address.row, address.col));
+        let mut cells = Vec::new();
+        for address in &addresses {
+            let contents = self.cells[address].source.clone();
+            cells.push(Self::build_cell(address, contents, &definitions)?);
+        }
+        self.definitions = definitions;
+        self.definitions_source = source;
+        for (address, cell) in addresses.iter().zip(cells) {
+            self.cells.insert(address.clone(), cell);
+            self.emit_cell_update(address);
+        }
+        self.recalculate(None, addresses);
+        Ok(())
+    }
+
+    pub fn workbook_definitions(&self) -> &interpreter::Definitions {
+        &self.definitions
+    }
+
+    pub fn workbook_definitions_source(&self) -> &str {
+        &self.definitions_source
+    }
+
+    /// Set the per-cell evaluation limits and recompute every formula with them.
+    pub fn set_eval_limits(&mut self, limits: interpreter::EvalLimits) -> AppResult<()> {
+        self.eval_limits = limits;
+        self.recompute_all_formulas()
+    }
+
+    pub fn eval_limits(&self) -> interpreter::EvalLimits {
+        self.eval_limits
+    }
+
+    /// Set the seed for random numbers and recompute every formula with it. Setting
+    /// the same seed again starts the same sequence of numbers again.
+    pub fn set_random_seed(&mut self, seed: u64) -> AppResult<()> {
+        self.random_seed = seed;
+        self.recalculation_count = 0;
+        self.recompute_all_formulas()
+    }
+
+    pub fn random_seed(&self) -> u64 {
+        self.random_seed
+    }
+
+    /// Set how formulas round (see `interpreter::RoundingMode`) and recompute every
+    /// formula with it.
+    pub fn set_rounding_mode(&mut self, mode: interpreter::RoundingMode) -> AppResult<()> {
+        self.rounding_mode = mode;
+        self.recompute_all_formulas()
+    }
+
+    pub fn rounding_mode(&self) -> interpreter::RoundingMode {
+        self.rounding_mode
+    }
+
+    fn recompute_all_formulas(&mut self) -> AppResult<()> {
+        let mut formula_cells: Vec<(SheetAddress, String)> = self
+            .cells
+            .iter()
+            .filter(|(_, cell)| cell.formula.is_some())
+            .map(|(address, cell)| (address.clone(), cell.source.clone()))
+            .collect();
+        // In a fixed order, so that random numbers come out the same every time.
+        formula_cells.sort_by_key(|(address, _)| (address.row, address.col));
+        let mut first_error = None;
+        for (address, source) in formula_cells {
+            if let Err(err) = self.set_cell(&address, source) {
+                first_error.get_or_insert(err);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
     fn emit_cell_update(&self, address: &SheetAddress) {
         if let Some(signal) = self.signals.get(address) {
             signal.emit();
@@ -288,15 +675,94 @@ impl Sheet {
         }
     }
 
+    /// Start recording how long recalculations take, discarding any earlier recording.
+    pub fn start_profiling(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    /// Stop recording, returning what was recorded.
+    pub fn stop_profiling(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    /// The recording so far, if profiling.
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
     pub fn debug_graphviz(&self) -> String {
         self.dep_graph.to_graphviz()
     }
 
+    /// Compile `contents` as it would be if entered in a cell.
+    fn compile_formula(&self, contents: &str) -> AppResult<interpreter::Program> {
+        match interpret_cell(contents)? {
+            InterpretCellResult::Expr(expr, _) => {
+                interpreter::compile_with_prelude(&expr, &self.definitions)
+            }
+            _ => Err(AppError::new("Not a formula")),
+        }
+    }
+
+    /// The compiled formula in the cell at `address`.
+    fn cell_program(&self, address: &SheetAddress) -> AppResult<&interpreter::Program> {
+        match self
+            .cells
+            .get(address)
+            .and_then(|cell| cell.formula.as_ref())
+        {
+            Some(formula) => Ok(&formula.program),
+            None => Err(AppError::new("The cell doesn't contain a formula")),
+        }
+    }
+
+    /// A listing of the code that `contents` compiles to when entered in a cell.
+    pub fn debug_disassemble(&self, contents: &str) -> AppResult<String> {
+        Ok(interpreter::disassemble(&self.compile_formula(contents)?))
+    }
+
+    /// A listing of the compiled formula in the cell at `address`.
+    pub fn debug_disassemble_cell(&self, address: &SheetAddress) -> AppResult<String> {
+        Ok(interpreter::disassemble(self.cell_program(address)?))
+    }
+
+    /// Start stepping through the evaluation of `contents` as if entered in a cell. The
+    /// session sees cells as they are now.
+    pub fn debug_session(&self, contents: &str) -> AppResult<interpreter::DebugSession> {
+        let program = self.compile_formula(contents)?;
+        Ok(interpreter::DebugSession::new(
+            &program,
+            &self.definitions,
+            self,
+            self.eval_limits,
+        ))
+    }
+
+    /// Start stepping through the evaluation of the formula in the cell at `address`.
+    pub fn debug_session_for_cell(
+        &self,
+        address: &SheetAddress,
+    ) -> AppResult<interpreter::DebugSession> {
+        Ok(interpreter::DebugSession::new(
+            self.cell_program(address)?,
+            &self.definitions,
+            self,
+            self.eval_limits,
+        ))
+    }
+
     pub fn new() -> Self {
         Self {
             cells: HashMap::new(),
             dep_graph: DepGraph::empty(),
             signals: HashMap::new(),
+            definitions: interpreter::Definitions::default(),
+            definitions_source: String::new(),
+            eval_limits: interpreter::EvalLimits::default(),
+            random_seed: 0,
+            rounding_mode: interpreter::RoundingMode::default(),
+            recalculation_count: 0,
+            profiler: None,
         }
     }
 }
@@ -307,10 +773,8 @@ mod tests {
 
     #[test]
     fn test_get_references_for_expr() {
-        let result = get_references_for_expr(&Expr::List(vec![
-            Expr::Keyword("a1".to_string()),
-            Expr::Keyword("b4".to_string()),
-        ]));
+        let result =
+            get_references_for_expr(&Expr::list(vec![Expr::keyword("a1"), Expr::keyword("b4")]));
         assert_eq!(
             result,
             Ok(vec![
@@ -319,7 +783,598 @@ mod tests {
             ])
         );
 
-        let result = get_references_for_expr(&Expr::Keyword("a1".to_string()));
+        let result = get_references_for_expr(&Expr::keyword("a1"));
         assert_eq!(result, Ok(vec![SheetAddress { col: 0, row: 0 },]));
+
+        // Keywords that aren't references are reported where they are in the formula.
+        let mut sheet = Sheet::new();
+        let err = sheet
+            .set_cell(
+                &SheetAddress { row: 0, col: 0 },
+                "#=(+ :a1 :total)".to_string(),
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Reference);
+        assert_eq!(err.span(), Some(Span { start: 9, end: 15 }));
+        assert!(
+            err.message().contains("expected a cell reference"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_number_precision() {
+        let mut sheet = Sheet::new();
+        let a1 = SheetAddress { row: 0, col: 0 };
+        let a2 = SheetAddress { row: 1, col: 0 };
+        let a3 = SheetAddress { row: 2, col: 0 };
+
+        sheet.set_cell(&a1, "16777217".to_string()).unwrap();
+        assert_eq!(sheet.get_cell(&a1).value.to_string(), "16777217");
+
+        sheet.set_cell(&a1, "1234567.89".to_string()).unwrap();
+        sheet.set_cell(&a2, "0.01".to_string()).unwrap();
+        sheet.set_cell(&a3, "#=(+ :a1 :a2)".to_string()).unwrap();
+        assert_eq!(sheet.get_cell(&a3).value.to_string(), "1234567.9");
+
+        sheet.set_cell(&a3, "#=(* :a1 100)".to_string()).unwrap();
+        assert_eq!(sheet.get_cell(&a3).value.to_string(), "123456789");
+
+        sheet.set_cell(&a1, "19.99".to_string()).unwrap();
+        sheet.set_cell(&a3, "#=(* :a1 3)".to_string()).unwrap();
+        assert_eq!(sheet.get_cell(&a3).value.to_string(), "59.97");
+    }
+
+    #[test]
+    fn test_decimal_mode() {
+        let mut sheet = Sheet::new();
+        let b3 = SheetAddress { row: 2, col: 1 };
+        for (row, amount) in ["0.1", "0.2", "10.05"].iter().enumerate() {
+            sheet
+                .set_cell(
+                    &SheetAddress {
+                        row: row as i32,
+                        col: 0,
+                    },
+                    amount.to_string(),
+                )
+                .unwrap();
+        }
+
+        sheet.set_cell(&b3, "#=(sum :a1-a3)".to_string()).unwrap();
+        assert_eq!(sheet.get_cell(&b3).value.to_string(), "10.35");
+        sheet
+            .set_cell(&b3, "#=(sum (dec :a1-a3))".to_string())
+            .unwrap();
+        assert_eq!(sheet.get_cell(&b3).value.to_string(), "10.35");
+        sheet
+            .set_cell(&b3, "#=(+ (dec :a1) (dec :a2))".to_string())
+            .unwrap();
+        assert_eq!(sheet.get_cell(&b3).value.to_string(), "0.3");
+        sheet
+            .set_cell(
+                &b3,
+                "#=(round (* (dec :a3) 0.5) 2 \"half-even\")".to_string(),
+            )
+            .unwrap();
+        assert_eq!(sheet.get_cell(&b3).value.to_string(), "5.02");
+
+        // Without a mode of its own, `round` uses the sheet's, and changing it
+        // recalculates.
+        sheet
+            .set_cell(&b3, "#=(round (* (dec :a3) 0.5) 2)".to_string())
+            .unwrap();
+        assert_eq!(sheet.get_cell(&b3).value.to_string(), "5.03");
+        sheet
+            .set_rounding_mode(interpreter::RoundingMode::HalfEven)
+            .unwrap();
+        assert_eq!(sheet.get_cell(&b3).value.to_string(), "5.02");
+        assert_eq!(sheet.rounding_mode(), interpreter::RoundingMode::HalfEven);
+    }
+
+    #[test]
+    fn test_infix_formulas() {
+        let mut sheet = Sheet::new();
+        let a1 = SheetAddress { row: 0, col: 0 };
+        let a2 = SheetAddress { row: 1, col: 0 };
+        let b1 = SheetAddress { row: 0, col: 1 };
+        let b2 = SheetAddress { row: 1, col: 1 };
+        sheet.set_cell(&a1, "3".to_string()).unwrap();
+        sheet.set_cell(&a2, "4".to_string()).unwrap();
+        sheet
+            .set_cell(&b1, "=A1*2 + SUM(A1:A2) / 7".to_string())
+            .unwrap();
+        assert_eq!(sheet.get_cell(&b1).value.to_string(), "7");
+        sheet
+            .set_cell(&b2, r#"=IF(B1 >= 7, "big", "small")"#.to_string())
+            .unwrap();
+        assert_eq!(sheet.get_cell(&b2).value.to_string(), "big");
+
+        // Dependencies are tracked just like for Lisp formulas.
+        sheet.set_cell(&a2, "-3".to_string()).unwrap();
+        assert_eq!(sheet.get_cell(&b1).value.to_string(), "6");
+        assert_eq!(sheet.get_cell(&b2).value.to_string(), "small");
+
+        let err = sheet.set_cell(&b1, "=(+ 1 2)".to_string()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Parse);
+        // Lisp formulas need the `#=` prefix; `=(+ 1 2)` reads as the infix `(+1 2)`.
+        assert_eq!(err.span().unwrap().start, 6);
+    }
+
+    #[test]
+    fn test_ranges_are_vectors() {
+        let mut sheet = Sheet::new();
+        let cell = |name: &str| SheetRange::parse(name).unwrap().start;
+        sheet.set_cell(&cell("a1"), "1".to_string()).unwrap();
+        sheet.set_cell(&cell("a2"), "2".to_string()).unwrap();
+        sheet.set_cell(&cell("b2"), "20".to_string()).unwrap();
+        sheet
+            .set_cell(&cell("c1"), "#=(get :a1-a2 1)".to_string())
+            .unwrap();
+        assert_eq!(sheet.get_cell(&cell("c1")).value.to_string(), "2");
+        sheet
+            .set_cell(&cell("c2"), "#=(get (get :a1-b2 1) 1)".to_string())
+            .unwrap();
+        assert_eq!(sheet.get_cell(&cell("c2")).value.to_string(), "20");
+        sheet
+            .set_cell(
+                &cell("c3"),
+                r#"#=(get {"x" (sum :a1-a2) "y" 0} "x")"#.to_string(),
+            )
+            .unwrap();
+        assert_eq!(sheet.get_cell(&cell("c3")).value.to_string(), "3");
+    }
+
+    #[test]
+    fn test_copy_cell() {
+        let mut sheet = Sheet::new();
+        let cell = |name: &str| SheetRange::parse(name).unwrap().start;
+        sheet.set_cell(&cell("a1"), "1".to_string()).unwrap();
+        sheet.set_cell(&cell("a2"), "2".to_string()).unwrap();
+        sheet.set_cell(&cell("b1"), "=A1*10".to_string()).unwrap();
+        sheet.copy_cell(&cell("b1"), &cell("b2")).unwrap();
+        assert_eq!(sheet.get_cell(&cell("b2")).source, "=A2 * 10");
+        assert_eq!(sheet.get_cell(&cell("b2")).value.to_string(), "20");
+
+        sheet
+            .set_cell(&cell("c1"), "#=(sum :a1-a2)".to_string())
+            .unwrap();
+        sheet.copy_cell(&cell("c1"), &cell("d1")).unwrap();
+        assert_eq!(sheet.get_cell(&cell("d1")).source, "#=(sum :b1-b2)");
+        assert_eq!(sheet.get_cell(&cell("d1")).value.to_string(), "30");
+
+        // Formulas without references are copied verbatim.
+        sheet.set_cell(&cell("c2"), "=1+1".to_string()).unwrap();
+        sheet.copy_cell(&cell("c2"), &cell("c3")).unwrap();
+        assert_eq!(sheet.get_cell(&cell("c3")).source, "=1+1");
+
+        // `A2` moved one column left would be off the sheet.
+        assert_eq!(
+            sheet
+                .copy_cell(&cell("b2"), &cell("a2"))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::Reference
+        );
+    }
+
+    #[test]
+    fn test_insert_row() {
+        let mut sheet = Sheet::new();
+        let cell = |name: &str| SheetRange::parse(name).unwrap().start;
+        sheet.set_cell(&cell("a1"), "1".to_string()).unwrap();
+        sheet.set_cell(&cell("a2"), "2".to_string()).unwrap();
+        sheet
+            .set_cell(&cell("a3"), "=SUM(A1:A2)".to_string())
+            .unwrap();
+        sheet
+            .set_cell(&cell("b1"), "#=(* :a2 10)".to_string())
+            .unwrap();
+
+        sheet.insert_row(1).unwrap();
+        assert_eq!(sheet.get_cell(&cell("a1")).source, "1");
+        assert_eq!(sheet.get_cell(&cell("a2")).source, "");
+        assert_eq!(sheet.get_cell(&cell("a3")).source, "2");
+        assert_eq!(sheet.get_cell(&cell("a4")).source, "=SUM(A1:A3)");
+        assert_eq!(sheet.get_cell(&cell("a4")).value.to_string(), "3");
+        assert_eq!(sheet.get_cell(&cell("b1")).source, "#=(* :a3 10)");
+        assert_eq!(sheet.get_cell(&cell("b1")).value.to_string(), "20");
+
+        // The new row is empty, and filling it in updates the grown range.
+        sheet.set_cell(&cell("a2"), "4".to_string()).unwrap();
+        assert_eq!(sheet.get_cell(&cell("a4")).value.to_string(), "7");
+
+        // Every formula is recalculated once, in the same order whatever the order of
+        // the cells in memory, so random numbers come out the same.
+        let new_sheet = || {
+            let mut sheet = Sheet::new();
+            for (name, contents) in &[("a1", "#=(rand)"), ("b2", "#=(+ :a1 (rand))"), ("a3", "5")] {
+                sheet.set_cell(&cell(name), contents.to_string()).unwrap();
+            }
+            sheet
+        };
+        let (mut first, mut second) = (new_sheet(), new_sheet());
+        let recalculations = first.recalculation_count;
+        first.insert_row(0).unwrap();
+        second.insert_row(0).unwrap();
+        assert_eq!(first.recalculation_count, recalculations + 1);
+        for name in &["a2", "b3", "a4"] {
+            assert_eq!(
+                first.get_cell(&cell(name)).value.to_string(),
+                second.get_cell(&cell(name)).value.to_string()
+            );
+        }
+        assert_eq!(first.get_cell(&cell("a1")).source, "");
+    }
+
+    #[test]
+    fn test_workbook_definitions() {
+        let mut sheet = Sheet::new();
+        let a1 = SheetAddress { row: 0, col: 0 };
+        let a2 = SheetAddress { row: 1, col: 0 };
+        sheet.set_cell(&a1, "5".to_string()).unwrap();
+        sheet.set_cell(&a2, "#=(double :a1)".to_string()).unwrap();
+        assert!(sheet
+            .get_cell(&a2)
+            .value
+            .to_string()
+            .starts_with("!INVALID: "));
+
+        sheet
+            .set_workbook_definitions(
+                "(defmacro double (x) `(* 2 ,x)) (defun triple (x) (* 3 x))".to_string(),
+            )
+            .unwrap();
+        sheet.set_cell(&a2, "#=(double :a1)".to_string()).unwrap();
+        assert_eq!(sheet.get_cell(&a2).value.to_string(), "10");
+
+        // Changing the definitions recomputes existing formulas.
+        sheet
+            .set_workbook_definitions("(defmacro double (x) `(+ ,x ,x 1))".to_string())
+            .unwrap();
+        assert_eq!(sheet.get_cell(&a2).value.to_string(), "11");
+
+        // Macros can call functions from the definitions while expanding.
+        sheet
+            .set_workbook_definitions(
+                "(defun plus-one (form) `(+ 1 ,form)) (defmacro double (x) (plus-one `(* 2 ,x)))"
+                    .to_string(),
+            )
+            .unwrap();
+        assert_eq!(sheet.get_cell(&a2).value.to_string(), "11");
+
+        // Broken definitions are rejected, keeping the ones in use.
+        for source in &["(defun", "(defmacro (x) x)", "(car (list))"] {
+            assert!(sheet.set_workbook_definitions(source.to_string()).is_err());
+            assert!(sheet
+                .workbook_definitions_source()
+                .starts_with("(defun plus-one"));
+            sheet.set_cell(&a2, "#=(double :a1)".to_string()).unwrap();
+            assert_eq!(sheet.get_cell(&a2).value.to_string(), "11");
+        }
+
+        // So are definitions that refer to cells, as formulas using them wouldn't be
+        // recalculated when the cells change.
+        for source in &["(def rate :a1)", "(defun rate () (+ 1 :a1))"] {
+            let err = sheet
+                .set_workbook_definitions(source.to_string())
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Reference);
+        }
+
+        // Definitions that call volatile built-ins make the formulas using them volatile.
+        sheet
+            .set_workbook_definitions("(defun roll () (randbetween 1 1000000))".to_string())
+            .unwrap();
+        sheet.set_cell(&a2, "#=(roll)".to_string()).unwrap();
+        let roll = sheet.get_cell(&a2).value.to_string();
+        sheet.set_cell(&a1, "6".to_string()).unwrap();
+        assert_ne!(sheet.get_cell(&a2).value.to_string(), roll);
+    }
+
+    #[test]
+    fn test_workbook_definitions_rollback() {
+        let mut sheet = Sheet::new();
+        let a1 = SheetAddress { row: 0, col: 0 };
+        let b1 = SheetAddress { row: 0, col: 1 };
+        sheet.set_cell(&a1, "#=(f 1)".to_string()).unwrap();
+        sheet.set_cell(&b1, "#=(g 1)".to_string()).unwrap();
+        let values = |sheet: &Sheet| {
+            vec![
+                sheet.get_cell(&a1).value.to_string(),
+                sheet.get_cell(&b1).value.to_string(),
+            ]
+        };
+        let before = values(&sheet);
+
+        // `f` fails to expand in A1, so B1 mustn't be recompiled against the definitions
+        // either.
+        assert!(sheet
+            .set_workbook_definitions("(defmacro f (x) (car 1))\n(defun g (x) 2)".to_string())
+            .is_err());
+        assert_eq!(sheet.workbook_definitions_source(), "");
+        assert_eq!(values(&sheet), before);
+        sheet.set_cell(&b1, "#=(g 1)".to_string()).unwrap();
+        assert_eq!(values(&sheet), before);
+    }
+
+    #[test]
+    fn test_runtime_error_in_cell() {
+        let mut sheet = Sheet::new();
+        let a1 = SheetAddress { row: 0, col: 0 };
+        sheet
+            .set_cell(&a1, "#=(begin (defun f (n) (+ 1 (f n))) (f 1))".to_string())
+            .unwrap();
+        assert!(sheet
+            .get_cell(&a1)
+            .value
+            .to_string()
+            .starts_with("!OVERFLOW: Stack overflow"));
+
+        // The error points at the part of the cell that failed. Errors in workbook
+        // definitions point at the call to them, since the cell shows only its own text.
+        sheet
+            .set_workbook_definitions("(defun first (xs) (car xs))".to_string())
+            .unwrap();
+        let mut failing_source = |contents: &str| {
+            sheet.set_cell(&a1, contents.to_string()).unwrap();
+            match sheet.get_cell(&a1).value {
+                SheetCellComputedValue::Error(err) => {
+                    let span = err.span().unwrap();
+                    contents[span.start..span.end].to_string()
+                }
+                value => panic!("Expected an error, got {:?}", value),
+            }
+        };
+        assert_eq!(failing_source("#=(+ 1 (car 2))"), "(car 2)");
+        assert_eq!(failing_source("#=(+ 1 (first (list)))"), "(first (list))");
+        assert_eq!(failing_source("=1 + first(2)"), "first(2)");
+    }
+
+    #[test]
+    fn test_error_stack_trace() {
+        let mut sheet = Sheet::new();
+        let a1 = SheetAddress { row: 0, col: 0 };
+        sheet
+            .set_cell(
+                &a1,
+                "#=(begin (defun second (lst) (car (cdr lst))) (map second '((1 2) (3))))"
+                    .to_string(),
+            )
+            .unwrap();
+        let value = sheet.get_cell(&a1).value;
+        assert_eq!(
+            value.to_string(),
+            "!INVALID: Cannot take the car of an empty list \
+             (in car, called from second, called from map)"
+        );
+        match value {
+            SheetCellComputedValue::Error(err) => {
+                assert_eq!(err.kind(), ErrorKind::General);
+                assert_eq!(err.message(), "Cannot take the car of an empty list");
+                assert_eq!(err.stack_trace().len(), 3);
+            }
+            _ => panic!("Expected an error"),
+        }
+    }
+
+    #[test]
+    fn test_caught_errors() {
+        let mut sheet = Sheet::new();
+        let a1 = SheetAddress { row: 0, col: 0 };
+        sheet
+            .set_cell(
+                &a1,
+                "#=(try (car (list)) (catch e (error-kind e)))".to_string(),
+            )
+            .unwrap();
+        assert_eq!(sheet.get_cell(&a1).value.to_string(), "general");
+        // An error value returned by a formula is shown as the cell's error.
+        sheet
+            .set_cell(&a1, "#=(try (error \"Bad input\") (catch e e))".to_string())
+            .unwrap();
+        match sheet.get_cell(&a1).value {
+            SheetCellComputedValue::Error(err) => {
+                assert_eq!((err.kind(), err.message()), (ErrorKind::User, "Bad input"));
+            }
+            value => panic!("Expected an error, got {:?}", value),
+        }
+    }
+
+    #[test]
+    fn test_eval_limits() {
+        let mut sheet = Sheet::new();
+        let a1 = SheetAddress { row: 0, col: 0 };
+        sheet
+            .set_eval_limits(interpreter::EvalLimits {
+                max_steps: 1_000,
+                max_call_depth: 100,
+            })
+            .unwrap();
+        sheet
+            .set_cell(&a1, "#=(begin (defun f (x) (f x)) (f 1))".to_string())
+            .unwrap();
+        assert!(matches!(
+            sheet.get_cell(&a1).value,
+            SheetCellComputedValue::Error(err) if err.kind() == ErrorKind::Timeout
+        ));
+        assert!(sheet
+            .get_cell(&a1)
+            .value
+            .to_string()
+            .starts_with("!TIMEOUT: "));
+
+        let a2 = SheetAddress { row: 1, col: 0 };
+        sheet
+            .set_cell(&a2, "#=(begin (defun f (n) (+ 1 (f n))) (f 1))".to_string())
+            .unwrap();
+        assert!(matches!(
+            sheet.get_cell(&a2).value,
+            SheetCellComputedValue::Error(err) if err.kind() == ErrorKind::StackOverflow
+        ));
+        assert!(sheet
+            .get_cell(&a2)
+            .value
+            .to_string()
+            .starts_with("!OVERFLOW: Stack overflow: exceeded the maximum call depth of 100"));
+    }
+
+    #[test]
+    fn test_volatile_formulas() {
+        let a1 = SheetAddress { row: 0, col: 0 };
+        let b1 = SheetAddress { row: 0, col: 1 };
+        let c1 = SheetAddress { row: 0, col: 2 };
+        let new_sheet = || {
+            let mut sheet = Sheet::new();
+            sheet.set_random_seed(7).unwrap();
+            sheet.set_cell(&a1, "#=(rand)".to_string()).unwrap();
+            sheet.set_cell(&b1, "=A1 * 2".to_string()).unwrap();
+            sheet
+                .set_cell(&c1, "=RANDBETWEEN(1, 6)".to_string())
+                .unwrap();
+            sheet
+        };
+        let value =
+            |sheet: &Sheet, address: &SheetAddress| sheet.get_cell(address).value.to_string();
+
+        let mut sheet = new_sheet();
+        assert!(sheet.cells[&a1].formula.as_ref().unwrap().volatile);
+        assert!(!sheet.cells[&b1].formula.as_ref().unwrap().volatile);
+        // The same seed and edits give the same numbers.
+        let other = new_sheet();
+        for address in &[&a1, &b1, &c1] {
+            assert_eq!(value(&sheet, address), value(&other, address));
+        }
+
+        let before = value(&sheet, &a1);
+        sheet.recalculate_volatile();
+        let after = value(&sheet, &a1);
+        assert_ne!(before, after);
+        let a1_value: f64 = after.parse().unwrap();
+        assert!((0.0..1.0).contains(&a1_value));
+        let b1_value: f64 = value(&sheet, &b1).parse().unwrap();
+        assert!((b1_value - a1_value * 2.0).abs() < 1e-9);
+
+        // Editing any cell recalculates them too.
+        sheet
+            .set_cell(&SheetAddress { row: 5, col: 5 }, "1".to_string())
+            .unwrap();
+        assert_ne!(value(&sheet, &a1), after);
+        for _ in 0..50 {
+            sheet.recalculate_volatile();
+            let roll: f64 = value(&sheet, &c1).parse().unwrap();
+            assert!((1.0..=6.0).contains(&roll) && roll.fract() == 0.0);
+        }
+
+        // Resetting the seed starts the sequence again.
+        sheet.set_random_seed(7).unwrap();
+        let mut other = new_sheet();
+        other.set_random_seed(7).unwrap();
+        assert_eq!(value(&sheet, &a1), value(&other, &a1));
+        assert_eq!(sheet.random_seed(), 7);
+    }
+
+    #[test]
+    fn test_debug_disassemble() {
+        let mut sheet = Sheet::new();
+        let a1 = SheetAddress { row: 0, col: 0 };
+        sheet.set_cell(&a1, "=B1 * 2".to_string()).unwrap();
+        let listing = sheet.debug_disassemble_cell(&a1).unwrap();
+        assert!(listing.contains("LoadKeyword :b1"), "{}", listing);
+        assert_eq!(sheet.debug_disassemble("=B1 * 2").unwrap(), listing);
+        // Constant parts of a formula are computed when it's compiled.
+        assert_eq!(
+            sheet.debug_disassemble("#=(+ 1 2)").unwrap(),
+            "0  LoadConst Number(3)\n"
+        );
+
+        assert!(sheet.debug_disassemble("text").is_err());
+        sheet.set_cell(&a1, "5".to_string()).unwrap();
+        assert!(sheet.debug_disassemble_cell(&a1).is_err());
+    }
+
+    #[test]
+    fn test_debug_session() {
+        let mut sheet = Sheet::new();
+        let a1 = SheetAddress { row: 0, col: 0 };
+        let b1 = SheetAddress { row: 0, col: 1 };
+        sheet.set_cell(&a1, "5".to_string()).unwrap();
+        sheet.set_cell(&b1, "=A1 * 2".to_string()).unwrap();
+        let mut session = sheet.debug_session_for_cell(&b1).unwrap();
+        // Changes after the session starts don't affect it.
+        sheet.set_cell(&a1, "6".to_string()).unwrap();
+        session.step_into();
+        session.step_into();
+        match session.state() {
+            interpreter::DebugState::Paused(pause) => {
+                assert_eq!(pause.frames[0].stack[1], interpreter::Value::Number(5.0));
+            }
+            _ => panic!("Expected to be paused"),
+        }
+        session.resume();
+        assert!(matches!(
+            session.state(),
+            interpreter::DebugState::Finished(Ok(interpreter::Value::Number(n))) if *n == 10.0
+        ));
+        assert!(sheet.debug_session_for_cell(&a1).is_err());
+        assert!(sheet.debug_session("=1 +").is_err());
+    }
+
+    #[test]
+    fn test_profiling() {
+        let mut sheet = Sheet::new();
+        let a1 = SheetAddress { row: 0, col: 0 };
+        sheet.set_cell(&a1, "1".to_string()).unwrap();
+        sheet
+            .set_cell(&SheetAddress { row: 0, col: 1 }, "=A1 + 1".to_string())
+            .unwrap();
+        sheet
+            .set_cell(&SheetAddress { row: 0, col: 2 }, "=SUM(A1:B1)".to_string())
+            .unwrap();
+        // Nothing is recorded until profiling starts.
+        assert!(sheet.profiler().is_none());
+
+        sheet.start_profiling();
+        sheet.set_cell(&a1, "2".to_string()).unwrap();
+        let profiler = sheet.stop_profiling().unwrap();
+        let recalculations = profiler.recalculations();
+        assert_eq!(recalculations.len(), 1);
+        let recalculation = &recalculations[0];
+        assert_eq!(recalculation.edited, Some(a1.clone()));
+        let mut cells: Vec<String> = recalculation
+            .cells
+            .iter()
+            .map(|cell| cell.address.to_string())
+            .collect();
+        cells.sort();
+        assert_eq!(cells, vec!["B1", "C1"]);
+        let c1 = recalculation
+            .cells
+            .iter()
+            .find(|cell| cell.address.col == 2)
+            .unwrap();
+        assert_eq!(c1.cells_read, 2);
+        assert!(c1.steps > 0);
+        assert!(!c1.failed);
+        // C1 was reached directly from A1, before B1 changed.
+        assert_eq!(
+            recalculation.chain(c1),
+            vec![a1.clone(), SheetAddress { row: 0, col: 2 }]
+        );
+        assert!(profiler.report().contains("A1 > B1"));
+
+        sheet.set_cell(&a1, "3".to_string()).unwrap();
+        assert!(sheet.profiler().is_none());
+    }
+
+    #[test]
+    fn test_format_number() {
+        assert_eq!(format_number(16777217.0), "16777217");
+        assert_eq!(format_number(9007199254740991.0), "9007199254740991");
+        assert_eq!(format_number(-42.0), "-42");
+        assert_eq!(format_number(0.1 + 0.2), "0.3");
+        assert_eq!(format_number(1234567.89), "1234567.89");
+        assert_eq!(format_number(0.000123), "0.000123");
     }
 }
@@ -3,14 +3,14 @@ use nom::{
     bytes::complete::tag,
     character::complete::{alpha1, digit1},
     combinator::{map, map_res},
-    error::VerboseError,
+    error::context,
     sequence::{separated_pair, tuple},
 };
 
 use super::SheetAddress;
 
-use crate::error::{AppError, AppResult};
-use crate::parser::ParseResult;
+use crate::error::{AppError, AppResult, ErrorKind};
+use crate::parser::{describe_parse_error, ParseResult};
 
 #[derive(Eq, PartialEq, Hash, Clone, Debug)]
 pub struct SheetRange {
@@ -167,7 +167,10 @@ fn parse_sheet_range<'a>(input: &'a str) -> ParseResult<'a, SheetRange> {
         Ok((input, SheetRange { start, end }))
     }
 
-    alt((composite_range, singular_range))(input)
+    context(
+        "a cell reference such as `a1` or `a1-b5`",
+        alt((composite_range, singular_range)),
+    )(input)
 }
 
 impl SheetRange {
@@ -175,12 +178,16 @@ impl SheetRange {
         self.end.row >= self.start.row && self.end.col >= self.start.col
     }
 
+    /// Errors are positioned within `input`.
     pub fn parse(input: &str) -> AppResult<Self> {
-        let range = parse_sheet_range(input)
-            .map_err(|e: nom::Err<VerboseError<&str>>| AppError::new(format!("{:#?}", e)))
-            .map(|(_, result)| result)?;
+        let (_, range) = parse_sheet_range(input).map_err(|err| {
+            let mut err = describe_parse_error(input, err);
+            err.set_kind(ErrorKind::Reference);
+            err
+        })?;
         if !range.is_valid() {
-            return Err(AppError::new(
+            return Err(AppError::with_kind(
+                ErrorKind::Reference,
                 "Invalid range: end must be bottom-right from start",
             ));
         }
@@ -221,6 +228,29 @@ impl SheetRange {
         }
     }
 
+    /// The keyword naming this range, e.g. `a1` or `a1-b5` (the inverse of `parse`).
+    /// Only single-letter columns can be named, since those are all `parse` reads.
+    pub fn to_keyword(&self) -> AppResult<String> {
+        let name = |address: &SheetAddress| {
+            if address.row < 0 || address.col < 0 || address.col >= 26 {
+                return Err(AppError::with_kind(
+                    ErrorKind::Reference,
+                    "Reference is outside the sheet",
+                ));
+            }
+            Ok(format!(
+                "{}{}",
+                (b'a' + address.col as u8) as char,
+                address.row + 1
+            ))
+        };
+        if self.start == self.end {
+            name(&self.start)
+        } else {
+            Ok(format!("{}-{}", name(&self.start)?, name(&self.end)?))
+        }
+    }
+
     /// Return an un-shaped iterator over all addresses in the range.
     pub fn addresses_flat(&self) -> SheetRangeFlatAddresses {
         SheetRangeFlatAddresses::new(self)
@@ -238,6 +268,18 @@ mod tests {
         assert_eq!(parse_sheet_column("c"), Ok(("", 2)));
     }
 
+    #[test]
+    fn test_to_keyword() {
+        for kw in &["a1", "c12", "a1-b5", "z3-z4"] {
+            assert_eq!(SheetRange::parse(kw).unwrap().to_keyword().unwrap(), *kw);
+        }
+        let off_sheet = SheetRange {
+            start: SheetAddress { row: -1, col: 0 },
+            end: SheetAddress { row: 0, col: 0 },
+        };
+        assert!(off_sheet.to_keyword().is_err());
+    }
+
     #[test]
     fn test_parse_sheet_address() {
         assert_eq!(
@@ -271,6 +313,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_errors() {
+        // Positioned at the missing row number.
+        let err = SheetRange::parse("a-b2").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Reference);
+        assert_eq!(
+            err.to_string(),
+            "Parse error at line 1, column 2: expected a cell reference such as `a1` or `a1-b5`\n\
+             a-b2\n \
+             ^"
+        );
+        let err = SheetRange::parse("b2-a1").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Reference);
+    }
+
     #[test]
     fn test_addresses_flat() {
         let range = SheetRange {